@@ -0,0 +1,161 @@
+// Replays access traces against every eviction policy and prints hit ratios,
+// and measures lookups per second as workers contend for the cache. These are
+// ignored by default; run them with
+//
+//     cargo test --release -- --ignored --nocapture hit_ratio
+//     cargo test --release -- --ignored --nocapture contention
+//
+// Set PS3_TRACE to a file of `<path> <size>` lines to replay a real trace,
+// e.g. one pulled out of an access log.
+
+use std::env;
+use std::fs::File;
+use std::io::{ BufRead, BufReader };
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+use cache::{ BoundedCache, ShardedCache, Policy, Weigh };
+
+struct Sized(usize);
+
+impl Weigh for Sized {
+    fn weight(&self) -> usize {
+        self.0
+    }
+}
+
+// Small deterministic generator so runs are comparable.
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+fn replay(policy: Policy, budget: usize, trace: &[(String, usize)]) -> f64 {
+    let mut cache = BoundedCache::new(budget, budget / 4, policy);
+    let mut hits = 0;
+    for &(ref key, size) in trace {
+        if cache.get(key).is_some() {
+            hits += 1;
+        } else {
+            cache.put(key.clone(), Sized(size));
+        }
+    }
+    hits as f64 / trace.len() as f64
+}
+
+fn report(name: &str, budget: usize, trace: &[(String, usize)]) {
+    println!("{} ({} requests, {} byte cache)", name, trace.len(), budget);
+    for policy in Policy::all() {
+        println!("    {:8} {:6.2}%", policy.name(), replay(policy, budget, trace) * 100.0);
+    }
+}
+
+// Skewed traffic over a few hundred small assets: low ids are far more
+// popular than high ones.
+fn skewed(rng: &mut XorShift, requests: usize) -> Vec<(String, usize)> {
+    (0..requests).map(|_| {
+        let spread = rng.below(500) + 1;
+        let id = rng.below(spread);
+        (format!("/asset/{}", id), 2048 + (id as usize % 7) * 1024)
+    }).collect()
+}
+
+// The same traffic interrupted by a crawler walking through large files that
+// are each fetched exactly once.
+fn skewed_with_scan(rng: &mut XorShift, requests: usize) -> Vec<(String, usize)> {
+    let mut trace = skewed(rng, requests);
+    let scan = (0..requests / 4).map(|n| (format!("/archive/{}", n), 64 * 1024));
+    let middle = trace.len() / 2;
+    let tail = trace.split_off(middle);
+    trace.extend(scan);
+    trace.extend(tail);
+    trace
+}
+
+// Popularity that drifts: the hot set moves every few thousand requests.
+fn shifting(rng: &mut XorShift, requests: usize) -> Vec<(String, usize)> {
+    (0..requests).map(|n| {
+        let phase = (n / 5000) as u64 * 200;
+        let spread = rng.below(400) + 1;
+        let id = phase + rng.below(spread);
+        (format!("/page/{}", id), 4096)
+    }).collect()
+}
+
+fn load_trace(path: &str) -> Vec<(String, usize)> {
+    let file = File::open(path).expect("could not open PS3_TRACE");
+    BufReader::new(file).lines()
+        .map(|line| line.expect("could not read PS3_TRACE"))
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next().and_then(|s| s.parse::<usize>().ok())) {
+                (Some(path), Some(size)) => Some((path.to_string(), size)),
+                _ => None
+            }
+        })
+        .collect()
+}
+
+#[test]
+#[ignore]
+fn hit_ratio_by_policy() {
+    let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+    let budget = 256 * 1024;
+
+    report("skewed", budget, &skewed(&mut rng, 100_000));
+    report("skewed with scan", budget, &skewed_with_scan(&mut rng, 100_000));
+    report("shifting", budget, &shifting(&mut rng, 100_000));
+
+    if let Ok(path) = env::var("PS3_TRACE") {
+        let trace = load_trace(&path);
+        let footprint = trace.iter().map(|&(_, size)| size).max().unwrap_or(0) * 64;
+        report(&path, footprint.max(budget), &trace);
+    }
+}
+
+// Each worker looks up skewed keys, filling misses, the way request threads
+// and the accept thread's scheduler share the file cache.
+fn lookups_per_second(cache: Arc<ShardedCache<u64, Sized>>, workers: usize, lookups: usize) -> f64 {
+    let started = Instant::now();
+    let threads = (0..workers).map(|worker| {
+        let cache = cache.clone();
+        thread::spawn(move || {
+            let mut rng = XorShift(0x9e37_79b9_7f4a_7c15 ^ (worker as u64 + 1));
+            for _ in 0..lookups {
+                let spread = rng.below(2000) + 1;
+                let key = rng.below(spread);
+                if !cache.contains(&key) || cache.shard(&key).get(&key).is_none() {
+                    cache.put(key, Sized(1024));
+                }
+            }
+        })
+    }).collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let elapsed = started.elapsed();
+    (workers * lookups) as f64 / (elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9)
+}
+
+#[test]
+#[ignore]
+fn contention_by_workers() {
+    println!("{:>8} {:>14} {:>14}", "workers", "1 shard", "16 shards");
+    for &workers in [1, 2, 4, 8, 16, 32].iter() {
+        let single = Arc::new(ShardedCache::new(1 << 20, 1 << 16, Policy::Lru, 1));
+        let sharded = Arc::new(ShardedCache::new(1 << 20, 1 << 16, Policy::Lru, 16));
+        println!("{:>8} {:>12.0}/s {:>12.0}/s", workers,
+                 lookups_per_second(single, workers, 200_000),
+                 lookups_per_second(sharded, workers, 200_000));
+    }
+}
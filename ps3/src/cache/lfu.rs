@@ -0,0 +1,55 @@
+use std::collections::{ HashMap, BTreeMap };
+use std::hash::Hash;
+use cache::Eviction;
+
+// Evicts whatever has been used the fewest times, oldest first among ties.
+pub struct Lfu<K: Hash + Eq + Clone> {
+    counts: HashMap<K, (u64, u64)>,
+    order: BTreeMap<(u64, u64), K>,
+    tick: u64
+}
+
+impl<K: Hash + Eq + Clone> Lfu<K> {
+    pub fn new() -> Self {
+        Lfu {
+            counts: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0
+        }
+    }
+
+    fn set(&mut self, key: &K, count: u64) {
+        self.removed_key(key);
+        self.tick += 1;
+        self.order.insert((count, self.tick), key.clone());
+        self.counts.insert(key.clone(), (count, self.tick));
+    }
+
+    fn removed_key(&mut self, key: &K) -> Option<u64> {
+        self.counts.remove(key).map(|position| {
+            self.order.remove(&position);
+            position.0
+        })
+    }
+}
+
+impl<K: Hash + Eq + Clone + Send> Eviction<K> for Lfu<K> {
+    fn accessed(&mut self, key: &K, hit: bool) {
+        if hit {
+            let count = self.counts.get(key).map(|&(count, _)| count).unwrap_or(0);
+            self.set(key, count + 1);
+        }
+    }
+
+    fn inserted(&mut self, key: &K, _size: usize) {
+        self.set(key, 1);
+    }
+
+    fn removed(&mut self, key: &K) {
+        self.removed_key(key);
+    }
+
+    fn victim(&mut self) -> Option<K> {
+        self.order.values().next().cloned()
+    }
+}
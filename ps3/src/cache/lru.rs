@@ -0,0 +1,34 @@
+use std::hash::Hash;
+use cache::Eviction;
+use cache::recency::RecencyList;
+
+// Evicts whatever was used longest ago.
+pub struct Lru<K: Hash + Eq + Clone> {
+    entries: RecencyList<K>
+}
+
+impl<K: Hash + Eq + Clone> Lru<K> {
+    pub fn new() -> Self {
+        Lru { entries: RecencyList::new() }
+    }
+}
+
+impl<K: Hash + Eq + Clone + Send> Eviction<K> for Lru<K> {
+    fn accessed(&mut self, key: &K, hit: bool) {
+        if hit {
+            self.entries.touch(key);
+        }
+    }
+
+    fn inserted(&mut self, key: &K, size: usize) {
+        self.entries.push(key.clone(), size);
+    }
+
+    fn removed(&mut self, key: &K) {
+        self.entries.remove(key);
+    }
+
+    fn victim(&mut self) -> Option<K> {
+        self.entries.oldest().cloned()
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+mod recency;
+mod lru;
+mod lfu;
+mod two_queue;
+mod tiny_lfu;
+mod sharded;
+#[cfg(test)]
+mod bench;
+
+use self::lru::Lru;
+use self::lfu::Lfu;
+use self::two_queue::TwoQueue;
+use self::tiny_lfu::TinyLfu;
+pub use self::sharded::ShardedCache;
+
+// Anything stored in the cache reports how many bytes it accounts for.
+pub trait Weigh {
+    fn weight(&self) -> usize;
+}
+
+impl Weigh for Vec<u8> {
+    fn weight(&self) -> usize {
+        self.len()
+    }
+}
+
+// Decides which entry leaves the cache when it is over budget. The cache tells
+// its policy about every lookup, insert and removal; `victim` names the next
+// key to evict, and is called repeatedly until the new entry fits.
+pub trait Eviction<K>: Send {
+    fn accessed(&mut self, key: &K, hit: bool);
+    fn inserted(&mut self, key: &K, size: usize);
+    fn removed(&mut self, key: &K);
+    fn victim(&mut self) -> Option<K>;
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Policy {
+    Lru,
+    Lfu,
+    TwoQueue,
+    TinyLfu
+}
+
+impl Policy {
+    #[cfg(test)]
+    pub fn all() -> Vec<Policy> {
+        vec![Policy::Lru, Policy::Lfu, Policy::TwoQueue, Policy::TinyLfu]
+    }
+
+    pub fn parse(name: &str) -> Result<Policy, String> {
+        match name {
+            "lru" => Ok(Policy::Lru),
+            "lfu" => Ok(Policy::Lfu),
+            "2q" => Ok(Policy::TwoQueue),
+            "tinylfu" => Ok(Policy::TinyLfu),
+            _ => Err(format!("{} is not a cache policy (lru, lfu, 2q, tinylfu)", name))
+        }
+    }
+
+    #[cfg(test)]
+    pub fn name(&self) -> &'static str {
+        match self {
+            &Policy::Lru => "lru",
+            &Policy::Lfu => "lfu",
+            &Policy::TwoQueue => "2q",
+            &Policy::TinyLfu => "tinylfu"
+        }
+    }
+
+    fn build<K: Hash + Eq + Clone + Send + 'static>(&self, budget: usize) -> Box<dyn Eviction<K>> {
+        match self {
+            &Policy::Lru => Box::new(Lru::new()),
+            &Policy::Lfu => Box::new(Lfu::new()),
+            &Policy::TwoQueue => Box::new(TwoQueue::new(budget)),
+            &Policy::TinyLfu => Box::new(TinyLfu::new(budget))
+        }
+    }
+}
+
+// A cache bounded by the total size of its values rather than the number of
+// entries. Values larger than `max_object` are never stored, and inserting
+// evicts entries chosen by the policy until the new value fits.
+pub struct BoundedCache<K: Hash + Eq + Clone, V: Weigh> {
+    entries: HashMap<K, V>,
+    policy: Box<dyn Eviction<K>>,
+    used: usize,
+    budget: usize,
+    max_object: usize
+}
+
+impl<K: Hash + Eq + Clone + Send + 'static, V: Weigh> BoundedCache<K, V> {
+    pub fn new(budget: usize, max_object: usize, policy: Policy) -> Self {
+        BoundedCache {
+            entries: HashMap::new(),
+            policy: policy.build(budget),
+            used: 0,
+            budget: budget,
+            max_object: max_object.min(budget)
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        self.get_mut(key).map(|value| &*value)
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let value = self.entries.get_mut(key);
+        self.policy.accessed(key, value.is_some());
+        value
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    pub fn admits(&self, size: usize) -> bool {
+        size <= self.max_object
+    }
+
+    pub fn put(&mut self, key: K, value: V) -> bool {
+        let size = value.weight();
+        if !self.admits(size) {
+            return false;
+        }
+        self.remove(&key);
+        while self.used + size > self.budget {
+            let evicted = self.policy.victim().and_then(|victim| self.remove(&victim));
+            if evicted.is_none() {
+                break;
+            }
+        }
+        self.used += size;
+        self.policy.inserted(&key, size);
+        self.entries.insert(key, value);
+        true
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.entries.remove(key).inspect(|value| {
+            self.policy.removed(key);
+            self.used -= value.weight();
+        })
+    }
+
+    pub fn remove_where<F: Fn(&K) -> bool>(&mut self, matches: F) {
+        let doomed = self.entries.keys()
+            .filter(|key| matches(key))
+            .cloned()
+            .collect::<Vec<K>>();
+        for key in doomed {
+            self.remove(&key);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        let keys = self.entries.keys().cloned().collect::<Vec<K>>();
+        for key in keys {
+            self.remove(&key);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn used_bytes(&self) -> usize {
+        self.used
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ BoundedCache, Policy };
+
+    #[test]
+    fn stores_and_retrieves_values() {
+        for policy in Policy::all() {
+            let mut cache = BoundedCache::new(100, 50, policy);
+            assert!(cache.put("a", vec![1, 2, 3]));
+
+            assert_eq!(cache.get(&"a"), Some(&vec![1, 2, 3]));
+            assert_eq!(cache.get(&"b"), None);
+            assert_eq!(cache.used_bytes(), 3);
+        }
+    }
+
+    #[test]
+    fn rejects_values_over_object_ceiling() {
+        let mut cache = BoundedCache::new(100, 10, Policy::Lru);
+
+        assert!(!cache.put("big", vec![0; 11]));
+        assert!(cache.put("fits", vec![0; 10]));
+        assert!(!cache.contains(&"big"));
+        assert_eq!(cache.used_bytes(), 10);
+    }
+
+    #[test]
+    fn evicts_least_recently_used_until_value_fits() {
+        let mut cache = BoundedCache::new(10, 10, Policy::Lru);
+        cache.put("a", vec![0; 3]);
+        cache.put("b", vec![0; 3]);
+        cache.put("c", vec![0; 3]);
+        let _ = cache.get(&"a");
+
+        cache.put("d", vec![0; 6]);
+
+        assert!(cache.contains(&"a"));
+        assert!(!cache.contains(&"b"));
+        assert!(!cache.contains(&"c"));
+        assert!(cache.contains(&"d"));
+        assert_eq!(cache.used_bytes(), 9);
+    }
+
+    #[test]
+    fn evicts_least_frequently_used() {
+        let mut cache = BoundedCache::new(9, 9, Policy::Lfu);
+        cache.put("a", vec![0; 3]);
+        cache.put("b", vec![0; 3]);
+        cache.put("c", vec![0; 3]);
+        let _ = cache.get(&"a");
+        let _ = cache.get(&"a");
+        let _ = cache.get(&"c");
+
+        cache.put("d", vec![0; 3]);
+
+        assert!(cache.contains(&"a"));
+        assert!(!cache.contains(&"b"));
+        assert!(cache.contains(&"c"));
+        assert!(cache.contains(&"d"));
+    }
+
+    #[test]
+    fn two_queue_keeps_reused_entries_through_a_scan() {
+        let mut cache = BoundedCache::new(40, 40, Policy::TwoQueue);
+        cache.put(0, vec![0; 4]);
+        for key in 1..21 {
+            cache.put(key, vec![0; 4]);
+        }
+        cache.put(0, vec![0; 4]);
+        for key in 21..41 {
+            cache.put(key, vec![0; 4]);
+        }
+
+        assert!(cache.contains(&0));
+        assert!(cache.used_bytes() <= 40);
+    }
+
+    #[test]
+    fn every_policy_stays_within_budget() {
+        for policy in Policy::all() {
+            let mut cache = BoundedCache::new(100, 30, policy);
+            for i in 0..500usize {
+                let key = i % 37;
+                if cache.get(&key).is_none() {
+                    cache.put(key, vec![0; 1 + (i * 7) % 30]);
+                }
+                assert!(cache.used_bytes() <= 100, "{} exceeded budget", policy.name());
+            }
+            assert_eq!(cache.used_bytes(), {
+                (0..37usize).filter_map(|k| cache.get(&k).map(|v| v.len())).sum::<usize>()
+            });
+        }
+    }
+
+    #[test]
+    fn removing_and_clearing_update_policy() {
+        for policy in Policy::all() {
+            let mut cache = BoundedCache::new(10, 10, policy);
+            cache.put("dir/a", vec![0; 2]);
+            cache.put("dir/b", vec![0; 2]);
+            cache.put("other", vec![0; 2]);
+
+            cache.remove_where(|k| k.starts_with("dir/"));
+            assert_eq!(cache.len(), 1);
+            assert_eq!(cache.used_bytes(), 2);
+
+            cache.clear();
+            assert_eq!(cache.len(), 0);
+            cache.put("new", vec![0; 10]);
+            assert!(cache.contains(&"new"), "{} lost track of evictions", policy.name());
+        }
+    }
+
+    #[test]
+    fn parses_policy_names() {
+        for policy in Policy::all() {
+            assert_eq!(Policy::parse(policy.name()), Ok(policy));
+        }
+        assert!(Policy::parse("fifo").is_err());
+    }
+}
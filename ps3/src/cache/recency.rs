@@ -0,0 +1,90 @@
+use std::collections::{ HashMap, BTreeMap };
+use std::hash::Hash;
+
+// Keys in the order they were last touched, along with the bytes each one
+// accounts for. The building block for every eviction policy.
+pub struct RecencyList<K: Hash + Eq + Clone> {
+    positions: HashMap<K, (u64, usize)>,
+    order: BTreeMap<u64, K>,
+    tick: u64,
+    bytes: usize
+}
+
+impl<K: Hash + Eq + Clone> RecencyList<K> {
+    pub fn new() -> Self {
+        RecencyList {
+            positions: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            bytes: 0
+        }
+    }
+
+    pub fn push(&mut self, key: K, size: usize) {
+        self.remove(&key);
+        self.tick += 1;
+        self.bytes += size;
+        self.order.insert(self.tick, key.clone());
+        self.positions.insert(key, (self.tick, size));
+    }
+
+    pub fn touch(&mut self, key: &K) -> bool {
+        match self.positions.get(key).map(|&(_, size)| size) {
+            Some(size) => {
+                self.push(key.clone(), size);
+                true
+            }
+            None => false
+        }
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<usize> {
+        self.positions.remove(key).map(|(tick, size)| {
+            self.order.remove(&tick);
+            self.bytes -= size;
+            size
+        })
+    }
+
+    pub fn oldest(&self) -> Option<&K> {
+        self.order.values().next()
+    }
+
+    pub fn pop_oldest(&mut self) -> Option<(K, usize)> {
+        let oldest = self.oldest().cloned();
+        oldest.and_then(|key| self.remove(&key).map(|size| (key, size)))
+    }
+
+    pub fn size_of(&self, key: &K) -> Option<usize> {
+        self.positions.get(key).map(|&(_, size)| size)
+    }
+
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RecencyList;
+
+    #[test]
+    fn orders_keys_by_last_touch() {
+        let mut list = RecencyList::new();
+        list.push("a", 1);
+        list.push("b", 2);
+        list.push("c", 3);
+        list.touch(&"a");
+
+        assert_eq!(list.bytes(), 6);
+        assert_eq!(list.pop_oldest(), Some(("b", 2)));
+        assert_eq!(list.pop_oldest(), Some(("c", 3)));
+        assert_eq!(list.pop_oldest(), Some(("a", 1)));
+        assert_eq!(list.pop_oldest(), None);
+        assert_eq!(list.bytes(), 0);
+    }
+}
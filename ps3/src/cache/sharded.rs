@@ -0,0 +1,126 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{ Hash, Hasher };
+use std::sync::{ Mutex, MutexGuard };
+use cache::{ BoundedCache, Policy, Weigh };
+
+// Splits the cache into independently locked shards chosen by key hash, so
+// that lookups for different files rarely wait on each other. Each shard gets
+// an equal part of the byte budget and runs its own eviction policy.
+pub struct ShardedCache<K: Hash + Eq + Clone, V: Weigh> {
+    shards: Vec<Mutex<BoundedCache<K, V>>>,
+    max_object: usize
+}
+
+impl<K: Hash + Eq + Clone + Send + 'static, V: Weigh> ShardedCache<K, V> {
+    // Uses fewer than `shards` shards if that many would leave a shard unable
+    // to hold an object of `max_object` bytes.
+    pub fn new(budget: usize, max_object: usize, policy: Policy, shards: usize) -> Self {
+        let max_object = max_object.min(budget);
+        let shards = shards.min(budget / max_object.max(1)).max(1);
+        ShardedCache {
+            shards: (0..shards)
+                .map(|_| Mutex::new(BoundedCache::new(budget / shards, max_object, policy)))
+                .collect(),
+            max_object: max_object
+        }
+    }
+
+    pub fn shard(&self, key: &K) -> MutexGuard<'_, BoundedCache<K, V>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let idx = hasher.finish() as usize % self.shards.len();
+        self.shards[idx].lock().unwrap()
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.shard(key).contains(key)
+    }
+
+    pub fn admits(&self, size: usize) -> bool {
+        size <= self.max_object
+    }
+
+    pub fn put(&self, key: K, value: V) -> bool {
+        self.shard(&key).put(key, value)
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.shard(key).remove(key)
+    }
+
+    pub fn remove_where<F: Fn(&K) -> bool>(&self, matches: F) {
+        for shard in self.shards.iter() {
+            shard.lock().unwrap().remove_where(&matches);
+        }
+    }
+
+    pub fn clear(&self) {
+        for shard in self.shards.iter() {
+            shard.lock().unwrap().clear();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().unwrap().len()).sum()
+    }
+
+    pub fn used_bytes(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().unwrap().used_bytes()).sum()
+    }
+
+    #[cfg(test)]
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::thread;
+    use cache::Policy;
+    use super::ShardedCache;
+
+    #[test]
+    fn keeps_enough_budget_per_shard_for_largest_object() {
+        assert_eq!(ShardedCache::<usize, Vec<u8>>::new(1 << 20, 1 << 16, Policy::Lru, 8).shard_count(), 8);
+        assert_eq!(ShardedCache::<usize, Vec<u8>>::new(1 << 16, 1 << 15, Policy::Lru, 8).shard_count(), 2);
+        assert_eq!(ShardedCache::<usize, Vec<u8>>::new(100, 1000, Policy::Lru, 8).shard_count(), 1);
+    }
+
+    #[test]
+    fn spreads_entries_across_shards_within_budget() {
+        let cache = ShardedCache::new(1000, 10, Policy::TinyLfu, 4);
+        for key in 0..500usize {
+            assert!(cache.put(key, vec![0; 10]));
+            assert!(cache.used_bytes() <= 1000);
+        }
+        assert!(cache.len() > 50);
+        assert!(cache.contains(&499));
+
+        cache.remove_where(|key| key % 2 == 0);
+        assert!(!cache.contains(&498));
+        cache.clear();
+        assert_eq!(cache.len(), 0);
+        assert_eq!(cache.used_bytes(), 0);
+    }
+
+    #[test]
+    fn shards_are_shared_between_threads() {
+        let cache = Arc::new(ShardedCache::new(1 << 16, 1 << 8, Policy::Lru, 8));
+        let workers = (0..4usize).map(|worker| {
+            let cache = cache.clone();
+            thread::spawn(move || {
+                for n in 0..100usize {
+                    let key = worker * 1000 + n;
+                    cache.put(key, vec![0; 16]);
+                    assert!(cache.shard(&key).get(&key).is_some());
+                }
+            })
+        }).collect::<Vec<_>>();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(cache.len(), 400);
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{ Hash, Hasher };
+use cache::Eviction;
+use cache::recency::RecencyList;
+
+const SEEDS: [u64; 4] = [0xc3a5_c85c_97cb_3127, 0xb492_b66f_be98_f273,
+                         0x9ae1_6a3b_2f90_404f, 0xcbf2_9ce4_8422_2325];
+const MAX_COUNT: u8 = 15;
+
+// Approximate access counts for every key seen, cached or not. Counts are
+// halved periodically so that popularity fades.
+struct FrequencySketch {
+    rows: Vec<Vec<u8>>,
+    mask: usize,
+    additions: usize,
+    sample_size: usize
+}
+
+impl FrequencySketch {
+    fn new(width: usize) -> Self {
+        let width = width.next_power_of_two();
+        FrequencySketch {
+            rows: (0..SEEDS.len()).map(|_| vec![0; width]).collect(),
+            mask: width - 1,
+            additions: 0,
+            sample_size: width * 10
+        }
+    }
+
+    fn index(&self, hash: u64, row: usize) -> usize {
+        ((hash ^ SEEDS[row]).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32) as usize & self.mask
+    }
+
+    fn increment(&mut self, hash: u64) {
+        for row in 0..self.rows.len() {
+            let idx = self.index(hash, row);
+            if self.rows[row][idx] < MAX_COUNT {
+                self.rows[row][idx] += 1;
+            }
+        }
+        self.additions += 1;
+        if self.additions >= self.sample_size {
+            for row in self.rows.iter_mut() {
+                for count in row.iter_mut() {
+                    *count /= 2;
+                }
+            }
+            self.additions /= 2;
+        }
+    }
+
+    fn frequency(&self, hash: u64) -> u8 {
+        (0..self.rows.len())
+            .map(|row| self.rows[row][self.index(hash, row)])
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+fn hash_key<K: Hash>(key: &K) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+// W-TinyLFU: new entries land in a small LRU window. When the window is full
+// its oldest entry has to beat the main cache's next victim on estimated
+// access frequency to get in; otherwise it is the one evicted. The main cache
+// is a segmented LRU where entries hit while on probation become protected.
+pub struct TinyLfu<K: Hash + Eq + Clone> {
+    window: RecencyList<K>,
+    probation: RecencyList<K>,
+    protected: RecencyList<K>,
+    sketch: FrequencySketch,
+    window_budget: usize,
+    main_budget: usize,
+    protected_budget: usize
+}
+
+impl<K: Hash + Eq + Clone> TinyLfu<K> {
+    pub fn new(budget: usize) -> Self {
+        let window_budget = (budget / 100).max(1);
+        let main_budget = budget - window_budget.min(budget);
+        TinyLfu {
+            window: RecencyList::new(),
+            probation: RecencyList::new(),
+            protected: RecencyList::new(),
+            sketch: FrequencySketch::new((budget / 4096).clamp(256, 1 << 20)),
+            window_budget: window_budget,
+            main_budget: main_budget,
+            protected_budget: main_budget / 5 * 4
+        }
+    }
+
+    fn main_bytes(&self) -> usize {
+        self.probation.bytes() + self.protected.bytes()
+    }
+
+    fn main_victim(&self) -> Option<K> {
+        self.probation.oldest().or_else(|| self.protected.oldest()).cloned()
+    }
+
+    fn promote(&mut self, key: &K) {
+        if let Some(size) = self.probation.remove(key) {
+            self.protected.push(key.clone(), size);
+            while self.protected.bytes() > self.protected_budget {
+                match self.protected.pop_oldest() {
+                    Some((demoted, size)) => self.probation.push(demoted, size),
+                    None => break
+                }
+            }
+        }
+    }
+
+    fn admit(&mut self, key: K) {
+        if let Some(size) = self.window.remove(&key) {
+            self.probation.push(key, size);
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone + Send> Eviction<K> for TinyLfu<K> {
+    fn accessed(&mut self, key: &K, hit: bool) {
+        self.sketch.increment(hash_key(key));
+        if hit && !self.window.touch(key) && !self.protected.touch(key) {
+            self.promote(key);
+        }
+    }
+
+    fn inserted(&mut self, key: &K, size: usize) {
+        self.window.push(key.clone(), size);
+    }
+
+    fn removed(&mut self, key: &K) {
+        self.window.remove(key);
+        self.probation.remove(key);
+        self.protected.remove(key);
+    }
+
+    fn victim(&mut self) -> Option<K> {
+        while self.window.bytes() > self.window_budget {
+            let candidate = match self.window.oldest() {
+                Some(key) => key.clone(),
+                None => break
+            };
+            let size = self.window.size_of(&candidate).unwrap_or(0);
+            if self.main_bytes() + size <= self.main_budget {
+                self.admit(candidate);
+                continue;
+            }
+            return match self.main_victim() {
+                None => Some(candidate),
+                Some(victim) => {
+                    if self.sketch.frequency(hash_key(&candidate)) > self.sketch.frequency(hash_key(&victim)) {
+                        self.admit(candidate);
+                        Some(victim)
+                    } else {
+                        Some(candidate)
+                    }
+                }
+            };
+        }
+        self.main_victim().or_else(|| self.window.oldest().cloned())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ FrequencySketch, hash_key };
+
+    #[test]
+    fn sketch_estimates_and_ages_frequencies() {
+        let mut sketch = FrequencySketch::new(16);
+        let hot = hash_key(&"hot");
+        let cold = hash_key(&"cold");
+        for _ in 0..5 {
+            sketch.increment(hot);
+        }
+        sketch.increment(cold);
+
+        assert!(sketch.frequency(hot) >= 5);
+        assert!(sketch.frequency(hot) > sketch.frequency(cold));
+
+        for _ in 0..200 {
+            sketch.increment(cold);
+        }
+        assert!(sketch.frequency(hot) < 5);
+    }
+}
@@ -0,0 +1,71 @@
+use std::hash::Hash;
+use cache::Eviction;
+use cache::recency::RecencyList;
+
+// Ghost keys remembered per resident entry, and never fewer than this.
+const MIN_GHOSTS: usize = 64;
+
+// 2Q: new entries wait in a FIFO that gets a quarter of the budget. Only keys
+// that come back after falling out of it (tracked as ghosts) make it into the
+// main LRU, so a one-off scan cannot push out the working set.
+pub struct TwoQueue<K: Hash + Eq + Clone> {
+    recent: RecencyList<K>,
+    frequent: RecencyList<K>,
+    ghosts: RecencyList<K>,
+    recent_budget: usize
+}
+
+impl<K: Hash + Eq + Clone> TwoQueue<K> {
+    pub fn new(budget: usize) -> Self {
+        TwoQueue {
+            recent: RecencyList::new(),
+            frequent: RecencyList::new(),
+            ghosts: RecencyList::new(),
+            recent_budget: budget / 4
+        }
+    }
+
+    fn remember(&mut self, key: K) {
+        self.ghosts.push(key, 0);
+        let max_ghosts = (self.recent.len() + self.frequent.len()).max(MIN_GHOSTS);
+        while self.ghosts.len() > max_ghosts {
+            self.ghosts.pop_oldest();
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone + Send> Eviction<K> for TwoQueue<K> {
+    fn accessed(&mut self, key: &K, hit: bool) {
+        if hit {
+            self.frequent.touch(key);
+        }
+    }
+
+    fn inserted(&mut self, key: &K, size: usize) {
+        if self.ghosts.remove(key).is_some() {
+            self.frequent.push(key.clone(), size);
+        } else {
+            self.recent.push(key.clone(), size);
+        }
+    }
+
+    fn removed(&mut self, key: &K) {
+        self.recent.remove(key);
+        self.frequent.remove(key);
+    }
+
+    fn victim(&mut self) -> Option<K> {
+        if self.recent.bytes() > self.recent_budget || self.frequent.len() == 0 {
+            let oldest = self.recent.oldest().cloned();
+            match oldest {
+                Some(key) => {
+                    self.remember(key.clone());
+                    Some(key)
+                }
+                None => self.frequent.oldest().cloned()
+            }
+        } else {
+            self.frequent.oldest().cloned()
+        }
+    }
+}
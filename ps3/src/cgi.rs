@@ -0,0 +1,299 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{ Component, Path, PathBuf };
+use std::str;
+use cmd_line::{ CmdIO, CmdLine };
+use http::{ Response, Status };
+use request::RequestInfo;
+use sandbox::{ ExecError, Sandbox };
+
+// Scripts run for requests under `dir`, one process per request, as described
+// by CGI/1.1 (RFC 3875). `dir` is both where the scripts live and the path
+// they are requested under.
+#[derive(Debug, Clone)]
+pub struct Cgi {
+    pub dir: PathBuf,
+    pub sandbox: Sandbox
+}
+
+// A script and the part of the request path that follows it.
+#[derive(Debug, PartialEq)]
+pub struct Script {
+    pub path: PathBuf,
+    pub path_info: String
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CgiError {
+    NotFound,
+    NotExecutable,
+    Failed(String)
+}
+
+impl Cgi {
+    // The script a request is for, or None when the request is not under the
+    // CGI directory. The first file along the path is the script.
+    pub fn script(&self, request_path: &str) -> Option<Result<Script, CgiError>> {
+        let rest = Path::new(request_path).strip_prefix(&self.dir).ok()?;
+        let parts = rest.components().collect::<Vec<Component>>();
+        let mut path = self.dir.clone();
+        for (idx, part) in parts.iter().enumerate() {
+            match part {
+                &Component::Normal(name) => path.push(name),
+                _ => return Some(Err(CgiError::NotFound))
+            }
+            if path.is_file() {
+                let path_info = parts[idx + 1..].iter()
+                    .map(|part| format!("/{}", part.as_os_str().to_string_lossy()))
+                    .collect::<String>();
+                return Some(Ok(Script { path: path, path_info: path_info }));
+            }
+            if !path.is_dir() {
+                break;
+            }
+        }
+        Some(Err(CgiError::NotFound))
+    }
+
+    // Runs the script with the request body on stdin and reads its response.
+    pub fn run(&self, script: &Script, info: &RequestInfo) -> Result<Response, CgiError> {
+        let executable = fs::metadata(&script.path)
+            .map(|data| data.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false);
+        if !executable {
+            return Err(CgiError::NotExecutable);
+        }
+        let name = script.path.to_str().ok_or(CgiError::NotFound)?;
+        let cmd = CmdLine { name: name, args: vec![], background: false, stdin: CmdIO::Pipe, stdout: CmdIO::Pipe };
+        let env = meta_variables(script, info);
+        self.sandbox.run_with_input(&[cmd], &env, info.body.clone())
+            .map_err(|e| match e {
+                ExecError::Refused(reason) | ExecError::Failed(reason) => {
+                    CgiError::Failed(format!("{}: {}", script.path.display(), reason))
+                }
+            })
+            .and_then(|output| {
+                parse_response(&output).map_err(|e| CgiError::Failed(format!("{}: {}", script.path.display(), e)))
+            })
+    }
+}
+
+// The environment a script runs with.
+pub fn meta_variables(script: &Script, info: &RequestInfo) -> Vec<(String, String)> {
+    let host = info.header("Host").map(|host| host.rsplitn(2, ':').last().unwrap_or(host).to_string());
+    let server_name = host
+        .or_else(|| info.local_addr.map(|address| address.ip().to_string()))
+        .unwrap_or_else(|| "localhost".to_string());
+    let server_port = info.local_addr.map(|address| address.port()).unwrap_or(80);
+    let mut env = vec![
+        ("GATEWAY_INTERFACE".to_string(), "CGI/1.1".to_string()),
+        ("SERVER_SOFTWARE".to_string(), "ps3".to_string()),
+        ("SERVER_PROTOCOL".to_string(), "HTTP/1.1".to_string()),
+        ("SERVER_NAME".to_string(), server_name),
+        ("SERVER_PORT".to_string(), server_port.to_string()),
+        ("REQUEST_METHOD".to_string(), info.method.clone()),
+        ("SCRIPT_NAME".to_string(), format!("/{}", script.path.display())),
+        ("PATH_INFO".to_string(), script.path_info.clone()),
+        ("QUERY_STRING".to_string(), info.query.clone())
+    ];
+    if let Some(address) = info.remote_addr {
+        env.push(("REMOTE_ADDR".to_string(), address.to_string()));
+    }
+    if !info.body.is_empty() {
+        env.push(("CONTENT_LENGTH".to_string(), info.body.len().to_string()));
+    }
+    if let Some(content_type) = info.header("Content-Type") {
+        env.push(("CONTENT_TYPE".to_string(), content_type.to_string()));
+    }
+    // Credentials are not passed on, and `Proxy` would become HTTP_PROXY,
+    // which many clients read as their proxy.
+    for &(ref name, ref value) in info.headers.iter() {
+        let skipped = ["Content-Type", "Content-Length", "Authorization", "Proxy"];
+        if skipped.iter().any(|skip| skip.eq_ignore_ascii_case(name)) {
+            continue;
+        }
+        let variable = format!("HTTP_{}", name.to_uppercase().replace('-', "_"));
+        match env.iter().position(|&(ref existing, _)| *existing == variable) {
+            Some(idx) => {
+                env[idx].1.push_str(", ");
+                env[idx].1.push_str(value);
+            }
+            None => env.push((variable, value.clone()))
+        }
+    }
+    env
+}
+
+// Splits a script's output into the response it describes. `Status`,
+// `Content-Type` and `Location` are CGI fields; anything else is passed on
+// to the client. A `Location` without a `Status` redirects with 302.
+pub fn parse_response(output: &[u8]) -> Result<Response, String> {
+    let (head, body) = split_head(output).ok_or("sent no header".to_string())?;
+    let head = str::from_utf8(head).map_err(|_| "header is not valid UTF-8".to_string())?;
+    let mut status = None;
+    let mut content_type = None;
+    let mut location = None;
+    let mut fields = vec![];
+    for line in head.lines().map(|line| line.trim_end_matches('\r')).filter(|line| !line.is_empty()) {
+        let colon = line.find(':').ok_or(format!("malformed header line {}", line))?;
+        let (name, value) = (line[..colon].trim(), line[colon + 1..].trim().to_string());
+        if name.eq_ignore_ascii_case("Status") {
+            status = Some(parse_status(&value)?);
+        } else if name.eq_ignore_ascii_case("Content-Type") {
+            content_type = Some(value);
+        } else if name.eq_ignore_ascii_case("Location") {
+            location = Some(value);
+        } else if !name.eq_ignore_ascii_case("Content-Length") && !name.eq_ignore_ascii_case("Transfer-Encoding") {
+            fields.push((name.to_string(), value));
+        }
+    }
+    if status.is_none() && content_type.is_none() && location.is_none() {
+        return Err("sent none of Status, Content-Type or Location".to_string());
+    }
+    let status = match (status, &location) {
+        (Some(status), _) => status,
+        (None, &Some(_)) => Status::Other(302, "Found".to_string()),
+        (None, &None) => Status::Ok
+    };
+    if let Some(location) = location {
+        fields.insert(0, ("Location".to_string(), location));
+    }
+    Ok(Response {
+        status: status,
+        content_type: content_type.unwrap_or_else(|| "text/html; charset=UTF-8".to_string()),
+        fields: fields,
+        body: body.to_vec()
+    })
+}
+
+fn split_head(output: &[u8]) -> Option<(&[u8], &[u8])> {
+    let crlf = output.windows(4).position(|window| window == b"\r\n\r\n").map(|at| (at, at + 4));
+    let lf = output.windows(2).position(|window| window == b"\n\n").map(|at| (at, at + 2));
+    let (end, body) = match (crlf, lf) {
+        (Some(crlf), Some(lf)) => if crlf.0 < lf.0 { crlf } else { lf },
+        (Some(found), None) | (None, Some(found)) => found,
+        (None, None) => return None
+    };
+    Some((&output[..end], &output[body..]))
+}
+
+fn parse_status(value: &str) -> Result<Status, String> {
+    let mut parts = value.splitn(2, ' ');
+    let code = parts.next()
+        .and_then(|code| code.parse::<u16>().ok())
+        .filter(|code| *code >= 100 && *code < 600)
+        .ok_or(format!("bad Status {}", value))?;
+    Ok(Status::from_code(code, parts.next().unwrap_or("").trim()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{ IpAddr, Ipv4Addr, SocketAddr };
+    use std::path::PathBuf;
+    use http::Status;
+    use request::RequestInfo;
+    use sandbox::Sandbox;
+    use super::{ meta_variables, parse_response, Cgi, CgiError, Script };
+
+    fn cgi() -> Cgi {
+        Cgi { dir: PathBuf::from("test/cgi-bin"), sandbox: Sandbox::default() }
+    }
+
+    fn post(body: &str) -> RequestInfo {
+        RequestInfo {
+            method: "POST".to_string(),
+            query: "a=1".to_string(),
+            remote_addr: Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 7))),
+            local_addr: Some(SocketAddr::from(([127, 0, 0, 1], 4414))),
+            headers: vec![
+                ("Host".to_string(), "example.com:4414".to_string()),
+                ("Content-Type".to_string(), "text/plain".to_string()),
+                ("X-Greeting".to_string(), "hi".to_string()),
+                ("x-greeting".to_string(), "there".to_string()),
+                ("Proxy".to_string(), "evil:8080".to_string())
+            ],
+            body: body.as_bytes().to_vec(),
+            ..RequestInfo::default()
+        }
+    }
+
+    #[test]
+    fn finds_script_and_path_info() {
+        assert_eq!(cgi().script("test/cgi-bin/env.sh/extra/path"),
+                   Some(Ok(Script { path: PathBuf::from("test/cgi-bin/env.sh"), path_info: "/extra/path".to_string() })));
+        assert_eq!(cgi().script("test/cgi-bin/env.sh"),
+                   Some(Ok(Script { path: PathBuf::from("test/cgi-bin/env.sh"), path_info: "".to_string() })));
+        assert_eq!(cgi().script("test/cgi-bin/missing.sh/x"), Some(Err(CgiError::NotFound)));
+        assert_eq!(cgi().script("test/cgi-bin/../response.html"), Some(Err(CgiError::NotFound)));
+        assert_eq!(cgi().script("test/cgi-binary/env.sh"), None);
+        assert_eq!(cgi().script("test/response.html"), None);
+    }
+
+    #[test]
+    fn describes_request_in_meta_variables() {
+        let script = Script { path: PathBuf::from("test/cgi-bin/env.sh"), path_info: "/x".to_string() };
+        let env = meta_variables(&script, &post("hello"));
+        let var = |name: &str| env.iter().find(|&&(ref n, _)| n == name).map(|&(_, ref v)| v.clone());
+
+        assert_eq!(var("REQUEST_METHOD"), Some("POST".to_string()));
+        assert_eq!(var("SCRIPT_NAME"), Some("/test/cgi-bin/env.sh".to_string()));
+        assert_eq!(var("PATH_INFO"), Some("/x".to_string()));
+        assert_eq!(var("QUERY_STRING"), Some("a=1".to_string()));
+        assert_eq!(var("CONTENT_LENGTH"), Some("5".to_string()));
+        assert_eq!(var("CONTENT_TYPE"), Some("text/plain".to_string()));
+        assert_eq!(var("REMOTE_ADDR"), Some("10.0.0.7".to_string()));
+        assert_eq!(var("SERVER_NAME"), Some("example.com".to_string()));
+        assert_eq!(var("SERVER_PORT"), Some("4414".to_string()));
+        assert_eq!(var("HTTP_X_GREETING"), Some("hi, there".to_string()));
+        assert_eq!(var("HTTP_CONTENT_TYPE"), None);
+        assert_eq!(var("HTTP_PROXY"), None);
+    }
+
+    #[test]
+    fn parses_cgi_fields_from_output() {
+        let response = parse_response(b"Status: 404 Not Found\r\nContent-Type: text/plain\r\nX-A: b\r\nContent-Length: 2\r\n\r\nno").unwrap();
+        assert_eq!(response.status, Status::FileNotFound);
+        assert_eq!(response.content_type, "text/plain");
+        assert_eq!(response.fields, vec![("X-A".to_string(), "b".to_string())]);
+        assert_eq!(response.body, b"no".to_vec());
+
+        let redirect = parse_response(b"Location: /next.html\n\n").unwrap();
+        assert_eq!(redirect.status, Status::Other(302, "Found".to_string()));
+        assert_eq!(redirect.fields, vec![("Location".to_string(), "/next.html".to_string())]);
+
+        assert!(parse_response(b"no header").is_err());
+        assert!(parse_response(b"X-Only: this\n\nbody").is_err());
+        assert!(parse_response(b"Status: teapot\n\n").is_err());
+    }
+
+    #[test]
+    fn runs_scripts_with_body_on_stdin() {
+        let form = cgi().script("test/cgi-bin/form.sh").unwrap().unwrap();
+        let response = cgi().run(&form, &post("name=ferris")).unwrap();
+
+        assert_eq!(response.status, Status::Other(201, "Created".to_string()));
+        assert_eq!(response.fields, vec![("X-Script".to_string(), "form".to_string())]);
+        assert_eq!(String::from_utf8(response.body).unwrap(), "received name=ferris\n");
+    }
+
+    #[test]
+    fn passes_meta_variables_to_scripts() {
+        let env = cgi().script("test/cgi-bin/env.sh/more").unwrap().unwrap();
+        let response = cgi().run(&env, &post("")).unwrap();
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(String::from_utf8(response.body).unwrap(),
+                   "GATEWAY_INTERFACE=CGI/1.1\nREQUEST_METHOD=POST\nSCRIPT_NAME=/test/cgi-bin/env.sh\n\
+                    PATH_INFO=/more\nQUERY_STRING=a=1\nCONTENT_LENGTH=\nCONTENT_TYPE=text/plain\n\
+                    HTTP_X_GREETING=hi, there\n");
+    }
+
+    #[test]
+    fn refuses_scripts_that_cannot_run_or_misbehave() {
+        let hidden = cgi().script("test/cgi-bin/not_executable.sh").unwrap().unwrap();
+        assert_eq!(cgi().run(&hidden, &post("")), Err(CgiError::NotExecutable));
+
+        let broken = cgi().script("test/cgi-bin/broken.sh").unwrap().unwrap();
+        assert_eq!(cgi().run(&broken, &post("")), Err(CgiError::Failed("test/cgi-bin/broken.sh: sent no header".to_string())));
+    }
+}
@@ -0,0 +1,1019 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::{ Path, PathBuf };
+use std::time::Duration;
+use rate_limit::{ LimitRule, RuleTarget, Cidr };
+use scheduling::Priority;
+use cache::Policy;
+use sandbox::{ self, Sandbox };
+use fastcgi::Address;
+use document_root::{ Hidden, Symlinks };
+use middleware::Layer;
+use proxy::{ Balance, GroupSpec };
+use router::{ Matcher, RouteSpec, Target };
+use ssi::directive::Encoding;
+
+// Server configuration is read from a plain text file of directives, one per
+// line. Blank lines and anything following a `#` are ignored.
+//
+//     listen 127.0.0.1:4414
+//     document_root public
+//     symlinks same-owner
+//     hidden_files deny
+//     rate_limit high 100 20
+//     rate_limit 10.0.0.0/8 10 1
+//     rate_limit_expiry 300
+//     cache_size 64M
+//     cache_max_object 1M
+//     cache_policy tinylfu
+//     cache_shards 16
+//     cache_revalidate 2
+//     cache_watch public
+//     cache_loaders 2
+//     cache_fill_wait 500
+//     cache_warm public/**/*.css
+//     cache_warm_rate 4M
+//     cache_manifest popular.manifest
+//     cache_manifest_size 100
+//     ssi_cache_ttl 10
+//     ssi_encoding entity
+//     ssi_exec_parallelism 4
+//     ssi_exec_allow date
+//     ssi_exec_timeout 10
+//     ssi_exec_cpu 5
+//     ssi_exec_memory 256M
+//     ssi_exec_files 64
+//     ssi_exec_processes 32
+//     ssi_exec_output 1M
+//     ssi_exec_user nobody
+//     ssi_exec_error [command refused]
+//     cgi_dir cgi-bin
+//     cgi_timeout 30
+//     cgi_output 16M
+//     cgi_user nobody
+//     fastcgi_backend php unix:/run/php-fpm.sock 8
+//     fastcgi_route **/*.php php
+//     fastcgi_timeout 30
+//     fastcgi_output 16M
+//     proxy_upstream app 127.0.0.1:8080 16
+//     proxy_upstream app 127.0.0.1:8081 16
+//     proxy_balance app least-connections
+//     proxy_health_check app /healthz 5
+//     proxy_eject app 3 30
+//     proxy_timeout 30
+//     route GET,HEAD prefix /docs/ static
+//     route * pattern /admin/** ssi
+//     route_auth admin alice:secret
+//     route_header Cache-Control no-store
+//     route_limit 10 1
+//     index_files index.html index.shtml
+//     directory_listing on
+//     sendfile_min 64K
+
+pub struct Config {
+    pub address: String,
+    pub document_root: PathBuf,
+    pub symlinks: Symlinks,
+    pub hidden_files: Hidden,
+    pub rate_limits: Vec<LimitRule>,
+    pub rate_limit_expiry: Duration,
+    pub cache_size: usize,
+    pub cache_max_object: usize,
+    pub cache_policy: Policy,
+    pub cache_shards: usize,
+    pub cache_revalidate: Duration,
+    pub cache_watch: Vec<PathBuf>,
+    pub cache_loaders: usize,
+    pub cache_fill_wait: Duration,
+    pub cache_warm: Vec<String>,
+    pub cache_warm_rate: usize,
+    pub cache_manifest: Option<PathBuf>,
+    pub cache_manifest_size: usize,
+    pub ssi_cache_ttl: Duration,
+    pub ssi_encoding: Encoding,
+    pub ssi_exec_parallelism: usize,
+    pub ssi_exec: Sandbox,
+    pub cgi_dir: Option<PathBuf>,
+    pub cgi_exec: Sandbox,
+    // Name, address and most connections of each FastCGI application.
+    pub fastcgi_backends: Vec<(String, Address, usize)>,
+    // Globs of request paths and the applications that answer them.
+    pub fastcgi_routes: Vec<(String, String)>,
+    pub fastcgi_timeout: Duration,
+    pub fastcgi_output: usize,
+    // Groups of upstream HTTP servers, in the order they were configured.
+    pub proxy_upstreams: Vec<GroupSpec>,
+    pub proxy_timeout: Duration,
+    // Routes tried, in order, before the standard ones.
+    pub routes: Vec<RouteSpec>,
+    // Files tried, in order, for a request that names a directory.
+    pub index_files: Vec<String>,
+    pub directory_listing: bool,
+    pub sendfile_min: Option<u64>
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            address: "127.0.0.1:4414".to_string(),
+            document_root: PathBuf::from("."),
+            symlinks: Symlinks::SameOwner,
+            hidden_files: Hidden::Deny,
+            rate_limits: vec![],
+            rate_limit_expiry: Duration::from_secs(300),
+            cache_size: 64 << 20,
+            cache_max_object: 1 << 20,
+            cache_policy: Policy::Lru,
+            cache_shards: 16,
+            cache_revalidate: Duration::from_secs(0),
+            cache_watch: vec![],
+            cache_loaders: 2,
+            cache_fill_wait: Duration::from_millis(500),
+            cache_warm: vec![],
+            cache_warm_rate: 4 << 20,
+            cache_manifest: None,
+            cache_manifest_size: 100,
+            ssi_cache_ttl: Duration::from_secs(0),
+            ssi_encoding: Encoding::None,
+            ssi_exec_parallelism: 4,
+            ssi_exec: Sandbox::default(),
+            cgi_dir: None,
+            cgi_exec: Sandbox { timeout: Duration::from_secs(30), max_output: 16 << 20, ..Sandbox::default() },
+            fastcgi_backends: vec![],
+            fastcgi_routes: vec![],
+            fastcgi_timeout: Duration::from_secs(30),
+            fastcgi_output: 16 << 20,
+            proxy_upstreams: vec![],
+            proxy_timeout: Duration::from_secs(30),
+            routes: vec![],
+            index_files: vec!["index.html".to_string(), "index.shtml".to_string()],
+            directory_listing: false,
+            sendfile_min: Some(64 << 10)
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let mut contents = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut contents))
+            .map_err(|e| format!("{}: {}", path.display(), e))
+            .and_then(|_| Config::parse(&contents))
+    }
+
+    pub fn parse(contents: &str) -> Result<Config, String> {
+        contents.lines()
+            .enumerate()
+            .map(|(idx, line)| (idx + 1, line.split('#').next().unwrap_or("")))
+            .filter(|&(_, line)| !line.trim().is_empty())
+            .try_fold(Config::default(), |config, (line_no, line)| {
+                let words = line.split_whitespace().collect::<Vec<&str>>();
+                apply_directive(config, &words)
+                    .map_err(|e| format!("line {}: {}", line_no, e))
+            })
+    }
+}
+
+fn apply_directive(mut config: Config, words: &[&str]) -> Result<Config, String> {
+    match words[0] {
+        "listen" => {
+            single_arg(words).map(|address| {
+                config.address = address.to_string();
+                config
+            })
+        }
+        "document_root" => {
+            single_arg(words).map(|dir| {
+                config.document_root = PathBuf::from(dir);
+                config
+            })
+        }
+        "symlinks" => {
+            single_arg(words)
+                .and_then(|s| Symlinks::parse(s))
+                .map(|policy| {
+                    config.symlinks = policy;
+                    config
+                })
+        }
+        "hidden_files" => {
+            single_arg(words)
+                .and_then(|s| Hidden::parse(s))
+                .map(|policy| {
+                    config.hidden_files = policy;
+                    config
+                })
+        }
+        "rate_limit" => {
+            parse_rate_limit(&words[1..]).map(|rule| {
+                config.rate_limits.push(rule);
+                config
+            })
+        }
+        "rate_limit_expiry" => {
+            single_arg(words)
+                .and_then(|s| parse_number::<u64>(s))
+                .and_then(|secs| if secs == 0 { Err("rate_limit_expiry must be at least 1 second".to_string()) } else { Ok(secs) })
+                .map(|secs| {
+                    config.rate_limit_expiry = Duration::from_secs(secs);
+                    config
+                })
+        }
+        "cache_size" => {
+            single_arg(words)
+                .and_then(|s| parse_size(s))
+                .map(|bytes| {
+                    config.cache_size = bytes;
+                    config
+                })
+        }
+        "cache_max_object" => {
+            single_arg(words)
+                .and_then(|s| parse_size(s))
+                .map(|bytes| {
+                    config.cache_max_object = bytes;
+                    config
+                })
+        }
+        "cache_policy" => {
+            single_arg(words)
+                .and_then(|s| Policy::parse(s))
+                .map(|policy| {
+                    config.cache_policy = policy;
+                    config
+                })
+        }
+        "cache_shards" => {
+            single_arg(words)
+                .and_then(|s| parse_number::<usize>(s))
+                .and_then(|n| if n > 0 { Ok(n) } else { Err("cache_shards must be at least 1".to_string()) })
+                .map(|n| {
+                    config.cache_shards = n;
+                    config
+                })
+        }
+        "cache_revalidate" => {
+            single_arg(words)
+                .and_then(|s| parse_number::<u64>(s))
+                .map(|secs| {
+                    config.cache_revalidate = Duration::from_secs(secs);
+                    config
+                })
+        }
+        "cache_watch" => {
+            single_arg(words).map(|dir| {
+                config.cache_watch.push(PathBuf::from(dir));
+                config
+            })
+        }
+        "cache_loaders" => {
+            single_arg(words)
+                .and_then(|s| parse_number::<usize>(s))
+                .and_then(|n| if n > 0 { Ok(n) } else { Err("cache_loaders must be at least 1".to_string()) })
+                .map(|n| {
+                    config.cache_loaders = n;
+                    config
+                })
+        }
+        "cache_fill_wait" => {
+            single_arg(words)
+                .and_then(|s| parse_number::<u64>(s))
+                .map(|millis| {
+                    config.cache_fill_wait = Duration::from_millis(millis);
+                    config
+                })
+        }
+        "cache_warm" => {
+            single_arg(words).map(|pattern| {
+                config.cache_warm.push(pattern.to_string());
+                config
+            })
+        }
+        "cache_warm_rate" => {
+            single_arg(words)
+                .and_then(|s| parse_size(s))
+                .map(|bytes| {
+                    config.cache_warm_rate = bytes;
+                    config
+                })
+        }
+        "cache_manifest" => {
+            single_arg(words).map(|file| {
+                config.cache_manifest = Some(PathBuf::from(file));
+                config
+            })
+        }
+        "cache_manifest_size" => {
+            single_arg(words)
+                .and_then(|s| parse_number::<usize>(s))
+                .map(|n| {
+                    config.cache_manifest_size = n;
+                    config
+                })
+        }
+        "ssi_cache_ttl" => {
+            single_arg(words)
+                .and_then(|s| parse_number::<u64>(s))
+                .map(|secs| {
+                    config.ssi_cache_ttl = Duration::from_secs(secs);
+                    config
+                })
+        }
+        "ssi_encoding" => {
+            single_arg(words)
+                .and_then(|s| Encoding::parse(s))
+                .map(|encoding| {
+                    config.ssi_encoding = encoding;
+                    config
+                })
+        }
+        "ssi_exec_parallelism" => {
+            single_arg(words)
+                .and_then(|s| parse_number::<usize>(s))
+                .and_then(|n| if n > 0 { Ok(n) } else { Err("ssi_exec_parallelism must be at least 1".to_string()) })
+                .map(|n| {
+                    config.ssi_exec_parallelism = n;
+                    config
+                })
+        }
+        "ssi_exec_allow" => {
+            single_arg(words).map(|name| {
+                config.ssi_exec.allow.push(name.to_string());
+                config
+            })
+        }
+        "ssi_exec_timeout" => {
+            single_arg(words)
+                .and_then(|s| parse_number::<u64>(s))
+                .map(|secs| {
+                    config.ssi_exec.timeout = Duration::from_secs(secs);
+                    config
+                })
+        }
+        "ssi_exec_cpu" => {
+            single_arg(words)
+                .and_then(|s| parse_number::<u64>(s))
+                .map(|secs| {
+                    config.ssi_exec.cpu_secs = Some(secs);
+                    config
+                })
+        }
+        "ssi_exec_memory" => {
+            single_arg(words)
+                .and_then(|s| parse_size(s))
+                .map(|bytes| {
+                    config.ssi_exec.memory = Some(bytes as u64);
+                    config
+                })
+        }
+        "ssi_exec_files" => {
+            single_arg(words)
+                .and_then(|s| parse_number::<u64>(s))
+                .map(|n| {
+                    config.ssi_exec.open_files = Some(n);
+                    config
+                })
+        }
+        "ssi_exec_processes" => {
+            single_arg(words)
+                .and_then(|s| parse_number::<u64>(s))
+                .map(|n| {
+                    config.ssi_exec.processes = Some(n);
+                    config
+                })
+        }
+        "ssi_exec_output" => {
+            single_arg(words)
+                .and_then(|s| parse_size(s))
+                .map(|bytes| {
+                    config.ssi_exec.max_output = bytes;
+                    config
+                })
+        }
+        "ssi_exec_user" => {
+            single_arg(words)
+                .and_then(|name| sandbox::user(name))
+                .map(|user| {
+                    config.ssi_exec.user = Some(user);
+                    config
+                })
+        }
+        "ssi_exec_error" if words.len() > 1 => {
+            config.ssi_exec.error_text = words[1..].join(" ");
+            Ok(config)
+        }
+        "cgi_dir" => {
+            // The directory is also the URL prefix of its scripts, so it has
+            // to be spelled the one way a request path would spell it.
+            single_arg(words).and_then(|dir| {
+                let dir = dir.trim_end_matches('/');
+                if dir.is_empty() || dir.split('/').any(|part| part.is_empty() || part == "." || part == "..") {
+                    return Err(format!("cgi_dir must be a relative path without . or .. in it, not {}", dir));
+                }
+                config.cgi_dir = Some(PathBuf::from(dir));
+                Ok(config)
+            })
+        }
+        "cgi_timeout" => {
+            single_arg(words)
+                .and_then(|s| parse_number::<u64>(s))
+                .map(|secs| {
+                    config.cgi_exec.timeout = Duration::from_secs(secs);
+                    config
+                })
+        }
+        "cgi_output" => {
+            single_arg(words)
+                .and_then(|s| parse_size(s))
+                .map(|bytes| {
+                    config.cgi_exec.max_output = bytes;
+                    config
+                })
+        }
+        "cgi_user" => {
+            single_arg(words)
+                .and_then(|name| sandbox::user(name))
+                .map(|user| {
+                    config.cgi_exec.user = Some(user);
+                    config
+                })
+        }
+        "fastcgi_backend" => {
+            parse_fastcgi_backend(&words[1..]).map(|backend| {
+                config.fastcgi_backends.retain(|&(ref name, _, _)| *name != backend.0);
+                config.fastcgi_backends.push(backend);
+                config
+            })
+        }
+        "fastcgi_route" => {
+            if words.len() != 3 {
+                return Err("fastcgi_route takes a path glob and a backend name".to_string());
+            }
+            if !config.fastcgi_backends.iter().any(|&(ref name, _, _)| name == words[2]) {
+                return Err(format!("no fastcgi_backend named {}", words[2]));
+            }
+            config.fastcgi_routes.push((words[1].trim_start_matches('/').to_string(), words[2].to_string()));
+            Ok(config)
+        }
+        "fastcgi_timeout" => {
+            single_arg(words)
+                .and_then(|s| parse_number::<u64>(s))
+                .map(|secs| {
+                    config.fastcgi_timeout = Duration::from_secs(secs);
+                    config
+                })
+        }
+        "fastcgi_output" => {
+            single_arg(words)
+                .and_then(|s| parse_size(s))
+                .map(|bytes| {
+                    config.fastcgi_output = bytes;
+                    config
+                })
+        }
+        "proxy_upstream" => {
+            let (name, address, max_idle) = parse_proxy_upstream(&words[1..])?;
+            if !config.proxy_upstreams.iter().any(|group| group.name == name) {
+                config.proxy_upstreams.push(GroupSpec::new(&name));
+            }
+            let group = config.proxy_upstreams.iter_mut().find(|group| group.name == name).unwrap();
+            group.servers.retain(|&(ref configured, _)| *configured != address);
+            group.servers.push((address, max_idle));
+            Ok(config)
+        }
+        "proxy_balance" => {
+            if words.len() < 3 {
+                return Err("proxy_balance takes an upstream and a balancing method".to_string());
+            }
+            let balance = Balance::parse(&words[2..])?;
+            upstream_group(&mut config, words[1])?.balance = balance;
+            Ok(config)
+        }
+        "proxy_health_check" => {
+            if words.len() != 4 || !words[2].starts_with('/') {
+                return Err("proxy_health_check takes an upstream, a path and an interval".to_string());
+            }
+            let interval = parse_number::<u64>(words[3])?;
+            if interval == 0 {
+                return Err("proxy_health_check needs an interval of at least 1 second".to_string());
+            }
+            upstream_group(&mut config, words[1])?.health_check = Some((words[2].to_string(), Duration::from_secs(interval)));
+            Ok(config)
+        }
+        "proxy_eject" => {
+            if words.len() != 4 {
+                return Err("proxy_eject takes an upstream, a number of errors and a cool-down".to_string());
+            }
+            let failures = parse_number::<u32>(words[2])?;
+            let cooldown = parse_number::<u64>(words[3])?;
+            let group = upstream_group(&mut config, words[1])?;
+            group.max_failures = failures;
+            group.cooldown = Duration::from_secs(cooldown);
+            Ok(config)
+        }
+        "proxy_timeout" => {
+            single_arg(words)
+                .and_then(|s| parse_number::<u64>(s))
+                .map(|secs| {
+                    config.proxy_timeout = Duration::from_secs(secs);
+                    config
+                })
+        }
+        "route" => {
+            if words.len() < 5 {
+                return Err("route takes methods, a kind of match, a path and a handler".to_string());
+            }
+            let methods = match words[1] {
+                "*" => vec![],
+                methods => methods.split(',').filter(|m| !m.is_empty()).map(|m| m.to_uppercase()).collect()
+            };
+            let matcher = Matcher::parse(words[2], words[3])?;
+            let target = Target::parse(&words[4..])?;
+            match target {
+                Target::FastCgi(ref backend) if !config.fastcgi_backends.iter().any(|&(ref name, _, _)| name == backend) => {
+                    return Err(format!("no fastcgi_backend named {}", backend));
+                }
+                Target::Proxy(ref upstream) if !config.proxy_upstreams.iter().any(|group| group.name == *upstream) => {
+                    return Err(format!("no proxy_upstream named {}", upstream));
+                }
+                _ => ()
+            }
+            config.routes.push(RouteSpec { methods: methods, matcher: matcher, target: target, layers: vec![] });
+            Ok(config)
+        }
+        "route_auth" => {
+            if words.len() < 3 {
+                return Err("route_auth takes a realm and one or more user:password pairs".to_string());
+            }
+            let users = words[2..].iter()
+                .map(|pair| {
+                    let mut parts = pair.splitn(2, ':');
+                    match (parts.next(), parts.next()) {
+                        (Some(user), Some(password)) if !user.is_empty() => Ok((user.to_string(), password.to_string())),
+                        _ => Err(format!("{} is not a user:password pair", pair))
+                    }
+                })
+                .collect::<Result<Vec<(String, String)>, String>>()?;
+            add_layer(config, words[0], Layer::Auth { realm: words[1].to_string(), users: users })
+        }
+        "route_header" => {
+            if words.len() < 3 {
+                return Err("route_header takes a field name and a value".to_string());
+            }
+            let field = (words[1].to_string(), words[2..].join(" "));
+            add_layer(config, words[0], Layer::Headers(vec![field]))
+        }
+        "route_limit" => {
+            if words.len() != 3 {
+                return Err("route_limit takes a burst size and a refill rate".to_string());
+            }
+            let (burst, rate) = parse_bucket(words[1], words[2])?;
+            add_layer(config, words[0], Layer::Limit { burst: burst, rate: rate })
+        }
+        "index_files" => {
+            if words.len() < 2 {
+                return Err("index_files takes at least one file name".to_string());
+            }
+            if words[1..].iter().any(|name| name.contains('/')) {
+                return Err("index_files takes file names, not paths".to_string());
+            }
+            config.index_files = words[1..].iter().map(|name| name.to_string()).collect();
+            Ok(config)
+        }
+        "directory_listing" => {
+            single_arg(words)
+                .and_then(|s| {
+                    match s {
+                        "on" => Ok(true),
+                        "off" => Ok(false),
+                        other => Err(format!("directory_listing must be on or off, not {}", other))
+                    }
+                })
+                .map(|on| {
+                    config.directory_listing = on;
+                    config
+                })
+        }
+        "sendfile_min" => {
+            single_arg(words)
+                .and_then(|s| if s == "off" { Ok(None) } else { parse_size(s).map(|n| Some(n as u64)) })
+                .map(|min| {
+                    config.sendfile_min = min;
+                    config
+                })
+        }
+        directive => Err(format!("unknown directive {}", directive))
+    }
+}
+
+// Middleware directives apply to the route configured last.
+fn add_layer(mut config: Config, directive: &str, layer: Layer) -> Result<Config, String> {
+    match config.routes.last_mut() {
+        Some(route) => route.layers.push(layer),
+        None => return Err(format!("{} must follow a route", directive))
+    }
+    Ok(config)
+}
+
+fn upstream_group<'a>(config: &'a mut Config, name: &str) -> Result<&'a mut GroupSpec, String> {
+    config.proxy_upstreams.iter_mut()
+        .find(|group| group.name == name)
+        .ok_or_else(|| format!("no proxy_upstream named {}", name))
+}
+
+fn single_arg<'a>(words: &[&'a str]) -> Result<&'a str, String> {
+    match words.len() {
+        2 => Ok(words[1]),
+        _ => Err(format!("{} takes exactly one argument", words[0]))
+    }
+}
+
+fn parse_number<T: ::std::str::FromStr>(word: &str) -> Result<T, String> {
+    word.parse::<T>().map_err(|_| format!("{} is not a valid number", word))
+}
+
+// Sizes are in bytes, optionally suffixed with K, M or G.
+fn parse_size(word: &str) -> Result<usize, String> {
+    let (digits, shift) = match word.chars().last() {
+        Some('K') | Some('k') => (&word[..word.len() - 1], 10),
+        Some('M') | Some('m') => (&word[..word.len() - 1], 20),
+        Some('G') | Some('g') => (&word[..word.len() - 1], 30),
+        _ => (word, 0)
+    };
+    digits.parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("{} is not a valid size", word))
+}
+
+fn parse_rate_limit(args: &[&str]) -> Result<LimitRule, String> {
+    if args.len() != 3 {
+        return Err("rate_limit takes a target, a burst size and a refill rate".to_string());
+    }
+    let target = match args[0] {
+        "high" => Ok(RuleTarget::Class(Priority::High)),
+        "low" => Ok(RuleTarget::Class(Priority::Low)),
+        cidr => Cidr::parse(cidr).map(|c| RuleTarget::Network(c))
+    };
+    target.and_then(|t| parse_bucket(args[1], args[2]).map(|(burst, rate)| LimitRule::new(args[0], t, burst, rate)))
+}
+
+// A token bucket's burst size, at least 1, and its refill rate per second,
+// which has to be positive for a limited client ever to get in again.
+fn parse_bucket(burst: &str, rate: &str) -> Result<(f64, f64), String> {
+    let (burst, rate) = (parse_number::<f64>(burst)?, parse_number::<f64>(rate)?);
+    if !burst.is_finite() || burst < 1.0 {
+        return Err(format!("a burst of {} is not a whole request or more", burst));
+    }
+    if !rate.is_finite() || rate <= 0.0 {
+        return Err(format!("a rate of {} requests per second is not positive", rate));
+    }
+    Ok((burst, rate))
+}
+
+fn parse_fastcgi_backend(args: &[&str]) -> Result<(String, Address, usize), String> {
+    if args.len() != 2 && args.len() != 3 {
+        return Err("fastcgi_backend takes a name, an address and optionally a connection limit".to_string());
+    }
+    let connections = match args.get(2) {
+        Some(n) => parse_number::<usize>(n)?,
+        None => 4
+    };
+    if connections == 0 {
+        return Err("fastcgi_backend needs at least 1 connection".to_string());
+    }
+    Address::parse(args[1]).map(|address| (args[0].to_string(), address, connections))
+}
+
+fn parse_proxy_upstream(args: &[&str]) -> Result<(String, String, usize), String> {
+    if args.len() != 2 && args.len() != 3 {
+        return Err("proxy_upstream takes a name, a host:port and optionally an idle connection limit".to_string());
+    }
+    let port = args[1].rsplit(':').next().and_then(|port| port.parse::<u16>().ok());
+    if !args[1].contains(':') || port.is_none() {
+        return Err(format!("{} is not host:port", args[1]));
+    }
+    let connections = match args.get(2) {
+        Some(n) => parse_number::<usize>(n)?,
+        None => 8
+    };
+    Ok((args[0].to_string(), args[1].to_string(), connections))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use std::path::PathBuf;
+    use scheduling::Priority;
+    use rate_limit::RuleTarget;
+    use cache::Policy;
+    use ssi::directive::Encoding;
+    use fastcgi::Address;
+    use document_root::{ Hidden, Symlinks };
+    use middleware::Layer;
+    use proxy::{ Balance, GroupSpec };
+    use router::{ Matcher, RouteSpec, Target };
+    use super::{ Config, parse_size };
+
+    #[test]
+    fn empty_file_uses_defaults() {
+        let config = Config::parse("").unwrap();
+
+        assert_eq!(config.address, "127.0.0.1:4414");
+        assert!(config.rate_limits.is_empty());
+    }
+
+    #[test]
+    fn parses_directives_ignoring_comments() {
+        let config = Config::parse(
+            "# ps3 settings
+            listen 0.0.0.0:8080   # everyone
+            rate_limit low 20 2.5
+            rate_limit 10.0.0.0/8 5 1
+            rate_limit_expiry 60").unwrap();
+
+        assert_eq!(config.address, "0.0.0.0:8080");
+        assert_eq!(config.rate_limit_expiry, Duration::from_secs(60));
+        assert_eq!(config.rate_limits.len(), 2);
+        assert_eq!(config.rate_limits[0].target, RuleTarget::Class(Priority::Low));
+        assert_eq!(config.rate_limits[1].label, "10.0.0.0/8");
+    }
+
+    #[test]
+    fn refuses_rate_limits_that_never_limit_or_never_refill() {
+        assert!(Config::parse("rate_limit_expiry 0").is_err());
+        assert!(Config::parse("rate_limit low 0.5 1").is_err());
+        assert!(Config::parse("rate_limit low -5 1").is_err());
+        assert!(Config::parse("rate_limit low inf 1").is_err());
+        assert!(Config::parse("rate_limit low 5 0").is_err());
+        assert!(Config::parse("rate_limit low 5 NaN").is_err());
+        assert!(Config::parse("rate_limit low 5 0.1").is_ok());
+    }
+
+    #[test]
+    fn parses_cache_sizes_with_suffixes() {
+        let config = Config::parse("cache_size 2M\ncache_max_object 512k").unwrap();
+
+        assert_eq!(config.cache_size, 2 * 1024 * 1024);
+        assert_eq!(config.cache_max_object, 512 * 1024);
+        assert_eq!(parse_size("1G"), Ok(1 << 30));
+        assert_eq!(parse_size("100"), Ok(100));
+        assert!(parse_size("lots").is_err());
+    }
+
+    #[test]
+    fn selects_eviction_policy() {
+        assert_eq!(Config::parse("").unwrap().cache_policy, Policy::Lru);
+        assert_eq!(Config::parse("cache_policy 2q").unwrap().cache_policy, Policy::TwoQueue);
+        assert_eq!(Config::parse("cache_shards 4").unwrap().cache_shards, 4);
+        assert!(Config::parse("cache_shards 0").is_err());
+
+        match Config::parse("cache_policy random") {
+            Ok(_) => panic!("accepted unknown policy"),
+            Err(e) => assert_eq!(e, "line 1: random is not a cache policy (lru, lfu, 2q, tinylfu)")
+        }
+    }
+
+    #[test]
+    fn collects_watched_directories() {
+        let config = Config::parse("cache_revalidate 5\ncache_watch public\ncache_watch test").unwrap();
+
+        assert_eq!(config.cache_revalidate, Duration::from_secs(5));
+        assert_eq!(config.cache_watch, vec![PathBuf::from("public"), PathBuf::from("test")]);
+    }
+
+    #[test]
+    fn requires_at_least_one_loader() {
+        let config = Config::parse("cache_loaders 4\ncache_fill_wait 250").unwrap();
+        assert_eq!(config.cache_loaders, 4);
+        assert_eq!(config.cache_fill_wait, Duration::from_millis(250));
+
+        assert!(Config::parse("cache_loaders 0").is_err());
+    }
+
+    #[test]
+    fn collects_warm_up_settings() {
+        let config = Config::parse(
+            "cache_warm public/**/*.css
+            cache_warm test/small.html
+            cache_warm_rate 1M
+            cache_manifest popular.manifest
+            cache_manifest_size 20").unwrap();
+
+        assert_eq!(config.cache_warm, vec!["public/**/*.css", "test/small.html"]);
+        assert_eq!(config.cache_warm_rate, 1 << 20);
+        assert_eq!(config.cache_manifest, Some(PathBuf::from("popular.manifest")));
+        assert_eq!(config.cache_manifest_size, 20);
+        assert_eq!(Config::parse("").unwrap().cache_manifest, None);
+    }
+
+    #[test]
+    fn render_caching_is_off_by_default() {
+        assert_eq!(Config::parse("").unwrap().ssi_cache_ttl, Duration::from_secs(0));
+        assert_eq!(Config::parse("ssi_cache_ttl 15").unwrap().ssi_cache_ttl, Duration::from_secs(15));
+    }
+
+    #[test]
+    fn sets_default_ssi_encoding() {
+        assert_eq!(Config::parse("").unwrap().ssi_encoding, Encoding::None);
+        assert_eq!(Config::parse("ssi_encoding entity").unwrap().ssi_encoding, Encoding::Entity);
+        assert!(Config::parse("ssi_encoding base64").is_err());
+    }
+
+    #[test]
+    fn limits_concurrent_ssi_commands() {
+        assert_eq!(Config::parse("").unwrap().ssi_exec_parallelism, 4);
+        assert_eq!(Config::parse("ssi_exec_parallelism 8").unwrap().ssi_exec_parallelism, 8);
+        assert!(Config::parse("ssi_exec_parallelism 0").is_err());
+    }
+
+    #[test]
+    fn collects_exec_sandbox_rules() {
+        let config = Config::parse(
+            "ssi_exec_allow date
+            ssi_exec_allow /usr/bin/uptime
+            ssi_exec_timeout 3
+            ssi_exec_cpu 2
+            ssi_exec_memory 64M
+            ssi_exec_files 16
+            ssi_exec_processes 8
+            ssi_exec_output 4K
+            ssi_exec_user 65534
+            ssi_exec_error <b>not allowed</b>").unwrap();
+        let sandbox = config.ssi_exec;
+
+        assert_eq!(sandbox.allow, vec!["date", "/usr/bin/uptime"]);
+        assert_eq!(sandbox.timeout, Duration::from_secs(3));
+        assert_eq!(sandbox.cpu_secs, Some(2));
+        assert_eq!(sandbox.memory, Some(64 << 20));
+        assert_eq!(sandbox.open_files, Some(16));
+        assert_eq!(sandbox.processes, Some(8));
+        assert_eq!(sandbox.max_output, 4096);
+        assert_eq!(sandbox.user.map(|(uid, _)| uid), Some(65534));
+        assert_eq!(sandbox.error_text, "<b>not allowed</b>");
+    }
+
+    #[test]
+    fn exec_sandbox_never_runs_as_root() {
+        assert!(Config::parse("ssi_exec_user root").is_err());
+        assert!(Config::parse("ssi_exec_user 0").is_err());
+        assert!(Config::parse("ssi_exec_user no-such-user-here").is_err());
+        assert_eq!(Config::parse("").unwrap().ssi_exec.user, None);
+    }
+
+    #[test]
+    fn configures_cgi_scripts() {
+        let config = Config::parse("cgi_dir cgi-bin/\ncgi_timeout 5\ncgi_output 64K\ncgi_user 65534").unwrap();
+
+        assert_eq!(config.cgi_dir, Some(PathBuf::from("cgi-bin")));
+        assert_eq!(config.cgi_exec.timeout, Duration::from_secs(5));
+        assert_eq!(config.cgi_exec.max_output, 64 << 10);
+        assert_eq!(config.cgi_exec.user.map(|(uid, _)| uid), Some(65534));
+        assert_eq!(Config::parse("").unwrap().cgi_dir, None);
+        assert!(Config::parse("cgi_user root").is_err());
+        assert_eq!(Config::parse("cgi_dir scripts/cgi").unwrap().cgi_dir, Some(PathBuf::from("scripts/cgi")));
+        for dir in &["/usr/lib/cgi-bin", "./cgi-bin", "cgi-bin/../secret", "cgi-bin/./x", "cgi//bin", "/"] {
+            assert!(Config::parse(&format!("cgi_dir {}", dir)).is_err(), "{}", dir);
+        }
+    }
+
+    #[test]
+    fn routes_paths_to_fastcgi_backends() {
+        let config = Config::parse(
+            "fastcgi_backend php unix:/run/php-fpm.sock 8
+            fastcgi_backend api 127.0.0.1:9000
+            fastcgi_route **/*.php php
+            fastcgi_route /api/** api
+            fastcgi_timeout 5
+            fastcgi_output 2M").unwrap();
+
+        assert_eq!(config.fastcgi_backends, vec![
+            ("php".to_string(), Address::Unix(PathBuf::from("/run/php-fpm.sock")), 8),
+            ("api".to_string(), Address::Tcp("127.0.0.1:9000".to_string()), 4)
+        ]);
+        assert_eq!(config.fastcgi_routes, vec![("**/*.php".to_string(), "php".to_string()), ("api/**".to_string(), "api".to_string())]);
+        assert_eq!(config.fastcgi_timeout, Duration::from_secs(5));
+        assert_eq!(config.fastcgi_output, 2 << 20);
+        assert!(Config::parse("fastcgi_route **/*.php php").is_err());
+        assert!(Config::parse("fastcgi_backend php localhost").is_err());
+        assert!(Config::parse("fastcgi_backend php 127.0.0.1:9000 0").is_err());
+    }
+
+    #[test]
+    fn configures_proxy_upstreams() {
+        let config = Config::parse(
+            "proxy_upstream app 127.0.0.1:8080 16
+            proxy_upstream api localhost:9000
+            proxy_upstream app 127.0.0.1:8081
+            proxy_upstream app 127.0.0.1:8080 4
+            proxy_balance app hash uri
+            proxy_health_check app /healthz 5
+            proxy_eject app 5 60
+            proxy_timeout 5
+            route * prefix /app/ proxy app").unwrap();
+
+        let mut app = GroupSpec::new("app");
+        app.servers = vec![("127.0.0.1:8081".to_string(), 8), ("127.0.0.1:8080".to_string(), 4)];
+        app.balance = Balance::parse(&["hash", "uri"]).unwrap();
+        app.health_check = Some(("/healthz".to_string(), Duration::from_secs(5)));
+        app.max_failures = 5;
+        app.cooldown = Duration::from_secs(60);
+        let mut api = GroupSpec::new("api");
+        api.servers = vec![("localhost:9000".to_string(), 8)];
+        assert_eq!(config.proxy_upstreams, vec![app, api]);
+        assert_eq!(config.proxy_timeout, Duration::from_secs(5));
+        assert_eq!(config.routes[0].target, Target::Proxy("app".to_string()));
+        assert!(Config::parse("route * prefix /app/ proxy app").is_err());
+        assert!(Config::parse("proxy_upstream app localhost").is_err());
+        assert!(Config::parse("proxy_upstream app localhost:http").is_err());
+        assert!(Config::parse("proxy_balance app round-robin").is_err());
+        assert!(Config::parse("proxy_upstream app localhost:80\nproxy_balance app random").is_err());
+        assert!(Config::parse("proxy_upstream app localhost:80\nproxy_health_check app healthz 5").is_err());
+        assert!(Config::parse("proxy_upstream app localhost:80\nproxy_health_check app /healthz 0").is_err());
+    }
+
+    #[test]
+    fn configures_routes_and_their_middleware() {
+        let config = Config::parse(
+            "fastcgi_backend app 127.0.0.1:9000
+            route get,head prefix /docs/ static
+            route_header Cache-Control no-store, private
+            route * pattern /api/:version/** fastcgi app
+            route_auth api alice:secret bob:pa:ss
+            route_limit 10 0.5").unwrap();
+
+        assert_eq!(config.routes, vec![
+            RouteSpec {
+                methods: vec!["GET".to_string(), "HEAD".to_string()],
+                matcher: Matcher::Prefix("/docs/".to_string()),
+                target: Target::Static,
+                layers: vec![Layer::Headers(vec![("Cache-Control".to_string(), "no-store, private".to_string())])]
+            },
+            RouteSpec {
+                methods: vec![],
+                matcher: Matcher::Pattern("/api/:version/**".to_string()),
+                target: Target::FastCgi("app".to_string()),
+                layers: vec![
+                    Layer::Auth { realm: "api".to_string(),
+                                  users: vec![("alice".to_string(), "secret".to_string()), ("bob".to_string(), "pa:ss".to_string())] },
+                    Layer::Limit { burst: 10.0, rate: 0.5 }
+                ]
+            }
+        ]);
+        assert!(Config::parse("route_header X-Frame-Options deny").is_err());
+        assert!(Config::parse("route GET prefix docs static").is_err());
+        assert!(Config::parse("route GET prefix /docs/ php").is_err());
+        assert!(Config::parse("route GET prefix /app/ fastcgi app").is_err());
+        assert!(Config::parse("route GET prefix /docs/ static\nroute_auth admin alice").is_err());
+        assert!(Config::parse("route GET prefix /docs/ static\nroute_limit 10 0").is_err());
+    }
+
+    #[test]
+    fn sets_document_root_and_its_policies() {
+        let defaults = Config::parse("").unwrap();
+        assert_eq!(defaults.document_root, PathBuf::from("."));
+        assert_eq!(defaults.symlinks, Symlinks::SameOwner);
+        assert_eq!(defaults.hidden_files, Hidden::Deny);
+
+        let config = Config::parse("document_root public\nsymlinks deny\nhidden_files hide").unwrap();
+        assert_eq!(config.document_root, PathBuf::from("public"));
+        assert_eq!(config.symlinks, Symlinks::Deny);
+        assert_eq!(config.hidden_files, Hidden::Hide);
+        assert!(Config::parse("symlinks follow").is_err());
+        assert!(Config::parse("hidden_files show").is_err());
+    }
+
+    #[test]
+    fn configures_directory_requests() {
+        let defaults = Config::parse("").unwrap();
+        assert_eq!(defaults.index_files, vec!["index.html".to_string(), "index.shtml".to_string()]);
+        assert!(!defaults.directory_listing);
+
+        let config = Config::parse("index_files default.htm index.shtml\ndirectory_listing on").unwrap();
+        assert_eq!(config.index_files, vec!["default.htm".to_string(), "index.shtml".to_string()]);
+        assert!(config.directory_listing);
+        assert!(Config::parse("index_files").is_err());
+        assert!(Config::parse("index_files sub/index.html").is_err());
+        assert!(Config::parse("directory_listing yes").is_err());
+    }
+
+    #[test]
+    fn sendfile_threshold_can_be_disabled() {
+        assert_eq!(Config::parse("").unwrap().sendfile_min, Some(64 * 1024));
+        assert_eq!(Config::parse("sendfile_min 1M").unwrap().sendfile_min, Some(1 << 20));
+        assert_eq!(Config::parse("sendfile_min off").unwrap().sendfile_min, None);
+    }
+
+    #[test]
+    fn reports_line_of_bad_directive() {
+        match Config::parse("listen 0.0.0.0:8080\nrate_limit everyone 1 1") {
+            Ok(_) => panic!("accepted invalid rate limit"),
+            Err(e) => assert_eq!(e, "line 2: everyone is not a valid CIDR block")
+        }
+        match Config::parse("\nbogus 1") {
+            Ok(_) => panic!("accepted unknown directive"),
+            Err(e) => assert_eq!(e, "line 2: unknown directive bogus")
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{ Component, Path, PathBuf };
+use freshness::cache_key;
+
+// Which symbolic links along a request path are followed. Wherever they
+// point, the file they lead to must still be inside the document root.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Symlinks {
+    Deny,
+    // Only links owned by the owner of what they point to, as with Apache's
+    // SymLinksIfOwnerMatch.
+    SameOwner,
+    Allow
+}
+
+impl Symlinks {
+    pub fn parse(word: &str) -> Result<Symlinks, String> {
+        match word {
+            "deny" => Ok(Symlinks::Deny),
+            "same-owner" => Ok(Symlinks::SameOwner),
+            "allow" => Ok(Symlinks::Allow),
+            other => Err(format!("symlinks must be deny, same-owner or allow, not {}", other))
+        }
+    }
+}
+
+// What becomes of requests for files and directories whose names start
+// with a dot.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hidden {
+    Deny,
+    // Answered as though they were not there.
+    Hide,
+    Allow
+}
+
+impl Hidden {
+    pub fn parse(word: &str) -> Result<Hidden, String> {
+        match word {
+            "deny" => Ok(Hidden::Deny),
+            "hide" => Ok(Hidden::Hide),
+            "allow" => Ok(Hidden::Allow),
+            other => Err(format!("hidden_files must be deny, hide or allow, not {}", other))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Denied {
+    NotFound,
+    OutsideRoot,
+    Hidden,
+    Symlink
+}
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Denied::NotFound => write!(f, "not found"),
+            &Denied::OutsideRoot => write!(f, "outside the document root"),
+            &Denied::Hidden => write!(f, "hidden"),
+            &Denied::Symlink => write!(f, "behind a symlink that may not be followed")
+        }
+    }
+}
+
+// The directory files are served from.
+#[derive(Debug, Clone)]
+pub struct DocumentRoot {
+    // As configured, less any leading `./`, so that paths under it match the
+    // cache's keys.
+    dir: PathBuf,
+    canonical: PathBuf,
+    symlinks: Symlinks,
+    hidden: Hidden
+}
+
+impl DocumentRoot {
+    pub fn new(dir: &Path, symlinks: Symlinks, hidden: Hidden) -> io::Result<DocumentRoot> {
+        let canonical = fs::canonicalize(dir)?;
+        if !canonical.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a directory", dir.display())));
+        }
+        Ok(DocumentRoot { dir: cache_key(dir), canonical: canonical, symlinks: symlinks, hidden: hidden })
+    }
+
+    // The root's canonical, absolute path.
+    pub fn canonical(&self) -> &Path {
+        &self.canonical
+    }
+
+    // Finds the file or directory at `relative`, a path from the root. Each
+    // step is checked against the hidden-file and symlink policies, and the
+    // canonical path of what is found has to be inside the root. The path
+    // returned is under the root as configured.
+    pub fn resolve(&self, relative: &Path) -> Result<PathBuf, Denied> {
+        let mut path = self.dir.clone();
+        for component in relative.components() {
+            let name = match component {
+                Component::Normal(name) => name,
+                Component::CurDir => continue,
+                _ => return Err(Denied::OutsideRoot)
+            };
+            if name.to_string_lossy().starts_with('.') {
+                match self.hidden {
+                    Hidden::Deny => return Err(Denied::Hidden),
+                    Hidden::Hide => return Err(Denied::NotFound),
+                    Hidden::Allow => ()
+                }
+            }
+            path.push(name);
+            let link = fs::symlink_metadata(&path).map_err(|_| Denied::NotFound)?;
+            if link.file_type().is_symlink() {
+                self.follow(&path, &link)?;
+            }
+        }
+        let canonical = fs::canonicalize(&path).map_err(|_| Denied::NotFound)?;
+        if !canonical.starts_with(&self.canonical) {
+            return Err(Denied::OutsideRoot);
+        }
+        Ok(path)
+    }
+
+    // Where `relative` would be under the root as configured, unchecked.
+    pub fn join(&self, relative: &Path) -> PathBuf {
+        self.dir.join(relative)
+    }
+
+    // The path from the root to `path`, a path under the root as configured.
+    pub fn relative<'a>(&self, path: &'a Path) -> Option<&'a Path> {
+        path.strip_prefix(&self.dir).ok()
+    }
+
+    fn follow(&self, path: &Path, link: &fs::Metadata) -> Result<(), Denied> {
+        match self.symlinks {
+            Symlinks::Deny => Err(Denied::Symlink),
+            Symlinks::SameOwner => {
+                let target = fs::metadata(path).map_err(|_| Denied::NotFound)?;
+                if target.uid() == link.uid() { Ok(()) } else { Err(Denied::Symlink) }
+            }
+            Symlinks::Allow => Ok(())
+        }
+    }
+}
+
+impl Default for DocumentRoot {
+    // The working directory.
+    fn default() -> Self {
+        DocumentRoot::new(Path::new("."), Symlinks::SameOwner, Hidden::Deny)
+            .expect("working directory is not readable")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::CString;
+    use std::fs::{ create_dir_all, remove_file, File };
+    use std::io::Write;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::symlink;
+    use std::path::{ Path, PathBuf };
+    use libc;
+    use super::{ Denied, DocumentRoot, Hidden, Symlinks };
+
+    // A document root with a page, a hidden file, a directory of secrets
+    // next to it and links leading both inside and out.
+    fn tree(name: &str) -> PathBuf {
+        let base = PathBuf::from("test/tmp/document_root").join(name);
+        create_dir_all(base.join("public/docs")).unwrap();
+        create_dir_all(base.join("private")).unwrap();
+        File::create(base.join("public/docs/page.html")).unwrap().write_all(b"<p>page</p>").unwrap();
+        File::create(base.join("public/.env")).unwrap().write_all(b"KEY=secret").unwrap();
+        File::create(base.join("private/secret.html")).unwrap().write_all(b"<p>secret</p>").unwrap();
+        for &(target, link) in &[("docs/page.html", "public/inside.html"), ("../private/secret.html", "public/outside.html"),
+                                 ("../private", "public/escape")] {
+            let _ = remove_file(base.join(link));
+            symlink(target, base.join(link)).unwrap();
+        }
+        base
+    }
+
+    fn root(base: &Path, symlinks: Symlinks, hidden: Hidden) -> DocumentRoot {
+        DocumentRoot::new(&base.join("public"), symlinks, hidden).unwrap()
+    }
+
+    #[test]
+    fn resolves_paths_under_the_root_as_configured() {
+        let base = tree("plain");
+        let root = root(&base, Symlinks::Deny, Hidden::Deny);
+
+        assert_eq!(root.resolve(Path::new("docs/page.html")), Ok(base.join("public/docs/page.html")));
+        assert_eq!(root.resolve(Path::new("./docs/")), Ok(base.join("public/docs")));
+        assert_eq!(root.resolve(Path::new("docs/missing.html")), Err(Denied::NotFound));
+        assert_eq!(DocumentRoot::new(Path::new("./test"), Symlinks::Deny, Hidden::Deny).unwrap().resolve(Path::new("response.html")),
+                   Ok(PathBuf::from("test/response.html")));
+    }
+
+    #[test]
+    fn refuses_to_climb_out_of_the_root() {
+        let base = tree("climb");
+        let root = root(&base, Symlinks::Allow, Hidden::Allow);
+
+        assert_eq!(root.resolve(Path::new("../private/secret.html")), Err(Denied::OutsideRoot));
+        assert_eq!(root.resolve(Path::new("docs/../../private/secret.html")), Err(Denied::OutsideRoot));
+        assert_eq!(root.resolve(Path::new("/etc/passwd")), Err(Denied::OutsideRoot));
+        // Left encoded, the dots are only a strange file name.
+        assert_eq!(root.resolve(Path::new("%2e%2e/private/secret.html")), Err(Denied::NotFound));
+        assert_eq!(root.resolve(Path::new("..%2fprivate/secret.html")), Err(Denied::NotFound));
+    }
+
+    #[test]
+    fn keeps_followed_symlinks_inside_the_root() {
+        let base = tree("symlinks");
+        let allow = root(&base, Symlinks::Allow, Hidden::Deny);
+
+        assert_eq!(allow.resolve(Path::new("inside.html")), Ok(base.join("public/inside.html")));
+        assert_eq!(allow.resolve(Path::new("outside.html")), Err(Denied::OutsideRoot));
+        assert_eq!(allow.resolve(Path::new("escape/secret.html")), Err(Denied::OutsideRoot));
+
+        let deny = root(&base, Symlinks::Deny, Hidden::Deny);
+        assert_eq!(deny.resolve(Path::new("inside.html")), Err(Denied::Symlink));
+        assert_eq!(deny.resolve(Path::new("escape/secret.html")), Err(Denied::Symlink));
+    }
+
+    #[test]
+    fn follows_symlinks_owned_by_their_target_owner() {
+        let base = tree("owners");
+        let same_owner = root(&base, Symlinks::SameOwner, Hidden::Deny);
+        assert_eq!(same_owner.resolve(Path::new("inside.html")), Ok(base.join("public/inside.html")));
+
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let link = CString::new(base.join("public/inside.html").as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::lchown(link.as_ptr(), 65534, 65534) }, 0);
+
+        assert_eq!(same_owner.resolve(Path::new("inside.html")), Err(Denied::Symlink));
+    }
+
+    #[test]
+    fn applies_hidden_file_policy() {
+        let base = tree("hidden");
+
+        assert_eq!(root(&base, Symlinks::Deny, Hidden::Deny).resolve(Path::new(".env")), Err(Denied::Hidden));
+        assert_eq!(root(&base, Symlinks::Deny, Hidden::Hide).resolve(Path::new(".env")), Err(Denied::NotFound));
+        assert_eq!(root(&base, Symlinks::Deny, Hidden::Allow).resolve(Path::new(".env")), Ok(base.join("public/.env")));
+    }
+}
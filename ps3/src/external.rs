@@ -1,14 +1,15 @@
 // Copied with modification from ps2
 
-use std::process::{ Command, Stdio, Child };
+use std::process::{ Stdio, Child };
 use cmd_line::{ CmdLine, CmdIO };
+use sandbox::{ Sandbox, stop };
 use std::fs::OpenOptions;
 use std::error::Error;
 
-pub fn run(cmd: &CmdLine) -> Result<Child, String> {
+pub fn run(cmd: &CmdLine, sandbox: &Sandbox, env: &[(String, String)]) -> Result<Child, String> {
     let stdin = use_std_io(&cmd.stdin);
     let stdout = use_std_io(&cmd.stdout);
-    Command::new(cmd.name)
+    sandbox.command(cmd.name, env)
         .args(&cmd.args)
         .stdin(stdin)
         .stdout(stdout)
@@ -16,36 +17,39 @@ pub fn run(cmd: &CmdLine) -> Result<Child, String> {
         .map_err(|e| e.description().to_string())
 }
 
-pub fn run_chain(cmds: &Vec<CmdLine>) -> Result<Child, String> {
-    let cmd = &cmds[0];
-    let first = run(cmd);
-
-    run_chain_iter(&cmds[1..], first)
-}
-
-fn run_chain_iter(cmds: &[CmdLine], previous: Result<Child, String>) -> Result<Child, String> {
-    match previous {
-        Ok(prev_cmd) => {
-            if cmds.is_empty() {
-                return Ok(prev_cmd)
-            } else {
-                if let Some(stdout) = prev_cmd.stdout {
-                    let cmd = &cmds[0];
-                    let next = Command::new(cmd.name)
+// Starts every command in the chain, each reading the output of the one
+// before. If one cannot be started, those already running are stopped.
+pub fn run_chain(cmds: &[CmdLine], sandbox: &Sandbox, env: &[(String, String)]) -> Result<Vec<Child>, String> {
+    let mut children: Vec<Child> = vec![];
+    for cmd in cmds {
+        let next = match children.last_mut() {
+            None => run(cmd, sandbox, env),
+            Some(previous) => {
+                match previous.stdout.take() {
+                    Some(stdout) => {
+                        sandbox.command(cmd.name, env)
                             .args(&cmd.args)
                             .stdin(stdout)
                             .stdout(use_std_io(&cmd.stdout))
                             .spawn()
-                            .map_err(|e| e.description().to_string());
-
-                    run_chain_iter(&cmds[1..], next)
-                } else {
-                    Err("Could not open stdout".to_string())
+                            .map_err(|e| e.to_string())
+                    }
+                    None => Err("Could not open stdout".to_string())
                 }
             }
-        },
-        Err(e) => Err(e)
+        };
+        match next {
+            Ok(child) => children.push(child),
+            Err(e) => {
+                stop(&children);
+                for child in children.iter_mut() {
+                    let _ = child.wait();
+                }
+                return Err(e);
+            }
+        }
     }
+    Ok(children)
 }
 
 fn use_std_io(io_path: &CmdIO) -> Stdio {
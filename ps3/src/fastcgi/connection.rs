@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{ self, Read, Write };
+use std::net::{ Shutdown, TcpStream, ToSocketAddrs };
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::mpsc::{ channel, Receiver, Sender };
+use std::thread;
+use std::time::Duration;
+use super::record::{ decode_pairs, encode_pairs, Record, END_REQUEST, GET_VALUES, GET_VALUES_RESULT };
+
+// Requests sent at once on one connection, however many the application
+// says it accepts.
+const MAX_MULTIPLEX: usize = 16;
+// How long an application has to say whether it multiplexes before it is
+// assumed not to.
+const NEGOTIATE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Address {
+    Unix(PathBuf),
+    Tcp(String)
+}
+
+impl Address {
+    // `unix:/path/to/socket` or `host:port`.
+    pub fn parse(text: &str) -> Result<Address, String> {
+        if text.starts_with("unix:") && text.len() > 5 {
+            Ok(Address::Unix(PathBuf::from(&text[5..])))
+        } else if text.contains(':') && !text.starts_with("unix:") {
+            Ok(Address::Tcp(text.to_string()))
+        } else {
+            Err(format!("{} is not unix:/path or host:port", text))
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Address::Unix(ref path) => write!(f, "unix:{}", path.display()),
+            &Address::Tcp(ref address) => write!(f, "{}", address)
+        }
+    }
+}
+
+enum Socket {
+    Unix(UnixStream),
+    Tcp(TcpStream)
+}
+
+impl Socket {
+    fn connect(address: &Address, timeout: Duration) -> io::Result<Socket> {
+        let socket = match address {
+            &Address::Unix(ref path) => Socket::Unix(UnixStream::connect(path)?),
+            &Address::Tcp(ref address) => {
+                let resolved = address.to_socket_addrs()?.next()
+                    .ok_or(io::Error::new(io::ErrorKind::NotFound, "address did not resolve"))?;
+                Socket::Tcp(TcpStream::connect_timeout(&resolved, timeout)?)
+            }
+        };
+        // A stalled application must not hold the write lock forever.
+        match &socket {
+            &Socket::Unix(ref stream) => stream.set_write_timeout(Some(timeout))?,
+            &Socket::Tcp(ref stream) => stream.set_write_timeout(Some(timeout))?
+        }
+        Ok(socket)
+    }
+
+    fn try_clone(&self) -> io::Result<Socket> {
+        match self {
+            &Socket::Unix(ref stream) => stream.try_clone().map(Socket::Unix),
+            &Socket::Tcp(ref stream) => stream.try_clone().map(Socket::Tcp)
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            &Socket::Unix(ref stream) => stream.set_read_timeout(timeout),
+            &Socket::Tcp(ref stream) => stream.set_read_timeout(timeout)
+        }
+    }
+
+    fn shutdown(&self) {
+        let _ = match self {
+            &Socket::Unix(ref stream) => stream.shutdown(Shutdown::Both),
+            &Socket::Tcp(ref stream) => stream.shutdown(Shutdown::Both)
+        };
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            &mut Socket::Unix(ref mut stream) => stream.read(buf),
+            &mut Socket::Tcp(ref mut stream) => stream.read(buf)
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            &mut Socket::Unix(ref mut stream) => stream.write(buf),
+            &mut Socket::Tcp(ref mut stream) => stream.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            &mut Socket::Unix(ref mut stream) => stream.flush(),
+            &mut Socket::Tcp(ref mut stream) => stream.flush()
+        }
+    }
+}
+
+type Waiting = Arc<Mutex<HashMap<u16, Sender<Record>>>>;
+
+// One connection to an application, shared by the requests in flight on it.
+// A reader thread hands each record to the request it belongs to; a request
+// keeps its id until the application ends it, even if it was abandoned.
+pub struct Connection {
+    writer: Mutex<Socket>,
+    waiting: Waiting,
+    closed: Arc<AtomicBool>,
+    // Requests the application takes at once on this connection.
+    max_requests: usize
+}
+
+impl Connection {
+    pub fn open(address: &Address, timeout: Duration) -> io::Result<Connection> {
+        let mut socket = Socket::connect(address, timeout)?;
+        let max_requests = negotiate(&mut socket)?;
+        let reader = socket.try_clone()?;
+        let waiting: Waiting = Arc::new(Mutex::new(HashMap::new()));
+        let closed = Arc::new(AtomicBool::new(false));
+        let (demux_waiting, demux_closed) = (waiting.clone(), closed.clone());
+        thread::spawn(move || demultiplex(reader, demux_waiting, demux_closed));
+        Ok(Connection { writer: Mutex::new(socket), waiting: waiting, closed: closed, max_requests: max_requests })
+    }
+
+    // Reserves a request id and the channel its records arrive on, unless
+    // the connection is full or closed.
+    pub fn start(&self) -> Option<(u16, Receiver<Record>)> {
+        let mut waiting = self.waiting.lock().unwrap();
+        if self.is_closed() || waiting.len() >= self.max_requests {
+            return None;
+        }
+        let id = (1..).find(|id| !waiting.contains_key(id)).unwrap_or(1);
+        let (records, received) = channel();
+        waiting.insert(id, records);
+        Some((id, received))
+    }
+
+    pub fn send(&self, records: &[Record]) -> io::Result<()> {
+        let mut bytes = vec![];
+        for record in records {
+            record.write_to(&mut bytes)?;
+        }
+        let mut writer = self.writer.lock().unwrap();
+        writer.write_all(&bytes).inspect_err(|_| {
+            self.closed.store(true, Ordering::SeqCst);
+            writer.shutdown();
+        })
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.waiting.lock().unwrap().len()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for Connection {
+    // Ends the reader thread.
+    fn drop(&mut self) {
+        self.writer.lock().unwrap().shutdown();
+    }
+}
+
+// Asks whether the application multiplexes requests on a connection, and
+// how many it takes at once.
+fn negotiate(socket: &mut Socket) -> io::Result<usize> {
+    let query = vec![("FCGI_MPXS_CONNS".to_string(), "".to_string()), ("FCGI_MAX_REQS".to_string(), "".to_string())];
+    Record::new(GET_VALUES, 0, encode_pairs(&query)).write_to(socket)?;
+    socket.set_read_timeout(Some(NEGOTIATE_TIMEOUT))?;
+    let answer = loop {
+        match Record::read_from(socket) {
+            Ok(ref record) if record.kind == GET_VALUES_RESULT => break decode_pairs(&record.content).unwrap_or_default(),
+            Ok(_) => continue,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => break vec![],
+            Err(e) => return Err(e)
+        }
+    };
+    socket.set_read_timeout(None)?;
+    let value = |name: &str| answer.iter().find(|&&(ref n, _)| n == name).map(|&(_, ref v)| v.clone());
+    if value("FCGI_MPXS_CONNS").as_ref().map(|v| &v[..]) != Some("1") {
+        return Ok(1);
+    }
+    let max_requests = value("FCGI_MAX_REQS").and_then(|v| v.parse::<usize>().ok()).unwrap_or(MAX_MULTIPLEX);
+    Ok(max_requests.clamp(1, MAX_MULTIPLEX))
+}
+
+fn demultiplex(mut reader: Socket, waiting: Waiting, closed: Arc<AtomicBool>) {
+    loop {
+        match Record::read_from(&mut reader) {
+            Ok(record) => {
+                let (id, end) = (record.request_id, record.kind == END_REQUEST);
+                let mut waiting = waiting.lock().unwrap();
+                if let Some(records) = waiting.get(&id) {
+                    let _ = records.send(record);
+                }
+                if end {
+                    waiting.remove(&id);
+                }
+            }
+            Err(_) => {
+                closed.store(true, Ordering::SeqCst);
+                // Dropping the senders tells every waiting request.
+                waiting.lock().unwrap().clear();
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,362 @@
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Condvar, Mutex };
+use std::sync::mpsc::{ Receiver, RecvTimeoutError };
+use std::time::{ Duration, Instant };
+use cgi::{ meta_variables, Script };
+use path::encode_path;
+use request::RequestInfo;
+
+mod connection;
+mod record;
+#[cfg(test)]
+mod responder;
+
+pub use self::connection::Address;
+use self::connection::Connection;
+use self::record::{ encode_pairs, stream, Record, ABORT_REQUEST, CANT_MPX_CONN, END_REQUEST, KEEP_CONN,
+                    OVERLOADED, PARAMS, REQUEST_COMPLETE, STDERR, STDIN, STDOUT, UNKNOWN_ROLE };
+
+// The configured applications, and the globs of the paths routed to each
+// over FastCGI in the order they were configured.
+#[derive(Clone, Default)]
+pub struct FastCgi {
+    backends: Vec<Arc<Backend>>,
+    routes: Vec<(String, Arc<Backend>)>
+}
+
+impl FastCgi {
+    pub fn new(backends: &[(String, Address, usize)], routes: &[(String, String)], timeout: Duration, max_output: usize) -> FastCgi {
+        let backends = backends.iter()
+            .map(|&(ref name, ref address, connections)| {
+                Arc::new(Backend::new(name, address.clone(), connections, timeout, max_output))
+            })
+            .collect::<Vec<Arc<Backend>>>();
+        let routes = routes.iter()
+            .filter_map(|&(ref pattern, ref name)| {
+                backends.iter().find(|backend| backend.name == *name).map(|backend| (pattern.clone(), backend.clone()))
+            })
+            .collect();
+        FastCgi { backends: backends, routes: routes }
+    }
+
+    pub fn backend(&self, name: &str) -> Option<Arc<Backend>> {
+        self.backends.iter().find(|backend| backend.name == name).cloned()
+    }
+
+    pub fn routes(&self) -> &[(String, Arc<Backend>)] {
+        &self.routes
+    }
+}
+
+// The CGI meta-variables for a request, plus those applications such as
+// PHP expect from a FastCGI server.
+pub fn params(root: &Path, path: &str, info: &RequestInfo) -> Vec<(String, String)> {
+    let script = Script { path: PathBuf::from(path), path_info: String::new() };
+    let mut params = meta_variables(&script, info);
+    params.push(("SCRIPT_FILENAME".to_string(), root.join(path).display().to_string()));
+    params.push(("DOCUMENT_ROOT".to_string(), root.display().to_string()));
+    let target = encode_path(&info.uri);
+    let uri = if info.query.is_empty() { target } else { format!("{}?{}", target, info.query) };
+    params.push(("REQUEST_URI".to_string(), uri));
+    params
+}
+
+// An application and the pool of connections to it. A request takes a slot
+// on the least busy connection, opens another if every one is full and
+// the pool has room, and otherwise waits for a slot to free up.
+pub struct Backend {
+    pub name: String,
+    address: Address,
+    max_connections: usize,
+    timeout: Duration,
+    // Bytes of stdout a request may produce before it is abandoned.
+    max_output: usize,
+    connections: Mutex<Connections>,
+    freed: Condvar
+}
+
+// Connections are opened without holding the lock, so those being opened
+// are counted against the limit until they are ready.
+struct Connections {
+    open: Vec<Arc<Connection>>,
+    opening: usize
+}
+
+impl Backend {
+    pub fn new(name: &str, address: Address, max_connections: usize, timeout: Duration, max_output: usize) -> Backend {
+        Backend {
+            name: name.to_string(),
+            address: address,
+            max_connections: max_connections.max(1),
+            timeout: timeout,
+            max_output: max_output,
+            connections: Mutex::new(Connections { open: vec![], opening: 0 }),
+            freed: Condvar::new()
+        }
+    }
+
+    // Sends one request and returns what the application wrote to stdout.
+    pub fn respond(&self, params: &[(String, String)], body: &[u8]) -> Result<Vec<u8>, String> {
+        let deadline = Instant::now() + self.timeout;
+        let result = self.reserve(deadline)
+            .and_then(|(connection, id, records)| self.exchange(&connection, id, &records, params, body, deadline));
+        self.freed.notify_all();
+        result.map_err(|e| format!("{} ({}): {}", self.name, self.address, e))
+    }
+
+    fn reserve(&self, deadline: Instant) -> Result<(Arc<Connection>, u16, Receiver<Record>), String> {
+        let mut connections = self.connections.lock().unwrap();
+        loop {
+            connections.open.retain(|connection| !connection.is_closed());
+            let mut by_load = connections.open.clone();
+            by_load.sort_by_key(|connection| connection.in_flight());
+            for connection in by_load {
+                if let Some((id, records)) = connection.start() {
+                    return Ok((connection, id, records));
+                }
+            }
+            if connections.open.len() + connections.opening < self.max_connections {
+                connections.opening += 1;
+                drop(connections);
+                let opened = Connection::open(&self.address, self.timeout);
+                connections = self.connections.lock().unwrap();
+                connections.opening -= 1;
+                self.freed.notify_all();
+                connections.open.push(Arc::new(opened.map_err(|e| e.to_string())?));
+                continue;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err("no free connection".to_string());
+            }
+            // Slots also free up when an abandoned request finally ends, which
+            // nobody signals, so waits are short.
+            let wait = (deadline - now).min(Duration::from_millis(10));
+            connections = self.freed.wait_timeout(connections, wait).unwrap().0;
+        }
+    }
+
+    fn exchange(&self, connection: &Connection, id: u16, records: &Receiver<Record>,
+                params: &[(String, String)], body: &[u8], deadline: Instant) -> Result<Vec<u8>, String> {
+        let mut request = vec![Record::begin_request(id, KEEP_CONN)];
+        request.extend(stream(PARAMS, id, &encode_pairs(params)));
+        request.extend(stream(STDIN, id, body));
+        connection.send(&request).map_err(|e| e.to_string())?;
+        let mut stdout = vec![];
+        loop {
+            match records.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(record) => {
+                    match record.kind {
+                        STDOUT if stdout.len() + record.content.len() > self.max_output => {
+                            let _ = connection.send(&[Record::new(ABORT_REQUEST, id, vec![])]);
+                            return Err(format!("wrote more than {} bytes", self.max_output));
+                        }
+                        STDOUT => stdout.extend_from_slice(&record.content),
+                        STDERR if !record.content.is_empty() => {
+                            println!("FastCGI [{}] error output: {}", self.name, String::from_utf8_lossy(&record.content).trim_end());
+                        }
+                        END_REQUEST => return end(&record).map(|_| stdout),
+                        _ => ()
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    let _ = connection.send(&[Record::new(ABORT_REQUEST, id, vec![])]);
+                    return Err(format!("no response within {}ms", self.timeout.as_millis()));
+                }
+                Err(RecvTimeoutError::Disconnected) => return Err("connection closed".to_string())
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn connections(&self) -> usize {
+        self.connections.lock().unwrap().open.len()
+    }
+}
+
+fn end(record: &Record) -> Result<(), String> {
+    match record.end_status() {
+        Some((_, REQUEST_COMPLETE)) => Ok(()),
+        Some((_, CANT_MPX_CONN)) => Err("application cannot multiplex this connection".to_string()),
+        Some((_, OVERLOADED)) => Err("application is overloaded".to_string()),
+        Some((_, UNKNOWN_ROLE)) => Err("application is not a responder".to_string()),
+        _ => Err("malformed END_REQUEST".to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::create_dir_all;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::{ Duration, Instant };
+    use request::RequestInfo;
+    use document_root::DocumentRoot;
+    use super::responder::{ self, Responder };
+    use super::{ params, Address, Backend, FastCgi };
+
+    fn backend(responder: &Responder, connections: usize) -> Backend {
+        Backend::new("app", responder.address.clone(), connections, Duration::from_secs(5), 1 << 20)
+    }
+
+    fn request(headers: &[(&str, &str)]) -> Vec<(String, String)> {
+        let info = RequestInfo {
+            method: "POST".to_string(),
+            uri: "/app/index.php".to_string(),
+            query: "page=2".to_string(),
+            headers: headers.iter().map(|&(name, value)| (name.to_string(), value.to_string())).collect(),
+            ..RequestInfo::default()
+        };
+        params(DocumentRoot::default().canonical(), "app/index.php", &info)
+    }
+
+    fn text(output: Result<Vec<u8>, String>) -> Result<String, String> {
+        output.map(|bytes| String::from_utf8(bytes).unwrap())
+    }
+
+    #[test]
+    fn answers_requests_with_params_and_body() {
+        let app = responder::tcp(true);
+
+        assert_eq!(text(backend(&app, 2).respond(&request(&[]), b"name=ferris")),
+                   Ok("Content-Type: text/plain\r\n\r\nPOST page=2 name=ferris".to_string()));
+    }
+
+    #[test]
+    fn sends_params_applications_expect() {
+        let params = request(&[]);
+        let param = |name: &str| params.iter().find(|&&(ref n, _)| n == name).map(|&(_, ref v)| v.clone());
+
+        assert!(param("SCRIPT_FILENAME").unwrap().ends_with("/app/index.php"));
+        assert!(Path::new(&param("SCRIPT_FILENAME").unwrap()).is_absolute());
+        assert_eq!(param("REQUEST_URI"), Some("/app/index.php?page=2".to_string()));
+        assert_eq!(param("SCRIPT_NAME"), Some("/app/index.php".to_string()));
+        assert_eq!(param("GATEWAY_INTERFACE"), Some("CGI/1.1".to_string()));
+    }
+
+    #[test]
+    fn reuses_pooled_connections() {
+        let app = responder::tcp(true);
+        let backend = backend(&app, 4);
+        for _ in 0..3 {
+            assert!(backend.respond(&request(&[]), b"").is_ok());
+        }
+
+        assert_eq!(app.accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn multiplexes_requests_on_one_connection() {
+        let app = responder::tcp(true);
+        let backend = Arc::new(backend(&app, 1));
+        let slow_backend = backend.clone();
+        let started = Instant::now();
+        let slow = thread::spawn(move || slow_backend.respond(&request(&[("X-Sleep", "400")]), b"slow"));
+        thread::sleep(Duration::from_millis(50));
+
+        assert!(text(backend.respond(&request(&[]), b"fast")).unwrap().ends_with("fast"));
+        assert!(started.elapsed() < Duration::from_millis(300));
+        assert!(text(slow.join().unwrap()).unwrap().ends_with("slow"));
+        assert_eq!(app.accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn opens_a_connection_per_request_for_applications_that_do_not_multiplex() {
+        let app = responder::tcp(false);
+        let backend = Arc::new(backend(&app, 2));
+        let started = Instant::now();
+        let requests = (0..2).map(|_| {
+            let backend = backend.clone();
+            thread::spawn(move || backend.respond(&request(&[("X-Sleep", "300")]), b""))
+        }).collect::<Vec<_>>();
+        for request in requests {
+            assert!(request.join().unwrap().is_ok());
+        }
+
+        assert!(started.elapsed() < Duration::from_millis(550));
+        assert_eq!(app.accepted.load(Ordering::SeqCst), 2);
+        assert_eq!(backend.connections(), 2);
+    }
+
+    #[test]
+    fn waits_for_a_free_connection_when_the_pool_is_full() {
+        let app = responder::tcp(false);
+        let backend = Arc::new(backend(&app, 1));
+        let started = Instant::now();
+        let requests = (0..2).map(|_| {
+            let backend = backend.clone();
+            thread::spawn(move || backend.respond(&request(&[("X-Sleep", "200")]), b""))
+        }).collect::<Vec<_>>();
+        for request in requests {
+            assert!(request.join().unwrap().is_ok());
+        }
+
+        assert!(started.elapsed() >= Duration::from_millis(400));
+        assert_eq!(app.accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn reports_refused_and_dropped_requests() {
+        let app = responder::tcp(true);
+        let backend = backend(&app, 1);
+
+        assert!(backend.respond(&request(&[("X-Overload", "1")]), b"").unwrap_err().ends_with("application is overloaded"));
+        assert!(backend.respond(&request(&[("X-Hang-Up", "1")]), b"").unwrap_err().ends_with("connection closed"));
+        assert!(backend.respond(&request(&[]), b"").is_ok());
+        assert_eq!(app.accepted.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn abandons_requests_that_take_too_long() {
+        let app = responder::tcp(true);
+        let backend = Backend::new("app", app.address.clone(), 1, Duration::from_millis(200), 1 << 20);
+        let started = Instant::now();
+
+        assert!(backend.respond(&request(&[("X-Sleep", "1000")]), b"").unwrap_err().ends_with("no response within 200ms"));
+        assert!(started.elapsed() < Duration::from_millis(600));
+        assert!(backend.respond(&request(&[]), b"").is_ok());
+    }
+
+    #[test]
+    fn abandons_requests_that_write_too_much() {
+        let app = responder::tcp(true);
+        let backend = Backend::new("app", app.address.clone(), 1, Duration::from_secs(5), 100);
+
+        assert_eq!(backend.respond(&request(&[]), &[b'x'; 200]).unwrap_err(),
+                   format!("app ({}): wrote more than 100 bytes", app.address));
+        assert!(backend.respond(&request(&[]), b"small").is_ok());
+    }
+
+    #[test]
+    fn talks_to_unix_socket_applications() {
+        create_dir_all("test/tmp").unwrap();
+        let app = responder::unix(Path::new("test/tmp/fastcgi.sock"));
+
+        assert!(text(backend(&app, 1).respond(&request(&[]), b"over unix")).unwrap().ends_with("over unix"));
+    }
+
+    #[test]
+    fn keeps_routes_in_order_by_backend() {
+        let backends = vec![
+            ("php".to_string(), Address::Tcp("127.0.0.1:9000".to_string()), 4),
+            ("api".to_string(), Address::Unix("/run/api.sock".into()), 4)
+        ];
+        let routes = vec![("**/*.php".to_string(), "php".to_string()), ("api/**".to_string(), "api".to_string())];
+        let fastcgi = FastCgi::new(&backends, &routes, Duration::from_secs(1), 1 << 20);
+
+        assert_eq!(fastcgi.routes().iter().map(|&(ref glob, ref backend)| (&glob[..], &backend.name[..])).collect::<Vec<_>>(),
+                   vec![("**/*.php", "php"), ("api/**", "api")]);
+        assert_eq!(fastcgi.backend("api").map(|b| b.name.clone()), Some("api".to_string()));
+        assert!(fastcgi.backend("missing").is_none());
+    }
+
+    #[test]
+    fn parses_backend_addresses() {
+        assert_eq!(Address::parse("unix:/run/php.sock"), Ok(Address::Unix("/run/php.sock".into())));
+        assert_eq!(Address::parse("127.0.0.1:9000"), Ok(Address::Tcp("127.0.0.1:9000".to_string())));
+        assert!(Address::parse("php").is_err());
+        assert!(Address::parse("unix:").is_err());
+    }
+}
@@ -0,0 +1,214 @@
+use std::io::{ self, Read, Write };
+
+// The record layer of FastCGI 1.0: every message between server and
+// application is an eight byte header followed by up to 65535 bytes of
+// content and some padding.
+
+pub const VERSION: u8 = 1;
+
+pub const BEGIN_REQUEST: u8 = 1;
+pub const ABORT_REQUEST: u8 = 2;
+pub const END_REQUEST: u8 = 3;
+pub const PARAMS: u8 = 4;
+pub const STDIN: u8 = 5;
+pub const STDOUT: u8 = 6;
+pub const STDERR: u8 = 7;
+pub const GET_VALUES: u8 = 9;
+pub const GET_VALUES_RESULT: u8 = 10;
+
+pub const RESPONDER: u16 = 1;
+pub const KEEP_CONN: u8 = 1;
+
+// Protocol statuses of an END_REQUEST.
+pub const REQUEST_COMPLETE: u8 = 0;
+pub const CANT_MPX_CONN: u8 = 1;
+pub const OVERLOADED: u8 = 2;
+pub const UNKNOWN_ROLE: u8 = 3;
+
+pub const MAX_CONTENT: usize = 65535;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub kind: u8,
+    // Zero for management records that belong to no request.
+    pub request_id: u16,
+    pub content: Vec<u8>
+}
+
+impl Record {
+    pub fn new(kind: u8, request_id: u16, content: Vec<u8>) -> Record {
+        Record { kind: kind, request_id: request_id, content: content }
+    }
+
+    pub fn begin_request(request_id: u16, flags: u8) -> Record {
+        let content = vec![(RESPONDER >> 8) as u8, RESPONDER as u8, flags, 0, 0, 0, 0, 0];
+        Record::new(BEGIN_REQUEST, request_id, content)
+    }
+
+    #[cfg(test)]
+    pub fn end_request(request_id: u16, app_status: u32, protocol_status: u8) -> Record {
+        let mut content = be32(app_status).to_vec();
+        content.extend_from_slice(&[protocol_status, 0, 0, 0]);
+        Record::new(END_REQUEST, request_id, content)
+    }
+
+    // The application and protocol status of an END_REQUEST.
+    pub fn end_status(&self) -> Option<(u32, u8)> {
+        if self.kind != END_REQUEST || self.content.len() < 5 {
+            return None;
+        }
+        let app_status = self.content[..4].iter().fold(0u32, |status, &byte| (status << 8) | byte as u32);
+        Some((app_status, self.content[4]))
+    }
+
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let length = self.content.len();
+        assert!(length <= MAX_CONTENT, "record content over 65535 bytes");
+        // Content is padded to a multiple of eight bytes.
+        let padding = (8 - length % 8) % 8;
+        let header = [
+            VERSION, self.kind,
+            (self.request_id >> 8) as u8, self.request_id as u8,
+            (length >> 8) as u8, length as u8,
+            padding as u8, 0
+        ];
+        let mut bytes = Vec::with_capacity(8 + length + padding);
+        bytes.extend_from_slice(&header);
+        bytes.extend_from_slice(&self.content);
+        bytes.extend_from_slice(&[0; 8][..padding]);
+        out.write_all(&bytes)
+    }
+
+    pub fn read_from<R: Read>(input: &mut R) -> io::Result<Record> {
+        let mut header = [0; 8];
+        input.read_exact(&mut header)?;
+        if header[0] != VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported FastCGI version {}", header[0])));
+        }
+        let length = ((header[4] as usize) << 8) | header[5] as usize;
+        let mut content = vec![0; length + header[6] as usize];
+        input.read_exact(&mut content)?;
+        content.truncate(length);
+        Ok(Record {
+            kind: header[1],
+            request_id: ((header[2] as u16) << 8) | header[3] as u16,
+            content: content
+        })
+    }
+}
+
+// A stream's worth of records: `data` split into records no longer than the
+// limit, followed by the empty record that ends the stream.
+pub fn stream(kind: u8, request_id: u16, data: &[u8]) -> Vec<Record> {
+    let mut records = data.chunks(MAX_CONTENT)
+        .map(|chunk| Record::new(kind, request_id, chunk.to_vec()))
+        .collect::<Vec<Record>>();
+    records.push(Record::new(kind, request_id, vec![]));
+    records
+}
+
+// Name-value pairs, as sent in PARAMS and GET_VALUES records. Lengths under
+// 128 take one byte and longer ones four, with the high bit set.
+pub fn encode_pairs(pairs: &[(String, String)]) -> Vec<u8> {
+    let mut bytes = vec![];
+    for &(ref name, ref value) in pairs {
+        encode_length(name.len(), &mut bytes);
+        encode_length(value.len(), &mut bytes);
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.extend_from_slice(value.as_bytes());
+    }
+    bytes
+}
+
+fn encode_length(length: usize, bytes: &mut Vec<u8>) {
+    if length < 128 {
+        bytes.push(length as u8);
+    } else {
+        bytes.extend_from_slice(&be32(length as u32 | 0x8000_0000));
+    }
+}
+
+pub fn decode_pairs(mut bytes: &[u8]) -> Result<Vec<(String, String)>, String> {
+    let mut pairs = vec![];
+    while !bytes.is_empty() {
+        let name_length = decode_length(&mut bytes)?;
+        let value_length = decode_length(&mut bytes)?;
+        if bytes.len() < name_length + value_length {
+            return Err("name-value pair is cut short".to_string());
+        }
+        let name = String::from_utf8_lossy(&bytes[..name_length]).into_owned();
+        let value = String::from_utf8_lossy(&bytes[name_length..name_length + value_length]).into_owned();
+        pairs.push((name, value));
+        bytes = &bytes[name_length + value_length..];
+    }
+    Ok(pairs)
+}
+
+fn decode_length(bytes: &mut &[u8]) -> Result<usize, String> {
+    match bytes.first() {
+        None => Err("name-value pair is cut short".to_string()),
+        Some(&short) if short < 128 => {
+            *bytes = &bytes[1..];
+            Ok(short as usize)
+        }
+        Some(_) if bytes.len() < 4 => Err("name-value pair is cut short".to_string()),
+        Some(_) => {
+            let length = bytes[..4].iter().fold(0usize, |length, &byte| (length << 8) | byte as usize);
+            *bytes = &bytes[4..];
+            Ok(length & 0x7fff_ffff)
+        }
+    }
+}
+
+fn be32(n: u32) -> [u8; 4] {
+    [(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use super::{ decode_pairs, encode_pairs, stream, Record, END_REQUEST, OVERLOADED, STDOUT };
+
+    #[test]
+    fn pads_records_to_eight_bytes() {
+        let mut bytes = vec![];
+        Record::new(STDOUT, 258, b"hello".to_vec()).write_to(&mut bytes).unwrap();
+
+        assert_eq!(bytes, b"\x01\x06\x01\x02\x00\x05\x03\x00hello\x00\x00\x00".to_vec());
+        assert_eq!(Record::read_from(&mut Cursor::new(bytes)).unwrap(), Record::new(STDOUT, 258, b"hello".to_vec()));
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        assert!(Record::read_from(&mut Cursor::new(b"\x02\x06\x00\x01\x00\x00\x00\x00".to_vec())).is_err());
+    }
+
+    #[test]
+    fn ends_streams_with_an_empty_record() {
+        let records = stream(STDOUT, 1, &vec![b'x'; 70000]);
+
+        assert_eq!(records.iter().map(|r| r.content.len()).collect::<Vec<usize>>(), vec![65535, 4465, 0]);
+        assert_eq!(stream(STDOUT, 1, b"").len(), 1);
+    }
+
+    #[test]
+    fn reads_end_request_status() {
+        let end = Record::end_request(3, 258, OVERLOADED);
+
+        assert_eq!(end.kind, END_REQUEST);
+        assert_eq!(end.end_status(), Some((258, OVERLOADED)));
+        assert_eq!(Record::new(STDOUT, 3, vec![]).end_status(), None);
+    }
+
+    #[test]
+    fn round_trips_short_and_long_pairs() {
+        let long = "v".repeat(300);
+        let pairs = vec![("SCRIPT_NAME".to_string(), "/app".to_string()), ("LONG".to_string(), long.clone())];
+        let bytes = encode_pairs(&pairs);
+
+        assert_eq!(&bytes[..2], &[11, 4][..]);
+        assert_eq!(&bytes[17..22], &[4, 0x80, 0, 1, 44][..]);
+        assert_eq!(decode_pairs(&bytes), Ok(pairs));
+        assert!(decode_pairs(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
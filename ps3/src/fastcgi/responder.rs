@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::io::{ Read, Write };
+use std::net::{ Shutdown, TcpListener, TcpStream };
+use std::os::unix::net::{ UnixListener, UnixStream };
+use std::path::Path;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::thread;
+use std::time::Duration;
+use super::connection::Address;
+use super::record::{ decode_pairs, encode_pairs, stream, Record, BEGIN_REQUEST, GET_VALUES, GET_VALUES_RESULT,
+                     OVERLOADED, PARAMS, REQUEST_COMPLETE, STDERR, STDIN, STDOUT };
+
+// A small FastCGI application for tests. Each request is answered on its own
+// thread, so requests sharing a connection can finish out of order. The reply
+// lists the request's method and query and echoes its body. Params steer it:
+// `HTTP_X_SLEEP` delays the reply by that many milliseconds, `HTTP_X_OVERLOAD`
+// refuses the request and `HTTP_X_HANG_UP` closes the connection instead of
+// answering.
+pub struct Responder {
+    pub address: Address,
+    // Connections accepted so far.
+    pub accepted: Arc<AtomicUsize>
+}
+
+trait Duplex: Read + Write + Send + Sized + 'static {
+    fn duplicate(&self) -> Self;
+    fn hang_up(&self);
+}
+
+impl Duplex for TcpStream {
+    fn duplicate(&self) -> Self {
+        self.try_clone().unwrap()
+    }
+
+    fn hang_up(&self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+impl Duplex for UnixStream {
+    fn duplicate(&self) -> Self {
+        self.try_clone().unwrap()
+    }
+
+    fn hang_up(&self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+pub fn tcp(multiplex: bool) -> Responder {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = Address::Tcp(listener.local_addr().unwrap().to_string());
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = accepted.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            thread::spawn(move || serve(stream, multiplex));
+        }
+    });
+    Responder { address: address, accepted: accepted }
+}
+
+pub fn unix(path: &Path) -> Responder {
+    let _ = ::std::fs::remove_file(path);
+    let listener = UnixListener::bind(path).unwrap();
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = accepted.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            thread::spawn(move || serve(stream, true));
+        }
+    });
+    Responder { address: Address::Unix(path.to_path_buf()), accepted: accepted }
+}
+
+fn serve<S: Duplex>(mut reader: S, multiplex: bool) {
+    let writer = Arc::new(Mutex::new(reader.duplicate()));
+    let mut requests: HashMap<u16, (Vec<u8>, Vec<u8>)> = HashMap::new();
+    while let Ok(record) = Record::read_from(&mut reader) {
+        let id = record.request_id;
+        match record.kind {
+            GET_VALUES => {
+                let values = vec![
+                    ("FCGI_MPXS_CONNS".to_string(), if multiplex { "1" } else { "0" }.to_string()),
+                    ("FCGI_MAX_REQS".to_string(), "10".to_string())
+                ];
+                let reply = Record::new(GET_VALUES_RESULT, 0, encode_pairs(&values));
+                reply.write_to(&mut *writer.lock().unwrap()).unwrap();
+            }
+            BEGIN_REQUEST => {
+                requests.insert(id, (vec![], vec![]));
+            }
+            PARAMS => {
+                if let Some(request) = requests.get_mut(&id) {
+                    request.0.extend_from_slice(&record.content);
+                }
+            }
+            STDIN if !record.content.is_empty() => {
+                if let Some(request) = requests.get_mut(&id) {
+                    request.1.extend_from_slice(&record.content);
+                }
+            }
+            STDIN => {
+                let (params, body) = requests.remove(&id).unwrap();
+                let params = decode_pairs(&params).unwrap();
+                let param = |name: &str| params.iter().find(|&&(ref n, _)| n == name).map(|&(_, ref v)| v.clone());
+                if param("HTTP_X_HANG_UP").is_some() {
+                    reader.hang_up();
+                    return;
+                }
+                let writer = writer.clone();
+                let sleep = param("HTTP_X_SLEEP").and_then(|ms| ms.parse::<u64>().ok()).unwrap_or(0);
+                let overload = param("HTTP_X_OVERLOAD").is_some();
+                let reply = format!("Content-Type: text/plain\r\n\r\n{} {} ",
+                                    param("REQUEST_METHOD").unwrap_or_default(), param("QUERY_STRING").unwrap_or_default());
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(sleep));
+                    let mut records = vec![];
+                    if overload {
+                        records.push(Record::end_request(id, 0, OVERLOADED));
+                    } else {
+                        let mut output = reply.into_bytes();
+                        output.extend_from_slice(&body);
+                        records.extend(stream(STDOUT, id, &output));
+                        records.extend(stream(STDERR, id, b"a warning"));
+                        records.push(Record::end_request(id, 0, REQUEST_COMPLETE));
+                    }
+                    let mut writer = writer.lock().unwrap();
+                    for record in records {
+                        let _ = record.write_to(&mut *writer);
+                    }
+                });
+            }
+            _ => ()
+        }
+    }
+}
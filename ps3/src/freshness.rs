@@ -0,0 +1,288 @@
+use std::io;
+use std::fs;
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+use std::time::{ Duration, Instant, SystemTime };
+use cache::Weigh;
+use handler::Cache;
+
+// What a cached file looked like on disk when it was read. A file whose
+// modification time or size no longer matches is considered changed.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Stamp {
+    modified: Option<SystemTime>,
+    len: u64
+}
+
+impl Stamp {
+    pub fn of(path: &Path) -> io::Result<Stamp> {
+        fs::metadata(path).map(|data| {
+            Stamp {
+                modified: data.modified().ok(),
+                len: data.len()
+            }
+        })
+    }
+}
+
+pub struct CachedFile {
+    pub bytes: Arc<[u8]>,
+    pub stamp: Stamp,
+    pub checked: Instant
+}
+
+impl Weigh for CachedFile {
+    fn weight(&self) -> usize {
+        self.bytes.len()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Freshness {
+    Fresh,
+    Verified,
+    Changed,
+    Deleted
+}
+
+// Files under a watched root are evicted by the watcher as soon as they
+// change. Anything else is checked against the disk once it has been served
+// from cache for `revalidate_after`.
+#[derive(Clone)]
+pub struct Invalidation {
+    pub revalidate_after: Duration,
+    pub watched: Vec<PathBuf>
+}
+
+impl Invalidation {
+    pub fn check(&self, path: &Path, cached: &CachedFile, now: Instant) -> Freshness {
+        if self.watched.iter().any(|root| path.starts_with(root))
+            || now.duration_since(cached.checked) < self.revalidate_after {
+            Freshness::Fresh
+        } else {
+            match Stamp::of(path) {
+                Err(_) => Freshness::Deleted,
+                Ok(ref stamp) if stamp == &cached.stamp => Freshness::Verified,
+                Ok(_) => Freshness::Changed
+            }
+        }
+    }
+}
+
+pub fn cache_key(path: &Path) -> PathBuf {
+    path.strip_prefix(".").unwrap_or(path).to_path_buf()
+}
+
+#[cfg(target_os = "linux")]
+pub fn watch(root: &Path, cache: Cache) -> io::Result<()> {
+    inotify::watch(root, cache)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn watch(_root: &Path, _cache: Cache) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "watching files requires inotify"))
+}
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    use std::collections::HashMap;
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::io;
+    use std::mem::size_of;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{ Path, PathBuf };
+    use std::ptr;
+    use std::thread;
+    use libc;
+    use handler::Cache;
+    use super::cache_key;
+
+    const WATCH_MASK: u32 = libc::IN_MODIFY | libc::IN_CLOSE_WRITE | libc::IN_ATTRIB |
+                            libc::IN_CREATE | libc::IN_DELETE | libc::IN_DELETE_SELF |
+                            libc::IN_MOVED_FROM | libc::IN_MOVED_TO | libc::IN_MOVE_SELF;
+
+    pub fn watch(root: &Path, cache: Cache) -> io::Result<()> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut dirs = HashMap::new();
+        match add_watches(fd, root, &mut dirs) {
+            Ok(_) => {
+                thread::spawn(move || read_events(fd, dirs, cache));
+                Ok(())
+            }
+            Err(e) => {
+                unsafe { libc::close(fd) };
+                Err(e)
+            }
+        }
+    }
+
+    fn add_watches(fd: i32, dir: &Path, dirs: &mut HashMap<i32, PathBuf>) -> io::Result<()> {
+        let c_path = ::std::ffi::CString::new(dir.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let wd = unsafe { libc::inotify_add_watch(fd, c_path.as_ptr(), WATCH_MASK) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        dirs.insert(wd, cache_key(dir));
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                add_watches(fd, &entry.path(), dirs)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_events(fd: i32, mut dirs: HashMap<i32, PathBuf>, cache: Cache) {
+        let header = size_of::<libc::inotify_event>();
+        let mut buf = [0u8; 4096];
+        loop {
+            let read = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if read < 0 {
+                if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                println!("Stopped watching files: {}", io::Error::last_os_error());
+                return;
+            }
+            let mut offset = 0;
+            while offset + header <= read as usize {
+                let event = unsafe {
+                    ptr::read_unaligned(buf.as_ptr().add(offset) as *const libc::inotify_event)
+                };
+                let name = &buf[offset + header..offset + header + event.len as usize];
+                let name = OsStr::from_bytes(name.split(|&b| b == 0).next().unwrap_or(&[]));
+                offset += header + event.len as usize;
+                handle_event(fd, &event, name, &mut dirs, &cache);
+            }
+        }
+    }
+
+    fn handle_event(fd: i32, event: &libc::inotify_event, name: &OsStr,
+                    dirs: &mut HashMap<i32, PathBuf>, cache: &Cache) {
+        if event.mask & libc::IN_Q_OVERFLOW != 0 {
+            cache.clear();
+            return;
+        }
+        if event.mask & libc::IN_IGNORED != 0 {
+            dirs.remove(&event.wd);
+            return;
+        }
+        let path = match dirs.get(&event.wd) {
+            Some(dir) => dir.join(name),
+            None => return
+        };
+        if event.mask & libc::IN_ISDIR != 0 {
+            if event.mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+                let _ = add_watches(fd, &path, dirs);
+            }
+            cache.remove_where(|key| key.starts_with(&path));
+        } else {
+            cache.remove(&path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{ File, create_dir_all, remove_file };
+    use std::io::Write;
+    use std::path::{ Path, PathBuf };
+    use std::sync::Arc;
+    use std::time::{ Duration, Instant };
+    use super::{ CachedFile, Freshness, Invalidation, Stamp, cache_key };
+
+    fn write_file(path: &Path, contents: &str) {
+        create_dir_all(path.parent().unwrap()).unwrap();
+        File::create(path).unwrap().write_all(contents.as_bytes()).unwrap();
+    }
+
+    fn cached(path: &Path) -> CachedFile {
+        CachedFile {
+            bytes: Arc::from(vec![]),
+            stamp: Stamp::of(path).unwrap(),
+            checked: Instant::now()
+        }
+    }
+
+    #[test]
+    fn recently_checked_files_are_fresh() {
+        let path = Path::new("test/tmp/freshness/recent.html");
+        write_file(path, "<h1>Recent</h1>");
+        let file = cached(path);
+        let invalidation = Invalidation { revalidate_after: Duration::from_secs(60), watched: vec![] };
+
+        let _ = remove_file(path);
+
+        assert_eq!(invalidation.check(path, &file, Instant::now()), Freshness::Fresh);
+    }
+
+    #[test]
+    fn detects_changed_and_deleted_files() {
+        let path = Path::new("test/tmp/freshness/changed.html");
+        write_file(path, "<h1>Before</h1>");
+        let file = cached(path);
+        let invalidation = Invalidation { revalidate_after: Duration::from_secs(0), watched: vec![] };
+
+        assert_eq!(invalidation.check(path, &file, Instant::now()), Freshness::Verified);
+
+        write_file(path, "<h1>After the change</h1>");
+        assert_eq!(invalidation.check(path, &file, Instant::now()), Freshness::Changed);
+
+        let _ = remove_file(path);
+        assert_eq!(invalidation.check(path, &file, Instant::now()), Freshness::Deleted);
+    }
+
+    #[test]
+    fn watched_files_are_trusted() {
+        let path = Path::new("test/tmp/freshness/watched.html");
+        write_file(path, "<h1>Watched</h1>");
+        let file = cached(path);
+        let invalidation = Invalidation {
+            revalidate_after: Duration::from_secs(0),
+            watched: vec![PathBuf::from("test/tmp")]
+        };
+
+        let _ = remove_file(path);
+
+        assert_eq!(invalidation.check(path, &file, Instant::now()), Freshness::Fresh);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn watcher_evicts_changed_files() {
+        use std::sync::Arc;
+        use std::thread;
+        use cache::{ ShardedCache, Policy };
+        use super::watch;
+
+        let path = Path::new("test/tmp/watched/page.html");
+        write_file(path, "<h1>Before</h1>");
+        let cache = Arc::new(ShardedCache::new(1 << 10, 1 << 10, Policy::Lru, 4));
+        cache.put(path.to_path_buf(), cached(path));
+        watch(Path::new("./test/tmp/watched"), cache.clone()).unwrap();
+
+        write_file(path, "<h1>After</h1>");
+
+        let mut evicted = false;
+        for _ in 0..100 {
+            if !cache.contains(&path.to_path_buf()) {
+                evicted = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let _ = remove_file(path);
+        assert!(evicted, "watcher did not evict changed file");
+    }
+
+    #[test]
+    fn cache_keys_are_relative_to_working_directory() {
+        assert_eq!(cache_key(Path::new("./test/a.html")), PathBuf::from("test/a.html"));
+        assert_eq!(cache_key(Path::new("test/a.html")), PathBuf::from("test/a.html"));
+    }
+}
@@ -1,14 +1,29 @@
 use std::collections::HashSet;
-use std::io::{ Read, BufReader, Write, copy };
+use std::io::{ copy, BufReader, Write };
 use std::io;
 use std::fs::File;
-use std::path::{ Path, PathBuf, Component };
-use std::sync::{ Arc, Mutex };
-use std::thread;
-use path::Path as ReqPath;
-use http::{ header, Status, Payload };
-use shell_interpolation::insert_shell_commands;
-use lru_cache::cache::LruCache;
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use path::{ encode_path, Path as ReqPath };
+use request::{ BodyStream, RequestInfo };
+use http::{ header, header_with, response_header, Chunked, Status, Payload, Response, Template };
+use shell_interpolation::{ prepare_template, is_template };
+use cache::ShardedCache;
+use rate_limit::Counters;
+use freshness::{ CachedFile, Freshness, Invalidation, Stamp };
+use loader::Loader;
+use warm::Popularity;
+use rendered::{ RenderCache, Recorder };
+use cgi::{ parse_response, Cgi, CgiError, Script };
+use fastcgi::{ self, Backend };
+use proxy::{ Group, Upstreams };
+use listing::listing;
+use document_root::{ Denied, DocumentRoot };
+use router::{ Call, Handler, Routes };
+use ssi;
+use zero_copy::{ Sink, send_stream };
 
 lazy_static!{
     static ref ALLOWED_FILE_TYPES: HashSet<&'static str> = {
@@ -32,30 +47,90 @@ enum AccessError {
     TypeNotAllowed
 }
 
-pub type Cache = Arc<Mutex<LruCache<PathBuf>>>;
+pub type Cache = Arc<ShardedCache<PathBuf, CachedFile>>;
+
+#[derive(Clone)]
+pub struct Context {
+    pub root: DocumentRoot,
+    pub cache: Cache,
+    pub invalidation: Invalidation,
+    pub loader: Loader,
+    pub popularity: Popularity,
+    pub rendered: RenderCache,
+    pub ssi: ssi::Settings,
+    pub routes: Routes,
+    pub upstreams: Upstreams,
+    pub index_files: Vec<String>,
+    pub directory_listing: bool,
+    pub sendfile_min: Option<u64>,
+    pub limits: Counters
+}
 
-pub fn handle_request<T: Write>(cache: &Cache, req_path: io::Result<ReqPath>, visitor_count: usize, stream: &mut T) -> Status {
+// `body` is the request body still on the connection, if the route's
+// handler streams it; otherwise it has been read into `info`.
+pub fn handle_request<T: Sink>(context: &Context, req_path: io::Result<ReqPath>, info: &RequestInfo,
+                               body: Option<BodyStream>, visitor_count: usize, stream: &mut T) -> Status {
     match req_path {
         Ok(path) => {
+            let path = path.to_string();
+            let mut call = Call::new(&path[1..], info, visitor_count);
+            call.body.set(body);
+            let routed = context.routes.dispatch(context, &mut call);
+            let extra = call.fields.iter().map(|&(ref name, ref value)| (&name[..], value.clone())).collect::<Vec<(&str, String)>>();
             let response_status =
-                router(cache, path, visitor_count)
+                routed
                     .and_then(|mut payload| {
-                        let header = header(&Status::Ok);
+                        let header = match &payload {
+                            &Payload::Template(_) => {
+                                let mut fields = extra.clone();
+                                fields.push(("Transfer-Encoding", "chunked".to_string()));
+                                header_with(&Status::Ok, &fields)
+                            }
+                            &Payload::Response(ref response) => {
+                                let mut fields = response.fields.clone();
+                                fields.extend(call.fields.iter().cloned());
+                                response_header(&response.status, &response.content_type, &fields)
+                            }
+                            &Payload::Relay(ref relay) => {
+                                let mut fields = relay.fields.clone();
+                                fields.extend(call.fields.iter().cloned());
+                                if relay.chunked {
+                                    fields.push(("Transfer-Encoding".to_string(), "chunked".to_string()));
+                                }
+                                response_header(&relay.status, &relay.content_type, &fields)
+                            }
+                            _ => header_with(&Status::Ok, &extra)
+                        };
                         stream.write(&header)
                             .and_then(|_| {
                                 match &mut payload {
                                     &mut Payload::Stream(ref mut f) => {
-                                        copy(f, stream)
+                                        send_stream(f, stream, context.sendfile_min)
                                     }
                                     &mut Payload::Block(ref s) => {
                                         stream.write(s.as_bytes()).map(|b| b as u64)
                                     }
+                                    &mut Payload::Bytes(ref b) => {
+                                        stream.write_all(b).map(|_| b.len() as u64)
+                                    }
+                                    &mut Payload::Template(ref template) => {
+                                        let env = ssi::Environment { request: info.clone(), visitor_count: visitor_count };
+                                        send_template(context, template, &env, stream)
+                                    }
+                                    &mut Payload::Response(ref response) => {
+                                        stream.write_all(&response.body).map(|_| response.body.len() as u64)
+                                    }
+                                    &mut Payload::Relay(ref mut relay) if relay.chunked => {
+                                        let mut chunked = Chunked::new(&mut *stream);
+                                        copy(&mut relay.body, &mut chunked).and_then(|sent| chunked.finish().map(|_| sent))
+                                    }
+                                    &mut Payload::Relay(ref mut relay) => copy(&mut relay.body, stream)
                                 }
                             })
                             .map_err(|_| Status::Error)
                     })
                     .map_err(|e| {
-                        match stream.write(&header(&e)) {
+                        match stream.write_all(&header_with(&e, &extra)) {
                             Ok(_) => e,
                             Err(_) => Status::Error
                         }
@@ -66,14 +141,81 @@ pub fn handle_request<T: Write>(cache: &Cache, req_path: io::Result<ReqPath>, vi
                     Err(e) => e
                 }
         }
+        // The request could be read but not made sense of.
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+            let status = Status::Other(400, "Bad Request".to_string());
+            match stream.write_all(&header(&status)) {
+                Ok(_) => status,
+                Err(_) => Status::Error
+            }
+        }
         Err(_) => Status::Error
     }
 }
 
-fn router(cache: &Cache, path: ReqPath, visitor_count: usize) -> Result<Payload, Status> {
-    match path {
-        ReqPath::Root => root_handler(visitor_count),
-        ReqPath::RelPath(path) => file_handler(cache, path)
+// The page at `/`, with the visitor count.
+pub struct Visitors;
+
+impl Handler for Visitors {
+    fn handle(&self, _: &Context, call: &Call) -> Result<Payload, Status> {
+        root_handler(call.visitor_count)
+    }
+}
+
+pub struct ServerStatus;
+
+impl Handler for ServerStatus {
+    fn handle(&self, context: &Context, call: &Call) -> Result<Payload, Status> {
+        status_handler(context, call.visitor_count)
+    }
+}
+
+// Files and directories under the document root. With `ssi` off, `.shtml`
+// files are sent as they are rather than rendered.
+pub struct Files {
+    pub ssi: bool
+}
+
+impl Handler for Files {
+    fn handle(&self, context: &Context, call: &Call) -> Result<Payload, Status> {
+        file_handler(context, call.path.clone(), call.info, self.ssi)
+    }
+}
+
+pub struct CgiScripts(pub Cgi);
+
+impl Handler for CgiScripts {
+    fn handle(&self, _: &Context, call: &Call) -> Result<Payload, Status> {
+        match self.0.script(&call.path) {
+            Some(script) => cgi_handler(&self.0, script, call.info),
+            None => Err(Status::FileNotFound)
+        }
+    }
+}
+
+pub struct FastCgiApp(pub Arc<Backend>);
+
+impl Handler for FastCgiApp {
+    fn handle(&self, context: &Context, call: &Call) -> Result<Payload, Status> {
+        fastcgi_handler(context, &self.0, &call.path, call.info)
+    }
+}
+
+// Requests forwarded to a server of an upstream group.
+pub struct Proxy(pub Arc<Group>);
+
+impl Handler for Proxy {
+    fn handle(&self, _: &Context, call: &Call) -> Result<Payload, Status> {
+        self.0.forward(call.info, call.body.take())
+            .map(Payload::Relay)
+            .map_err(|e| {
+                println!("Proxy error: {}: {}", self.0.name, e);
+                e.status()
+            })
+    }
+
+    fn streams_body(&self) -> bool {
+        true
     }
 }
 
@@ -93,10 +235,101 @@ fn root_handler(visitor_count: usize) -> Result<Payload, Status> {
     Ok(Payload::Block(response.to_string()))
 }
 
-fn file_handler(cache: &Cache, path: String) -> Result<Payload, Status> {
-    let file_path = Path::new(&path);
-    valid_file(&file_path)
-        .and_then(|p| open_file(cache, p))
+fn status_handler(context: &Context, visitor_count: usize) -> Result<Payload, Status> {
+    let limit_rows = context.limits.rules.iter()
+        .map(|&(ref label, ref count)| {
+            format!("<tr><td>{}</td><td>{}</td></tr>\n", label, count.load(Ordering::Relaxed))
+        })
+        .collect::<String>();
+    let now = Instant::now();
+    let upstream_rows = context.upstreams.groups().iter()
+        .flat_map(|group| {
+            group.report(now).into_iter()
+                .map(|server| {
+                    format!("<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                            group.name, server.address, server.state, server.active, server.failures)
+                })
+                .collect::<Vec<String>>()
+        })
+        .collect::<String>();
+    let (cached_files, cached_bytes) = (context.cache.len(), context.cache.used_bytes());
+    let response =
+        format!("<doctype !html><html><head><title>Server Status</title></head>
+                <body>
+                <h1>Server Status</h1>
+                <p>Visitor Count: {}</p>
+                <h2>Cache</h2>
+                <p>Cached Files: {}</p>
+                <p>Cached Bytes: {}</p>
+                <p>Cache Fills: {}</p>
+                <p>Rendered Pages: {}</p>
+                <h2>Rate Limits</h2>
+                <p>Tracked Clients: {}</p>
+                <table>
+                <tr><th>Rule</th><th>Limited Requests</th></tr>
+                {}<tr><td>Total</td><td>{}</td></tr>
+                </table>
+                <h2>Upstreams</h2>
+                <table>
+                <tr><th>Group</th><th>Server</th><th>State</th><th>Active Requests</th><th>Recent Errors</th></tr>
+                {}</table>
+                </body></html>\r\n",
+                visitor_count,
+                cached_files,
+                cached_bytes,
+                context.loader.fills.load(Ordering::Relaxed),
+                context.rendered.len(),
+                context.limits.tracked_clients.load(Ordering::Relaxed),
+                limit_rows,
+                context.limits.total_limited(),
+                upstream_rows
+            );
+    Ok(Payload::Block(response))
+}
+
+fn cgi_handler(cgi: &Cgi, script: Result<Script, CgiError>, info: &RequestInfo) -> Result<Payload, Status> {
+    script.and_then(|script| cgi.run(&script, info))
+        .map(Payload::Response)
+        .map_err(|e| {
+            match e {
+                CgiError::NotFound => Status::FileNotFound,
+                CgiError::NotExecutable => Status::NotAuthorized,
+                CgiError::Failed(reason) => {
+                    println!("CGI error: {}", reason);
+                    Status::Error
+                }
+            }
+        })
+}
+
+fn fastcgi_handler(context: &Context, backend: &Backend, path: &str, info: &RequestInfo) -> Result<Payload, Status> {
+    backend.respond(&fastcgi::params(context.root.canonical(), path, info), &info.body)
+        .and_then(|output| parse_response(&output).map_err(|e| format!("{}: {}", backend.name, e)))
+        .map(Payload::Response)
+        .map_err(|e| {
+            println!("FastCGI error: {}", e);
+            Status::Other(502, "Bad Gateway".to_string())
+        })
+}
+
+fn file_handler(context: &Context, path: String, info: &RequestInfo, ssi: bool) -> Result<Payload, Status> {
+    let file = match context.root.resolve(Path::new(&path)) {
+        Ok(ref dir) if dir.is_dir() => return directory_handler(context, &path, dir, info, ssi),
+        _ if !valid_file_type(Path::new(&path)) => Err(AccessError::TypeNotAllowed),
+        Ok(file) => Ok(file),
+        Err(Denied::NotFound) => {
+            // A cached copy of a file since deleted is of no more use.
+            context.cache.remove(&context.root.join(Path::new(&path)));
+            Err(AccessError::NotFound)
+        }
+        Err(_) => Err(AccessError::OutOfBounds)
+    };
+    file
+        .and_then(|file| {
+            open_file(context, &file, ssi).inspect(|_| {
+                context.popularity.record(&file);
+            })
+        })
         .map_err(|e| {
             match e {
                 AccessError::NotFound => Status::FileNotFound,
@@ -106,78 +339,166 @@ fn file_handler(cache: &Cache, path: String) -> Result<Payload, Status> {
         })
 }
 
-fn valid_file<'a>(path: &'a Path) -> Result<&'a Path, AccessError> {
-    if path.is_absolute() {
-        Err(AccessError::OutOfBounds)
-    } else if path.components().any(|c| c == Component::ParentDir) {
-        Err(AccessError::OutOfBounds)
-    } else if !valid_file_type(path) {
-        Err(AccessError::TypeNotAllowed)
-    } else {
-        Ok(path)
+// A directory is answered with the first of its index files there is, or
+// else a listing if those are turned on. Its path has to end in `/` for
+// relative links on the page to resolve inside it.
+fn directory_handler(context: &Context, path: &str, dir: &Path, info: &RequestInfo, ssi: bool) -> Result<Payload, Status> {
+    if !path.ends_with('/') {
+        let query = if info.query.is_empty() { String::new() } else { format!("?{}", info.query) };
+        return Ok(Payload::Response(Response::redirect(&format!("{}{}", encode_path(&format!("/{}/", path)), query))));
+    }
+    for name in &context.index_files {
+        if dir.join(name).is_file() {
+            return file_handler(context, format!("{}{}", path, name), info, ssi);
+        }
     }
+    if !context.directory_listing {
+        return Err(Status::NotAuthorized);
+    }
+    listing(&context.root, dir, &format!("/{}", path), &info.params).map_err(|_| Status::FileNotFound)
 }
 
-fn open_file(cache: &Cache, path: &Path) -> Result<Payload, AccessError> {
-    let path_buf = path.to_owned();
-    match cache.lock().unwrap().get(&path_buf) {
-        Some(bytes) => {
-            String::from_utf8(bytes.to_vec())
-                .map_err(|_| AccessError::NotFound)
-                .map(|s| Payload::Block(s))
+fn open_file(context: &Context, path: &Path, ssi: bool) -> Result<Payload, AccessError> {
+    let now = Instant::now();
+    let template = ssi && is_template(path);
+    if template {
+        if let Some(html) = context.rendered.get(path, now) {
+            return Ok(Payload::Bytes(html));
         }
-        None => {
-            cache_file(cache, path);
+    }
+    let sources = Stamp::of(path).map(|stamp| vec![(path.to_path_buf(), stamp)]).unwrap_or_default();
+    let contents = match cached_contents(context, path) {
+        Ok(None) => Ok(context.loader.load(path)),
+        cached => cached
+    };
+    match contents {
+        Err(e) => Err(e),
+        Ok(Some(bytes)) => Ok(Payload::Bytes(bytes)),
+        Ok(None) => {
             File::open(path)
                 .map_err(|_| AccessError::NotFound)
                 .map(|f| Payload::Stream(BufReader::new(f)))
         }
     }.and_then(|p| {
-        insert_shell_commands(path, p).map_err(|_| AccessError::NotFound)
+        if template { prepare_template(path, p, sources).map_err(|_| AccessError::NotFound) } else { Ok(p) }
     })
 }
 
-fn cache_file(cache: &Cache, path: &Path) {
+// Renders a template into the response as chunks, keeping the page for later
+// requests if it may be reused.
+fn send_template<T: Write>(context: &Context, template: &Template, env: &ssi::Environment, stream: &mut T) -> io::Result<u64> {
+    let mut recorder = Recorder::new(Chunked::new(stream));
+    let outcome = ssi::stream(&template.path, &template.contents, &context.ssi, env, &mut recorder)?;
+    let (chunked, html) = recorder.into_parts();
+    chunked.finish()?;
+    let sent = html.len() as u64;
+    context.rendered.store(&template.path, template.sources.clone(), outcome, html, Instant::now());
+    Ok(sent)
+}
+
+// Cached files are checked against the disk before being served; changed files
+// are dropped from the cache and deleted files are not found.
+fn cached_contents(context: &Context, path: &Path) -> Result<Option<Arc<[u8]>>, AccessError> {
     let path_buf = path.to_owned();
-    let cache_handle = cache.clone();
-    thread::spawn(move || {
-        let mut contents = Vec::new();
-        match File::open(&path_buf).and_then(|mut f| f.read_to_end(&mut contents)) {
-            Ok(_) => {
-                cache_handle.lock().unwrap().put(path_buf, contents);
+    let now = Instant::now();
+    let checked = context.cache.shard(&path_buf).get(&path_buf).map(|file| {
+        (context.invalidation.check(path, file, now), Arc::clone(&file.bytes))
+    });
+    match checked {
+        None => Ok(None),
+        Some((Freshness::Fresh, bytes)) => Ok(Some(bytes)),
+        Some((Freshness::Verified, bytes)) => {
+            if let Some(file) = context.cache.shard(&path_buf).get_mut(&path_buf) {
+                file.checked = now;
             }
-            Err(_) => {}
+            Ok(Some(bytes))
         }
-
-    });
+        Some((Freshness::Changed, _)) => {
+            context.cache.remove(&path_buf);
+            Ok(None)
+        }
+        Some((Freshness::Deleted, _)) => {
+            context.cache.remove(&path_buf);
+            Err(AccessError::NotFound)
+        }
+    }
 }
 
-fn valid_file_type(path: &Path) -> bool {
+pub fn valid_file_type(path: &Path) -> bool {
     match path.extension().and_then(|e| e.to_str()) {
         None => false,
         Some(ext) => ALLOWED_FILE_TYPES.contains(ext)
     }
 }
 
+// A context for tests elsewhere: the working directory with the standard
+// routes.
+#[cfg(test)]
+pub fn test_context() -> Context {
+    test::context_with_cache(1 << 20, 1 << 16)
+}
+
 #[cfg(test)]
 mod test {
     use regex::Regex;
-    use path::Path;
-    use http::Status;
+    use path::{ query_params, Path };
+    use http::{ header, Status };
     use std::io;
-    use std::sync::{ Arc, Mutex };
-    use lru_cache::cache::LruCache;
-    use super::{ handle_request, Cache };
+    use std::io::Read;
+    use std::sync::Arc;
+    use std::time::{ Duration, Instant };
+    use std::net::{ SocketAddr, SocketAddrV4, Ipv4Addr, TcpListener };
+    use std::thread;
+    use cache::{ ShardedCache, Policy };
+    use rate_limit::{ RateLimiter, LimitRule, RuleTarget };
+    use scheduling::Priority;
+    use freshness::{ CachedFile, Invalidation, Stamp };
+    use loader::Loader;
+    use warm::Popularity;
+    use rendered::RenderCache;
+    use request::RequestInfo;
+    use ssi;
+    use cgi::Cgi;
+    use fastcgi::{ Address, FastCgi };
+    use document_root::{ DocumentRoot, Hidden, Symlinks };
+    use sandbox::Sandbox;
+    use router::{ table, Matcher, RouteSpec, Target };
+    use middleware::Layer;
+    use proxy::{ GroupSpec, Upstreams };
+    use std::fs::{ File, create_dir_all, remove_file };
+    use std::io::Write;
+    use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+    use super::{ handle_request, Context };
+
+    fn new_context() -> Context {
+        context_with_cache(1 << 20, 1 << 16)
+    }
 
-    fn new_cache() -> Cache {
-        Arc::new(Mutex::new(LruCache::new(512)))
+    pub fn context_with_cache(budget: usize, max_object: usize) -> Context {
+        let cache = Arc::new(ShardedCache::new(budget, max_object, Policy::Lru, 1));
+        Context {
+            root: DocumentRoot::default(),
+            cache: cache.clone(),
+            invalidation: Invalidation { revalidate_after: Duration::from_secs(0), watched: vec![] },
+            loader: Loader::new(cache, 1, Duration::from_secs(5)),
+            popularity: Popularity::new(),
+            rendered: RenderCache::new(Duration::from_secs(0)),
+            ssi: ssi::Settings::default(),
+            routes: table(&[], None, &FastCgi::default(), &Upstreams::default()).unwrap(),
+            upstreams: Upstreams::default(),
+            index_files: vec!["index.html".to_string(), "index.shtml".to_string()],
+            directory_listing: false,
+            sendfile_min: Some(0),
+            limits: RateLimiter::new(vec![], Duration::from_secs(60)).counters()
+        }
     }
 
     #[test]
     fn root_handler_writes_html() {
         let mut output: Vec<u8> = Vec::new();
-        let cache = new_cache();
-        handle_request(&cache, Ok(Path::Root), 5, &mut output);
+        let context = new_context();
+        handle_request(&context, Ok(Path::Root), &RequestInfo::default(), None, 5, &mut output);
 
         let html = String::from_utf8(output).unwrap();
 
@@ -188,11 +509,31 @@ mod test {
         assert!(hello.is_match(&html));
     }
 
+    #[test]
+    fn status_handler_reports_rate_limited_requests() {
+        let rule = LimitRule::new("low", RuleTarget::Class(Priority::Low), 1.0, 1.0);
+        let mut limiter = RateLimiter::new(vec![rule], Duration::from_secs(60));
+        let client = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 80));
+        let now = Instant::now();
+        let _ = limiter.check(&client, now);
+        let _ = limiter.check(&client, now);
+
+        let mut output: Vec<u8> = Vec::new();
+        let context = Context { limits: limiter.counters(), ..new_context() };
+        handle_request(&context, Ok(Path::Status), &RequestInfo::default(), None, 7, &mut output);
+
+        let html = String::from_utf8(output).unwrap();
+
+        assert!(Regex::new(r"Visitor Count: 7").unwrap().is_match(&html));
+        assert!(Regex::new(r"Tracked Clients: 1").unwrap().is_match(&html));
+        assert!(Regex::new(r"<td>low</td><td>1</td>").unwrap().is_match(&html));
+    }
+
     #[test]
     fn file_handler_returns_given_file() {
         let mut output: Vec<u8> = Vec::new();
-        let cache = new_cache();
-        handle_request(&cache, Ok(Path::RelPath("test/response.html".to_string())), 5, &mut output);
+        let context = new_context();
+        handle_request(&context, Ok(Path::RelPath("test/response.html".to_string())), &RequestInfo::default(), None, 5, &mut output);
 
         let html = String::from_utf8(output).unwrap();
 
@@ -201,11 +542,24 @@ mod test {
         assert!(response.is_match(&html));
     }
 
+    #[test]
+    fn counts_files_served_for_popularity_manifest() {
+        let context = new_context();
+        for _ in 0..2 {
+            handle_request(&context, Ok(Path::RelPath("test/small.html".to_string())), &RequestInfo::default(), None, 5, &mut Vec::new());
+        }
+        handle_request(&context, Ok(Path::RelPath("test/response.html".to_string())), &RequestInfo::default(), None, 5, &mut Vec::new());
+        handle_request(&context, Ok(Path::RelPath("test/missing.html".to_string())), &RequestInfo::default(), None, 5, &mut Vec::new());
+
+        assert_eq!(context.popularity.top(5),
+                   vec![(PathBuf::from("test/small.html"), 2), (PathBuf::from("test/response.html"), 1)]);
+    }
+
     #[test]
     fn fails_for_nonexistent_file() {
         let mut output: Vec<u8> = Vec::new();
-        let cache = new_cache();
-        handle_request(&cache, Ok(Path::RelPath("test/does_not_exist.html".to_string())), 5, &mut output);
+        let context = new_context();
+        handle_request(&context, Ok(Path::RelPath("test/does_not_exist.html".to_string())), &RequestInfo::default(), None, 5, &mut output);
 
         let html = String::from_utf8(output).unwrap();
 
@@ -217,8 +571,8 @@ mod test {
     #[test]
     fn fails_for_root_access() {
         let mut output: Vec<u8> = Vec::new();
-        let cache = new_cache();
-        handle_request(&cache, Ok(Path::RelPath("/etc/hosts".to_string())), 5, &mut output);
+        let context = new_context();
+        handle_request(&context, Ok(Path::RelPath("/etc/hosts".to_string())), &RequestInfo::default(), None, 5, &mut output);
 
         let html = String::from_utf8(output).unwrap();
         let response = Regex::new(r"401 Not Authorized").unwrap();
@@ -229,8 +583,8 @@ mod test {
     #[test]
     fn fails_for_parent_dir_access() {
         let mut output: Vec<u8> = Vec::new();
-        let cache = new_cache();
-        handle_request(&cache, Ok(Path::RelPath("../README.md".to_string())), 6, &mut output);
+        let context = new_context();
+        handle_request(&context, Ok(Path::RelPath("../README.md".to_string())), &RequestInfo::default(), None, 6, &mut output);
 
         let html = String::from_utf8(output).unwrap();
         let response = Regex::new(r"401 Not Authorized").unwrap();
@@ -241,8 +595,8 @@ mod test {
     #[test]
     fn fails_for_embedded_parent_dir_access() {
         let mut output: Vec<u8> = Vec::new();
-        let cache = new_cache();
-        handle_request(&cache, Ok(Path::RelPath("test/../../index.html".to_string())), 6, &mut output);
+        let context = new_context();
+        handle_request(&context, Ok(Path::RelPath("test/../../index.html".to_string())), &RequestInfo::default(), None, 6, &mut output);
 
         let html = String::from_utf8(output).unwrap();
         let response = Regex::new(r"401 Not Authorized").unwrap();
@@ -253,8 +607,8 @@ mod test {
     #[test]
     fn fails_for_unallowed_file_type() {
         let mut output: Vec<u8> = Vec::new();
-        let cache = new_cache();
-        handle_request(&cache, Ok(Path::RelPath("test/passwords.txt".to_string())), 6, &mut output);
+        let context = new_context();
+        handle_request(&context, Ok(Path::RelPath("test/passwords.txt".to_string())), &RequestInfo::default(), None, 6, &mut output);
 
         let html = String::from_utf8(output).unwrap();
         let response = Regex::new(r"401 Not Authorized").unwrap();
@@ -265,8 +619,8 @@ mod test {
     #[test]
     fn not_authorized_supersedes_not_found() {
         let mut output: Vec<u8> = Vec::new();
-        let cache = new_cache();
-        handle_request(&cache, Ok(Path::RelPath("test/does_not_exist.txt".to_string())), 6, &mut output);
+        let context = new_context();
+        handle_request(&context, Ok(Path::RelPath("test/does_not_exist.txt".to_string())), &RequestInfo::default(), None, 6, &mut output);
 
         let html = String::from_utf8(output).unwrap();
         let response = Regex::new(r"401 Not Authorized").unwrap();
@@ -274,23 +628,349 @@ mod test {
         assert!(response.is_match(&html));
     }
 
+    // The body of a response, with any chunk framing removed.
+    fn body(response: &str) -> String {
+        let split = response.find("\r\n\r\n").unwrap();
+        let (head, mut rest) = (&response[..split], &response[split + 4..]);
+        if !head.contains("Transfer-Encoding: chunked") {
+            return rest.to_string();
+        }
+        let mut body = String::new();
+        loop {
+            let line_end = rest.find("\r\n").unwrap();
+            let len = usize::from_str_radix(&rest[..line_end], 16).unwrap();
+            if len == 0 {
+                assert_eq!(&rest[line_end..], "\r\n\r\n");
+                return body;
+            }
+            body.push_str(&rest[line_end + 2..line_end + 2 + len]);
+            rest = &rest[line_end + 4 + len..];
+        }
+    }
+
     #[test]
     fn interpolates_shell_command_in_shtml() {
         let mut output: Vec<u8> = Vec::new();
-        let cache = new_cache();
-        handle_request(&cache, Ok(Path::RelPath("test/world.shtml".to_string())), 6, &mut output);
+        let context = new_context();
+        handle_request(&context, Ok(Path::RelPath("test/world.shtml".to_string())), &RequestInfo::default(), None, 6, &mut output);
 
         let html = String::from_utf8(output).unwrap();
-        let response = Regex::new("<h1>\"Hello World\"\n</h1>").unwrap();
 
-        assert!(response.is_match(&html));
+        assert!(html.contains("Transfer-Encoding: chunked\r\n"));
+        assert_eq!(body(&html), "<h1>\"Hello World\"\n</h1>\n");
+    }
+
+    #[test]
+    fn runs_cgi_scripts_with_their_status_and_headers() {
+        let cgi = Cgi { dir: PathBuf::from("test/cgi-bin"), sandbox: Sandbox::default() };
+        let context = Context { routes: table(&[], Some(cgi), &FastCgi::default(), &Upstreams::default()).unwrap(), ..new_context() };
+        let info = RequestInfo { method: "POST".to_string(), body: b"x=1".to_vec(), ..RequestInfo::default() };
+        let request = |path: &str| {
+            let mut output: Vec<u8> = Vec::new();
+            handle_request(&context, Ok(Path::RelPath(path.to_string())), &info, None, 6, &mut output);
+            String::from_utf8(output).unwrap()
+        };
+
+        assert_eq!(request("test/cgi-bin/form.sh"),
+                   "HTTP/1.1 201 Created\r\nContent-Type: text/plain\r\nX-Script: form\r\n\r\nreceived x=1\n");
+        assert_eq!(request("test/cgi-bin/moved.sh"),
+                   "HTTP/1.1 302 Found\r\nContent-Type: text/html; charset=UTF-8\r\nLocation: /elsewhere.html\r\n\r\n");
+        assert!(request("test/cgi-bin/missing.sh").starts_with("HTTP/1.1 404 Not Found"));
+        assert!(request("test/cgi-bin/broken.sh").starts_with("HTTP/1.1 500 Internal Server Error"));
+        assert!(request("test/response.html").contains("<h1>"));
+    }
+
+    #[test]
+    fn answers_bad_gateway_when_a_fastcgi_backend_is_down() {
+        let backends = vec![("app".to_string(), Address::Tcp("127.0.0.1:1".to_string()), 1)];
+        let fastcgi = FastCgi::new(&backends, &[("test/*.php".to_string(), "app".to_string())], Duration::from_secs(1), 1 << 20);
+        let context = Context { routes: table(&[], None, &fastcgi, &Upstreams::default()).unwrap(), ..new_context() };
+        let request = |path: &str| {
+            let mut output: Vec<u8> = Vec::new();
+            handle_request(&context, Ok(Path::RelPath(path.to_string())), &RequestInfo::default(), None, 6, &mut output);
+            String::from_utf8(output).unwrap()
+        };
+
+        assert!(request("test/index.php").starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+        assert!(request("test/response.html").starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn runs_configured_routes_through_their_middleware() {
+        let layers = vec![
+            Layer::Headers(vec![("Cache-Control".to_string(), "no-store".to_string())]),
+            Layer::Auth { realm: "tests".to_string(), users: vec![("alice".to_string(), "secret".to_string())] }
+        ];
+        let specs = vec![
+            RouteSpec { methods: vec!["GET".to_string()], matcher: Matcher::Prefix("/test/".to_string()), target: Target::Static, layers: layers }
+        ];
+        let context = Context { routes: table(&specs, None, &FastCgi::default(), &Upstreams::default()).unwrap(), ..new_context() };
+        let request = |method: &str, path: &str, headers: &[(&str, &str)]| {
+            let info = RequestInfo {
+                method: method.to_string(),
+                headers: headers.iter().map(|&(name, value)| (name.to_string(), value.to_string())).collect(),
+                ..RequestInfo::default()
+            };
+            let mut output: Vec<u8> = Vec::new();
+            handle_request(&context, Ok(Path::RelPath(path.to_string())), &info, None, 6, &mut output);
+            String::from_utf8(output).unwrap()
+        };
+
+        assert_eq!(request("GET", "test/world.shtml", &[]),
+                   "HTTP/1.1 401 Not Authorized\r\nContent-Type: text/html; charset=UTF-8\r\n\
+                    Cache-Control: no-store\r\nWWW-Authenticate: Basic realm=\"tests\"\r\n\r\n");
+        let raw = request("GET", "test/world.shtml", &[("Authorization", "Basic YWxpY2U6c2VjcmV0")]);
+        assert!(raw.starts_with("HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=UTF-8\r\nCache-Control: no-store\r\n\r\n"));
+        assert!(raw.contains("<!-- #exec"), "static route rendered the page");
+        assert_eq!(request("POST", "test/world.shtml", &[]),
+                   "HTTP/1.1 405 Method Not Allowed\r\nContent-Type: text/html; charset=UTF-8\r\nAllow: GET\r\n\r\n");
+        assert!(request("GET", "README.md", &[]).starts_with("HTTP/1.1 401 Not Authorized\r\nContent-Type: text/html; charset=UTF-8\r\n\r\n"));
+    }
+
+    #[test]
+    fn relays_proxied_routes_from_their_upstream() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut head = [0; 1024];
+            let _ = stream.read(&mut head);
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nTransfer-Encoding: chunked\r\nX-App: 1\r\n\r\n\
+                               5\r\nhello\r\n0\r\n\r\n").unwrap();
+        });
+        let mut app = GroupSpec::new("app");
+        app.servers = vec![(address, 4)];
+        let mut down = GroupSpec::new("down");
+        down.servers = vec![("127.0.0.1:1".to_string(), 4)];
+        let upstreams = Upstreams::new(&[app, down], Duration::from_secs(1));
+        let specs = vec![
+            RouteSpec { methods: vec![], matcher: Matcher::Prefix("/app/".to_string()), target: Target::Proxy("app".to_string()), layers: vec![] },
+            RouteSpec { methods: vec![], matcher: Matcher::Prefix("/down/".to_string()), target: Target::Proxy("down".to_string()), layers: vec![] }
+        ];
+        let context = Context { routes: table(&specs, None, &FastCgi::default(), &upstreams).unwrap(), upstreams: upstreams.clone(), ..new_context() };
+        let request = |path: &str| {
+            let mut output: Vec<u8> = Vec::new();
+            handle_request(&context, Ok(Path::RelPath(path.to_string())), &RequestInfo { method: "GET".to_string(), ..RequestInfo::default() }, None, 6, &mut output);
+            String::from_utf8(output).unwrap()
+        };
+
+        assert_eq!(request("app/page"), "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nX-App: 1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                                         5\r\nhello\r\n0\r\n\r\n");
+        assert!(request("down/page").starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+
+        let mut output: Vec<u8> = Vec::new();
+        handle_request(&context, Ok(Path::Status), &RequestInfo::default(), None, 7, &mut output);
+        let html = String::from_utf8(output).unwrap();
+        assert!(html.contains("<td>app</td>"));
+        assert!(Regex::new(r"<td>down</td><td>127.0.0.1:1</td><td>up</td><td>0</td><td>1</td>").unwrap().is_match(&html));
+    }
+
+    #[test]
+    fn redirects_directories_to_a_trailing_slash() {
+        create_dir_all("test/tmp/handler/plain dir").unwrap();
+        let context = new_context();
+        let info = RequestInfo { query: "sort=size".to_string(), ..RequestInfo::default() };
+        let mut output: Vec<u8> = Vec::new();
+        handle_request(&context, Ok(Path::RelPath("test/tmp/handler/plain dir".to_string())), &info, None, 6, &mut output);
+
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   "HTTP/1.1 301 Moved Permanently\r\nContent-Type: text/html; charset=UTF-8\r\n\
+                    Location: /test/tmp/handler/plain%20dir/?sort=size\r\n\r\n");
+    }
+
+    #[test]
+    fn serves_first_index_file_of_a_directory() {
+        let dir = PathBuf::from("test/tmp/handler/indexed");
+        create_dir_all(&dir).unwrap();
+        let _ = remove_file(dir.join("index.html"));
+        File::create(dir.join("index.shtml")).unwrap().write_all(b"<p><!--#echo var=\"DOCUMENT_NAME\" --></p>").unwrap();
+        let context = new_context();
+        let request = || {
+            let mut output: Vec<u8> = Vec::new();
+            handle_request(&context, Ok(Path::RelPath("test/tmp/handler/indexed/".to_string())), &RequestInfo::default(), None, 6, &mut output);
+            String::from_utf8(output).unwrap()
+        };
+
+        assert_eq!(body(&request()), "<p>index.shtml</p>");
+        File::create(dir.join("index.html")).unwrap().write_all(b"<h1>Index</h1>").unwrap();
+        assert_eq!(body(&request()), "<h1>Index</h1>");
+    }
+
+    #[test]
+    fn lists_directories_only_when_enabled() {
+        let dir = PathBuf::from("test/tmp/handler/listed");
+        create_dir_all(dir.join("sub")).unwrap();
+        File::create(dir.join("big.css")).unwrap().write_all(&[b' '; 2048]).unwrap();
+        File::create(dir.join("small.js")).unwrap().write_all(b";").unwrap();
+        File::create(dir.join(".hidden")).unwrap().write_all(b"secret").unwrap();
+        for &(target, link) in &[("small.js", "linked.js"), ("/etc/passwd", "outside")] {
+            let _ = remove_file(dir.join(link));
+            symlink(target, dir.join(link)).unwrap();
+        }
+        let request = |context: &Context, query: &str| {
+            let info = RequestInfo { params: query_params(query), ..RequestInfo::default() };
+            let mut output: Vec<u8> = Vec::new();
+            handle_request(context, Ok(Path::RelPath("test/tmp/handler/listed/".to_string())), &info, None, 6, &mut output);
+            String::from_utf8(output).unwrap()
+        };
+
+        assert!(request(&new_context(), "").starts_with("HTTP/1.1 401 Not Authorized"));
+
+        let context = Context { directory_listing: true, ..new_context() };
+        let html = request(&context, "sort=size&order=desc");
+        assert!(html.contains("<title>Index of /test/tmp/handler/listed/</title>"));
+        assert!(!html.contains(".hidden"));
+        assert!(html.contains("linked.js</a>"));
+        assert!(!html.contains("outside"));
+        let (sub, big, small) = (html.find("sub/</a>").unwrap(), html.find("big.css</a>").unwrap(), html.find("small.js</a>").unwrap());
+        assert!(sub < big && big < small);
+
+        let json = request(&context, "format=json&sort=name");
+        assert!(json.starts_with("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n"));
+        assert!(Regex::new(r#"\{"name":"sub","type":"directory",.*"name":"big.css","type":"file","size":2048,"#).unwrap().is_match(&json));
+
+        let root = DocumentRoot::new(&PathBuf::from("."), Symlinks::Deny, Hidden::Allow).unwrap();
+        let html = request(&Context { root: root, ..context }, "");
+        assert!(html.contains(".hidden</a>"));
+        assert!(!html.contains("linked.js"));
+    }
+
+    #[test]
+    fn keeps_requests_inside_the_document_root() {
+        let base = PathBuf::from("test/tmp/handler/rooted");
+        create_dir_all(base.join("public")).unwrap();
+        create_dir_all(base.join("private")).unwrap();
+        File::create(base.join("public/page.html")).unwrap().write_all(b"<p>public</p>").unwrap();
+        File::create(base.join("public/.draft.html")).unwrap().write_all(b"<p>secret</p>").unwrap();
+        File::create(base.join("public/include.shtml")).unwrap().write_all(b"<!--#include virtual=\"/leak.html\" -->").unwrap();
+        File::create(base.join("private/secret.html")).unwrap().write_all(b"<p>secret</p>").unwrap();
+        let _ = remove_file(base.join("public/leak.html"));
+        symlink("../private/secret.html", base.join("public/leak.html")).unwrap();
+        let root = DocumentRoot::new(&base.join("public"), Symlinks::Allow, Hidden::Deny).unwrap();
+        let context = Context { root: root.clone(), ssi: ssi::Settings { root: root, ..ssi::Settings::default() }, ..new_context() };
+        let request = |path: &str| {
+            let mut output: Vec<u8> = Vec::new();
+            handle_request(&context, Ok(Path::RelPath(path.to_string())), &RequestInfo::default(), None, 6, &mut output);
+            String::from_utf8(output).unwrap()
+        };
+
+        assert_eq!(body(&request("page.html")), "<p>public</p>");
+        for path in ["leak.html", ".draft.html", "../private/secret.html", "%2e%2e/private/secret.html", "..%2fprivate%2fsecret.html"] {
+            let response = request(path);
+            assert!(!response.starts_with("HTTP/1.1 200"), "{} was served", path);
+            assert!(!response.contains("secret"), "{} leaked", path);
+        }
+        let included = request("include.shtml");
+        assert!(!included.contains("secret"));
+        assert!(included.contains(ssi::DEFAULT_ERRMSG));
+    }
+
+    #[test]
+    fn reuses_rendered_shtml_until_template_changes() {
+        let path = PathBuf::from("test/tmp/handler/rendered.shtml");
+        create_dir_all(path.parent().unwrap()).unwrap();
+        File::create(&path).unwrap().write_all(b"<!--#cache ttl=\"60\" --><!-- #exec date +%s%N -->").unwrap();
+        let context = Context { rendered: RenderCache::new(Duration::from_secs(0)), ..new_context() };
+        let request = || {
+            let mut output: Vec<u8> = Vec::new();
+            handle_request(&context, Ok(Path::RelPath("test/tmp/handler/rendered.shtml".to_string())), &RequestInfo::default(), None, 6, &mut output);
+            String::from_utf8(output).unwrap()
+        };
+
+        let first = request();
+        assert_eq!(body(&request()), body(&first));
+        assert_eq!(context.rendered.len(), 1);
+
+        File::create(&path).unwrap().write_all(b"<!--#cache off --><!-- #exec echo edited -->").unwrap();
+        let edited = request();
+        let _ = remove_file(&path);
+
+        assert!(Regex::new("edited\n").unwrap().is_match(&edited));
+        assert_eq!(context.rendered.len(), 0);
+    }
+
+    #[test]
+    fn replaces_cached_file_changed_on_disk() {
+        let path = PathBuf::from("test/tmp/handler/changed.html");
+        create_dir_all(path.parent().unwrap()).unwrap();
+        File::create(&path).unwrap().write_all(b"<h1>Stale</h1>").unwrap();
+        let context = new_context();
+        let stale = CachedFile { bytes: Arc::from(&b"<h1>Stale</h1>"[..]), stamp: Stamp::of(&path).unwrap(), checked: Instant::now() };
+        context.cache.put(path.clone(), stale);
+
+        File::create(&path).unwrap().write_all(b"<h1>Edited on disk</h1>").unwrap();
+        let mut output: Vec<u8> = Vec::new();
+        handle_request(&context, Ok(Path::RelPath("test/tmp/handler/changed.html".to_string())), &RequestInfo::default(), None, 6, &mut output);
+        let _ = remove_file(&path);
+
+        let html = String::from_utf8(output).unwrap();
+        assert!(Regex::new(r"<h1>Edited on disk</h1>").unwrap().is_match(&html));
+    }
+
+    #[test]
+    fn deleted_cached_file_is_not_found() {
+        let path = PathBuf::from("test/tmp/handler/deleted.html");
+        create_dir_all(path.parent().unwrap()).unwrap();
+        File::create(&path).unwrap().write_all(b"<h1>Deleted</h1>").unwrap();
+        let context = new_context();
+        let cached = CachedFile { bytes: Arc::from(&b"<h1>Deleted</h1>"[..]), stamp: Stamp::of(&path).unwrap(), checked: Instant::now() };
+        context.cache.put(path.clone(), cached);
+
+        remove_file(&path).unwrap();
+        let mut output: Vec<u8> = Vec::new();
+        let status = handle_request(&context, Ok(Path::RelPath("test/tmp/handler/deleted.html".to_string())), &RequestInfo::default(), None, 6, &mut output);
+
+        assert_eq!(status, Status::FileNotFound);
+        assert!(!context.cache.contains(&path));
+    }
+
+    #[test]
+    fn serves_binary_files_cold_cached_and_evicted() {
+        for name in ["test/pixel.png", "favicon.ico"] {
+            let mut expected = header(&Status::Ok);
+            File::open(name).unwrap().read_to_end(&mut expected).unwrap();
+            let path = PathBuf::from(name);
+            let context = context_with_cache(1 << 16, 1 << 15);
+
+            let mut cold: Vec<u8> = Vec::new();
+            let status = handle_request(&context, Ok(Path::RelPath(name.to_string())), &RequestInfo::default(), None, 6, &mut cold);
+            assert_eq!(status, Status::Ok);
+            assert!(cold == expected, "cold request for {} differs from file", name);
+            assert!(context.cache.contains(&path));
+
+            let mut cached: Vec<u8> = Vec::new();
+            let status = handle_request(&context, Ok(Path::RelPath(name.to_string())), &RequestInfo::default(), None, 6, &mut cached);
+            assert_eq!(status, Status::Ok);
+            assert!(cached == expected, "cached request for {} differs from file", name);
+
+            let filler = CachedFile { bytes: Arc::from(vec![0; 1 << 15]), stamp: Stamp::of(&path).unwrap(), checked: Instant::now() };
+            context.cache.put(PathBuf::from("filler-a"), filler);
+            let filler = CachedFile { bytes: Arc::from(vec![0; 1 << 15]), stamp: Stamp::of(&path).unwrap(), checked: Instant::now() };
+            context.cache.put(PathBuf::from("filler-b"), filler);
+            assert!(!context.cache.contains(&path));
+
+            let mut evicted: Vec<u8> = Vec::new();
+            let status = handle_request(&context, Ok(Path::RelPath(name.to_string())), &RequestInfo::default(), None, 6, &mut evicted);
+            assert_eq!(status, Status::Ok);
+            assert!(evicted == expected, "evicted request for {} differs from file", name);
+        }
+    }
+
+    #[test]
+    fn answers_bad_request_for_malformed_requests() {
+        let mut output: Vec<u8> = Vec::new();
+        let context = new_context();
+        let status = handle_request(&context, Err(io::Error::new(io::ErrorKind::InvalidData, "/a%2Fb has an encoded slash")), &RequestInfo::default(), None, 6, &mut output);
+
+        assert_eq!(status, Status::Other(400, "Bad Request".to_string()));
+        assert!(String::from_utf8(output).unwrap().starts_with("HTTP/1.1 400 Bad Request\r\n"));
     }
 
     #[test]
     fn returns_error_if_path_not_parsed() {
         let mut output: Vec<u8> = Vec::new();
-        let cache = new_cache();
-        let status = handle_request(&cache, Err(io::Error::new(io::ErrorKind::Other, "Whoops")), 6, &mut output);
+        let context = new_context();
+        let status = handle_request(&context, Err(io::Error::new(io::ErrorKind::Other, "Whoops")), &RequestInfo::default(), None, 6, &mut output);
 
         assert_eq!(status, Status::Error);
     }
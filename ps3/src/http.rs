@@ -1,13 +1,43 @@
 use std::fmt;
 use std::fs::File;
-use std::io::{ BufReader };
+use std::io::{ self, BufReader, Read, Write };
+use std::path::PathBuf;
+use std::sync::Arc;
+use freshness::Stamp;
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum Status {
     Ok,
     FileNotFound,
     Error,
-    NotAuthorized
+    NotAuthorized,
+    TooManyRequests,
+    // Any other status, with its reason phrase.
+    Other(u16, String)
+}
+
+impl Status {
+    pub fn from_code(code: u16, reason: &str) -> Status {
+        match code {
+            200 => Status::Ok,
+            401 => Status::NotAuthorized,
+            404 => Status::FileNotFound,
+            429 => Status::TooManyRequests,
+            500 => Status::Error,
+            _ => Status::Other(code, reason.to_string())
+        }
+    }
+
+    pub fn code(&self) -> u16 {
+        match self {
+            &Status::Ok => 200,
+            &Status::FileNotFound => 404,
+            &Status::Error => 500,
+            &Status::NotAuthorized => 401,
+            &Status::TooManyRequests => 429,
+            &Status::Other(code, _) => code
+        }
+    }
 }
 
 impl fmt::Display for Status {
@@ -16,23 +46,120 @@ impl fmt::Display for Status {
             &Status::Ok => write!(f, "200 OK"),
             &Status::FileNotFound => write!(f, "404 Not Found"),
             &Status::Error => write!(f, "500 Internal Server Error"),
-            &Status::NotAuthorized => write!(f, "401 Not Authorized")
+            &Status::NotAuthorized => write!(f, "401 Not Authorized"),
+            &Status::TooManyRequests => write!(f, "429 Too Many Requests"),
+            &Status::Other(code, ref reason) => write!(f, "{} {}", code, reason)
         }
     }
 }
 
 pub enum Payload {
     Stream(BufReader<File>),
-    Block(String)
+    Block(String),
+    Bytes(Arc<[u8]>),
+    // Rendered while it is sent, in chunks.
+    Template(Template),
+    // A complete response with its own status and headers, from a program or
+    // the server itself.
+    Response(Response),
+    // A response from another server, sent on as it arrives.
+    Relay(Relay)
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    pub status: Status,
+    pub content_type: String,
+    pub fields: Vec<(String, String)>,
+    pub body: Vec<u8>
+}
+
+impl Response {
+    // Sends the client to `location` for good.
+    pub fn redirect(location: &str) -> Response {
+        Response {
+            status: Status::Other(301, "Moved Permanently".to_string()),
+            content_type: "text/html; charset=UTF-8".to_string(),
+            fields: vec![("Location".to_string(), location.to_string())],
+            body: vec![]
+        }
+    }
+}
+
+// The head of a response from another server and its body, still to be read.
+pub struct Relay {
+    pub status: Status,
+    pub content_type: String,
+    pub fields: Vec<(String, String)>,
+    // Whether the body has to be sent in chunks, its length not being known.
+    pub chunked: bool,
+    pub body: Box<dyn Read + Send>
+}
+
+// A server-side include page waiting to be rendered.
+pub struct Template {
+    pub path: PathBuf,
+    pub contents: Arc<[u8]>,
+    // The page itself, stamped before it was read.
+    pub sources: Vec<(PathBuf, Stamp)>
+}
+
+// Writes a body with chunked transfer encoding, each write becoming a chunk,
+// for responses whose length is not known before they are sent.
+pub struct Chunked<W: Write> {
+    inner: W
+}
+
+impl<W: Write> Chunked<W> {
+    pub fn new(inner: W) -> Self {
+        Chunked { inner: inner }
+    }
+
+    // Writes the last, empty chunk.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.inner.write_all(b"0\r\n\r\n")?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for Chunked<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !buf.is_empty() {
+            self.inner.write_all(format!("{:X}\r\n", buf.len()).as_bytes())?;
+            self.inner.write_all(buf)?;
+            self.inner.write_all(b"\r\n")?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
 }
 
 pub fn header(status: &Status) -> Vec<u8> {
-    format!("HTTP/1.1 {}\r\nContent-Type: text/html; charset=UTF-8\r\n\r\n", status).into_bytes()
+    header_with(status, &[])
+}
+
+pub fn header_with(status: &Status, fields: &[(&str, String)]) -> Vec<u8> {
+    let fields = fields.iter()
+        .map(|&(name, ref value)| (name.to_string(), value.clone()))
+        .collect::<Vec<(String, String)>>();
+    response_header(status, "text/html; charset=UTF-8", &fields)
+}
+
+pub fn response_header(status: &Status, content_type: &str, fields: &[(String, String)]) -> Vec<u8> {
+    let extra = fields.iter()
+        .map(|&(ref name, ref value)| format!("{}: {}\r\n", name, value))
+        .collect::<String>();
+    format!("HTTP/1.1 {}\r\nContent-Type: {}\r\n{}\r\n", status, content_type, extra).into_bytes()
 }
 
 #[cfg(test)]
 mod test {
-    use super::{ Status, header };
+    use std::io::Write;
+    use super::{ Chunked, Status, header, header_with, response_header };
 
     #[test]
     fn formats_status_into_header() {
@@ -44,5 +171,31 @@ mod test {
                    header(&Status::Error));
         assert_eq!("HTTP/1.1 401 Not Authorized\r\nContent-Type: text/html; charset=UTF-8\r\n\r\n".to_string().into_bytes(),
                    header(&Status::NotAuthorized));
+        assert_eq!("HTTP/1.1 429 Too Many Requests\r\nContent-Type: text/html; charset=UTF-8\r\n\r\n".to_string().into_bytes(),
+                   header(&Status::TooManyRequests));
+    }
+
+    #[test]
+    fn appends_extra_fields_to_header() {
+        assert_eq!("HTTP/1.1 429 Too Many Requests\r\nContent-Type: text/html; charset=UTF-8\r\nRetry-After: 3\r\n\r\n".to_string().into_bytes(),
+                   header_with(&Status::TooManyRequests, &[("Retry-After", "3".to_string())]));
+    }
+
+    #[test]
+    fn writes_other_statuses_and_content_types() {
+        assert_eq!("HTTP/1.1 302 Found\r\nContent-Type: text/plain\r\nLocation: /next\r\n\r\n".to_string().into_bytes(),
+                   response_header(&Status::Other(302, "Found".to_string()), "text/plain",
+                                   &[("Location".to_string(), "/next".to_string())]));
+    }
+
+    #[test]
+    fn frames_writes_as_chunks() {
+        let mut chunked = Chunked::new(Vec::new());
+        chunked.write_all(b"<h1>").unwrap();
+        chunked.write_all(b"").unwrap();
+        chunked.write_all(&[b'x'; 26]).unwrap();
+
+        assert_eq!(String::from_utf8(chunked.finish().unwrap()).unwrap(),
+                   format!("4\r\n<h1>\r\n1A\r\n{}\r\n0\r\n\r\n", "x".repeat(26)));
     }
 }
@@ -0,0 +1,226 @@
+use std::cmp::Ordering;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{ SystemTime, UNIX_EPOCH };
+use document_root::{ Denied, DocumentRoot };
+use http::{ Payload, Response, Status };
+use ssi::directive::{ Encoding, SizeFormat };
+use ssi::format::{ encode, format_size, format_time };
+
+const TIMEFMT: &'static str = "%Y-%m-%d %H:%M";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub name: String,
+    pub directory: bool,
+    pub size: u64,
+    pub modified: SystemTime
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Key {
+    Name,
+    Size,
+    Modified
+}
+
+// How a listing was asked for: `?sort=name|size|mtime&order=asc|desc` and
+// `format=html|json`. Parameters it does not know are ignored.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct View {
+    key: Key,
+    descending: bool,
+    json: bool
+}
+
+impl View {
+    fn parse(params: &[(String, String)]) -> View {
+        let mut view = View { key: Key::Name, descending: false, json: false };
+        for &(ref name, ref value) in params {
+            match (&name[..], &value[..]) {
+                ("sort", "name") => view.key = Key::Name,
+                ("sort", "size") => view.key = Key::Size,
+                ("sort", "mtime") => view.key = Key::Modified,
+                ("order", "desc") => view.descending = true,
+                ("order", "asc") => view.descending = false,
+                ("format", "json") => view.json = true,
+                ("format", "html") => view.json = false,
+                _ => ()
+            }
+        }
+        view
+    }
+}
+
+// Lists `dir`, a directory under `root` that was requested as `uri`.
+pub fn listing(root: &DocumentRoot, dir: &Path, uri: &str, params: &[(String, String)]) -> io::Result<Payload> {
+    let view = View::parse(params);
+    let mut entries = read_entries(root, dir)?;
+    sort(&mut entries, view);
+    if view.json {
+        Ok(Payload::Response(Response {
+            status: Status::Ok,
+            content_type: "application/json".to_string(),
+            fields: vec![],
+            body: json(uri, &entries).into_bytes()
+        }))
+    } else {
+        Ok(Payload::Block(html(uri, &entries, view)))
+    }
+}
+
+// Only entries a request could be served are listed: hidden files and
+// symlinks are left out as the root's policies would refuse them.
+pub fn read_entries(root: &DocumentRoot, dir: &Path) -> io::Result<Vec<Entry>> {
+    let relative = root.relative(dir).unwrap_or(dir).to_path_buf();
+    let mut entries = vec![];
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let data = match root.resolve(&relative.join(&name)).and_then(|path| fs::metadata(path).map_err(|_| Denied::NotFound)) {
+            Ok(data) => data,
+            Err(_) => continue
+        };
+        entries.push(Entry {
+            name: name.to_string_lossy().into_owned(),
+            directory: data.is_dir(),
+            size: if data.is_dir() { 0 } else { data.len() },
+            modified: data.modified().unwrap_or(UNIX_EPOCH)
+        });
+    }
+    Ok(entries)
+}
+
+// Directories come first, then entries in the order asked for. Ties are
+// broken by name.
+fn sort(entries: &mut [Entry], view: View) {
+    entries.sort_by(|a, b| {
+        let by_key = match view.key {
+            Key::Name => Ordering::Equal,
+            Key::Size => a.size.cmp(&b.size),
+            Key::Modified => a.modified.cmp(&b.modified)
+        }.then_with(|| a.name.cmp(&b.name));
+        let by_key = if view.descending { by_key.reverse() } else { by_key };
+        b.directory.cmp(&a.directory).then(by_key)
+    });
+}
+
+fn html(uri: &str, entries: &[Entry], view: View) -> String {
+    let title = encode(uri, Encoding::Entity);
+    let heading = |label: &str, key: Key, param: &str| {
+        let order = if view.key == key && !view.descending { "desc" } else { "asc" };
+        format!("<th><a href=\"?sort={}&amp;order={}\">{}</a></th>", param, order, label)
+    };
+    let mut rows = String::new();
+    if uri != "/" {
+        rows.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in entries {
+        let slash = if entry.directory { "/" } else { "" };
+        let size = if entry.directory { "-".to_string() } else { format_size(entry.size, SizeFormat::Abbrev) };
+        rows.push_str(&format!("<tr><td><a href=\"{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>\n",
+                               encode(&entry.name, Encoding::Url), slash, encode(&entry.name, Encoding::Entity), slash,
+                               size, format_time(entry.modified, TIMEFMT, false)));
+    }
+    format!("<doctype !html><html><head><title>Index of {0}</title></head>
+            <body>
+            <h1>Index of {0}</h1>
+            <table>
+            <tr>{1}{2}{3}</tr>
+            {4}</table>
+            </body></html>\r\n",
+            title, heading("Name", Key::Name, "name"), heading("Size", Key::Size, "size"),
+            heading("Last Modified", Key::Modified, "mtime"), rows)
+}
+
+fn json(uri: &str, entries: &[Entry]) -> String {
+    let entries = entries.iter()
+        .map(|entry| {
+            let modified = entry.modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            format!("{{\"name\":{},\"type\":\"{}\",\"size\":{},\"modified\":{}}}",
+                    json_string(&entry.name), if entry.directory { "directory" } else { "file" }, entry.size, modified)
+        })
+        .collect::<Vec<String>>();
+    format!("{{\"path\":{},\"entries\":[{}]}}", json_string(uri), entries.join(","))
+}
+
+fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c)
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{ Duration, UNIX_EPOCH };
+    use path::query_params;
+    use super::{ html, json, sort, Entry, Key, View };
+
+    fn view(query: &str) -> View {
+        View::parse(&query_params(query))
+    }
+
+    fn entry(name: &str, directory: bool, size: u64, modified: u64) -> Entry {
+        Entry { name: name.to_string(), directory: directory, size: size, modified: UNIX_EPOCH + Duration::from_secs(modified) }
+    }
+
+    fn names(entries: &[Entry]) -> Vec<&str> {
+        entries.iter().map(|entry| &entry.name[..]).collect()
+    }
+
+    fn sample() -> Vec<Entry> {
+        vec![entry("b.html", false, 300, 20), entry("a.css", false, 10, 30), entry("img", true, 0, 10), entry("c.js", false, 20, 10)]
+    }
+
+    #[test]
+    fn reads_sort_order_and_format_from_query() {
+        assert_eq!(view(""), View { key: Key::Name, descending: false, json: false });
+        assert_eq!(view("sort=size&order=desc&format=json"), View { key: Key::Size, descending: true, json: true });
+        assert_eq!(view("sort=mtime&bogus&sort=nope"), View { key: Key::Modified, descending: false, json: false });
+    }
+
+    #[test]
+    fn sorts_directories_first_then_by_key() {
+        let mut entries = sample();
+        sort(&mut entries, view(""));
+        assert_eq!(names(&entries), vec!["img", "a.css", "b.html", "c.js"]);
+
+        sort(&mut entries, view("sort=size&order=desc"));
+        assert_eq!(names(&entries), vec!["img", "b.html", "c.js", "a.css"]);
+
+        sort(&mut entries, view("sort=mtime"));
+        assert_eq!(names(&entries), vec!["img", "c.js", "b.html", "a.css"]);
+    }
+
+    #[test]
+    fn links_and_escapes_entries_in_html() {
+        let entries = vec![entry("<b>&.html", false, 2048, 0), entry("sub dir", true, 0, 0)];
+        let page = html("/docs/", &entries, view("sort=name"));
+
+        assert!(page.contains("<title>Index of /docs/</title>"));
+        assert!(page.contains("<a href=\"../\">../</a>"));
+        assert!(page.contains("<a href=\"%3Cb%3E%26.html\">&lt;b&gt;&amp;.html</a></td><td>2K</td><td>1970-01-01 00:00</td>"));
+        assert!(page.contains("<a href=\"sub%20dir/\">sub dir/</a></td><td>-</td>"));
+        assert!(page.contains("<a href=\"?sort=name&amp;order=desc\">Name</a>"));
+        assert!(page.contains("<a href=\"?sort=size&amp;order=asc\">Size</a>"));
+    }
+
+    #[test]
+    fn lists_entries_as_json() {
+        let entries = vec![entry("say \"hi\".txt", false, 5, 60), entry("img", true, 0, 0)];
+
+        assert_eq!(json("/docs/", &entries),
+                   "{\"path\":\"/docs/\",\"entries\":[\
+                    {\"name\":\"say \\\"hi\\\".txt\",\"type\":\"file\",\"size\":5,\"modified\":60},\
+                    {\"name\":\"img\",\"type\":\"directory\",\"size\":0,\"modified\":0}]}");
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Mutex, Condvar };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::sync::mpsc::{ sync_channel, SyncSender, Receiver, TrySendError };
+use std::thread;
+use std::time::{ Duration, Instant };
+use freshness::{ CachedFile, Stamp };
+use handler::Cache;
+
+// Requests queued behind each loader thread before misses fall back to
+// streaming from disk without filling the cache.
+const QUEUE_PER_LOADER: usize = 16;
+
+// The outcome of a single fill, shared by every request waiting on it.
+pub struct Fill {
+    result: Mutex<Option<Option<Arc<[u8]>>>>,
+    ready: Condvar
+}
+
+impl Fill {
+    fn new() -> Self {
+        Fill { result: Mutex::new(None), ready: Condvar::new() }
+    }
+
+    fn complete(&self, contents: Option<Arc<[u8]>>) {
+        *self.result.lock().unwrap() = Some(contents);
+        self.ready.notify_all();
+    }
+
+    // None if the fill failed or did not finish within `timeout`.
+    pub fn wait(&self, timeout: Duration) -> Option<Arc<[u8]>> {
+        let deadline = Instant::now() + timeout;
+        let mut result = self.result.lock().unwrap();
+        while result.is_none() {
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            result = self.ready.wait_timeout(result, deadline - now).unwrap().0;
+        }
+        result.as_ref().and_then(|contents| contents.clone())
+    }
+}
+
+pub enum Flight {
+    Leader(Arc<Fill>),
+    Follower(Arc<Fill>)
+}
+
+// Tracks fills in progress so that concurrent misses for one key share a
+// single load.
+#[derive(Clone)]
+pub struct SingleFlight {
+    inflight: Arc<Mutex<HashMap<PathBuf, Arc<Fill>>>>
+}
+
+impl SingleFlight {
+    pub fn new() -> Self {
+        SingleFlight { inflight: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    pub fn begin(&self, key: &Path) -> Flight {
+        let mut inflight = self.inflight.lock().unwrap();
+        match inflight.get(key) {
+            Some(fill) => Flight::Follower(fill.clone()),
+            None => {
+                let fill = Arc::new(Fill::new());
+                inflight.insert(key.to_owned(), fill.clone());
+                Flight::Leader(fill)
+            }
+        }
+    }
+
+    pub fn complete(&self, key: &Path, contents: Option<Arc<[u8]>>) {
+        if let Some(fill) = self.inflight.lock().unwrap().remove(key) {
+            fill.complete(contents);
+        }
+    }
+}
+
+// A fixed pool of threads that read files into the cache.
+#[derive(Clone)]
+pub struct Loader {
+    cache: Cache,
+    flights: SingleFlight,
+    jobs: SyncSender<PathBuf>,
+    wait: Duration,
+    pub fills: Arc<AtomicUsize>
+}
+
+impl Loader {
+    pub fn new(cache: Cache, loaders: usize, wait: Duration) -> Self {
+        let (jobs, queue) = sync_channel(loaders * QUEUE_PER_LOADER);
+        let queue = Arc::new(Mutex::new(queue));
+        let loader = Loader {
+            cache: cache,
+            flights: SingleFlight::new(),
+            jobs: jobs,
+            wait: wait,
+            fills: Arc::new(AtomicUsize::new(0))
+        };
+        for _ in 0..loaders {
+            let worker = loader.clone();
+            let queue = queue.clone();
+            thread::spawn(move || worker.run(queue));
+        }
+        loader
+    }
+
+    // Loads `path` into the cache, waiting for a fill already in progress if
+    // there is one. None means the caller should stream the file itself.
+    pub fn load(&self, path: &Path) -> Option<Arc<[u8]>> {
+        let cacheable = path.metadata()
+            .map(|data| self.cache.admits(data.len() as usize))
+            .unwrap_or(false);
+        if !cacheable {
+            return None;
+        }
+        match self.flights.begin(path) {
+            Flight::Follower(fill) => fill.wait(self.wait),
+            Flight::Leader(fill) => {
+                match self.jobs.try_send(path.to_owned()) {
+                    Ok(_) => fill.wait(self.wait),
+                    Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                        self.flights.complete(path, None);
+                        None
+                    }
+                }
+            }
+        }
+    }
+
+    // Reads `path` into the cache on the calling thread unless it is already
+    // cached, too large, or being filled by someone else. Returns the number of
+    // bytes read.
+    pub fn prefetch(&self, path: &Path) -> Option<usize> {
+        let admitted = path.metadata()
+            .map(|data| {
+                data.is_file() && self.cache.admits(data.len() as usize) && !self.cache.contains(&path.to_path_buf())
+            })
+            .unwrap_or(false);
+        if !admitted {
+            return None;
+        }
+        match self.flights.begin(path) {
+            Flight::Follower(_) => None,
+            Flight::Leader(_) => {
+                let contents = self.read(path);
+                let read = contents.as_ref().map(|bytes| bytes.len());
+                self.flights.complete(path, contents);
+                read
+            }
+        }
+    }
+
+    fn run(&self, queue: Arc<Mutex<Receiver<PathBuf>>>) {
+        loop {
+            let job = queue.lock().unwrap().recv();
+            match job {
+                Ok(path) => {
+                    let contents = self.read(&path);
+                    self.flights.complete(&path, contents);
+                }
+                Err(_) => return
+            }
+        }
+    }
+
+    fn read(&self, path: &Path) -> Option<Arc<[u8]>> {
+        let mut contents = Vec::new();
+        let read = Stamp::of(path).and_then(|stamp| {
+            File::open(path)
+                .and_then(|mut f| f.read_to_end(&mut contents))
+                .map(|_| stamp)
+        });
+        match read {
+            Ok(stamp) => {
+                self.fills.fetch_add(1, Ordering::Relaxed);
+                let bytes = Arc::from(contents);
+                let file = CachedFile { bytes: Arc::clone(&bytes), stamp: stamp, checked: Instant::now() };
+                self.cache.put(path.to_owned(), file);
+                Some(bytes)
+            }
+            Err(_) => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::{ Path, PathBuf };
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::Duration;
+    use cache::{ ShardedCache, Policy };
+    use super::{ Flight, Loader, SingleFlight };
+
+    #[test]
+    fn concurrent_misses_share_one_fill() {
+        let flights = SingleFlight::new();
+        let path = Path::new("test/small.html");
+        let leader = match flights.begin(path) {
+            Flight::Leader(fill) => fill,
+            Flight::Follower(_) => panic!("first request should lead")
+        };
+        let followers = (0..10).map(|_| {
+            match flights.begin(path) {
+                Flight::Leader(_) => panic!("second request should follow"),
+                Flight::Follower(fill) => thread::spawn(move || fill.wait(Duration::from_secs(5)))
+            }
+        }).collect::<Vec<_>>();
+
+        flights.complete(path, Some(Arc::from(&b"<h1>Little Response</h1>"[..])));
+
+        for follower in followers {
+            assert_eq!(follower.join().unwrap(), Some(Arc::from(&b"<h1>Little Response</h1>"[..])));
+        }
+        assert_eq!(leader.wait(Duration::from_secs(0)), Some(Arc::from(&b"<h1>Little Response</h1>"[..])));
+        match flights.begin(path) {
+            Flight::Leader(_) => (),
+            Flight::Follower(_) => panic!("completed fill should be forgotten")
+        }
+    }
+
+    #[test]
+    fn waiting_gives_up_after_timeout() {
+        let flights = SingleFlight::new();
+        let path = Path::new("test/small.html");
+        let _ = flights.begin(path);
+
+        match flights.begin(path) {
+            Flight::Follower(fill) => assert_eq!(fill.wait(Duration::from_millis(10)), None),
+            Flight::Leader(_) => panic!("second request should follow")
+        }
+    }
+
+    #[test]
+    fn loads_file_into_cache() {
+        let cache = Arc::new(ShardedCache::new(1 << 20, 1 << 16, Policy::Lru, 4));
+        let loader = Loader::new(cache.clone(), 2, Duration::from_secs(5));
+
+        let contents = loader.load(Path::new("test/response.html"));
+
+        assert_eq!(contents, Some(Arc::from(&b"<h1>Test Response</h1>\n"[..])));
+        assert!(cache.contains(&PathBuf::from("test/response.html")));
+        assert_eq!(loader.fills.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn prefetches_only_uncached_files() {
+        let cache = Arc::new(ShardedCache::new(1 << 20, 1 << 16, Policy::Lru, 4));
+        let loader = Loader::new(cache.clone(), 1, Duration::from_secs(5));
+
+        assert_eq!(loader.prefetch(Path::new("test/response.html")), Some(23));
+        assert_eq!(loader.prefetch(Path::new("test/response.html")), None);
+        assert_eq!(loader.prefetch(Path::new("test")), None);
+        assert!(cache.contains(&PathBuf::from("test/response.html")));
+    }
+
+    #[test]
+    fn does_not_load_files_over_object_ceiling() {
+        let cache = Arc::new(ShardedCache::new(1 << 20, 8, Policy::Lru, 4));
+        let loader = Loader::new(cache.clone(), 1, Duration::from_secs(5));
+
+        assert_eq!(loader.load(Path::new("test/response.html")), None);
+        assert_eq!(loader.load(Path::new("test/does_not_exist.html")), None);
+        assert_eq!(loader.fills.load(Ordering::Relaxed), 0);
+    }
+}
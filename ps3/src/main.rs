@@ -14,13 +14,18 @@
 #[macro_use]
 extern crate lazy_static;
 extern crate regex;
-extern crate lru_cache;
+extern crate libc;
 
 use std::net::TcpListener;
 use std::str;
 use std::thread;
+use std::env;
+use std::io::Write;
+use std::path::{ Path, PathBuf };
+use std::process::exit;
 use std::sync::{ Arc, Mutex };
-use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+use std::time::{ Duration, Instant };
 
 mod path;
 mod handler;
@@ -30,32 +35,121 @@ mod cmd_line;
 mod external;
 mod scheduling;
 mod request;
+mod config;
+mod rate_limit;
+mod cache;
+mod freshness;
+mod loader;
+mod warm;
+mod rendered;
+mod zero_copy;
+mod ssi;
+mod sandbox;
+mod cgi;
+mod fastcgi;
+mod listing;
+mod document_root;
+mod router;
+mod middleware;
+mod proxy;
 
 use scheduling::{ schedule, queues, IpAddressable };
 use request::{ build_request, Request };
-use lru_cache::cache::LruCache;
-use handler::{ handle_request, Cache };
+use cache::ShardedCache;
+use handler::{ handle_request, Context };
+use config::Config;
+use rate_limit::{ RateLimiter, retry_after_secs };
+use http::{ header_with, Status };
+use freshness::{ Invalidation, cache_key, watch };
+use loader::Loader;
+use warm::{ Popularity, warm_up };
+use rendered::RenderCache;
+use cgi::Cgi;
+use fastcgi::FastCgi;
+use document_root::DocumentRoot;
+use router::table;
+use proxy::Upstreams;
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
 
 fn main() {
-    let addr = "127.0.0.1:4414";
-    let listener = TcpListener::bind(addr).unwrap();
+    let config = match env::args().nth(1) {
+        Some(path) => Config::load(Path::new(&path)).unwrap_or_else(|e| {
+            println!("Invalid configuration: {}", e);
+            exit(1);
+        }),
+        None => Config::default()
+    };
+    let root = DocumentRoot::new(&config.document_root, config.symlinks, config.hidden_files).unwrap_or_else(|e| {
+        println!("Invalid document root [{}]: {}", config.document_root.display(), e);
+        exit(1);
+    });
+    let listener = TcpListener::bind(&config.address[..]).unwrap();
     let visitor_count: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
     let (hq, lq) = queues();
     let high_priority = Arc::new(Mutex::new(hq));
     let low_priority = Arc::new(Mutex::new(lq));
-    let lru_cache: Cache = Arc::new(Mutex::new(LruCache::new(512)));
+    let mut limiter = RateLimiter::new(config.rate_limits, config.rate_limit_expiry);
+    let cache = Arc::new(ShardedCache::new(config.cache_size, config.cache_max_object, config.cache_policy, config.cache_shards));
+    let watched = config.cache_watch.iter()
+        .filter(|root| {
+            match watch(root, cache.clone()) {
+                Ok(_) => true,
+                Err(e) => {
+                    println!("Not watching [{}]: {}", root.display(), e);
+                    false
+                }
+            }
+        })
+        .map(|root| cache_key(root))
+        .collect();
+    let cgi_exec = config.cgi_exec.clone();
+    let cgi = config.cgi_dir.clone().map(|dir| Cgi { dir: dir, sandbox: cgi_exec });
+    let fastcgi = FastCgi::new(&config.fastcgi_backends, &config.fastcgi_routes, config.fastcgi_timeout, config.fastcgi_output);
+    let upstreams = Upstreams::new(&config.proxy_upstreams, config.proxy_timeout);
+    upstreams.watch_health();
+    let routes = table(&config.routes, cgi, &fastcgi, &upstreams).unwrap_or_else(|e| {
+        println!("Invalid routes: {}", e);
+        exit(1);
+    });
+    let context = Context {
+        root: root.clone(),
+        cache: cache.clone(),
+        invalidation: Invalidation { revalidate_after: config.cache_revalidate, watched: watched },
+        loader: Loader::new(cache, config.cache_loaders, config.cache_fill_wait),
+        popularity: Popularity::new(),
+        rendered: RenderCache::new(config.ssi_cache_ttl),
+        ssi: ssi::Settings {
+            exec_parallelism: config.ssi_exec_parallelism,
+            sandbox: config.ssi_exec.clone(),
+            encoding: config.ssi_encoding,
+            root: root
+        },
+        routes: routes,
+        upstreams: upstreams,
+        index_files: config.index_files.clone(),
+        directory_listing: config.directory_listing,
+        sendfile_min: config.sendfile_min,
+        limits: limiter.counters()
+    };
+    if let Some(ref manifest) = config.cache_manifest {
+        save_manifest_on_shutdown(context.popularity.clone(), manifest.clone(), config.cache_manifest_size);
+    }
+    if config.cache_manifest.is_some() || !config.cache_warm.is_empty() {
+        warm_up(context.loader.clone(), config.cache_manifest.clone(), config.cache_warm.clone(), config.cache_warm_rate);
+    }
 
-    println!("Listening on [{}] ...", addr);
+    println!("Listening on [{}] ...", config.address);
 
     for _ in 1..4 {
         let count = visitor_count.clone();
         let high = high_priority.clone();
-        let cache = lru_cache.clone();
+        let context = context.clone();
         thread::spawn(move || {
             loop {
                 let mut queue = high.lock().unwrap();
                 if let Some(request) = queue.pop().map(|s| s.request) {
-                    handle_incoming(&cache, request, count.load(Ordering::Relaxed));
+                    handle_incoming(&context, request, count.load(Ordering::Relaxed));
                 } else {
                     thread::yield_now();
                 }
@@ -65,12 +159,12 @@ fn main() {
     for _ in 1..2 {
         let count = visitor_count.clone();
         let low = low_priority.clone();
-        let cache = lru_cache.clone();
+        let context = context.clone();
         thread::spawn(move || {
             loop {
                 let mut queue = low.lock().unwrap();
                 if let Some(request) = queue.pop().map(|s| s.request) {
-                    handle_incoming(&cache, request, count.load(Ordering::Relaxed));
+                    handle_incoming(&context, request, count.load(Ordering::Relaxed));
                 } else {
                     thread::yield_now();
                 }
@@ -82,12 +176,17 @@ fn main() {
         match stream {
             Err(_) => (),
             Ok(stream) => {
-                safe_increment(&visitor_count);
-
-                let mut high_queue = high_priority.lock().unwrap();
-                let mut low_queue = low_priority.lock().unwrap();
                 let request = build_request(stream);
-                schedule(&lru_cache, request, &mut high_queue, &mut low_queue)
+                match rate_limit(&mut limiter, &request) {
+                    Err(wait) => reject(request, wait),
+                    Ok(_) => {
+                        safe_increment(&visitor_count);
+
+                        let mut high_queue = high_priority.lock().unwrap();
+                        let mut low_queue = low_priority.lock().unwrap();
+                        schedule(&context.cache, &context.root, request, &mut high_queue, &mut low_queue)
+                    }
+                }
             }
         }
     }
@@ -95,17 +194,63 @@ fn main() {
     drop(listener);
 }
 
-fn handle_incoming(cache: &Cache, mut request: Request, visitor_count: usize) {
+fn handle_incoming(context: &Context, mut request: Request, visitor_count: usize) {
     match request.ip_address() {
         Err(_) => (),
         Ok(pn) => println!("Received connection from: [{}]", pn),
     }
+    let streams = match request.path {
+        Ok(ref path) => context.routes.streams_body(&path.to_string(), &request.info.method),
+        Err(_) => false
+    };
+    let body = if streams { request.stream_body() } else { request.read_body(); None };
 
-    let status = handle_request(cache, request.path, visitor_count, &mut request.stream);
+    let status = handle_request(context, request.path, &request.info, body, visitor_count, &mut request.stream);
     println!("Response Status: {}", status);
     println!("Connection terminates.");
 }
 
+fn rate_limit(limiter: &mut RateLimiter, request: &Request) -> Result<(), Duration> {
+    match request.ip_address() {
+        Err(_) => Ok(()),
+        Ok(address) => limiter.check(&address, Instant::now())
+    }
+}
+
+fn reject(mut request: Request, wait: Duration) {
+    let retry_after = retry_after_secs(wait);
+    let header = header_with(&Status::TooManyRequests, &[("Retry-After", retry_after.to_string())]);
+    let body = format!("<doctype !html><html><body><h1>{}</h1>\r\n\
+                        <p>Please try again in {} seconds.</p></body></html>\r\n",
+                       Status::TooManyRequests, retry_after);
+    let _ = request.stream.write_all(&header)
+        .and_then(|_| request.stream.write_all(body.as_bytes()));
+    println!("Response Status: {}", Status::TooManyRequests);
+}
+
+extern "C" fn request_shutdown(_: libc::c_int) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+// Writes the most requested files to `manifest` when the server is interrupted
+// or terminated, so the next start can warm the cache with them.
+fn save_manifest_on_shutdown(popularity: Popularity, manifest: PathBuf, size: usize) {
+    unsafe {
+        libc::signal(libc::SIGINT, request_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, request_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+    thread::spawn(move || {
+        while !SHUTDOWN.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(100));
+        }
+        match popularity.write_manifest(&manifest, size) {
+            Ok(_) => println!("Saved popular files to [{}]", manifest.display()),
+            Err(e) => println!("Could not save [{}]: {}", manifest.display(), e)
+        }
+        exit(0);
+    });
+}
+
 fn safe_increment(visitor_count: &Arc<AtomicUsize>) {
     let current = visitor_count.load(Ordering::Relaxed);
     let changed = visitor_count.compare_and_swap(current, current + 1, Ordering::Relaxed);
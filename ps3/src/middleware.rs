@@ -0,0 +1,136 @@
+use std::net::SocketAddr;
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, Instant };
+use http::{ Payload, Status };
+use handler::Context;
+use rate_limit::{ LimitRule, RateLimiter, RuleTarget, retry_after_secs };
+use router::{ Call, Middleware, Next };
+
+// Middleware as configured for a route.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Layer {
+    // HTTP Basic authentication against a list of users and their passwords.
+    Auth { realm: String, users: Vec<(String, String)> },
+    // Header fields added to every response, errors included.
+    Headers(Vec<(String, String)>),
+    // A token bucket per client for this route alone.
+    Limit { burst: f64, rate: f64 }
+}
+
+impl Layer {
+    pub fn build(&self) -> Arc<dyn Middleware> {
+        match self {
+            &Layer::Auth { ref realm, ref users } => Arc::new(BasicAuth { realm: realm.clone(), users: users.clone() }),
+            &Layer::Headers(ref fields) => Arc::new(AddHeaders(fields.clone())),
+            &Layer::Limit { burst, rate } => {
+                let rule = LimitRule::new("route", RuleTarget::Any, burst, rate);
+                Arc::new(RouteLimit(Mutex::new(RateLimiter::new(vec![rule], Duration::from_secs(600)))))
+            }
+        }
+    }
+}
+
+struct BasicAuth {
+    realm: String,
+    users: Vec<(String, String)>
+}
+
+impl BasicAuth {
+    fn admits(&self, authorization: &str) -> bool {
+        let mut parts = authorization.trim().splitn(2, ' ');
+        let scheme = parts.next().unwrap_or("");
+        let credentials = parts.next().and_then(|encoded| base64_decode(encoded.trim())).and_then(|bytes| String::from_utf8(bytes).ok());
+        match credentials {
+            Some(ref credentials) if scheme.eq_ignore_ascii_case("Basic") => {
+                let mut parts = credentials.splitn(2, ':');
+                let (user, password) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+                self.users.iter().any(|&(ref name, ref secret)| name == user && secret == password)
+            }
+            _ => false
+        }
+    }
+}
+
+impl Middleware for BasicAuth {
+    fn apply(&self, context: &Context, call: &mut Call, next: Next) -> Result<Payload, Status> {
+        if call.info.header("Authorization").is_some_and(|value| self.admits(value)) {
+            return next.run(context, call);
+        }
+        call.fields.push(("WWW-Authenticate".to_string(), format!("Basic realm=\"{}\"", self.realm.replace('"', "'"))));
+        Err(Status::NotAuthorized)
+    }
+}
+
+struct AddHeaders(Vec<(String, String)>);
+
+impl Middleware for AddHeaders {
+    fn apply(&self, context: &Context, call: &mut Call, next: Next) -> Result<Payload, Status> {
+        call.fields.extend(self.0.iter().cloned());
+        next.run(context, call)
+    }
+}
+
+struct RouteLimit(Mutex<RateLimiter>);
+
+impl Middleware for RouteLimit {
+    fn apply(&self, context: &Context, call: &mut Call, next: Next) -> Result<Payload, Status> {
+        let checked = match call.info.remote_addr {
+            Some(ip) => self.0.lock().unwrap().check(&SocketAddr::new(ip, 0), Instant::now()),
+            None => Ok(())
+        };
+        match checked {
+            Ok(_) => next.run(context, call),
+            Err(wait) => {
+                call.fields.push(("Retry-After".to_string(), retry_after_secs(wait).to_string()));
+                Err(Status::TooManyRequests)
+            }
+        }
+    }
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in text.trim_end_matches('=').bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None
+        };
+        buffer = (buffer << 6) | u32::from(value);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ base64_decode, BasicAuth };
+
+    #[test]
+    fn decodes_base64() {
+        assert_eq!(base64_decode("YWxpY2U6c2VjcmV0"), Some(b"alice:secret".to_vec()));
+        assert_eq!(base64_decode("Ym9iOnB3"), Some(b"bob:pw".to_vec()));
+        assert_eq!(base64_decode("YQ=="), Some(b"a".to_vec()));
+        assert_eq!(base64_decode("not base64!"), None);
+    }
+
+    #[test]
+    fn admits_only_listed_users() {
+        let auth = BasicAuth { realm: "admin".to_string(), users: vec![("alice".to_string(), "secret".to_string())] };
+
+        assert!(auth.admits("Basic YWxpY2U6c2VjcmV0"));
+        assert!(auth.admits("basic  YWxpY2U6c2VjcmV0 "));
+        assert!(!auth.admits("Basic Ym9iOnB3"));
+        assert!(!auth.admits("Bearer YWxpY2U6c2VjcmV0"));
+        assert!(!auth.admits("Basic"));
+    }
+}
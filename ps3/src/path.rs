@@ -2,12 +2,13 @@ use regex::Regex;
 use std::fmt;
 
 lazy_static! {
-    static ref PATH_REGEX: Regex = Regex::new(r"GET /(\S*)\s").unwrap();
+    static ref PATH_REGEX: Regex = Regex::new(r"^[A-Z]+ /(\S*)\s").unwrap();
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Path {
     Root,
+    Status,
     RelPath(String)
 }
 
@@ -15,15 +16,132 @@ impl fmt::Display for Path {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             &Path::Root => write!(f, "/"),
+            &Path::Status => write!(f, "/server-status"),
             &Path::RelPath(ref path) => write!(f, "/{}", path)
         }
     }
 }
 
-pub fn path(body: &str) -> Path {
-    match extract_request_path(body) {
-        "" => Path::Root,
-        request_path => Path::RelPath(request_path.to_string())
+// The path a request is for, percent-decoded and without dot segments.
+pub fn path(body: &str) -> Result<Path, String> {
+    decode_path(split_query(extract_request_path(body)).0).map(|decoded| {
+        match &decoded[..] {
+            "" => Path::Root,
+            "server-status" => Path::Status,
+            request_path => Path::RelPath(request_path.to_string())
+        }
+    })
+}
+
+// Everything after the first `?` in the request target, as sent.
+pub fn query(body: &str) -> String {
+    split_query(extract_request_path(body)).1.to_string()
+}
+
+// The name-value pairs of a query string, decoded as a form would encode
+// them. What cannot be decoded is kept as sent.
+pub fn query_params(query: &str) -> Vec<(String, String)> {
+    query.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            let name = decode_component(parts.next().unwrap_or(""));
+            (name, decode_component(parts.next().unwrap_or("")))
+        })
+        .collect()
+}
+
+// Decodes each segment of a path relative to `/` and then resolves `.` and
+// `..` segments, so that encoded dots are resolved too and `..` stops at the
+// root. Bad escapes, encoded slashes and NULs, which no file name holds, and
+// paths that are not UTF-8 are refused.
+pub fn decode_path(raw: &str) -> Result<String, String> {
+    let raw_segments = raw.split('/').collect::<Vec<&str>>();
+    let last = raw_segments.len() - 1;
+    let mut segments: Vec<String> = vec![];
+    for (idx, raw_segment) in raw_segments.into_iter().enumerate() {
+        let bytes = percent_decode(raw_segment, false)?;
+        if bytes.contains(&b'/') {
+            return Err(format!("/{} has an encoded slash", raw));
+        }
+        if bytes.contains(&0) {
+            return Err(format!("/{} has an encoded NUL", raw));
+        }
+        let segment = String::from_utf8(bytes).map_err(|_| format!("/{} is not UTF-8", raw))?;
+        match &segment[..] {
+            "." | ".." => {
+                if segment == ".." {
+                    segments.pop();
+                }
+                // A path ending in a dot segment names a directory.
+                if idx == last {
+                    segments.push(String::new());
+                }
+            }
+            _ => segments.push(segment)
+        }
+    }
+    Ok(segments.join("/"))
+}
+
+// Percent-encodes a decoded path for use in a header or link, leaving its
+// slashes alone.
+pub fn encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for &byte in path.as_bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => encoded.push(byte as char),
+            byte => encoded.push_str(&format!("%{:02X}", byte))
+        }
+    }
+    encoded
+}
+
+fn decode_component(text: &str) -> String {
+    percent_decode(text, true)
+        .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+        .unwrap_or_else(|_| text.to_string())
+}
+
+fn percent_decode(text: &str, plus_as_space: bool) -> Result<Vec<u8>, String> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        match bytes[idx] {
+            b'%' => {
+                match (bytes.get(idx + 1).and_then(hex_value), bytes.get(idx + 2).and_then(hex_value)) {
+                    (Some(high), Some(low)) => decoded.push(high << 4 | low),
+                    _ => return Err(format!("{} has a bad percent-escape", text))
+                }
+                idx += 3;
+            }
+            b'+' if plus_as_space => {
+                decoded.push(b' ');
+                idx += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                idx += 1;
+            }
+        }
+    }
+    Ok(decoded)
+}
+
+fn hex_value(digit: &u8) -> Option<u8> {
+    match *digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None
+    }
+}
+
+fn split_query(target: &str) -> (&str, &str) {
+    match target.find('?') {
+        Some(idx) => (&target[..idx], &target[idx + 1..]),
+        None => (target, "")
     }
 }
 
@@ -38,7 +156,7 @@ fn extract_request_path(body: &str) -> &str {
 
 #[cfg(test)]
 mod test {
-    use super::{Path, path};
+    use super::{Path, path, query, query_params, decode_path, encode_path};
 
     #[test]
     fn returns_root_for_empty_path() {
@@ -56,7 +174,7 @@ mod test {
 
         let request_path = path(&request);
 
-        assert_eq!(request_path, Path::Root);
+        assert_eq!(request_path, Ok(Path::Root));
     }
 
     #[test]
@@ -75,6 +193,73 @@ mod test {
 
         let request_path = path(&request);
 
-        assert_eq!(request_path, Path::RelPath("index.html".to_string()));
+        assert_eq!(request_path, Ok(Path::RelPath("index.html".to_string())));
+    }
+
+    #[test]
+    fn returns_status_for_server_status_path() {
+        let request = "GET /server-status HTTP/1.1\r\nHost: localhost:4414\r\n\r\n";
+
+        assert_eq!(path(request), Ok(Path::Status));
+    }
+
+    #[test]
+    fn separates_query_string_from_path() {
+        let request = "GET /search.shtml?q=rust&page=2 HTTP/1.1\r\nHost: localhost:4414\r\n\r\n";
+
+        assert_eq!(path(request), Ok(Path::RelPath("search.shtml".to_string())));
+        assert_eq!(query(request), "q=rust&page=2");
+        assert_eq!(query("GET /?debug HTTP/1.1\r\n"), "debug");
+        assert_eq!(path("GET /?debug HTTP/1.1\r\n"), Ok(Path::Root));
+        assert_eq!(query("GET /index.html HTTP/1.1\r\n"), "");
+    }
+
+    #[test]
+    fn decodes_percent_escapes_in_path() {
+        assert_eq!(path("GET /my%20page.html?x=1 HTTP/1.1\r\n"), Ok(Path::RelPath("my page.html".to_string())));
+        assert_eq!(decode_path("caf%C3%A9/men%c3%BC.html"), Ok("café/menü.html".to_string()));
+        assert_eq!(decode_path("a+b.html"), Ok("a+b.html".to_string()));
+        assert!(decode_path("bad%2.html").is_err());
+        assert!(decode_path("bad%+1.html").is_err());
+        assert!(decode_path("trail%").is_err());
+        assert!(decode_path("latin%E9.html").is_err());
+        assert!(decode_path("nul%00.html").is_err());
+    }
+
+    #[test]
+    fn refuses_encoded_slashes() {
+        assert!(decode_path("a%2Fb.html").is_err());
+        assert!(decode_path("..%2f..%2fetc/passwd").is_err());
+        assert_eq!(decode_path("a%252Fb.html"), Ok("a%2Fb.html".to_string()));
+    }
+
+    #[test]
+    fn removes_dot_segments_without_climbing_past_root() {
+        assert_eq!(decode_path("a/./b/../c.html"), Ok("a/c.html".to_string()));
+        assert_eq!(decode_path("../../etc/passwd"), Ok("etc/passwd".to_string()));
+        assert_eq!(decode_path("%2e%2e/%2E%2E/etc/passwd"), Ok("etc/passwd".to_string()));
+        assert_eq!(decode_path("docs/"), Ok("docs/".to_string()));
+        assert_eq!(decode_path("docs/."), Ok("docs/".to_string()));
+        assert_eq!(decode_path("docs/sub/.."), Ok("docs/".to_string()));
+        assert_eq!(decode_path("docs/.."), Ok("".to_string()));
+        assert_eq!(decode_path("..."), Ok("...".to_string()));
+        assert_eq!(path("GET /docs/../server-status HTTP/1.1\r\n"), Ok(Path::Status));
+    }
+
+    #[test]
+    fn decodes_query_parameters() {
+        assert_eq!(query_params("q=rust+lang&page=2&&flag&sym=%26%3D&bad=%zz"), vec![
+            ("q".to_string(), "rust lang".to_string()),
+            ("page".to_string(), "2".to_string()),
+            ("flag".to_string(), "".to_string()),
+            ("sym".to_string(), "&=".to_string()),
+            ("bad".to_string(), "%zz".to_string())
+        ]);
+        assert_eq!(query_params(""), vec![]);
+    }
+
+    #[test]
+    fn encodes_paths_for_links() {
+        assert_eq!(encode_path("/my docs/café.html"), "/my%20docs/caf%C3%A9.html");
     }
 }
@@ -0,0 +1,117 @@
+use std::io::{ self, BufRead, BufReader, Read };
+use std::net::TcpStream;
+use std::sync::Arc;
+use super::Pool;
+
+// How the end of a response body is found (RFC 7230 3.3.3).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Framing {
+    Empty,
+    Length(u64),
+    // Left of the current chunk, and whether a chunk has been read yet.
+    Chunked { remaining: u64, started: bool },
+    // Whatever comes before the upstream closes the connection.
+    Close
+}
+
+// A response body read from an upstream connection as the client is sent
+// it. A connection that is read to the end of its body goes back to the
+// pool if it may be reused; one dropped before that is closed.
+pub struct Body {
+    reader: Option<BufReader<TcpStream>>,
+    framing: Framing,
+    pool: Option<Arc<Pool>>
+}
+
+impl Body {
+    pub fn new(reader: BufReader<TcpStream>, framing: Framing, pool: Option<Arc<Pool>>) -> Body {
+        let mut body = Body { reader: Some(reader), framing: framing, pool: pool };
+        if framing == Framing::Empty || framing == Framing::Length(0) {
+            body.finish();
+        }
+        body
+    }
+
+    fn finish(&mut self) {
+        if let (Some(reader), Some(pool)) = (self.reader.take(), self.pool.take()) {
+            // Bytes past the end of the body would be read as the next response.
+            if reader.buffer().is_empty() {
+                pool.put(reader.into_inner());
+            }
+        }
+    }
+
+    // Reads the size line of the next chunk, and the trailer after the last.
+    fn next_chunk(reader: &mut BufReader<TcpStream>, started: bool) -> io::Result<u64> {
+        let mut line = String::new();
+        if started {
+            reader.read_line(&mut line)?;
+            if line.trim_end() != "" {
+                return Err(invalid("chunk is longer than its size"));
+            }
+            line.clear();
+        }
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "upstream closed mid-body"));
+        }
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = u64::from_str_radix(size, 16).map_err(|_| invalid("bad chunk size"))?;
+        if size == 0 {
+            loop {
+                line.clear();
+                if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+                    break;
+                }
+            }
+        }
+        Ok(size)
+    }
+}
+
+impl Read for Body {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = {
+            let reader = match self.reader.as_mut() {
+                Some(reader) => reader,
+                None => return Ok(0)
+            };
+            match self.framing {
+                Framing::Empty => 0,
+                Framing::Length(remaining) => {
+                    let limit = (buf.len() as u64).min(remaining) as usize;
+                    let read = reader.read(&mut buf[..limit])?;
+                    if read == 0 && limit > 0 {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "upstream closed mid-body"));
+                    }
+                    self.framing = Framing::Length(remaining - read as u64);
+                    read
+                }
+                Framing::Chunked { remaining, started } => {
+                    let remaining = if remaining == 0 { Body::next_chunk(reader, started)? } else { remaining };
+                    if remaining == 0 {
+                        self.framing = Framing::Empty;
+                        0
+                    } else {
+                        let limit = (buf.len() as u64).min(remaining) as usize;
+                        let read = reader.read(&mut buf[..limit])?;
+                        if read == 0 && limit > 0 {
+                            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "upstream closed mid-chunk"));
+                        }
+                        self.framing = Framing::Chunked { remaining: remaining - read as u64, started: true };
+                        read
+                    }
+                }
+                Framing::Close => reader.read(buf)?
+            }
+        };
+        match self.framing {
+            Framing::Empty | Framing::Length(0) => self.finish(),
+            _ => ()
+        }
+        Ok(read)
+    }
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
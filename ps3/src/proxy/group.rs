@@ -0,0 +1,502 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{ Hash, Hasher };
+use std::io::{ self, Read };
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::thread;
+use std::time::{ Duration, Instant };
+use http::Relay;
+use request::{ BodyStream, RequestInfo };
+use super::{ ProxyError, Upstream };
+
+// Points each server has on the hash ring. More points spread keys more
+// evenly, and spread a removed server's keys over more of the others.
+const RING_POINTS: usize = 64;
+
+// What a consistent hash is taken of.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HashKey {
+    Client,
+    Uri
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Balance {
+    RoundRobin,
+    // The server with the fewest requests in flight.
+    LeastConnections,
+    // The same server for the same key, for as long as it is available.
+    Hash(HashKey)
+}
+
+impl Balance {
+    pub fn parse(words: &[&str]) -> Result<Balance, String> {
+        match words {
+            &["round-robin"] => Ok(Balance::RoundRobin),
+            &["least-connections"] => Ok(Balance::LeastConnections),
+            &["hash"] | &["hash", "client"] => Ok(Balance::Hash(HashKey::Client)),
+            &["hash", "uri"] => Ok(Balance::Hash(HashKey::Uri)),
+            _ => Err(format!("balancing must be round-robin, least-connections or hash [client|uri], not {}", words.join(" ")))
+        }
+    }
+}
+
+// An upstream group as configured.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupSpec {
+    pub name: String,
+    // The address of each server and the idle connections kept to it.
+    pub servers: Vec<(String, usize)>,
+    pub balance: Balance,
+    // The path probed on each server, and how often.
+    pub health_check: Option<(String, Duration)>,
+    // Consecutive errors that take a server out of rotation, or 0 for none,
+    // and for how long.
+    pub max_failures: u32,
+    pub cooldown: Duration
+}
+
+impl GroupSpec {
+    pub fn new(name: &str) -> GroupSpec {
+        GroupSpec {
+            name: name.to_string(),
+            servers: vec![],
+            balance: Balance::RoundRobin,
+            health_check: None,
+            max_failures: 3,
+            cooldown: Duration::from_secs(30)
+        }
+    }
+}
+
+struct Health {
+    failures: u32,
+    ejected_until: Option<Instant>,
+    failing_check: bool
+}
+
+struct Server {
+    upstream: Upstream,
+    active: AtomicUsize,
+    health: Mutex<Health>
+}
+
+impl Server {
+    fn available(&self, now: Instant) -> bool {
+        let health = self.health.lock().unwrap();
+        !health.failing_check && health.ejected_until.is_none_or(|until| now >= until)
+    }
+
+    fn succeeded(&self) {
+        let mut health = self.health.lock().unwrap();
+        health.failures = 0;
+        health.ejected_until = None;
+    }
+
+    // Counts an error, ejecting the server at `max_failures` in a row. It
+    // comes back after `cooldown` with a clean slate.
+    fn failed(&self, max_failures: u32, cooldown: Duration, now: Instant) {
+        let mut health = self.health.lock().unwrap();
+        health.failures += 1;
+        if max_failures > 0 && health.failures >= max_failures {
+            println!("Ejecting {} server {} for {}s after {} errors",
+                     self.upstream.name, self.upstream.address, cooldown.as_secs(), health.failures);
+            health.failures = 0;
+            health.ejected_until = Some(now + cooldown);
+        }
+    }
+
+    // A server is healthy if the path answers with a success or a redirect.
+    fn probe(&self, path: &str) -> bool {
+        let info = RequestInfo { method: "GET".to_string(), uri: path.to_string(), ..RequestInfo::default() };
+        match self.upstream.forward(&info, None) {
+            Ok(mut relay) => io::copy(&mut relay.body, &mut io::sink()).is_ok() && relay.status.code() < 400,
+            Err(_) => false
+        }
+    }
+}
+
+// A relayed body, counted against its server until it has been sent.
+struct InFlight {
+    body: Box<dyn Read + Send>,
+    server: Arc<Server>
+}
+
+impl Read for InFlight {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.body.read(buf)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.server.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// How a server is doing, for the status page.
+pub struct Report {
+    pub address: String,
+    pub state: String,
+    pub active: usize,
+    pub failures: u32
+}
+
+// Servers that answer the same requests, one chosen for each request.
+pub struct Group {
+    pub name: String,
+    servers: Vec<Arc<Server>>,
+    balance: Balance,
+    // Points on the hash ring, in order, and the servers they belong to.
+    ring: Vec<(u64, usize)>,
+    next: AtomicUsize,
+    health_check: Option<(String, Duration)>,
+    max_failures: u32,
+    cooldown: Duration
+}
+
+impl Group {
+    pub fn new(spec: &GroupSpec, timeout: Duration) -> Group {
+        let servers = spec.servers.iter()
+            .map(|&(ref address, max_idle)| {
+                Arc::new(Server {
+                    upstream: Upstream::new(&spec.name, address, max_idle, timeout),
+                    active: AtomicUsize::new(0),
+                    health: Mutex::new(Health { failures: 0, ejected_until: None, failing_check: false })
+                })
+            })
+            .collect::<Vec<Arc<Server>>>();
+        let mut ring = servers.iter().enumerate()
+            .flat_map(|(idx, server)| (0..RING_POINTS).map(move |point| (hash(&format!("{}#{}", server.upstream.address, point)), idx)))
+            .collect::<Vec<(u64, usize)>>();
+        ring.sort();
+        Group {
+            name: spec.name.clone(),
+            servers: servers,
+            balance: spec.balance,
+            ring: ring,
+            next: AtomicUsize::new(0),
+            health_check: spec.health_check.clone(),
+            max_failures: spec.max_failures,
+            cooldown: spec.cooldown
+        }
+    }
+
+    // Forwards the request to a server chosen by the group's balancing.
+    // A server that cannot be reached is passed over for the next one.
+    pub fn forward(&self, info: &RequestInfo, mut body: Option<BodyStream>) -> Result<Relay, ProxyError> {
+        let mut tried = vec![];
+        let mut error = ProxyError::NoneAvailable;
+        loop {
+            let now = Instant::now();
+            let idx = match self.choose(info, &tried, now) {
+                Some(idx) => idx,
+                None => return Err(error)
+            };
+            tried.push(idx);
+            let server = &self.servers[idx];
+            server.active.fetch_add(1, Ordering::SeqCst);
+            match server.upstream.forward(info, body.as_mut()) {
+                Ok(mut relay) => {
+                    // A server error is relayed as it is, but counts against
+                    // the server like any other failure.
+                    if relay.status.code() >= 500 {
+                        server.failed(self.max_failures, self.cooldown, now);
+                    } else {
+                        server.succeeded();
+                    }
+                    relay.body = Box::new(InFlight { body: relay.body, server: server.clone() });
+                    return Ok(relay);
+                }
+                Err(e) => {
+                    server.active.fetch_sub(1, Ordering::SeqCst);
+                    server.failed(self.max_failures, self.cooldown, now);
+                    // Only a server that could not be connected to never saw
+                    // the request, so only then is it safe to send it again.
+                    match e {
+                        ProxyError::Unreachable(_) => error = e,
+                        e => return Err(e)
+                    }
+                }
+            }
+        }
+    }
+
+    fn choose(&self, info: &RequestInfo, tried: &[usize], now: Instant) -> Option<usize> {
+        let candidate = |idx: &usize| !tried.contains(idx) && self.servers[*idx].available(now);
+        let count = self.servers.len();
+        if count == 0 {
+            return None;
+        }
+        match self.balance {
+            Balance::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (start..start + count).map(|idx| idx % count).find(|idx| candidate(idx))
+            }
+            Balance::LeastConnections => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (start..start + count).map(|idx| idx % count)
+                    .filter(|idx| candidate(idx))
+                    .min_by_key(|&idx| self.servers[idx].active.load(Ordering::SeqCst))
+            }
+            Balance::Hash(key) => {
+                let key = match key {
+                    HashKey::Client => info.remote_addr.map(|ip| ip.to_string()).unwrap_or_default(),
+                    HashKey::Uri => info.uri.clone()
+                };
+                let start = match self.ring.binary_search(&(hash(&key), 0)) {
+                    Ok(at) | Err(at) => at
+                };
+                (start..start + self.ring.len()).map(|at| self.ring[at % self.ring.len()].1).find(|idx| candidate(idx))
+            }
+        }
+    }
+
+    // Probes every server once. Those that fail are out of rotation until
+    // they pass again.
+    pub fn check(&self) {
+        let path = match self.health_check {
+            Some((ref path, _)) => path,
+            None => return
+        };
+        for server in &self.servers {
+            let failing = !server.probe(path);
+            let mut health = server.health.lock().unwrap();
+            if failing != health.failing_check {
+                println!("{} server {} {} its health check", self.name, server.upstream.address, if failing { "failed" } else { "passed" });
+            }
+            health.failing_check = failing;
+        }
+    }
+
+    pub fn report(&self, now: Instant) -> Vec<Report> {
+        self.servers.iter()
+            .map(|server| {
+                let health = server.health.lock().unwrap();
+                let state = match health.ejected_until {
+                    _ if health.failing_check => "failing health check".to_string(),
+                    Some(until) if until > now => format!("ejected for {}s", (until - now).as_secs() + 1),
+                    _ => "up".to_string()
+                };
+                Report {
+                    address: server.upstream.address.clone(),
+                    state: state,
+                    active: server.active.load(Ordering::SeqCst),
+                    failures: health.failures
+                }
+            })
+            .collect()
+    }
+}
+
+// Runs the group's health checks on a thread of its own, if it has any.
+pub fn watch(group: Arc<Group>) {
+    if let Some((_, interval)) = group.health_check.clone() {
+        thread::spawn(move || {
+            loop {
+                group.check();
+                thread::sleep(interval);
+            }
+        });
+    }
+}
+
+fn hash(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{ BufRead, BufReader, Read, Write };
+    use std::net::{ IpAddr, Ipv4Addr, TcpListener };
+    use std::thread;
+    use std::time::{ Duration, Instant };
+    use request::RequestInfo;
+    use super::{ Balance, Group, GroupSpec, HashKey };
+    use super::super::ProxyError;
+
+    // A server that answers every request with its name, and its health
+    // check path with `health`.
+    fn server(name: &'static str, health: &'static str) -> String {
+        serve(name, "200 OK", health)
+    }
+
+    fn serve(name: &'static str, status: &'static str, health: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    loop {
+                        let mut request_line = String::new();
+                        if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+                            return;
+                        }
+                        let mut line = String::new();
+                        while reader.read_line(&mut line).unwrap_or(0) > 0 && line != "\r\n" {
+                            line.clear();
+                        }
+                        let response = if request_line.starts_with("GET /health ") {
+                            format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", health)
+                        } else {
+                            format!("HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n{}", status, name.len(), name)
+                        };
+                        if stream.write_all(response.as_bytes()).is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        address
+    }
+
+    fn group(servers: Vec<String>, balance: Balance) -> Group {
+        let mut spec = GroupSpec::new("app");
+        spec.servers = servers.into_iter().map(|address| (address, 4)).collect();
+        spec.balance = balance;
+        spec.health_check = Some(("/health".to_string(), Duration::from_secs(1)));
+        spec.cooldown = Duration::from_millis(300);
+        Group::new(&spec, Duration::from_secs(1))
+    }
+
+    fn request(uri: &str, client: u8) -> RequestInfo {
+        RequestInfo {
+            method: "GET".to_string(),
+            uri: uri.to_string(),
+            remote_addr: Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, client))),
+            ..RequestInfo::default()
+        }
+    }
+
+    fn answer(group: &Group, info: &RequestInfo) -> Result<String, ProxyError> {
+        group.forward(info, None).map(|mut relay| {
+            let mut body = String::new();
+            relay.body.read_to_string(&mut body).unwrap();
+            body
+        })
+    }
+
+    fn states(group: &Group) -> Vec<String> {
+        group.report(Instant::now()).into_iter().map(|server| server.state).collect()
+    }
+
+    #[test]
+    fn takes_turns_round_robin() {
+        let group = group(vec![server("a", "200 OK"), server("b", "200 OK"), server("c", "200 OK")], Balance::RoundRobin);
+        let answers = (0..6).map(|_| answer(&group, &request("/", 1)).unwrap()).collect::<Vec<String>>();
+
+        assert_eq!(answers, vec!["a", "b", "c", "a", "b", "c"]);
+    }
+
+    #[test]
+    fn prefers_servers_with_fewer_requests_in_flight() {
+        let group = group(vec![server("a", "200 OK"), server("b", "200 OK")], Balance::LeastConnections);
+        let held = group.forward(&request("/", 1), None).unwrap();
+        assert_eq!(group.report(Instant::now()).iter().map(|server| server.active).collect::<Vec<usize>>(), vec![1, 0]);
+
+        for _ in 0..3 {
+            assert_eq!(answer(&group, &request("/", 1)).unwrap(), "b");
+        }
+        drop(held);
+        assert_eq!(group.report(Instant::now()).iter().map(|server| server.active).sum::<usize>(), 0);
+    }
+
+    #[test]
+    fn hashes_keys_to_the_same_server_while_it_is_available() {
+        let servers = vec![server("a", "200 OK"), server("b", "200 OK"), server("c", "200 OK"), server("d", "200 OK")];
+        let by_client = group(servers, Balance::Hash(HashKey::Client));
+        let chosen = (0..40u8).map(|client| answer(&by_client, &request("/", client)).unwrap()).collect::<Vec<String>>();
+
+        for client in 0..40u8 {
+            assert_eq!(answer(&by_client, &request("/other", client)).unwrap(), chosen[client as usize]);
+        }
+        for name in &["a", "b", "c", "d"] {
+            assert!(chosen.iter().any(|answer| answer == name), "no key went to {}", name);
+        }
+
+        let by_uri = group(vec![server("a", "200 OK"), server("b", "200 OK")], Balance::Hash(HashKey::Uri));
+        assert_eq!(answer(&by_uri, &request("/page", 1)).unwrap(), answer(&by_uri, &request("/page", 2)).unwrap());
+    }
+
+    #[test]
+    fn ejects_failing_servers_until_their_cool_down_ends() {
+        let group = group(vec!["127.0.0.1:1".to_string(), server("b", "200 OK")], Balance::RoundRobin);
+
+        // Unreachable servers are passed over for the next.
+        for _ in 0..3 {
+            assert_eq!(answer(&group, &request("/", 1)).unwrap(), "b");
+        }
+        assert!(states(&group)[0].starts_with("ejected for"));
+        assert_eq!(group.report(Instant::now())[0].failures, 0);
+        for _ in 0..4 {
+            assert_eq!(answer(&group, &request("/", 1)).unwrap(), "b");
+        }
+        assert!(states(&group)[0].starts_with("ejected for"));
+
+        thread::sleep(Duration::from_millis(350));
+        assert_eq!(states(&group), vec!["up", "up"]);
+        assert_eq!(answer(&group, &request("/", 1)).unwrap(), "b");
+        assert_eq!(group.report(Instant::now())[0].failures, 1);
+
+        let alone = super::super::Upstreams::new(&[GroupSpec { servers: vec![("127.0.0.1:1".to_string(), 1)], ..GroupSpec::new("down") }],
+                                                  Duration::from_secs(1));
+        let down = alone.group("down").unwrap();
+        match down.forward(&request("/", 1), None) {
+            Err(ProxyError::Unreachable(_)) => (),
+            _ => panic!("expected the only server to be unreachable")
+        }
+        for _ in 0..2 {
+            let _ = down.forward(&request("/", 1), None);
+        }
+        assert_eq!(down.forward(&request("/", 1), None).err(), Some(ProxyError::NoneAvailable));
+    }
+
+    #[test]
+    fn ejects_servers_answering_with_server_errors() {
+        let group = group(vec![serve("a", "503 Service Unavailable", "200 OK"), server("b", "200 OK")], Balance::RoundRobin);
+        let statuses = (0..6).map(|_| group.forward(&request("/", 1), None).unwrap().status.code()).collect::<Vec<u16>>();
+
+        assert_eq!(statuses, vec![503, 200, 503, 200, 503, 200]);
+        assert!(states(&group)[0].starts_with("ejected for"));
+        assert_eq!(answer(&group, &request("/", 1)).unwrap(), "b");
+        assert_eq!(answer(&group, &request("/", 1)).unwrap(), "b");
+    }
+
+    #[test]
+    fn sends_requests_that_reached_a_server_only_once() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let garbled = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut head = [0; 1024];
+                let _ = stream.read(&mut head);
+                let _ = stream.write_all(b"HTTP/1.1 200 \xff\xfe\r\n\r\n");
+            }
+        });
+        let group = group(vec![garbled, server("b", "200 OK")], Balance::RoundRobin);
+        let posted = RequestInfo { method: "POST".to_string(), ..request("/", 1) };
+
+        match group.forward(&posted, None) {
+            Err(ProxyError::BadResponse(_)) => (),
+            other => panic!("expected a bad response, got {:?}", other.map(|relay| relay.status))
+        }
+        assert_eq!(answer(&group, &posted).unwrap(), "b");
+    }
+
+    #[test]
+    fn takes_servers_failing_health_checks_out_of_rotation() {
+        let group = group(vec![server("a", "503 Service Unavailable"), server("b", "204 No Content")], Balance::RoundRobin);
+        group.check();
+
+        assert_eq!(states(&group), vec!["failing health check", "up"]);
+        for _ in 0..3 {
+            assert_eq!(answer(&group, &request("/", 1)).unwrap(), "b");
+        }
+    }
+}
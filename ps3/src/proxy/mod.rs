@@ -0,0 +1,579 @@
+use std::fmt;
+use std::io::{ self, BufRead, BufReader, Write };
+use std::net::{ IpAddr, TcpStream, ToSocketAddrs };
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, Instant };
+use http::{ Relay, Status };
+use path::encode_path;
+use request::{ BodyStream, RequestInfo };
+
+mod body;
+mod group;
+
+use self::body::{ Body, Framing };
+use self::group::watch;
+pub use self::group::{ Balance, Group, GroupSpec };
+
+// Idle connections are closed after this long, before the upstream is
+// likely to have closed them itself.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+// Responses whose headers do not fit are refused.
+const MAX_HEAD: usize = 16 << 10;
+// Fields that describe one connection rather than the message, and so are
+// not forwarded in either direction (RFC 7230 6.1).
+const HOP_BY_HOP: [&'static str; 8] = [
+    "Connection", "Keep-Alive", "Proxy-Authenticate", "Proxy-Authorization", "TE", "Trailer", "Transfer-Encoding", "Upgrade"
+];
+
+#[derive(Debug, PartialEq)]
+pub enum ProxyError {
+    // The upstream could not be connected to, so it never saw the request.
+    Unreachable(String),
+    TimedOut,
+    // What came back was not an HTTP response.
+    BadResponse(String),
+    // Every server in the group is out of rotation.
+    NoneAvailable
+}
+
+impl ProxyError {
+    pub fn status(&self) -> Status {
+        match self {
+            &ProxyError::TimedOut => Status::Other(504, "Gateway Timeout".to_string()),
+            &ProxyError::NoneAvailable => Status::Other(503, "Service Unavailable".to_string()),
+            _ => Status::Other(502, "Bad Gateway".to_string())
+        }
+    }
+
+    // A failure reading the response, once the request has gone out.
+    fn from_io(error: io::Error) -> ProxyError {
+        match error.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => ProxyError::TimedOut,
+            _ => ProxyError::BadResponse(error.to_string())
+        }
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &ProxyError::Unreachable(ref reason) => write!(f, "unreachable: {}", reason),
+            &ProxyError::TimedOut => write!(f, "timed out"),
+            &ProxyError::BadResponse(ref reason) => write!(f, "bad response: {}", reason),
+            &ProxyError::NoneAvailable => write!(f, "no server available")
+        }
+    }
+}
+
+// Connections to an upstream left open between requests.
+pub struct Pool {
+    idle: Mutex<Vec<(TcpStream, Instant)>>,
+    max_idle: usize
+}
+
+impl Pool {
+    fn take(&self) -> Option<TcpStream> {
+        let mut idle = self.idle.lock().unwrap();
+        let now = Instant::now();
+        idle.retain(|&(_, since)| now.duration_since(since) < IDLE_TIMEOUT);
+        idle.pop().map(|(stream, _)| stream)
+    }
+
+    fn put(&self, stream: TcpStream) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.max_idle {
+            idle.push((stream, Instant::now()));
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+}
+
+// Why an exchange on a connection failed. A pooled connection the upstream
+// had already closed fails before any of the response is read, and the
+// request can be sent again on a new one.
+enum Failure {
+    Stale,
+    Failed(ProxyError)
+}
+
+// An HTTP/1.1 server requests are forwarded to, and the pool of connections
+// to it. Each connection carries one request at a time.
+pub struct Upstream {
+    // The group it belongs to.
+    pub name: String,
+    pub address: String,
+    timeout: Duration,
+    pool: Arc<Pool>
+}
+
+impl Upstream {
+    pub fn new(name: &str, address: &str, max_idle: usize, timeout: Duration) -> Upstream {
+        Upstream {
+            name: name.to_string(),
+            address: address.to_string(),
+            timeout: timeout,
+            pool: Arc::new(Pool { idle: Mutex::new(vec![]), max_idle: max_idle })
+        }
+    }
+
+    // Sends the request on and returns the response as soon as its head has
+    // arrived; its body is read as it is relayed. The request body is sent
+    // from `body` as it arrives if the client is still sending it, and from
+    // `info` otherwise. A pooled connection found closed is only replaced for
+    // idempotent requests: the upstream may have acted on a request it never
+    // answered, so the others always go out on a new connection and are never
+    // sent twice. So do requests streaming a body, which cannot be sent again.
+    pub fn forward(&self, info: &RequestInfo, mut body: Option<&mut BodyStream>) -> Result<Relay, ProxyError> {
+        let length = body.as_ref().map_or(info.body.len() as u64, |body| body.length);
+        let head = request_head(info, &self.address, length);
+        let resendable = body.as_ref().is_none_or(|body| body.length == 0);
+        let pooled = if is_idempotent(&info.method) && resendable { self.pool.take() } else { None };
+        if let Some(stream) = pooled {
+            match self.exchange(stream, &head, info, body.as_deref_mut()) {
+                Ok(relay) => return Ok(relay),
+                Err(Failure::Failed(e)) => return Err(e),
+                Err(Failure::Stale) => ()
+            }
+        }
+        match self.exchange(self.connect()?, &head, info, body) {
+            Ok(relay) => Ok(relay),
+            Err(Failure::Failed(e)) => Err(e),
+            Err(Failure::Stale) => Err(ProxyError::BadResponse("connection closed without a response".to_string()))
+        }
+    }
+
+    fn connect(&self) -> Result<TcpStream, ProxyError> {
+        let unreachable = |e: io::Error| ProxyError::Unreachable(e.to_string());
+        let address = self.address.to_socket_addrs().map_err(unreachable)?.next()
+            .ok_or(ProxyError::Unreachable(format!("{} did not resolve", self.address)))?;
+        let stream = TcpStream::connect_timeout(&address, self.timeout).map_err(unreachable)?;
+        stream.set_read_timeout(Some(self.timeout))
+            .and_then(|_| stream.set_write_timeout(Some(self.timeout)))
+            .and_then(|_| stream.set_nodelay(true))
+            .map_err(unreachable)?;
+        Ok(stream)
+    }
+
+    // The body is always sent with its length; bodies over the request limit
+    // never get this far. One that ends early leaves the request unfinished.
+    fn exchange(&self, mut stream: TcpStream, head: &[u8], info: &RequestInfo, body: Option<&mut BodyStream>) -> Result<Relay, Failure> {
+        let sent = stream.write_all(head).and_then(|_| match body {
+            Some(body) => io::copy(&mut body.reader, &mut stream).and_then(|copied| {
+                if copied < body.length {
+                    Err(io::Error::new(io::ErrorKind::UnexpectedEof, "request body ended early"))
+                } else {
+                    Ok(())
+                }
+            }),
+            None => stream.write_all(&info.body)
+        });
+        if let Err(e) = sent {
+            return Err(match e.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Failure::Failed(ProxyError::TimedOut),
+                _ => Failure::Stale
+            });
+        }
+        let mut reader = BufReader::new(stream);
+        let (version, status, fields) = loop {
+            let (version, status, fields) = read_head(&mut reader)?;
+            // Interim responses such as 100 Continue are not relayed.
+            if status.0 >= 200 {
+                break (version, status, fields);
+            }
+        };
+        let framing = framing(&info.method, status.0, &fields);
+        let reusable = framing != Framing::Close && keeps_alive(version, &fields);
+        let chunked = matches!(framing, Framing::Chunked { .. } | Framing::Close);
+        let mut fields = without_hop_by_hop(fields);
+        if chunked {
+            fields.retain(|&(ref name, _)| !name.eq_ignore_ascii_case("Content-Length"));
+        }
+        let content_type = fields.iter()
+            .position(|&(ref name, _)| name.eq_ignore_ascii_case("Content-Type"))
+            .map(|at| fields.remove(at).1)
+            .unwrap_or_else(|| "application/octet-stream".to_string());
+        let pool = if reusable { Some(self.pool.clone()) } else { None };
+        Ok(Relay {
+            status: Status::from_code(status.0, &status.1),
+            content_type: content_type,
+            fields: fields,
+            chunked: chunked,
+            body: Box::new(Body::new(reader, framing, pool))
+        })
+    }
+}
+
+// The configured upstream groups, by name.
+#[derive(Clone, Default)]
+pub struct Upstreams {
+    groups: Vec<Arc<Group>>
+}
+
+impl Upstreams {
+    pub fn new(specs: &[GroupSpec], timeout: Duration) -> Upstreams {
+        Upstreams { groups: specs.iter().map(|spec| Arc::new(Group::new(spec, timeout))).collect() }
+    }
+
+    pub fn group(&self, name: &str) -> Option<Arc<Group>> {
+        self.groups.iter().find(|group| group.name == name).cloned()
+    }
+
+    pub fn groups(&self) -> &[Arc<Group>] {
+        &self.groups
+    }
+
+    // Starts the active health checks of every group that has them.
+    pub fn watch_health(&self) {
+        for group in &self.groups {
+            watch(group.clone());
+        }
+    }
+}
+
+// The request as the upstream is sent it: hop-by-hop fields are dropped, the
+// client is added to `X-Forwarded-For` and `Forwarded`, and the body is sent
+// with its length.
+fn request_head(info: &RequestInfo, upstream: &str, length: u64) -> Vec<u8> {
+    let target = encode_path(&info.uri);
+    let target = if info.query.is_empty() { target } else { format!("{}?{}", target, info.query) };
+    let host = info.header("Host").unwrap_or(upstream).to_string();
+    let mut fields = without_hop_by_hop(info.headers.clone());
+    fields.retain(|&(ref name, _)| {
+        !["Host", "Content-Length", "Expect", "X-Forwarded-For", "Forwarded"].iter().any(|skipped| name.eq_ignore_ascii_case(skipped))
+    });
+    let client = info.remote_addr.map(|ip| ip.to_string());
+    let forwarded_for = match (info.header("X-Forwarded-For"), client.as_ref()) {
+        (Some(prior), Some(client)) => Some(format!("{}, {}", prior, client)),
+        (prior, client) => prior.map(|prior| prior.to_string()).or(client.cloned())
+    };
+    let element = format!("for={};host=\"{}\";proto=http", forwarded_node(info.remote_addr), host.replace('"', ""));
+    let forwarded = match info.header("Forwarded") {
+        Some(prior) => format!("{}, {}", prior, element),
+        None => element
+    };
+    let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", info.method, target, host);
+    for &(ref name, ref value) in &fields {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if let Some(forwarded_for) = forwarded_for {
+        head.push_str(&format!("X-Forwarded-For: {}\r\n", forwarded_for));
+    }
+    head.push_str(&format!("Forwarded: {}\r\n", forwarded));
+    if info.header("X-Forwarded-Proto").is_none() {
+        head.push_str("X-Forwarded-Proto: http\r\n");
+    }
+    if info.header("X-Forwarded-Host").is_none() {
+        head.push_str(&format!("X-Forwarded-Host: {}\r\n", host));
+    }
+    if length > 0 || info.header("Content-Length").is_some() {
+        head.push_str(&format!("Content-Length: {}\r\n", length));
+    }
+    head.push_str("\r\n");
+    head.into_bytes()
+}
+
+// Methods that can be sent again without changing what they did (RFC 7231
+// 4.2.2).
+fn is_idempotent(method: &str) -> bool {
+    ["GET", "HEAD", "OPTIONS", "TRACE", "PUT", "DELETE"].contains(&method)
+}
+
+// A client in a `Forwarded` element (RFC 7239 6), IPv6 addresses quoted.
+fn forwarded_node(ip: Option<IpAddr>) -> String {
+    match ip {
+        Some(IpAddr::V4(ip)) => ip.to_string(),
+        Some(IpAddr::V6(ip)) => format!("\"[{}]\"", ip),
+        None => "unknown".to_string()
+    }
+}
+
+// The fields less the hop-by-hop ones, including any that `Connection`
+// names.
+fn without_hop_by_hop(fields: Vec<(String, String)>) -> Vec<(String, String)> {
+    let listed = fields.iter()
+        .filter(|&&(ref name, _)| name.eq_ignore_ascii_case("Connection"))
+        .flat_map(|&(_, ref value)| value.split(',').map(|name| name.trim().to_string()).collect::<Vec<String>>())
+        .collect::<Vec<String>>();
+    fields.into_iter()
+        .filter(|&(ref name, _)| {
+            !HOP_BY_HOP.iter().any(|hop| name.eq_ignore_ascii_case(hop)) && !listed.iter().any(|hop| name.eq_ignore_ascii_case(hop))
+        })
+        .collect()
+}
+
+fn field<'a>(fields: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    fields.iter()
+        .find(|&&(ref field, _)| field.eq_ignore_ascii_case(name))
+        .map(|&(_, ref value)| &value[..])
+}
+
+fn has_token(fields: &[(String, String)], name: &str, token: &str) -> bool {
+    fields.iter()
+        .filter(|&&(ref field, _)| field.eq_ignore_ascii_case(name))
+        .any(|&(_, ref value)| value.split(',').any(|part| part.trim().eq_ignore_ascii_case(token)))
+}
+
+fn framing(method: &str, status: u16, fields: &[(String, String)]) -> Framing {
+    if method == "HEAD" || status == 204 || status == 304 {
+        Framing::Empty
+    } else if has_token(fields, "Transfer-Encoding", "chunked") {
+        Framing::Chunked { remaining: 0, started: false }
+    } else {
+        match field(fields, "Content-Length").and_then(|length| length.trim().parse::<u64>().ok()) {
+            Some(length) => Framing::Length(length),
+            None => Framing::Close
+        }
+    }
+}
+
+fn keeps_alive(version: u8, fields: &[(String, String)]) -> bool {
+    match version {
+        1 => !has_token(fields, "Connection", "close"),
+        _ => has_token(fields, "Connection", "keep-alive")
+    }
+}
+
+// The minor version, status and fields of a response.
+type Head = (u8, (u16, String), Vec<(String, String)>);
+
+// A connection that ends before any of the head arrives was stale.
+fn read_head(reader: &mut BufReader<TcpStream>) -> Result<Head, Failure> {
+    let mut line = String::new();
+    match reader.read_line(&mut line) {
+        Ok(0) => return Err(Failure::Stale),
+        Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => return Err(Failure::Stale),
+        Err(e) => return Err(Failure::Failed(ProxyError::from_io(e))),
+        Ok(_) => ()
+    }
+    let bad = |reason: String| Failure::Failed(ProxyError::BadResponse(reason));
+    let status_line = line.trim_end().to_string();
+    let mut parts = status_line.splitn(3, ' ');
+    let version = match parts.next() {
+        Some("HTTP/1.1") => 1,
+        Some("HTTP/1.0") => 0,
+        _ => return Err(bad(format!("{} is not an HTTP/1.x status line", status_line)))
+    };
+    let code = parts.next().and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| bad(format!("{} has no status code", status_line)))?;
+    let reason = parts.next().unwrap_or("").to_string();
+    let mut fields = vec![];
+    let mut size = line.len();
+    loop {
+        line.clear();
+        match reader.read_line(&mut line) {
+            Ok(0) => return Err(bad("connection closed in the headers".to_string())),
+            Ok(read) => size += read,
+            Err(e) => return Err(Failure::Failed(ProxyError::from_io(e)))
+        }
+        if size > MAX_HEAD {
+            return Err(bad("headers too long".to_string()));
+        }
+        let trimmed = line.trim_end();
+        if trimmed.is_empty() {
+            return Ok((version, (code, reason), fields));
+        }
+        match trimmed.find(':') {
+            Some(colon) => fields.push((trimmed[..colon].trim().to_string(), trimmed[colon + 1..].trim().to_string())),
+            None => return Err(bad(format!("{} is not a header field", trimmed)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{ BufRead, BufReader, Cursor, Read, Write };
+    use std::net::{ IpAddr, Ipv4Addr, TcpListener };
+    use std::sync::Arc;
+    use std::sync::atomic::{ AtomicUsize, Ordering };
+    use std::sync::mpsc::{ channel, Receiver };
+    use std::thread;
+    use std::time::Duration;
+    use http::{ Relay, Status };
+    use request::{ BodyStream, RequestInfo };
+    use super::{ request_head, ProxyError, Upstream };
+
+    // An upstream that answers each request it reads with the next of
+    // `responses`, closing connections after `per_connection` requests, and
+    // reports the requests it read and the connections it accepted.
+    fn upstream(responses: Vec<&'static str>, per_connection: usize) -> (String, Receiver<String>, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (sender, requests) = channel();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        thread::spawn(move || {
+            let mut responses = responses.into_iter();
+            for stream in listener.incoming() {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                for _ in 0..per_connection {
+                    let mut request = String::new();
+                    let mut line = String::new();
+                    while reader.read_line(&mut line).unwrap_or(0) > 0 && line != "\r\n" {
+                        request.push_str(&line);
+                        line.clear();
+                    }
+                    if request.is_empty() {
+                        break;
+                    }
+                    let length = request.lines()
+                        .find(|line| line.starts_with("Content-Length: "))
+                        .map(|line| line[16..].parse::<usize>().unwrap())
+                        .unwrap_or(0);
+                    let mut body = vec![0; length];
+                    reader.read_exact(&mut body).unwrap();
+                    request.push_str(&String::from_utf8(body).unwrap());
+                    let _ = sender.send(request);
+                    match responses.next() {
+                        Some(response) => stream.write_all(response.as_bytes()).unwrap(),
+                        None => return
+                    }
+                }
+            }
+        });
+        (address, requests, accepted)
+    }
+
+    fn info(method: &str, headers: &[(&str, &str)]) -> RequestInfo {
+        RequestInfo {
+            method: method.to_string(),
+            uri: "/app/a b".to_string(),
+            query: "x=1".to_string(),
+            remote_addr: Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 7))),
+            headers: headers.iter().map(|&(name, value)| (name.to_string(), value.to_string())).collect(),
+            ..RequestInfo::default()
+        }
+    }
+
+    fn text(relay: &mut Relay) -> String {
+        let mut body = String::new();
+        relay.body.read_to_string(&mut body).unwrap();
+        body
+    }
+
+    #[test]
+    fn rewrites_request_headers_for_the_upstream() {
+        let posted = RequestInfo {
+            body: b"a=1".to_vec(),
+            ..info("POST", &[("Host", "example.com"), ("Connection", "keep-alive, X-Secret"), ("X-Secret", "1"),
+                             ("Keep-Alive", "timeout=5"), ("TE", "trailers"), ("X-Forwarded-For", "192.0.2.1"),
+                             ("Accept", "text/html"), ("Content-Length", "3")])
+        };
+
+        assert_eq!(String::from_utf8(request_head(&posted, "127.0.0.1:8080", 3)).unwrap(),
+                   "POST /app/a%20b?x=1 HTTP/1.1\r\nHost: example.com\r\nAccept: text/html\r\n\
+                    X-Forwarded-For: 192.0.2.1, 10.0.0.7\r\nForwarded: for=10.0.0.7;host=\"example.com\";proto=http\r\n\
+                    X-Forwarded-Proto: http\r\nX-Forwarded-Host: example.com\r\nContent-Length: 3\r\n\r\n");
+
+        let bare = RequestInfo { remote_addr: None, ..info("GET", &[("Forwarded", "for=192.0.2.1")]) };
+        let head = String::from_utf8(request_head(&bare, "127.0.0.1:8080", 0)).unwrap();
+        assert!(head.starts_with("GET /app/a%20b?x=1 HTTP/1.1\r\nHost: 127.0.0.1:8080\r\n"));
+        assert!(head.contains("\r\nForwarded: for=192.0.2.1, for=unknown;host=\"127.0.0.1:8080\";proto=http\r\n"));
+        assert!(!head.contains("X-Forwarded-For"));
+    }
+
+    #[test]
+    fn relays_responses_and_reuses_connections() {
+        let (address, requests, accepted) = upstream(vec![
+            "HTTP/1.1 201 Created\r\nContent-Type: text/plain\r\nContent-Length: 5\r\nConnection: X-Hop\r\nX-Hop: 1\r\nX-App: yes\r\n\r\nhello",
+            "HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3;ext=1\r\nabc\r\n2\r\nde\r\n0\r\nX-Trailer: t\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok"
+        ], 3);
+        let upstream = Upstream::new("app", &address, 4, Duration::from_secs(5));
+
+        let mut first = upstream.forward(&RequestInfo { body: b"a=1".to_vec(), ..info("POST", &[]) }, None).unwrap();
+        assert_eq!(first.status, Status::Other(201, "Created".to_string()));
+        assert_eq!(first.content_type, "text/plain");
+        assert_eq!(first.fields, vec![("Content-Length".to_string(), "5".to_string()), ("X-App".to_string(), "yes".to_string())]);
+        assert!(!first.chunked);
+        assert_eq!(text(&mut first), "hello");
+        assert!(requests.recv().unwrap().ends_with("Content-Length: 3\r\na=1"));
+        assert_eq!(upstream.pool.len(), 1);
+
+        let mut second = upstream.forward(&info("GET", &[]), None).unwrap();
+        assert!(second.chunked);
+        assert_eq!(second.fields, vec![]);
+        assert_eq!(text(&mut second), "abcde");
+
+        let mut third = upstream.forward(&info("GET", &[]), None).unwrap();
+        assert_eq!(text(&mut third), "ok");
+        assert_eq!(upstream.pool.len(), 0);
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn reconnects_when_a_pooled_connection_was_closed() {
+        let (address, requests, accepted) = upstream(vec!["HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na",
+                                                          "HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nb",
+                                                          "HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nc"], 1);
+        let upstream = Upstream::new("app", &address, 4, Duration::from_secs(5));
+        assert_eq!(text(&mut upstream.forward(&info("GET", &[]), None).unwrap()), "a");
+        assert_eq!(upstream.pool.len(), 1);
+        requests.recv().unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        assert_eq!(text(&mut upstream.forward(&info("GET", &[]), None).unwrap()), "b");
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+        requests.recv().unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        // The closed connection is left in the pool rather than tried.
+        let posted = RequestInfo { body: b"x=1".to_vec(), ..info("POST", &[("Content-Length", "3")]) };
+        assert_eq!(text(&mut upstream.forward(&posted, None).unwrap()), "c");
+        assert_eq!(accepted.load(Ordering::SeqCst), 3);
+        assert_eq!(upstream.pool.len(), 2);
+    }
+
+    #[test]
+    fn streams_bodies_still_arriving_from_the_client() {
+        let (address, requests, accepted) = upstream(vec!["HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na",
+                                                          "HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nb"], 1);
+        let upstream = Upstream::new("app", &address, 4, Duration::from_secs(5));
+        let put = info("PUT", &[("Content-Length", "11")]);
+        assert_eq!(text(&mut upstream.forward(&info("GET", &[]), None).unwrap()), "a");
+        requests.recv().unwrap();
+
+        // Even an idempotent request goes out on a new connection, since the
+        // body cannot be sent twice, so the pooled one is left alone.
+        let mut body = BodyStream { length: 11, reader: Box::new(Cursor::new(b"name=ferris and more".to_vec()).take(11)) };
+        assert_eq!(text(&mut upstream.forward(&put, Some(&mut body)).unwrap()), "b");
+        assert!(requests.recv().unwrap().ends_with("Content-Length: 11\r\nname=ferris"));
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+        assert_eq!(upstream.pool.len(), 2);
+
+        let mut short = BodyStream { length: 11, reader: Box::new(Cursor::new(b"name=".to_vec())) };
+        assert!(upstream.forward(&put, Some(&mut short)).is_err());
+    }
+
+    #[test]
+    fn reports_unreachable_and_slow_upstreams() {
+        let closed = Upstream::new("closed", "127.0.0.1:1", 4, Duration::from_secs(1));
+        match closed.forward(&info("GET", &[]), None) {
+            Err(ProxyError::Unreachable(_)) => (),
+            other => panic!("expected an unreachable upstream, got {:?}", other.map(|relay| relay.status))
+        }
+        assert_eq!(ProxyError::Unreachable(String::new()).status(), Status::Other(502, "Bad Gateway".to_string()));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let slow = Upstream::new("slow", &listener.local_addr().unwrap().to_string(), 4, Duration::from_millis(200));
+        let error = slow.forward(&info("GET", &[]), None).err();
+        assert_eq!(error, Some(ProxyError::TimedOut));
+        assert_eq!(error.unwrap().status(), Status::Other(504, "Gateway Timeout".to_string()));
+    }
+
+    #[test]
+    fn refuses_responses_that_are_not_http() {
+        let (address, _requests, _) = upstream(vec!["SSH-2.0-OpenSSH\r\n\r\n"], 1);
+        let upstream = Upstream::new("app", &address, 4, Duration::from_secs(5));
+
+        match upstream.forward(&info("GET", &[]), None) {
+            Err(ProxyError::BadResponse(reason)) => assert!(reason.contains("not an HTTP/1.x status line")),
+            other => panic!("expected a bad response, got {:?}", other.map(|relay| relay.status))
+        }
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::net::{ IpAddr, SocketAddr };
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::time::{ Duration, Instant };
+use scheduling::{ Priority, priority_of };
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u32
+}
+
+impl Cidr {
+    pub fn parse(block: &str) -> Result<Cidr, String> {
+        let invalid = || format!("{} is not a valid CIDR block", block);
+        let mut parts = block.splitn(2, '/');
+        let network = parts.next()
+            .and_then(|ip| ip.parse::<IpAddr>().ok())
+            .ok_or_else(&invalid)?;
+        let max_prefix = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128
+        };
+        let prefix = match parts.next() {
+            None => max_prefix,
+            Some(p) => p.parse::<u32>().map_err(|_| invalid())?
+        };
+        if prefix > max_prefix {
+            Err(invalid())
+        } else {
+            Ok(Cidr { network: network, prefix: prefix })
+        }
+    }
+
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (&self.network, addr) {
+            (&IpAddr::V4(net), &IpAddr::V4(ip)) => {
+                same_prefix(u32::from(net) as u128, u32::from(ip) as u128, 32, self.prefix)
+            }
+            (&IpAddr::V6(net), &IpAddr::V6(ip)) => {
+                same_prefix(u128::from(net), u128::from(ip), 128, self.prefix)
+            }
+            _ => false
+        }
+    }
+}
+
+fn same_prefix(network: u128, ip: u128, bits: u32, prefix: u32) -> bool {
+    prefix == 0 || (network >> (bits - prefix)) == (ip >> (bits - prefix))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RuleTarget {
+    Class(Priority),
+    Network(Cidr),
+    // Every client.
+    Any
+}
+
+// A token bucket per client: `burst` requests may arrive at once, after which
+// clients are held to `rate` requests per second.
+pub struct LimitRule {
+    pub label: String,
+    pub target: RuleTarget,
+    burst: f64,
+    rate: f64
+}
+
+impl LimitRule {
+    pub fn new(label: &str, target: RuleTarget, burst: f64, rate: f64) -> Self {
+        LimitRule {
+            label: label.to_string(),
+            target: target,
+            burst: burst.max(1.0),
+            rate: rate
+        }
+    }
+
+    // Network rules are checked first, then priority classes, then rules
+    // for every client.
+    fn precedence(&self) -> u8 {
+        match self.target {
+            RuleTarget::Network(_) => 0,
+            RuleTarget::Class(_) => 1,
+            RuleTarget::Any => 2
+        }
+    }
+
+    fn applies_to(&self, addr: &SocketAddr) -> bool {
+        match self.target {
+            RuleTarget::Class(ref class) => &priority_of(addr) == class,
+            RuleTarget::Network(ref cidr) => cidr.contains(&addr.ip()),
+            RuleTarget::Any => true
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    last_seen: Instant
+}
+
+impl Bucket {
+    fn take(&mut self, rule: &LimitRule, now: Instant) -> Result<(), Duration> {
+        let elapsed = duration_secs(now.duration_since(self.last_seen));
+        self.tokens = (self.tokens + elapsed * rule.rate).min(rule.burst);
+        self.last_seen = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else if rule.rate > 0.0 {
+            Err(Duration::from_millis(((1.0 - self.tokens) / rule.rate * 1000.0).ceil() as u64))
+        } else {
+            Err(Duration::from_secs(u64::from(u32::MAX)))
+        }
+    }
+}
+
+fn duration_secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1_000_000_000.0
+}
+
+pub struct LimitCounters {
+    pub rules: Vec<(String, AtomicUsize)>,
+    pub tracked_clients: AtomicUsize
+}
+
+impl LimitCounters {
+    pub fn total_limited(&self) -> usize {
+        self.rules.iter().map(|&(_, ref count)| count.load(Ordering::Relaxed)).sum()
+    }
+}
+
+pub type Counters = Arc<LimitCounters>;
+
+// Buckets are dropped once a client has been idle for `expiry`, so only
+// recently active clients take up memory. A client is held to the first rule
+// that applies to it, by precedence and then in the order given.
+pub struct RateLimiter {
+    rules: Vec<LimitRule>,
+    buckets: HashMap<IpAddr, Bucket>,
+    expiry: Duration,
+    last_sweep: Instant,
+    counters: Counters
+}
+
+impl RateLimiter {
+    pub fn new(mut rules: Vec<LimitRule>, expiry: Duration) -> Self {
+        rules.sort_by_key(|rule| rule.precedence());
+        let counters = LimitCounters {
+            rules: rules.iter().map(|r| (r.label.clone(), AtomicUsize::new(0))).collect(),
+            tracked_clients: AtomicUsize::new(0)
+        };
+        RateLimiter {
+            rules: rules,
+            buckets: HashMap::new(),
+            expiry: expiry,
+            last_sweep: Instant::now(),
+            counters: Arc::new(counters)
+        }
+    }
+
+    pub fn counters(&self) -> Counters {
+        self.counters.clone()
+    }
+
+    pub fn check(&mut self, addr: &SocketAddr, now: Instant) -> Result<(), Duration> {
+        if now.duration_since(self.last_sweep) >= self.expiry {
+            self.sweep(now);
+        }
+        let result = match self.rules.iter().position(|r| r.applies_to(addr)) {
+            None => Ok(()),
+            Some(idx) => {
+                let rule = &self.rules[idx];
+                let bucket = self.buckets.entry(addr.ip()).or_insert(Bucket {
+                    tokens: rule.burst,
+                    last_seen: now
+                });
+                bucket.take(rule, now).inspect_err(|_| {
+                    self.counters.rules[idx].1.fetch_add(1, Ordering::Relaxed);
+                })
+            }
+        };
+        self.counters.tracked_clients.store(self.buckets.len(), Ordering::Relaxed);
+        result
+    }
+
+    fn sweep(&mut self, now: Instant) {
+        let expiry = self.expiry;
+        self.buckets.retain(|_, bucket| now.duration_since(bucket.last_seen) < expiry);
+        self.last_sweep = now;
+    }
+}
+
+pub fn retry_after_secs(wait: Duration) -> u64 {
+    if wait.subsec_nanos() > 0 {
+        wait.as_secs() + 1
+    } else {
+        wait.as_secs().max(1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{ SocketAddr, SocketAddrV4, Ipv4Addr, IpAddr };
+    use std::sync::atomic::Ordering;
+    use std::time::{ Duration, Instant };
+    use scheduling::Priority;
+    use super::{ Cidr, LimitRule, RateLimiter, RuleTarget, retry_after_secs };
+
+    fn v4(a: u8, b: u8, c: u8, d: u8) -> SocketAddr {
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(a, b, c, d), 80))
+    }
+
+    #[test]
+    fn cidr_matches_addresses_in_block() {
+        let block = Cidr::parse("10.1.0.0/16").unwrap();
+
+        assert!(block.contains(&IpAddr::V4(Ipv4Addr::new(10, 1, 200, 3))));
+        assert!(!block.contains(&IpAddr::V4(Ipv4Addr::new(10, 2, 0, 1))));
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(&IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8))));
+        assert!(Cidr::parse("::1").unwrap().contains(&"::1".parse().unwrap()));
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("ten/8").is_err());
+    }
+
+    #[test]
+    fn limits_after_burst_and_refills_over_time() {
+        let rule = LimitRule::new("low", RuleTarget::Class(Priority::Low), 2.0, 1.0);
+        let mut limiter = RateLimiter::new(vec![rule], Duration::from_secs(60));
+        let client = v4(10, 0, 0, 1);
+        let start = Instant::now();
+
+        assert!(limiter.check(&client, start).is_ok());
+        assert!(limiter.check(&client, start).is_ok());
+        assert_eq!(limiter.check(&client, start), Err(Duration::from_secs(1)));
+        assert!(limiter.check(&client, start + Duration::from_secs(1)).is_ok());
+        assert_eq!(limiter.counters().total_limited(), 1);
+    }
+
+    #[test]
+    fn network_rules_take_precedence_over_classes() {
+        let rules = vec![
+            LimitRule::new("low", RuleTarget::Class(Priority::Low), 100.0, 100.0),
+            LimitRule::new("10.0.0.0/8", RuleTarget::Network(Cidr::parse("10.0.0.0/8").unwrap()), 1.0, 0.5)
+        ];
+        let mut limiter = RateLimiter::new(rules, Duration::from_secs(60));
+        let now = Instant::now();
+
+        assert!(limiter.check(&v4(10, 0, 0, 1), now).is_ok());
+        assert_eq!(limiter.check(&v4(10, 0, 0, 1), now), Err(Duration::from_secs(2)));
+        assert!(limiter.check(&v4(192, 168, 0, 1), now).is_ok());
+        assert!(limiter.check(&v4(192, 168, 0, 1), now).is_ok());
+        assert_eq!(limiter.counters().rules[0].0, "10.0.0.0/8");
+        assert_eq!(limiter.counters().rules[0].1.load(Ordering::Relaxed), 1);
+        assert_eq!(limiter.counters().rules[1].1.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn unmatched_clients_are_not_limited_or_tracked() {
+        let rule = LimitRule::new("high", RuleTarget::Class(Priority::High), 1.0, 1.0);
+        let mut limiter = RateLimiter::new(vec![rule], Duration::from_secs(60));
+        let now = Instant::now();
+
+        for _ in 0..10 {
+            assert!(limiter.check(&v4(10, 0, 0, 1), now).is_ok());
+        }
+        assert_eq!(limiter.counters().tracked_clients.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn idle_buckets_expire() {
+        let rule = LimitRule::new("low", RuleTarget::Class(Priority::Low), 1.0, 1.0);
+        let mut limiter = RateLimiter::new(vec![rule], Duration::from_secs(10));
+        let start = Instant::now();
+
+        let _ = limiter.check(&v4(10, 0, 0, 1), start);
+        let _ = limiter.check(&v4(10, 0, 0, 2), start + Duration::from_secs(5));
+        assert_eq!(limiter.counters().tracked_clients.load(Ordering::Relaxed), 2);
+
+        let _ = limiter.check(&v4(10, 0, 0, 2), start + Duration::from_secs(12));
+        assert_eq!(limiter.counters().tracked_clients.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn retry_after_rounds_up_to_whole_seconds() {
+        assert_eq!(retry_after_secs(Duration::from_millis(1)), 1);
+        assert_eq!(retry_after_secs(Duration::from_millis(1500)), 2);
+        assert_eq!(retry_after_secs(Duration::from_secs(3)), 3);
+    }
+}
@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::io::{ self, Write };
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, Instant };
+use freshness::Stamp;
+use shell_interpolation::CacheTtl;
+use ssi::Outcome;
+
+// A rendered template along with every file it was built from. The page is
+// reused until it expires or any of those files changes on disk.
+struct RenderedPage {
+    html: Arc<[u8]>,
+    sources: Vec<(PathBuf, Stamp)>,
+    expires: Instant
+}
+
+impl RenderedPage {
+    fn current(&self, now: Instant) -> bool {
+        now < self.expires && self.sources.iter().all(|&(ref path, ref stamp)| {
+            Stamp::of(path).map(|s| &s == stamp).unwrap_or(false)
+        })
+    }
+}
+
+// Output of server-side includes, keyed by template. Pages without their own
+// `#cache` directive are kept for `default_ttl`, which is zero unless
+// configured.
+#[derive(Clone)]
+pub struct RenderCache {
+    pages: Arc<Mutex<HashMap<PathBuf, RenderedPage>>>,
+    default_ttl: Duration
+}
+
+impl RenderCache {
+    pub fn new(default_ttl: Duration) -> Self {
+        RenderCache { pages: Arc::new(Mutex::new(HashMap::new())), default_ttl: default_ttl }
+    }
+
+    pub fn get(&self, template: &Path, now: Instant) -> Option<Arc<[u8]>> {
+        let mut pages = self.pages.lock().unwrap();
+        let current = pages.get(template).map(|page| page.current(now));
+        match current {
+            Some(true) => pages.get(template).map(|page| Arc::clone(&page.html)),
+            Some(false) => {
+                pages.remove(template);
+                None
+            }
+            None => None
+        }
+    }
+
+    // Keeps the rendered output of `template` if its TTL allows. `sources`
+    // are the files the page was built from, stamped before they were read.
+    pub fn store(&self, template: &Path, mut sources: Vec<(PathBuf, Stamp)>, outcome: Outcome, html: Vec<u8>, now: Instant) {
+        sources.extend(outcome.sources);
+        let ttl = match outcome.ttl {
+            CacheTtl::Default => self.default_ttl,
+            CacheTtl::For(ttl) => ttl,
+            CacheTtl::Never => Duration::from_secs(0)
+        };
+        if ttl > Duration::from_secs(0) {
+            let page = RenderedPage { html: Arc::from(html), sources: sources, expires: now + ttl };
+            self.pages.lock().unwrap().insert(template.to_path_buf(), page);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pages.lock().unwrap().len()
+    }
+}
+
+// Passes a page through to the client while keeping a copy for the cache.
+pub struct Recorder<W: Write> {
+    inner: W,
+    pub recorded: Vec<u8>
+}
+
+impl<W: Write> Recorder<W> {
+    pub fn new(inner: W) -> Self {
+        Recorder { inner: inner, recorded: vec![] }
+    }
+
+    pub fn into_parts(self) -> (W, Vec<u8>) {
+        (self.inner, self.recorded)
+    }
+}
+
+impl<W: Write> Write for Recorder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.recorded.extend_from_slice(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{ File, create_dir_all, remove_file };
+    use std::io::Write;
+    use std::path::{ Path, PathBuf };
+    use std::time::{ Duration, Instant };
+    use freshness::Stamp;
+    use shell_interpolation::CacheTtl;
+    use ssi::Outcome;
+    use super::{ RenderCache, Recorder };
+
+    fn outcome(ttl: CacheTtl) -> Outcome {
+        Outcome { ttl: ttl, sources: vec![] }
+    }
+
+    fn sources(path: &Path) -> Vec<(PathBuf, Stamp)> {
+        vec![(path.to_path_buf(), Stamp::of(path).unwrap())]
+    }
+
+    #[test]
+    fn serves_rendered_page_until_it_expires() {
+        let template = Path::new("test/small.shtml");
+        let cache = RenderCache::new(Duration::from_secs(0));
+        let now = Instant::now();
+
+        cache.store(template, sources(template), outcome(CacheTtl::For(Duration::from_secs(10))), b"<h1>hi</h1>".to_vec(), now);
+
+        assert_eq!(cache.get(template, now + Duration::from_secs(9)).as_ref().map(|b| &b[..]), Some(&b"<h1>hi</h1>"[..]));
+        assert_eq!(cache.get(template, now + Duration::from_secs(10)), None);
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn uses_default_ttl_unless_page_opts_out() {
+        let template = Path::new("test/small.shtml");
+        let now = Instant::now();
+
+        let uncached = RenderCache::new(Duration::from_secs(0));
+        uncached.store(template, sources(template), outcome(CacheTtl::Default), b"a".to_vec(), now);
+        assert_eq!(uncached.len(), 0);
+
+        let cached = RenderCache::new(Duration::from_secs(60));
+        cached.store(template, sources(template), outcome(CacheTtl::Never), b"a".to_vec(), now);
+        assert_eq!(cached.len(), 0);
+        cached.store(template, sources(template), outcome(CacheTtl::Default), b"a".to_vec(), now);
+        assert_eq!(cached.get(template, now).as_ref().map(|b| &b[..]), Some(&b"a"[..]));
+    }
+
+    #[test]
+    fn drops_page_when_a_source_changes() {
+        create_dir_all("test/tmp").unwrap();
+        let template = Path::new("test/tmp/rendered.shtml");
+        let included = Path::new("test/tmp/rendered_footer.html");
+        File::create(template).unwrap().write_all(b"<h1>page</h1>").unwrap();
+        File::create(included).unwrap().write_all(b"<p>footer</p>").unwrap();
+        let cache = RenderCache::new(Duration::from_secs(60));
+        let now = Instant::now();
+        let mut all_sources = sources(template);
+        all_sources.extend(sources(included));
+
+        cache.store(template, all_sources, outcome(CacheTtl::Default), b"<h1>page</h1><p>footer</p>".to_vec(), now);
+        assert!(cache.get(template, now).is_some());
+
+        File::create(included).unwrap().write_all(b"<p>new footer</p>").unwrap();
+
+        assert_eq!(cache.get(template, now), None);
+        remove_file(template).unwrap();
+        remove_file(included).unwrap();
+    }
+
+    #[test]
+    fn records_what_it_passes_through() {
+        let mut recorder = Recorder::new(Vec::new());
+        recorder.write_all(b"<h1>").unwrap();
+        recorder.write_all(b"hi</h1>").unwrap();
+
+        let (inner, recorded) = recorder.into_parts();
+        assert_eq!(inner, recorded);
+        assert_eq!(&recorded[..], &b"<h1>hi</h1>"[..]);
+    }
+}
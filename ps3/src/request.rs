@@ -1,35 +1,363 @@
-use std::net::TcpStream;
-use std::io::Read;
+use std::net::{ IpAddr, SocketAddr, TcpStream };
+use std::io::{ Cursor, Read };
 use std::io;
+use std::mem;
 use std::str;
-use path::{ Path, path };
+use std::time::{ Duration, Instant };
+use path::{ Path, path, query, query_params };
+
+// Requests whose headers do not fit are refused.
+const MAX_HEAD: usize = 8 << 10;
+// Bodies longer than this are refused.
+const MAX_BODY: usize = 16 << 20;
+// Clients that send nothing for this long are given up on.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+// Clients that take longer than this to send all their headers, however
+// steadily they trickle them in, are given up on too.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct Request {
     pub stream: TcpStream,
-    pub path: io::Result<Path>
+    pub path: io::Result<Path>,
+    pub info: RequestInfo,
+    // Whatever was read past the headers: the start of the body.
+    rest: Vec<u8>
+}
+
+// A request body left on the connection, to be sent on as it arrives.
+pub struct BodyStream {
+    // The `Content-Length` announced.
+    pub length: u64,
+    pub reader: Box<dyn Read + Send>
+}
+
+impl Request {
+    // Reads the body the headers announce. This is left to the thread that
+    // answers the request, so the accept thread only waits for headers and
+    // rate-limited requests never have their bodies read.
+    pub fn read_body(&mut self) {
+        if self.path.is_err() {
+            return;
+        }
+        let rest = mem::take(&mut self.rest);
+        match read_body(&mut self.stream, &self.info, rest) {
+            Ok(body) => self.info.body = body,
+            Err(error) => {
+                println!("Received request error:\n{}", error);
+                self.path = Err(error);
+            }
+        }
+    }
+
+    // Leaves the body on the connection instead, for a handler that sends it
+    // on without holding all of it.
+    pub fn stream_body(&mut self) -> Option<BodyStream> {
+        if self.path.is_err() {
+            return None;
+        }
+        let rest = mem::take(&mut self.rest);
+        let stream = body_length(&self.info).and_then(|length| {
+            self.stream.try_clone().map(|stream| {
+                BodyStream { length: length, reader: Box::new(Cursor::new(rest).chain(stream).take(length)) }
+            })
+        });
+        match stream {
+            Ok(body) => Some(body),
+            Err(error) => {
+                println!("Received request error:\n{}", error);
+                self.path = Err(error);
+                None
+            }
+        }
+    }
+}
+
+// What a page rendered for the request may know about it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RequestInfo {
+    // The requested path, without the query string.
+    pub uri: String,
+    pub query: String,
+    // The query string's name-value pairs, decoded.
+    pub params: Vec<(String, String)>,
+    pub method: String,
+    pub remote_addr: Option<IpAddr>,
+    // The address the request arrived on.
+    pub local_addr: Option<SocketAddr>,
+    pub user_agent: Option<String>,
+    // Every header field, in the order sent.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>
+}
+
+impl RequestInfo {
+    // The value of the first header called `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|&&(ref field, _)| field.eq_ignore_ascii_case(name))
+            .map(|&(_, ref value)| &value[..])
+    }
+
+    // The value of the first query parameter called `name`.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.iter()
+            .find(|&&(ref param, _)| param == name)
+            .map(|&(_, ref value)| &value[..])
+    }
 }
 
 pub fn build_request(mut stream: TcpStream) -> Request {
-    let path = read_request(&mut stream);
+    let _ = stream.set_read_timeout(Some(READ_TIMEOUT));
+    let (path, mut info, rest) = read_request(&mut stream);
+    let _ = stream.set_read_timeout(Some(READ_TIMEOUT));
+    info.remote_addr = stream.peer_addr().ok().map(|address| address.ip());
+    info.local_addr = stream.local_addr().ok();
     Request {
         stream: stream,
-        path: path
+        path: path,
+        info: info,
+        rest: rest
     }
 }
 
-fn read_request(stream: &mut TcpStream) -> io::Result<Path> {
-    let mut buf = [0 ;500];
-    stream.read(&mut buf).unwrap();
-    match str::from_utf8(&buf) {
+// The request line and headers, and any bytes of the body read with them.
+fn read_request(stream: &mut TcpStream) -> (io::Result<Path>, RequestInfo, Vec<u8>) {
+    let (head, rest) = match read_head(&mut Deadline::new(stream, HEAD_TIMEOUT)) {
+        Ok(read) => read,
         Err(error) => {
             println!("Received request error:\n{}", error);
-            Err(io::Error::new(io::ErrorKind::Other, error))
+            return (Err(error), RequestInfo::default(), vec![]);
+        }
+    };
+    match str::from_utf8(&head) {
+        Err(error) => {
+            println!("Received request error:\n{}", error);
+            (Err(io::Error::other(error)), RequestInfo::default(), vec![])
         }
         Ok(body) => {
-            let req_path = path(body);
             println!("Recieved request body:\n{}", body);
+            let req_path = match path(body) {
+                Ok(req_path) => req_path,
+                Err(error) => {
+                    println!("Received request error:\n{}", error);
+                    return (Err(io::Error::new(io::ErrorKind::InvalidData, error)), RequestInfo::default(), vec![]);
+                }
+            };
             println!("Requested Path: {}\n", req_path);
-            Ok(req_path)
+            let info = request_info(body, &req_path);
+            (Ok(req_path), info, rest)
+        }
+    }
+}
+
+// Reads up to the blank line ending the headers. Anything read past it is
+// the start of the body.
+fn read_head<R: Read>(stream: &mut R) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let mut head = vec![];
+    let mut buf = [0; 500];
+    loop {
+        if let Some(end) = head_end(&head) {
+            let rest = head.split_off(end);
+            return Ok((head, rest));
+        }
+        if head.len() > MAX_HEAD {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "request headers too long"));
+        }
+        match stream.read(&mut buf)? {
+            0 => return Ok((head, vec![])),
+            read => head.extend_from_slice(&buf[..read])
         }
     }
 }
+
+// A stream whose reads all have to finish by the same moment: each one
+// only waits for whatever time is left.
+struct Deadline<'a> {
+    stream: &'a mut TcpStream,
+    deadline: Instant
+}
+
+impl<'a> Deadline<'a> {
+    fn new(stream: &'a mut TcpStream, timeout: Duration) -> Deadline<'a> {
+        Deadline {
+            stream: stream,
+            deadline: Instant::now() + timeout
+        }
+    }
+}
+
+impl<'a> Read for Deadline<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left == Duration::from_secs(0) {
+            return Err(too_slow());
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf).map_err(|e| match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => too_slow(),
+            _ => e
+        })
+    }
+}
+
+fn too_slow() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "request headers took too long")
+}
+
+fn head_end(head: &[u8]) -> Option<usize> {
+    head.windows(4).position(|window| window == b"\r\n\r\n").map(|at| at + 4)
+        .or_else(|| head.windows(2).position(|window| window == b"\n\n").map(|at| at + 2))
+}
+
+// The `Content-Length` bytes following the headers.
+fn read_body<R: Read>(stream: &mut R, info: &RequestInfo, mut body: Vec<u8>) -> io::Result<Vec<u8>> {
+    let length = body_length(info)? as usize;
+    if body.len() < length {
+        let missing = (length - body.len()) as u64;
+        stream.take(missing).read_to_end(&mut body)?;
+    }
+    body.truncate(length);
+    Ok(body)
+}
+
+fn body_length(info: &RequestInfo) -> io::Result<u64> {
+    let length = match info.header("Content-Length") {
+        None => 0,
+        Some(length) => length.parse::<u64>()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad Content-Length"))?
+    };
+    if length > MAX_BODY as u64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "request body too long"));
+    }
+    Ok(length)
+}
+
+fn request_info(body: &str, req_path: &Path) -> RequestInfo {
+    let headers = headers(body);
+    let user_agent = headers.iter()
+        .find(|&&(ref name, _)| name.eq_ignore_ascii_case("User-Agent"))
+        .map(|&(_, ref value)| value.clone());
+    RequestInfo {
+        uri: req_path.to_string(),
+        query: query(body),
+        params: query_params(&query(body)),
+        method: body.split_whitespace().next().unwrap_or("").to_string(),
+        remote_addr: None,
+        local_addr: None,
+        user_agent: user_agent,
+        headers: headers,
+        body: vec![]
+    }
+}
+
+// The header fields of a request, up to the blank line.
+fn headers(body: &str) -> Vec<(String, String)> {
+    body.lines()
+        .skip(1)
+        .map(|line| line.trim_matches(|c: char| c.is_whitespace() || c == '\0'))
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| {
+            let colon = line.find(':')?;
+            Some((line[..colon].trim().to_string(), line[colon + 1..].trim().to_string()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{ Cursor, Read, Write };
+    use std::io::ErrorKind;
+    use std::net::{ TcpListener, TcpStream };
+    use std::thread;
+    use std::time::{ Duration, Instant };
+    use path::Path;
+    use super::{ build_request, read_body, read_head, request_info, Deadline };
+
+    #[test]
+    fn finds_headers_ignoring_case() {
+        let body = "GET /a.shtml?x=1 HTTP/1.1\r\nHost: localhost\r\nuser-agent:  curl/7.54 \r\n\r\nUser-Agent: body\0\0";
+        let info = request_info(body, &Path::RelPath("a.shtml".to_string()));
+
+        assert_eq!(info.header("User-Agent"), Some("curl/7.54"));
+        assert_eq!(info.header("Referer"), None);
+        assert_eq!(info.headers.len(), 2);
+    }
+
+    #[test]
+    fn reads_body_after_headers() {
+        let mut stream = Cursor::new(b"POST /cgi-bin/form HTTP/1.1\r\nContent-Length: 11\r\n\r\nname=ferris and more".to_vec());
+        let (head, rest) = read_head(&mut stream).unwrap();
+        let head = String::from_utf8(head).unwrap();
+        let info = request_info(&head, &Path::RelPath("cgi-bin/form".to_string()));
+
+        assert_eq!(info.method, "POST");
+        assert_eq!(read_body(&mut stream, &info, rest).unwrap(), b"name=ferris".to_vec());
+    }
+
+    #[test]
+    fn gives_up_on_headers_trickled_in_too_slowly() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let trickle = thread::spawn(move || {
+            for _ in 0..20 {
+                if client.write_all(b"X").is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+        let mut stream = listener.accept().unwrap().0;
+        let started = Instant::now();
+
+        let error = read_head(&mut Deadline::new(&mut stream, Duration::from_millis(300))).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_millis(600));
+        drop(stream);
+        trickle.join().unwrap();
+    }
+
+    #[test]
+    fn describes_request_for_pages() {
+        let body = "GET /docs/a%20b.shtml?x=1&q=a+b HTTP/1.1\r\nUser-Agent: curl\r\n\r\n";
+        let info = request_info(body, &Path::RelPath("docs/a b.shtml".to_string()));
+
+        assert_eq!(info.uri, "/docs/a b.shtml");
+        assert_eq!(info.query, "x=1&q=a+b");
+        assert_eq!(info.param("q"), Some("a b"));
+        assert_eq!(info.param("y"), None);
+        assert_eq!(info.user_agent, Some("curl".to_string()));
+    }
+
+    #[test]
+    fn leaves_the_body_to_be_read_later() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(b"POST /form HTTP/1.1\r\nContent-Length: 11\r\n\r\nname=").unwrap();
+        let mut request = build_request(listener.accept().unwrap().0);
+
+        assert_eq!(request.info.method, "POST");
+        assert!(request.info.body.is_empty());
+        client.write_all(b"ferris").unwrap();
+        request.read_body();
+        assert_eq!(request.info.body, b"name=ferris".to_vec());
+    }
+
+    #[test]
+    fn streams_the_body_as_it_arrives() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(b"POST /form HTTP/1.1\r\nContent-Length: 11\r\n\r\nname=").unwrap();
+        let mut request = build_request(listener.accept().unwrap().0);
+        let mut body = request.stream_body().unwrap();
+
+        assert_eq!(body.length, 11);
+        let mut start = [0; 5];
+        body.reader.read_exact(&mut start).unwrap();
+        assert_eq!(&start, b"name=");
+        client.write_all(b"ferris and more").unwrap();
+        let mut rest = String::new();
+        body.reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "ferris");
+        assert!(request.info.body.is_empty());
+    }
+}
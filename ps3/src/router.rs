@@ -0,0 +1,466 @@
+use std::cell::Cell;
+use std::sync::Arc;
+use cgi::Cgi;
+use fastcgi::FastCgi;
+use handler::{ CgiScripts, Context, FastCgiApp, Files, Proxy, ServerStatus, Visitors };
+use http::{ Payload, Status };
+use middleware::Layer;
+use proxy::Upstreams;
+use request::{ BodyStream, RequestInfo };
+use warm::glob_match;
+
+// What a handler is given to answer a request.
+pub struct Call<'a> {
+    // The decoded request path, without its leading `/`.
+    pub path: String,
+    pub info: &'a RequestInfo,
+    pub visitor_count: usize,
+    // Parameters captured by the route's pattern.
+    pub params: Vec<(String, String)>,
+    // Header fields for the response, whichever response it turns out to be.
+    pub fields: Vec<(String, String)>,
+    // The body still on the connection, when the handler streams it rather
+    // than reading it from `info`.
+    pub body: Cell<Option<BodyStream>>
+}
+
+impl<'a> Call<'a> {
+    pub fn new(path: &str, info: &'a RequestInfo, visitor_count: usize) -> Call<'a> {
+        Call { path: path.to_string(), info: info, visitor_count: visitor_count, params: vec![], fields: vec![], body: Cell::new(None) }
+    }
+
+    #[cfg(test)]
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.iter()
+            .find(|&&(ref param, _)| param == name)
+            .map(|&(_, ref value)| &value[..])
+    }
+}
+
+pub trait Handler: Send + Sync {
+    fn handle(&self, context: &Context, call: &Call) -> Result<Payload, Status>;
+
+    // Whether the request body is left on the connection for the handler,
+    // in `Call::body`, rather than read into `RequestInfo::body` first.
+    fn streams_body(&self) -> bool {
+        false
+    }
+}
+
+// Runs around a route's handler. It may answer the request itself rather
+// than run the rest of the chain, and may add header fields to the response.
+pub trait Middleware: Send + Sync {
+    fn apply(&self, context: &Context, call: &mut Call, next: Next) -> Result<Payload, Status>;
+}
+
+// The middleware still to run for a request, and then its handler.
+pub struct Next<'a> {
+    middleware: &'a [Arc<dyn Middleware>],
+    handler: &'a dyn Handler
+}
+
+impl<'a> Next<'a> {
+    pub fn run(self, context: &Context, call: &mut Call) -> Result<Payload, Status> {
+        match self.middleware.split_first() {
+            Some((first, rest)) => first.apply(context, call, Next { middleware: rest, handler: self.handler }),
+            None => self.handler.handle(context, call)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Matcher {
+    Exact(String),
+    // Paths starting with the prefix. A prefix ending in `/` also matches the
+    // path without it, so that the directory itself can be redirected.
+    Prefix(String),
+    // Paths matching segment by segment: `:name` captures one segment, a last
+    // `*name` captures whatever is left, `**` matches any number of segments
+    // and other segments are globs. Names are letters, digits and `_`.
+    Pattern(String)
+}
+
+impl Matcher {
+    pub fn parse(kind: &str, path: &str) -> Result<Matcher, String> {
+        if !path.starts_with('/') {
+            return Err(format!("route path {} does not start with /", path));
+        }
+        match kind {
+            "exact" => Ok(Matcher::Exact(path.to_string())),
+            "prefix" => Ok(Matcher::Prefix(path.to_string())),
+            "pattern" => Ok(Matcher::Pattern(path.to_string())),
+            other => Err(format!("route kind must be exact, prefix or pattern, not {}", other))
+        }
+    }
+
+    // The parameters captured from `path` if it matches.
+    pub fn matches(&self, path: &str) -> Option<Vec<(String, String)>> {
+        match self {
+            &Matcher::Exact(ref exact) if path == exact => Some(vec![]),
+            &Matcher::Prefix(ref prefix) if path.starts_with(&prefix[..]) || path == prefix.trim_end_matches('/') => {
+                Some(vec![])
+            }
+            &Matcher::Pattern(ref pattern) => {
+                let pattern = pattern.split('/').collect::<Vec<&str>>();
+                let path = path.split('/').collect::<Vec<&str>>();
+                let mut captures = vec![];
+                if match_segments(&pattern, &path, &mut captures) { Some(captures) } else { None }
+            }
+            _ => None
+        }
+    }
+}
+
+fn match_segments(pattern: &[&str], path: &[&str], captures: &mut Vec<(String, String)>) -> bool {
+    match pattern.first() {
+        None => path.is_empty(),
+        Some(&"**") => {
+            (0..path.len() + 1).any(|skip| {
+                let captured = captures.len();
+                match_segments(&pattern[1..], &path[skip..], captures) || {
+                    captures.truncate(captured);
+                    false
+                }
+            })
+        }
+        Some(segment) if segment.starts_with('*') && is_name(&segment[1..]) && pattern.len() == 1 => {
+            captures.push((segment[1..].to_string(), path.join("/")));
+            true
+        }
+        Some(segment) if segment.starts_with(':') && is_name(&segment[1..]) => {
+            match path.first() {
+                Some(value) if !value.is_empty() => {
+                    captures.push((segment[1..].to_string(), value.to_string()));
+                    match_segments(&pattern[1..], &path[1..], captures)
+                }
+                _ => false
+            }
+        }
+        Some(segment) => {
+            match path.first() {
+                Some(name) if glob_match(segment, name) => match_segments(&pattern[1..], &path[1..], captures),
+                _ => false
+            }
+        }
+    }
+}
+
+fn is_name(text: &str) -> bool {
+    !text.is_empty() && text.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+// What answers a configured route.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    Visitors,
+    Status,
+    // Files as they are.
+    Static,
+    // Files, with server-side includes rendered.
+    Ssi,
+    Cgi,
+    FastCgi(String),
+    // Forwarded to the named upstream.
+    Proxy(String)
+}
+
+impl Target {
+    pub fn parse(words: &[&str]) -> Result<Target, String> {
+        match words {
+            &["visitors"] => Ok(Target::Visitors),
+            &["status"] => Ok(Target::Status),
+            &["static"] => Ok(Target::Static),
+            &["ssi"] => Ok(Target::Ssi),
+            &["cgi"] => Ok(Target::Cgi),
+            &["fastcgi", backend] => Ok(Target::FastCgi(backend.to_string())),
+            &["proxy", upstream] => Ok(Target::Proxy(upstream.to_string())),
+            _ => Err(format!("{} is not a handler", words.join(" ")))
+        }
+    }
+}
+
+// A route as configured, before its handler and middleware are built.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteSpec {
+    // Methods the route answers, or every method if empty.
+    pub methods: Vec<String>,
+    pub matcher: Matcher,
+    pub target: Target,
+    pub layers: Vec<Layer>
+}
+
+pub struct Route {
+    pub methods: Vec<String>,
+    pub matcher: Matcher,
+    // Run in order before the handler.
+    pub middleware: Vec<Arc<dyn Middleware>>,
+    pub handler: Arc<dyn Handler>
+}
+
+// Parameters captured by a route's pattern, by name.
+type Params = Vec<(String, String)>;
+
+#[derive(Clone, Default)]
+pub struct Routes {
+    routes: Arc<Vec<Route>>
+}
+
+impl Routes {
+    pub fn new(routes: Vec<Route>) -> Routes {
+        Routes { routes: Arc::new(routes) }
+    }
+
+    // Answers with the first route that matches both the path and the
+    // method. Once a route has matched the path, only routes with the same
+    // matcher are tried after it, so other methods never slip past it (and
+    // its middleware) to a broader route; they are not allowed.
+    pub fn dispatch(&self, context: &Context, call: &mut Call) -> Result<Payload, Status> {
+        match self.find(&format!("/{}", call.path), &call.info.method) {
+            Ok((route, params)) => {
+                call.params = params;
+                Next { middleware: &route.middleware, handler: &*route.handler }.run(context, call)
+            }
+            Err(ref allowed) if allowed.is_empty() => Err(Status::FileNotFound),
+            Err(allowed) => {
+                call.fields.push(("Allow".to_string(), allowed.join(", ")));
+                Err(Status::Other(405, "Method Not Allowed".to_string()))
+            }
+        }
+    }
+
+    // Whether the route `dispatch` would answer with streams the body.
+    pub fn streams_body(&self, path: &str, method: &str) -> bool {
+        self.find(path, method).map(|(route, _)| route.handler.streams_body()).unwrap_or(false)
+    }
+
+    // The route for the path and method and the parameters it captured, or
+    // the methods allowed if only routes for other methods match.
+    fn find(&self, path: &str, method: &str) -> Result<(&Route, Params), Vec<String>> {
+        let mut allowed: Vec<String> = vec![];
+        let mut claimed: Option<&Matcher> = None;
+        for route in self.routes.iter() {
+            if claimed.is_some_and(|matcher| *matcher != route.matcher) {
+                continue;
+            }
+            let params = match route.matcher.matches(path) {
+                Some(params) => params,
+                None => continue
+            };
+            if !route.methods.is_empty() && !route.methods.iter().any(|allows| allows == method) {
+                claimed = Some(&route.matcher);
+                allowed.extend(route.methods.iter().filter(|method| !allowed.contains(method)).cloned().collect::<Vec<String>>());
+                continue;
+            }
+            return Ok((route, params));
+        }
+        Err(allowed)
+    }
+}
+
+// The configured routes, then the standard ones: the visitor page at `/`,
+// the status page, FastCGI routes, the CGI directory and then every other
+// file, with server-side includes.
+pub fn table(specs: &[RouteSpec], cgi: Option<Cgi>, fastcgi: &FastCgi, upstreams: &Upstreams) -> Result<Routes, String> {
+    let mut standard = vec![
+        standard_route(Matcher::Exact("/".to_string()), Target::Visitors),
+        standard_route(Matcher::Exact("/server-status".to_string()), Target::Status)
+    ];
+    standard.extend(fastcgi.routes().iter().map(|&(ref glob, ref backend)| {
+        standard_route(Matcher::Pattern(format!("/{}", glob)), Target::FastCgi(backend.name.clone()))
+    }));
+    if let Some(ref cgi) = cgi {
+        standard.push(standard_route(Matcher::Prefix(format!("/{}/", cgi.dir.display())), Target::Cgi));
+    }
+    standard.push(standard_route(Matcher::Prefix("/".to_string()), Target::Ssi));
+    specs.iter().chain(standard.iter())
+        .map(|spec| {
+            let handler: Arc<dyn Handler> = match spec.target {
+                Target::Visitors => Arc::new(Visitors),
+                Target::Status => Arc::new(ServerStatus),
+                Target::Static => Arc::new(Files { ssi: false }),
+                Target::Ssi => Arc::new(Files { ssi: true }),
+                Target::Cgi => Arc::new(CgiScripts(cgi.clone().ok_or("cgi routes need a cgi_dir".to_string())?)),
+                Target::FastCgi(ref name) => {
+                    Arc::new(FastCgiApp(fastcgi.backend(name).ok_or(format!("no fastcgi_backend named {}", name))?))
+                }
+                Target::Proxy(ref name) => {
+                    Arc::new(Proxy(upstreams.group(name).ok_or(format!("no proxy_upstream named {}", name))?))
+                }
+            };
+            Ok(Route {
+                methods: spec.methods.clone(),
+                matcher: spec.matcher.clone(),
+                middleware: spec.layers.iter().map(Layer::build).collect(),
+                handler: handler
+            })
+        })
+        .collect::<Result<Vec<Route>, String>>()
+        .map(Routes::new)
+}
+
+fn standard_route(matcher: Matcher, target: Target) -> RouteSpec {
+    RouteSpec { methods: vec![], matcher: matcher, target: target, layers: vec![] }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{ AtomicUsize, Ordering };
+    use handler::{ test_context, Context };
+    use http::{ Payload, Status };
+    use request::RequestInfo;
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use cgi::Cgi;
+    use fastcgi::{ Address, FastCgi };
+    use proxy::Upstreams;
+    use sandbox::Sandbox;
+    use super::{ table, Call, Handler, Matcher, Middleware, Next, Route, RouteSpec, Routes, Target };
+
+    fn captures(matcher: &Matcher, path: &str) -> Option<Vec<(String, String)>> {
+        matcher.matches(path)
+    }
+
+    fn pair(name: &str, value: &str) -> (String, String) {
+        (name.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn matches_exact_and_prefix_routes() {
+        let exact = Matcher::parse("exact", "/server-status").unwrap();
+        assert_eq!(captures(&exact, "/server-status"), Some(vec![]));
+        assert_eq!(captures(&exact, "/server-status/"), None);
+
+        let prefix = Matcher::parse("prefix", "/docs/").unwrap();
+        assert_eq!(captures(&prefix, "/docs/a/b.html"), Some(vec![]));
+        assert_eq!(captures(&prefix, "/docs"), Some(vec![]));
+        assert_eq!(captures(&prefix, "/docsx"), None);
+
+        assert!(Matcher::parse("exact", "docs").is_err());
+        assert!(Matcher::parse("regex", "/docs").is_err());
+    }
+
+    #[test]
+    fn captures_pattern_parameters() {
+        let pattern = Matcher::parse("pattern", "/users/:id/files/*rest").unwrap();
+        assert_eq!(captures(&pattern, "/users/42/files/a/b.txt"), Some(vec![pair("id", "42"), pair("rest", "a/b.txt")]));
+        assert_eq!(captures(&pattern, "/users/42/files/"), Some(vec![pair("id", "42"), pair("rest", "")]));
+        assert_eq!(captures(&pattern, "/users//files/a"), None);
+        assert_eq!(captures(&pattern, "/users/42"), None);
+
+        let glob = Matcher::Pattern("/**/*.php".to_string());
+        assert_eq!(captures(&glob, "/app/admin/index.php"), Some(vec![]));
+        assert_eq!(captures(&glob, "/index.php"), Some(vec![]));
+        assert_eq!(captures(&glob, "/index.html"), None);
+
+        let nested = Matcher::Pattern("/**/:section/index.html".to_string());
+        assert_eq!(captures(&nested, "/a/b/c/index.html"), Some(vec![pair("section", "c")]));
+    }
+
+    struct Echo;
+
+    impl Handler for Echo {
+        fn handle(&self, _: &Context, call: &Call) -> Result<Payload, Status> {
+            Ok(Payload::Block(format!("{} {:?}", call.path, call.param("id"))))
+        }
+    }
+
+    // Counts the requests it sees and refuses those without `X-Pass`.
+    struct Gate(Arc<AtomicUsize>);
+
+    impl Middleware for Gate {
+        fn apply(&self, context: &Context, call: &mut Call, next: Next) -> Result<Payload, Status> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            call.fields.push(("X-Gate".to_string(), "seen".to_string()));
+            if call.info.header("X-Pass").is_some() { next.run(context, call) } else { Err(Status::NotAuthorized) }
+        }
+    }
+
+    fn answer(routes: &Routes, method: &str, path: &str, headers: &[(&str, &str)]) -> (Result<String, Status>, Vec<(String, String)>) {
+        let info = RequestInfo {
+            method: method.to_string(),
+            headers: headers.iter().map(|&(name, value)| pair(name, value)).collect(),
+            ..RequestInfo::default()
+        };
+        let mut call = Call::new(path, &info, 0);
+        let result = routes.dispatch(&test_context(), &mut call).map(|payload| {
+            match payload {
+                Payload::Block(text) => text,
+                _ => panic!("unexpected payload")
+            }
+        });
+        (result, call.fields)
+    }
+
+    #[test]
+    fn dispatches_to_first_route_matching_path_and_method() {
+        let seen = Arc::new(AtomicUsize::new(0));
+        let routes = Routes::new(vec![
+            Route { methods: vec!["POST".to_string()], matcher: Matcher::Pattern("/users/:id".to_string()),
+                    middleware: vec![Arc::new(Gate(seen.clone()))], handler: Arc::new(Echo) },
+            Route { methods: vec!["GET".to_string(), "HEAD".to_string()], matcher: Matcher::Pattern("/users/:id".to_string()),
+                    middleware: vec![], handler: Arc::new(Echo) },
+            Route { methods: vec!["GET".to_string()], matcher: Matcher::Prefix("/files/private/".to_string()),
+                    middleware: vec![Arc::new(Gate(seen.clone()))], handler: Arc::new(Echo) },
+            Route { methods: vec![], matcher: Matcher::Prefix("/files/".to_string()), middleware: vec![], handler: Arc::new(Echo) }
+        ]);
+
+        assert_eq!(answer(&routes, "GET", "users/7", &[]).0, Ok("users/7 Some(\"7\")".to_string()));
+        assert_eq!(answer(&routes, "POST", "users/7", &[("X-Pass", "1")]),
+                   (Ok("users/7 Some(\"7\")".to_string()), vec![pair("X-Gate", "seen")]));
+        assert_eq!(answer(&routes, "POST", "users/7", &[]).0, Err(Status::NotAuthorized));
+        assert_eq!(seen.load(Ordering::SeqCst), 2);
+
+        assert_eq!(answer(&routes, "DELETE", "users/7", &[]),
+                   (Err(Status::Other(405, "Method Not Allowed".to_string())), vec![pair("Allow", "POST, GET, HEAD")]));
+        assert_eq!(answer(&routes, "DELETE", "files/a.txt", &[]).0, Ok("files/a.txt None".to_string()));
+        assert_eq!(answer(&routes, "POST", "files/private/a.txt", &[]),
+                   (Err(Status::Other(405, "Method Not Allowed".to_string())), vec![pair("Allow", "GET")]));
+        assert_eq!(answer(&routes, "GET", "other", &[]).0, Err(Status::FileNotFound));
+    }
+
+    // Takes the body from the connection, as a proxy does.
+    struct Upload;
+
+    impl Handler for Upload {
+        fn handle(&self, _: &Context, call: &Call) -> Result<Payload, Status> {
+            Ok(Payload::Block(call.path.clone()))
+        }
+
+        fn streams_body(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn tells_whether_the_route_for_a_request_streams_its_body() {
+        let routes = Routes::new(vec![
+            Route { methods: vec!["POST".to_string()], matcher: Matcher::Prefix("/app/".to_string()), middleware: vec![], handler: Arc::new(Upload) },
+            Route { methods: vec![], matcher: Matcher::Prefix("/".to_string()), middleware: vec![], handler: Arc::new(Echo) }
+        ]);
+
+        assert!(routes.streams_body("/app/upload", "POST"));
+        assert!(!routes.streams_body("/app/upload", "PUT"));
+        assert!(!routes.streams_body("/upload", "POST"));
+    }
+
+    #[test]
+    fn puts_configured_routes_before_standard_ones() {
+        let backends = vec![("php".to_string(), Address::Tcp("127.0.0.1:9000".to_string()), 1)];
+        let fastcgi = FastCgi::new(&backends, &[("**/*.php".to_string(), "php".to_string())], Duration::from_secs(1), 1 << 20);
+        let cgi = Cgi { dir: PathBuf::from("cgi-bin"), sandbox: Sandbox::default() };
+        let specs = vec![RouteSpec { methods: vec![], matcher: Matcher::Prefix("/docs/".to_string()), target: Target::Static, layers: vec![] }];
+        let routes = table(&specs, Some(cgi), &fastcgi, &Upstreams::default()).unwrap();
+
+        assert_eq!(routes.routes.iter().map(|route| route.matcher.clone()).collect::<Vec<Matcher>>(), vec![
+            Matcher::Prefix("/docs/".to_string()),
+            Matcher::Exact("/".to_string()),
+            Matcher::Exact("/server-status".to_string()),
+            Matcher::Pattern("/**/*.php".to_string()),
+            Matcher::Prefix("/cgi-bin/".to_string()),
+            Matcher::Prefix("/".to_string())
+        ]);
+        let cgi_route = vec![RouteSpec { methods: vec![], matcher: Matcher::Prefix("/run/".to_string()), target: Target::Cgi, layers: vec![] }];
+        assert!(table(&cgi_route, None, &fastcgi, &Upstreams::default()).is_err());
+    }
+}
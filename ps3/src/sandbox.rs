@@ -0,0 +1,359 @@
+use std::ffi::CString;
+use std::io::{ self, Read, Write };
+use std::os::unix::process::{ CommandExt, ExitStatusExt };
+use std::process::{ Child, Command };
+use std::sync::mpsc::{ channel, RecvTimeoutError };
+use std::thread;
+use std::time::{ Duration, Instant };
+use libc;
+use cmd_line::{ CmdIO, CmdLine };
+use external::run_chain;
+use ssi::DEFAULT_ERRMSG;
+
+// The only variable commands inherit from the server; everything else in
+// their environment is given to them explicitly.
+const PATH: &'static str = "/usr/local/bin:/usr/bin:/bin";
+
+// Variables a page may not give commands: they would choose which
+// executable runs, or what is loaded into it.
+pub fn is_reserved(name: &str) -> bool {
+    name == "PATH" || name.starts_with("LD_")
+}
+
+// Restrictions on the commands pages run with `#exec`. Each command runs in
+// its own process group with a cleared environment, and is killed along with
+// anything it started if it outlives `timeout`.
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+    // Executables that may be run, exactly as written in the page. Empty
+    // allows any.
+    pub allow: Vec<String>,
+    pub timeout: Duration,
+    pub cpu_secs: Option<u64>,
+    // Bytes of address space.
+    pub memory: Option<u64>,
+    pub open_files: Option<u64>,
+    // Counted across every process of the user the command runs as.
+    pub processes: Option<u64>,
+    // Bytes a command may write before it is killed.
+    pub max_output: usize,
+    // Uid and gid to run as, instead of the server's.
+    pub user: Option<(u32, u32)>,
+    // Shown in place of the output of a command that breaks these rules.
+    pub error_text: String
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Sandbox {
+            allow: vec![],
+            timeout: Duration::from_secs(10),
+            cpu_secs: None,
+            memory: None,
+            open_files: None,
+            processes: None,
+            max_output: 1 << 20,
+            user: None,
+            error_text: DEFAULT_ERRMSG.to_string()
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ExecError {
+    // The command broke a sandbox rule, and why.
+    Refused(String),
+    // The command could not be run.
+    Failed(String)
+}
+
+impl Sandbox {
+    // Runs a command, or a pipeline, to completion and returns what the last
+    // command wrote to stdout.
+    pub fn run(&self, cmds: &[CmdLine], env: &[(String, String)]) -> Result<Vec<u8>, ExecError> {
+        self.run_with_input(cmds, env, vec![])
+    }
+
+    // Like `run`, with `input` written to the first command's stdin.
+    pub fn run_with_input(&self, cmds: &[CmdLine], env: &[(String, String)], input: Vec<u8>) -> Result<Vec<u8>, ExecError> {
+        self.check(cmds)?;
+        let mut children = run_chain(cmds, self, env).map_err(ExecError::Failed)?;
+        let result = self.collect(&mut children, input);
+        stop(&children);
+        let killed = children.iter_mut()
+            .filter_map(|child| child.wait().ok().and_then(|status| status.signal()))
+            .find(|&signal| signal != libc::SIGPIPE);
+        match (result, killed) {
+            (Err(e), _) => Err(e),
+            (Ok(_), Some(signal)) => Err(ExecError::Refused(format!("killed by signal {}", signal))),
+            (Ok(stdout), None) => Ok(stdout)
+        }
+    }
+
+    fn check(&self, cmds: &[CmdLine]) -> Result<(), ExecError> {
+        for cmd in cmds {
+            if !self.allow.is_empty() && !self.allow.iter().any(|allowed| allowed == cmd.name) {
+                return Err(ExecError::Refused(format!("{} is not an allowed command", cmd.name)));
+            }
+            match (&cmd.stdin, &cmd.stdout) {
+                (&CmdIO::File(_), _) | (_, &CmdIO::File(_)) => {
+                    return Err(ExecError::Refused("commands may not redirect to or from files".to_string()));
+                }
+                _ => ()
+            }
+        }
+        Ok(())
+    }
+
+    // A command for `name` with these restrictions applied.
+    pub fn command(&self, name: &str, env: &[(String, String)]) -> Command {
+        let mut command = Command::new(name);
+        command.env_clear()
+            .envs(env.iter().filter(|&&(ref name, _)| !is_reserved(name)).cloned())
+            .env("PATH", PATH)
+            .process_group(0);
+        if let Some((uid, gid)) = self.user {
+            command.gid(gid).uid(uid);
+        }
+        let (cpu, memory, files, processes) = (self.cpu_secs, self.memory, self.open_files, self.processes);
+        macro_rules! limit {
+            ($resource:expr, $value:expr) => {
+                if let Some(value) = $value {
+                    let limit = libc::rlimit { rlim_cur: value as libc::rlim_t, rlim_max: value as libc::rlim_t };
+                    if libc::setrlimit($resource, &limit) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+            }
+        }
+        unsafe {
+            command.pre_exec(move || {
+                limit!(libc::RLIMIT_CPU, cpu);
+                limit!(libc::RLIMIT_AS, memory);
+                limit!(libc::RLIMIT_NOFILE, files);
+                limit!(libc::RLIMIT_NPROC, processes);
+                Ok(())
+            });
+        }
+        command
+    }
+
+    // Reads the pipeline's output until it finishes, the deadline passes or
+    // it writes too much.
+    fn collect(&self, children: &mut [Child], input: Vec<u8>) -> Result<Vec<u8>, ExecError> {
+        let deadline = Instant::now() + self.timeout;
+        for child in children.iter_mut().skip(1) {
+            drop(child.stdin.take());
+        }
+        // Written on its own thread so a command that does not read all its
+        // input cannot block the server.
+        if let Some(mut stdin) = children.first_mut().and_then(|child| child.stdin.take()) {
+            if !input.is_empty() {
+                thread::spawn(move || {
+                    let _ = stdin.write_all(&input);
+                });
+            }
+        }
+        let stdout = children.last_mut().and_then(|child| child.stdout.take());
+        let mut stdout = match stdout {
+            Some(stdout) => stdout,
+            None => return Err(ExecError::Failed("Could not open stdout".to_string()))
+        };
+        let max_output = self.max_output;
+        let (done, read) = channel();
+        thread::spawn(move || {
+            let mut output = vec![];
+            let result = (&mut stdout).take(max_output as u64 + 1).read_to_end(&mut output);
+            let _ = done.send(result.map(|_| output));
+        });
+        let output = match read.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(Ok(ref output)) if output.len() > max_output => {
+                return Err(ExecError::Refused(format!("wrote more than {} bytes", max_output)));
+            }
+            Ok(Ok(output)) => output,
+            Ok(Err(e)) => return Err(ExecError::Failed(e.to_string())),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => {
+                return Err(self.timed_out());
+            }
+        };
+        // The output is complete, but a command may have closed stdout and
+        // kept running.
+        while Instant::now() < deadline {
+            let mut running = false;
+            for child in children.iter_mut() {
+                running = running || child.try_wait().map(|status| status.is_none()).unwrap_or(false);
+            }
+            if !running {
+                return Ok(output);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        Err(self.timed_out())
+    }
+
+    fn timed_out(&self) -> ExecError {
+        ExecError::Refused(format!("ran for more than {}ms", self.timeout.as_millis()))
+    }
+}
+
+// Kills every process group in a pipeline, including anything its commands
+// started.
+pub fn stop(children: &[Child]) {
+    for child in children.iter() {
+        unsafe {
+            libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+        }
+    }
+}
+
+// Looks up the uid and gid for `ssi_exec_user`, which may be a user name or a
+// numeric uid.
+pub fn user(name: &str) -> Result<(u32, u32), String> {
+    let entry = match name.parse::<u32>() {
+        Ok(uid) => unsafe { libc::getpwuid(uid) },
+        Err(_) => {
+            let name = CString::new(name).map_err(|e| e.to_string())?;
+            unsafe { libc::getpwnam(name.as_ptr()) }
+        }
+    };
+    let (uid, gid) = if entry.is_null() {
+        match name.parse::<u32>() {
+            Ok(uid) => (uid, uid),
+            Err(_) => return Err(format!("no such user {}", name))
+        }
+    } else {
+        unsafe { ((*entry).pw_uid, (*entry).pw_gid) }
+    };
+    if uid == 0 {
+        Err(format!("{} is root", name))
+    } else {
+        Ok((uid, gid))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{ File, Permissions, create_dir_all, set_permissions };
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::{ Duration, Instant };
+    use libc;
+    use cmd_line::{ parse_command, CmdLine, ParsedCommand };
+    use super::{ ExecError, Sandbox };
+
+    fn run(sandbox: &Sandbox, line: &str) -> Result<String, ExecError> {
+        let cmds: Vec<CmdLine> = match parse_command(line).unwrap() {
+            ParsedCommand::SingleCommand(cmd) => vec![cmd],
+            ParsedCommand::PipeChain(cmds) => cmds
+        };
+        sandbox.run(&cmds, &[("GREETING".to_string(), "hi".to_string())])
+            .map(|stdout| String::from_utf8(stdout).unwrap())
+    }
+
+    fn refused(result: Result<String, ExecError>) -> String {
+        match result {
+            Err(ExecError::Refused(reason)) => reason,
+            other => panic!("Was not refused: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn runs_only_allowed_commands() {
+        let sandbox = Sandbox { allow: vec!["echo".to_string(), "/usr/bin/tr".to_string()], ..Sandbox::default() };
+
+        assert_eq!(run(&sandbox, "echo hi | /usr/bin/tr a-z A-Z"), Ok("HI\n".to_string()));
+        assert_eq!(refused(run(&sandbox, "printenv")), "printenv is not an allowed command");
+        assert_eq!(refused(run(&sandbox, "echo hi | tr a-z A-Z")), "tr is not an allowed command");
+        assert_eq!(refused(run(&Sandbox::default(), "echo hi > test/tmp/exec.txt")),
+                   "commands may not redirect to or from files");
+    }
+
+    #[test]
+    fn writes_input_to_first_command() {
+        let cmds: Vec<CmdLine> = match parse_command("cat | tr a-z A-Z").unwrap() {
+            ParsedCommand::PipeChain(cmds) => cmds,
+            _ => unreachable!()
+        };
+
+        assert_eq!(Sandbox::default().run_with_input(&cmds, &[], b"body".to_vec()), Ok(b"BODY".to_vec()));
+    }
+
+    #[test]
+    fn clears_environment() {
+        assert_eq!(run(&Sandbox::default(), "printenv"), Ok("GREETING=hi\nPATH=/usr/local/bin:/usr/bin:/bin\n".to_string()));
+    }
+
+    #[test]
+    fn pages_cannot_choose_where_commands_are_found() {
+        let dir = "test/tmp/sandbox/bin";
+        create_dir_all(dir).unwrap();
+        File::create(format!("{}/date", dir)).unwrap().write_all(b"#!/bin/sh\necho fake\n").unwrap();
+        set_permissions(format!("{}/date", dir), Permissions::from_mode(0o755)).unwrap();
+        let sandbox = Sandbox { allow: vec!["date".to_string()], ..Sandbox::default() };
+        let cmds = match parse_command("date +%Y").unwrap() {
+            ParsedCommand::SingleCommand(cmd) => vec![cmd],
+            _ => unreachable!()
+        };
+        let env = vec![("PATH".to_string(), dir.to_string()), ("LD_PRELOAD".to_string(), "/nowhere.so".to_string())];
+        let year = String::from_utf8(sandbox.run(&cmds, &env).unwrap()).unwrap();
+
+        assert!(year.trim().parse::<u32>().is_ok(), "ran {}", year);
+    }
+
+    #[test]
+    fn kills_commands_that_run_too_long() {
+        let sandbox = Sandbox { timeout: Duration::from_millis(200), ..Sandbox::default() };
+        let started = Instant::now();
+
+        assert_eq!(refused(run(&sandbox, "sleep 5")), "ran for more than 200ms");
+        assert_eq!(refused(run(&sandbox, "sleep 5 | echo early")), "ran for more than 200ms");
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn caps_output() {
+        let sandbox = Sandbox { max_output: 1000, ..Sandbox::default() };
+
+        assert_eq!(refused(run(&sandbox, "yes")), "wrote more than 1000 bytes");
+        assert_eq!(run(&sandbox, "yes | head -c 1000").map(|out| out.len()), Ok(1000));
+    }
+
+    #[test]
+    fn applies_resource_limits() {
+        let sandbox = Sandbox {
+            memory: Some(1 << 30),
+            open_files: Some(32),
+            processes: Some(4096),
+            cpu_secs: Some(30),
+            ..Sandbox::default()
+        };
+        let limits = run(&sandbox, "cat /proc/self/limits").unwrap();
+        let limit = |name: &str| {
+            limits.lines()
+                .find(|line| line.starts_with(name))
+                .map(|line| line[name.len()..].split_whitespace().next().unwrap().to_string())
+        };
+
+        assert_eq!(limit("Max cpu time"), Some("30".to_string()));
+        assert_eq!(limit("Max address space"), Some((1u64 << 30).to_string()));
+        assert_eq!(limit("Max open files"), Some("32".to_string()));
+        assert_eq!(limit("Max processes"), Some("4096".to_string()));
+    }
+
+    #[test]
+    fn kills_commands_over_their_cpu_limit() {
+        let sandbox = Sandbox { cpu_secs: Some(1), ..Sandbox::default() };
+
+        assert!(refused(run(&sandbox, "sha256sum /dev/zero")).starts_with("killed by signal"));
+    }
+
+    #[test]
+    fn runs_as_another_user() {
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let sandbox = Sandbox { user: Some((65534, 65534)), ..Sandbox::default() };
+
+        assert_eq!(run(&sandbox, "id -u"), Ok("65534\n".to_string()));
+    }
+}
@@ -3,14 +3,15 @@ use std::cmp::Ordering;
 use std::net::{ SocketAddr };
 use std::collections::BinaryHeap;
 use std::fs::File;
-use std::path::PathBuf;
+use std::path::Path as FsPath;
 
 use request::Request;
 use path::Path;
 use handler::Cache;
+use document_root::DocumentRoot;
 
-#[derive(Eq, PartialEq, Debug)]
-enum Priority {
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum Priority {
     High,
     Low
 }
@@ -67,30 +68,38 @@ pub fn queues<R>() -> (FastLane<R>, SlowLane<R>) where R: IpAddressable + Pathab
     (BinaryHeap::new(), BinaryHeap::new())
 }
 
-pub fn schedule<R>(cache: &Cache, request: R, high_queue: &mut FastLane<R>, low_queue: &mut SlowLane<R>)
+pub fn schedule<R>(cache: &Cache, root: &DocumentRoot, request: R, high_queue: &mut FastLane<R>, low_queue: &mut SlowLane<R>)
     where R: IpAddressable + Pathable {
     match priority(&request) {
-        Priority::High => high_queue.push(scheduled_request(cache, request)),
-        Priority::Low => low_queue.push(scheduled_request(cache, request))
+        Priority::High => high_queue.push(scheduled_request(cache, root, request)),
+        Priority::Low => low_queue.push(scheduled_request(cache, root, request))
     }
 }
 
-fn scheduled_request<R>(cache: &Cache, request: R) -> WeightedRequest<R>
+fn scheduled_request<R>(cache: &Cache, root: &DocumentRoot, request: R) -> WeightedRequest<R>
     where R: IpAddressable + Pathable{
 
-    let weight = weight(cache, &request.path());
+    let weight = weight(cache, root, request.path());
     WeightedRequest {
         weight: weight,
         request: request,
     }
 }
 
-fn weight(cache: &Cache, req_path: &io::Result<Path>) -> u64 {
+// Files are found the way they will be served, under the document root, so
+// the cache is asked about the same key the file handler uses. A path the
+// root would refuse weighs the most.
+fn weight(cache: &Cache, root: &DocumentRoot, req_path: &io::Result<Path>) -> u64 {
     match req_path {
         &Err(_) => 0,
         &Ok(Path::Root) => 1,
+        &Ok(Path::Status) => 1,
         &Ok(Path::RelPath(ref path)) => {
-            File::open(path)
+            let file = match root.resolve(FsPath::new(path)) {
+                Ok(file) => file,
+                Err(_) => return u64::MAX
+            };
+            File::open(&file)
                 .and_then(|f| f.metadata())
                 .map(|data| data.len())
                 .map(|size| {
@@ -102,9 +111,10 @@ fn weight(cache: &Cache, req_path: &io::Result<Path>) -> u64 {
 
                 })
                 .map(|weight| {
-                    match cache.lock().unwrap().get(&PathBuf::from(path)) {
-                        Some(_) => weight / 10,
-                        None => weight
+                    if cache.contains(&file) {
+                        weight / 10
+                    } else {
+                        weight
                     }
                 })
                 .unwrap_or(u64::max_value())
@@ -115,8 +125,14 @@ fn weight(cache: &Cache, req_path: &io::Result<Path>) -> u64 {
 fn priority(request: &IpAddressable) -> Priority {
     match request.ip_address() {
         Err(_) => Priority::Low,
-        Ok(SocketAddr::V6(_)) => Priority::Low,
-        Ok(SocketAddr::V4(address)) => {
+        Ok(address) => priority_of(&address)
+    }
+}
+
+pub fn priority_of(address: &SocketAddr) -> Priority {
+    match address {
+        &SocketAddr::V6(_) => Priority::Low,
+        &SocketAddr::V4(address) => {
             let octets = address.ip().octets();
             let prefix = (octets[0], octets[1]);
             if prefix == (128, 143) || prefix == (137, 54) {
@@ -133,7 +149,8 @@ mod test {
     use std::io;
     use std::io::Read;
     use std::fs::File;
-    use std::path::PathBuf;
+    use std::path::{ PathBuf, Path as StdPath };
+    use std::time::Instant;
     use std::net::{
         SocketAddr,
         SocketAddrV4,
@@ -143,15 +160,18 @@ mod test {
     };
     use path::Path;
     use handler::Cache;
-    use lru_cache::cache::LruCache;
-    use std::sync::{ Arc, Mutex };
+    use document_root::{ DocumentRoot, Hidden, Symlinks };
+    use cache::{ ShardedCache, Policy };
+    use freshness::{ CachedFile, Stamp };
+    use std::sync::Arc;
     use super::{
         IpAddressable,
         Pathable,
         Priority,
         priority,
         queues,
-        schedule
+        schedule,
+        weight
     };
 
     struct FakeRequest<'a> {
@@ -173,7 +193,7 @@ mod test {
     }
 
     fn new_cache() -> Cache {
-        Arc::new(Mutex::new(LruCache::new(512)))
+        Arc::new(ShardedCache::new(1 << 20, 1 << 16, Policy::Lru, 4))
     }
 
     #[test]
@@ -225,11 +245,12 @@ mod test {
 
         let (mut fast, mut slow) = queues();
         let cache = new_cache();
+        let root = DocumentRoot::default();
 
-        schedule(&cache, error_req, &mut fast, &mut slow);
-        schedule(&cache, big_req, &mut fast, &mut slow);
-        schedule(&cache, small_req, &mut fast, &mut slow);
-        schedule(&cache, root_req, &mut fast, &mut slow);
+        schedule(&cache, &root, error_req, &mut fast, &mut slow);
+        schedule(&cache, &root, big_req, &mut fast, &mut slow);
+        schedule(&cache, &root, small_req, &mut fast, &mut slow);
+        schedule(&cache, &root, root_req, &mut fast, &mut slow);
 
         let order = fast.into_sorted_vec().iter().map(|wr| wr.request.name).collect::<Vec<&str>>();
         assert_eq!(order, vec!["Error", "Root", "Small", "Big"]);
@@ -256,10 +277,11 @@ mod test {
 
         let (mut fast, mut slow) = queues();
         let cache = new_cache();
+        let root = DocumentRoot::default();
 
-        schedule(&cache, small_shtml_req, &mut fast, &mut slow);
-        schedule(&cache, small_req, &mut fast, &mut slow);
-        schedule(&cache, med_req, &mut fast, &mut slow);
+        schedule(&cache, &root, small_shtml_req, &mut fast, &mut slow);
+        schedule(&cache, &root, small_req, &mut fast, &mut slow);
+        schedule(&cache, &root, med_req, &mut fast, &mut slow);
 
         let order = fast.into_sorted_vec().iter().map(|wr| wr.request.name).collect::<Vec<&str>>();
         assert_eq!(order, vec!["Small", "Medium", "Dynamic"]);
@@ -281,15 +303,40 @@ mod test {
 
         let (mut fast, mut slow) = queues();
         let cache = new_cache();
+        let root = DocumentRoot::default();
         let mut cache_contents = Vec::new();
         let _ = File::open("test/cache_response.html")
             .and_then(|mut f| f.read_to_end(&mut cache_contents));
-        cache.lock().unwrap().put(PathBuf::from("test/cache_response.html"), cache_contents);
+        let cached_file = CachedFile {
+            bytes: Arc::from(cache_contents),
+            stamp: Stamp::of(StdPath::new("test/cache_response.html")).unwrap(),
+            checked: Instant::now()
+        };
+        cache.put(PathBuf::from("test/cache_response.html"), cached_file);
 
-        schedule(&cache, read, &mut fast, &mut slow);
-        schedule(&cache, cached, &mut fast, &mut slow);
+        schedule(&cache, &root, read, &mut fast, &mut slow);
+        schedule(&cache, &root, cached, &mut fast, &mut slow);
 
         let order = fast.into_sorted_vec().iter().map(|wr| wr.request.name).collect::<Vec<&str>>();
         assert_eq!(order, vec!["Cache Hit", "File IO"]);
     }
+
+    #[test]
+    fn weighs_files_under_the_document_root() {
+        let cache = new_cache();
+        let root = DocumentRoot::new(StdPath::new("test"), Symlinks::SameOwner, Hidden::Deny).unwrap();
+        let cached_file = CachedFile {
+            bytes: Arc::from(b"<p>cached</p>".to_vec()),
+            stamp: Stamp::of(StdPath::new("test/large.html")).unwrap(),
+            checked: Instant::now()
+        };
+        cache.put(PathBuf::from("test/large.html"), cached_file);
+        let weigh = |path: &str| weight(&cache, &root, &Ok(Path::RelPath(path.to_string())));
+
+        assert_eq!(weigh("small.html"), 25);
+        assert_eq!(weigh("small.shtml"), 2 * StdPath::new("test/small.shtml").metadata().unwrap().len());
+        assert_eq!(weigh("large.html"), StdPath::new("test/large.html").metadata().unwrap().len() / 10);
+        assert_eq!(weigh("../Cargo.toml"), u64::MAX);
+        assert_eq!(weigh("test/small.html"), u64::MAX);
+    }
 }
@@ -1,62 +1,131 @@
-use std::path::Path;
-use std::process::Child;
-use std::error::Error;
-use std::fs::File;
-use std::io::{ Read, BufReader };
-use regex::{ Regex, Captures };
+use std::path::{ Path, PathBuf };
+use std::io::Read;
+use std::sync::{ Arc, Mutex };
+use std::sync::mpsc::{ channel, Receiver, Sender };
+use std::thread;
+use std::time::Duration;
 use cmd_line::{ parse_command, ParsedCommand };
-use external::{ run, run_chain };
-use http::Payload;
+use freshness::Stamp;
+use http::{ Payload, Template };
+use sandbox::{ ExecError, Sandbox };
 
-lazy_static! {
-    static ref SHELL_REGEX: Regex = Regex::new(r#"<!--\s*#exec\s+(.+)-->"#).unwrap();
+// How long a rendered page may be served from the render cache. Pages set it
+// with `<!--#cache ttl="30" -->` or opt out with `<!--#cache off -->`, and an
+// `#exec` can shorten it with a leading `ttl="5"`; the shortest one wins.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CacheTtl {
+    Default,
+    For(Duration),
+    Never
 }
 
-pub fn insert_shell_commands(path: &Path, payload: Payload) -> Result<Payload, String> {
-    match payload {
-        Payload::Stream(file) => insert_shell_commands_file(path, file),
-        Payload::Block(string) => substitute_shell_command(string)
+impl CacheTtl {
+    pub fn seconds(secs: u64) -> CacheTtl {
+        if secs == 0 { CacheTtl::Never } else { CacheTtl::For(Duration::from_secs(secs)) }
+    }
+
+    pub fn shortest(self, other: CacheTtl) -> CacheTtl {
+        match (self, other) {
+            (CacheTtl::Never, _) | (_, CacheTtl::Never) => CacheTtl::Never,
+            (CacheTtl::Default, ttl) | (ttl, CacheTtl::Default) => ttl,
+            (CacheTtl::For(a), CacheTtl::For(b)) => CacheTtl::For(a.min(b))
+        }
     }
 }
 
-fn insert_shell_commands_file(path: &Path, mut file: BufReader<File>) -> Result<Payload, String> {
-    match path.extension().and_then(|e| e.to_str()) {
-        Some("shtml") => {
-            let mut contents = String::new();
-            match file.read_to_string(&mut contents) {
-                Ok(_) => substitute_shell_command(contents),
-                Err(e) => Err(e.description().to_string())
-            }
+// Reads a `.shtml` page into a template to be rendered as it is sent. Other
+// files are served untouched.
+pub fn prepare_template(path: &Path, payload: Payload, sources: Vec<(PathBuf, Stamp)>) -> Result<Payload, String> {
+    if !is_template(path) {
+        return Ok(payload);
+    }
+    let contents: Arc<[u8]> = match payload {
+        Payload::Stream(mut file) => {
+            let mut contents = vec![];
+            file.read_to_end(&mut contents).map_err(|e| e.to_string())?;
+            Arc::from(contents)
         }
-        _ => Ok(Payload::Stream(file))
+        Payload::Block(string) => Arc::from(string.into_bytes()),
+        Payload::Bytes(bytes) => bytes,
+        other @ Payload::Template(_) | other @ Payload::Response(_) | other @ Payload::Relay(_) => return Ok(other)
+    };
+    Ok(Payload::Template(Template { path: path.to_path_buf(), contents: contents, sources: sources }))
+}
+
+pub fn is_template(path: &Path) -> bool {
+    path.extension().and_then(|e| e.to_str()) == Some("shtml")
+}
+
+// A command line from a template and the variables to run it with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShellCommand {
+    pub line: String,
+    pub env: Vec<(String, String)>
+}
+
+// The output of the command, or for one that breaks the sandbox's rules, its
+// error text. The error text is markup and should not be escaped.
+pub fn run_shell_command(command: &ShellCommand, sandbox: &Sandbox) -> Result<String, String> {
+    let cmds = match parse_command(&command.line) {
+        Ok(ParsedCommand::SingleCommand(cmd)) => vec![cmd],
+        Ok(ParsedCommand::PipeChain(cmds)) => cmds,
+        Err(e) => return Ok(e)
+    };
+    match sandbox.run(&cmds, &command.env) {
+        Ok(stdout) => Ok(String::from_utf8(stdout).unwrap_or_else(|e| e.to_string())),
+        Err(ExecError::Refused(reason)) => {
+            println!("Refused to run [{}]: {}", command.line, reason);
+            Err(sandbox.error_text.clone())
+        }
+        Err(ExecError::Failed(e)) => Ok(e)
     }
 }
 
+type Job = (ShellCommand, Sender<Result<String, String>>);
 
-fn substitute_shell_command(contents: String) -> Result<Payload, String> {
-    let replaced_string = SHELL_REGEX.replace(&contents,
-        |captured: &Captures| {
-            match parse_command(&captured[1]) {
-                Ok(ParsedCommand::SingleCommand(cmd)) => {
-                    output(run(&cmd))
-                }
-                Ok(ParsedCommand::PipeChain(cmds)) => {
-                    output(run_chain(&cmds))
-                }
-                Err(e) => e
-            }
-        }).into_owned().to_string();
-    Ok(Payload::Block(replaced_string))
+// Runs the commands of one page on up to `parallelism` threads, started as
+// commands arrive. Threads exit once the pool is dropped and its queue is
+// empty.
+pub struct CommandPool {
+    jobs: Sender<Job>,
+    queue: Arc<Mutex<Receiver<Job>>>,
+    sandbox: Arc<Sandbox>,
+    parallelism: usize,
+    workers: usize
 }
 
-fn output(cmd: Result<Child, String>) -> String {
-    cmd.and_then(|c| c.wait_with_output().map_err(|e| e.description().to_string()))
-       .map(|c| c.stdout)
-       .and_then(|bytes| {
-           String::from_utf8(bytes)
-               .map_err(|e| e.description().to_string())
-       })
-       .unwrap_or_else(|e| e)
+impl CommandPool {
+    pub fn new(parallelism: usize, sandbox: &Sandbox) -> CommandPool {
+        let (jobs, queue) = channel();
+        CommandPool {
+            jobs: jobs,
+            queue: Arc::new(Mutex::new(queue)),
+            sandbox: Arc::new(sandbox.clone()),
+            parallelism: parallelism.max(1),
+            workers: 0
+        }
+    }
+
+    // Queues `command`; its result arrives on the returned receiver.
+    pub fn submit(&mut self, command: ShellCommand) -> Receiver<Result<String, String>> {
+        if self.workers < self.parallelism {
+            let queue = self.queue.clone();
+            let sandbox = self.sandbox.clone();
+            thread::spawn(move || loop {
+                let job = queue.lock().unwrap().recv();
+                match job {
+                    Ok((command, reply)) => {
+                        let _ = reply.send(run_shell_command(&command, &sandbox));
+                    }
+                    Err(_) => return
+                }
+            });
+            self.workers += 1;
+        }
+        let (reply, result) = channel();
+        let _ = self.jobs.send((command, reply));
+        result
+    }
 }
 
 #[cfg(test)]
@@ -64,8 +133,27 @@ mod test {
     use std::path::Path;
     use std::fs::File;
     use std::io::{ BufReader, Read };
+    use std::sync::Arc;
+    use std::time::Duration;
     use http::Payload;
-    use super::insert_shell_commands;
+    use sandbox::Sandbox;
+    use ssi::{ self, Environment, Settings };
+    use super::{ prepare_template, CacheTtl, CommandPool, ShellCommand };
+
+    fn render(template: &str) -> (String, CacheTtl) {
+        let page = ssi::render(Path::new("test/page.shtml"), template.as_bytes(), &Settings::default(), &Environment::default());
+        (String::from_utf8(page.html).unwrap(), page.ttl)
+    }
+
+    fn rendered(path: &Path, payload: Payload) -> String {
+        match prepare_template(path, payload, vec![]).unwrap() {
+            Payload::Template(template) => {
+                let page = ssi::render(&template.path, &template.contents, &Settings::default(), &Environment::default());
+                String::from_utf8(page.html).unwrap()
+            }
+            _ => panic!("Did not prepare template")
+        }
+    }
 
     #[test]
     fn pass_through_if_not_shtml() {
@@ -75,7 +163,7 @@ mod test {
         let _ = expect_file.read_to_string(&mut expected);
 
         let test_file = Payload::Stream(BufReader::new(File::open(&path).unwrap()));
-        let interpolated = insert_shell_commands(&path, test_file).unwrap();
+        let interpolated = prepare_template(path, test_file, vec![]).unwrap();
 
         match interpolated {
             Payload::Stream(mut bfr) => {
@@ -83,23 +171,76 @@ mod test {
                 let _  = bfr.read_to_string(&mut actual).unwrap();
                 assert_eq!(actual, expected);
             }
-            Payload::Block(_) => assert!(false, "Transformed file")
+            Payload::Block(_) | Payload::Template(_) | Payload::Response(_) | Payload::Relay(_) => panic!("Transformed file"),
+            Payload::Bytes(_) => panic!("Read file into memory")
         }
     }
 
     #[test]
-    fn executes_shell_command_in_interpolated_shtml_file() {
+    fn cached_files_pass_through_if_not_shtml() {
+        let path = Path::new("test/improper_template.html");
+        let cached = Payload::Bytes(Arc::from(&b"<!-- #exec echo hi -->"[..]));
+
+        match prepare_template(path, cached, vec![]).unwrap() {
+            Payload::Bytes(bytes) => assert_eq!(&bytes[..], &b"<!-- #exec echo hi -->"[..]),
+            _ => panic!("Transformed cached file")
+        }
+    }
+
+    #[test]
+    fn executes_shell_command_in_cached_shtml_file() {
         let path = Path::new("test/world.shtml");
-        let expected = "<h1>\"Hello World\"\n</h1>\n".to_string();
+        let cached = Payload::Bytes(Arc::from(&b"<h1><!-- #exec echo hi --></h1>"[..]));
+
+        assert_eq!(rendered(path, cached), "<h1>hi\n</h1>");
+    }
 
+    #[test]
+    fn executes_shell_command_in_interpolated_shtml_file() {
+        let path = Path::new("test/world.shtml");
         let test_file = Payload::Stream(BufReader::new(File::open(&path).unwrap()));
-        let interpolated = insert_shell_commands(&path, test_file).unwrap();
 
-        match interpolated {
-            Payload::Stream(_) =>  assert!(false, "Did not transform file"),
-            Payload::Block(interpolated) => {
-                assert_eq!(interpolated, expected);
-            }
+        assert_eq!(rendered(path, test_file), "<h1>\"Hello World\"\n</h1>\n");
+    }
+
+    #[test]
+    fn pages_set_and_opt_out_of_render_caching() {
+        assert_eq!(render("<p>plain</p>"), ("<p>plain</p>".to_string(), CacheTtl::Default));
+        assert_eq!(render("<!--#cache ttl=\"30\" --><p>x</p>"),
+                   ("<p>x</p>".to_string(), CacheTtl::For(Duration::from_secs(30))));
+        assert_eq!(render("<!--#cache off --><p>x</p>").1, CacheTtl::Never);
+        assert_eq!(render("<!--#cache ttl=\"0\" --><p>x</p>").1, CacheTtl::Never);
+    }
+
+    #[test]
+    fn shortest_directive_ttl_wins() {
+        let (html, ttl) = render("<!--#cache ttl=\"60\" --><h1><!-- #exec ttl=\"5\" echo hi --></h1>");
+
+        assert_eq!(html, "<h1>hi\n</h1>");
+        assert_eq!(ttl, CacheTtl::For(Duration::from_secs(5)));
+        assert_eq!(render("<!--#cache off --><!-- #exec ttl=\"5\" echo hi -->").1, CacheTtl::Never);
+    }
+
+    #[test]
+    fn untemplated_files_are_not_rendered() {
+        let cached = Payload::Bytes(Arc::from(&b"<p>static</p>"[..]));
+
+        match prepare_template(Path::new("test/a.html"), cached, vec![]).unwrap() {
+            Payload::Bytes(_) => (),
+            _ => panic!("Prepared an untemplated file")
+        }
+    }
+
+    #[test]
+    fn pool_returns_each_result_to_its_caller() {
+        let mut pool = CommandPool::new(3, &Sandbox::default());
+        let results = (0..6)
+            .map(|n| pool.submit(ShellCommand { line: format!("echo {}", n), env: vec![] }))
+            .collect::<Vec<_>>();
+
+        for (n, result) in results.into_iter().enumerate() {
+            assert_eq!(result.recv().unwrap(), Ok(format!("{}\n", n)));
         }
+        assert_eq!(pool.workers, 3);
     }
 }
@@ -0,0 +1,437 @@
+// Server-side include directives are HTML comments whose body starts with
+// `#name`, followed by `attribute="value"` pairs:
+//
+//     <!--#include virtual="/footer.html" -->
+//
+// `#exec` also accepts a bare command line (`<!-- #exec date -->`) for
+// templates written before attributes were supported.
+
+use ssi::expr::Expr;
+use sandbox::is_reserved;
+
+// The expression of an `#if` or `#elif`, or why it could not be parsed. A bad
+// condition is still a conditional, so that the blocks around it balance.
+pub type Condition = Result<Expr, String>;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Source {
+    // Relative to the directory of the page containing the directive.
+    File(String),
+    // A URL path, relative to the document root.
+    Virtual(String)
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SizeFormat {
+    Bytes,
+    Abbrev
+}
+
+// How `#echo` and `#exec` escape what they output.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Encoding {
+    None,
+    // HTML entities for `&`, `<`, `>` and quotes.
+    Entity,
+    // Percent-encoding of everything but unreserved URL characters.
+    Url
+}
+
+impl Encoding {
+    pub fn parse(name: &str) -> Result<Encoding, String> {
+        match name {
+            "none" => Ok(Encoding::None),
+            "entity" => Ok(Encoding::Entity),
+            "url" => Ok(Encoding::Url),
+            other => Err(format!("encoding must be none, entity or url, not {}", other))
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Directive {
+    Include(Source),
+    // Without an encoding, the site's default applies.
+    Echo {
+        var: String,
+        encoding: Option<Encoding>
+    },
+    Set {
+        var: String,
+        value: String
+    },
+    Fsize(Source),
+    Flastmod(Source),
+    Config {
+        timefmt: Option<String>,
+        sizefmt: Option<SizeFormat>,
+        errmsg: Option<String>
+    },
+    Exec {
+        command: String,
+        ttl: Option<u64>,
+        encoding: Option<Encoding>
+    },
+    // None opts the page out of render caching.
+    Cache(Option<u64>),
+    If(Condition),
+    Elif(Condition),
+    Else,
+    Endif
+}
+
+// Templates are handled as bytes, so pages in any ASCII-compatible encoding
+// pass through unchanged; only directives themselves must be UTF-8.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Segment<'a> {
+    Text(&'a [u8]),
+    Directive(Directive),
+    // A directive that could not be parsed, and why.
+    Malformed(String)
+}
+
+pub fn parse(template: &[u8]) -> Vec<Segment<'_>> {
+    let mut segments = vec![];
+    let mut text_start = 0;
+    let mut cursor = 0;
+    while let Some(found) = find(&template[cursor..], b"<!--") {
+        let open = cursor + found;
+        let body_start = open + 4;
+        let directive_start = template[body_start..].iter()
+            .position(|b| !b.is_ascii_whitespace())
+            .map(|skipped| body_start + skipped);
+        if directive_start.map(|start| template[start]) != Some(b'#') {
+            cursor = body_start;
+            continue;
+        }
+        let close = match find(&template[body_start..], b"-->") {
+            Some(close) => body_start + close,
+            None => break
+        };
+        if open > text_start {
+            segments.push(Segment::Text(&template[text_start..open]));
+        }
+        let body = ::std::str::from_utf8(&template[body_start..close])
+            .map_err(|_| "directive is not valid UTF-8".to_string())
+            .and_then(|body| parse_directive(body.trim()));
+        segments.push(match body {
+            Ok(directive) => Segment::Directive(directive),
+            Err(reason) => Segment::Malformed(reason)
+        });
+        cursor = close + 3;
+        text_start = cursor;
+    }
+    if text_start < template.len() {
+        segments.push(Segment::Text(&template[text_start..]));
+    }
+    segments
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+fn parse_directive(body: &str) -> Result<Directive, String> {
+    let body = &body[1..];
+    let name_end = body.find(char::is_whitespace).unwrap_or(body.len());
+    let (name, args) = (&body[..name_end], body[name_end..].trim());
+    match name {
+        "exec" => parse_exec(args),
+        "cache" if args == "off" => Ok(Directive::Cache(None)),
+        "cache" => {
+            attributes(args)
+                .and_then(|attrs| only(name, attrs, &["ttl"]))
+                .and_then(|attrs| required(name, &attrs, "ttl"))
+                .and_then(|ttl| parse_ttl(&ttl))
+                .map(|ttl| Directive::Cache(Some(ttl)))
+        }
+        "include" => attributes(args).and_then(|attrs| source(name, attrs)).map(Directive::Include),
+        "fsize" => attributes(args).and_then(|attrs| source(name, attrs)).map(Directive::Fsize),
+        "flastmod" => attributes(args).and_then(|attrs| source(name, attrs)).map(Directive::Flastmod),
+        "echo" => {
+            attributes(args)
+                .and_then(|attrs| only(name, attrs, &["var", "encoding"]))
+                .and_then(|attrs| {
+                    let encoding = match lookup(&attrs, "encoding") {
+                        Some(encoding) => Some(Encoding::parse(encoding)?),
+                        None => None
+                    };
+                    Ok(Directive::Echo { var: required(name, &attrs, "var")?, encoding: encoding })
+                })
+        }
+        "set" => {
+            attributes(args)
+                .and_then(|attrs| only(name, attrs, &["var", "value"]))
+                .and_then(|attrs| {
+                    let var = required(name, &attrs, "var")?;
+                    if var.is_empty() || !var.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                        return Err(format!("{} is not a valid variable name", var));
+                    }
+                    if is_reserved(&var) {
+                        return Err(format!("{} may not be set", var));
+                    }
+                    Ok(Directive::Set { var: var, value: required(name, &attrs, "value")? })
+                })
+        }
+        "config" => {
+            attributes(args)
+                .and_then(|attrs| only(name, attrs, &["timefmt", "sizefmt", "errmsg"]))
+                .and_then(|attrs| {
+                    if attrs.is_empty() {
+                        return Err("#config needs timefmt, sizefmt or errmsg".to_string());
+                    }
+                    let sizefmt = match lookup(&attrs, "sizefmt") {
+                        None => None,
+                        Some("bytes") => Some(SizeFormat::Bytes),
+                        Some("abbrev") => Some(SizeFormat::Abbrev),
+                        Some(other) => return Err(format!("sizefmt must be bytes or abbrev, not {}", other))
+                    };
+                    Ok(Directive::Config {
+                        timefmt: lookup(&attrs, "timefmt").map(|s| s.to_string()),
+                        sizefmt: sizefmt,
+                        errmsg: lookup(&attrs, "errmsg").map(|s| s.to_string())
+                    })
+                })
+        }
+        "if" => Ok(Directive::If(condition(name, args))),
+        "elif" => Ok(Directive::Elif(condition(name, args))),
+        "else" | "endif" if !args.is_empty() => Err(format!("#{} takes no attributes", name)),
+        "else" => Ok(Directive::Else),
+        "endif" => Ok(Directive::Endif),
+        "" => Err("directive has no name".to_string()),
+        other => Err(format!("unknown directive #{}", other))
+    }
+}
+
+// `#exec` takes optional leading `ttl` and `encoding` attributes, then either
+// `cmd="..."` or the bare command line.
+fn parse_exec(args: &str) -> Result<Directive, String> {
+    let (mut ttl, mut encoding, mut rest) = (None, None, args);
+    loop {
+        if let Some((value, after)) = leading_attribute(rest, "ttl") {
+            ttl = Some(parse_ttl(&value)?);
+            rest = after;
+        } else if let Some((value, after)) = leading_attribute(rest, "encoding") {
+            encoding = Some(Encoding::parse(&value)?);
+            rest = after;
+        } else {
+            break;
+        }
+    }
+    let command = match leading_attribute(rest, "cmd") {
+        Some((value, rest)) if rest.trim().is_empty() => value,
+        Some(_) => return Err("#exec takes only cmd, ttl and encoding".to_string()),
+        None => rest.trim().to_string()
+    };
+    if command.is_empty() {
+        Err("#exec needs a command".to_string())
+    } else {
+        Ok(Directive::Exec { command: command, ttl: ttl, encoding: encoding })
+    }
+}
+
+fn condition(directive: &str, args: &str) -> Condition {
+    attributes(args)
+        .and_then(|attrs| only(directive, attrs, &["expr"]))
+        .and_then(|attrs| required(directive, &attrs, "expr"))
+        .and_then(|expr| Expr::parse(&expr))
+}
+
+fn parse_ttl(value: &str) -> Result<u64, String> {
+    value.parse::<u64>().map_err(|_| format!("{} is not a valid ttl", value))
+}
+
+fn leading_attribute<'a>(args: &'a str, name: &str) -> Option<(String, &'a str)> {
+    let args = args.trim_start();
+    if args.starts_with(name) && args[name.len()..].starts_with('=') {
+        attribute_value(&args[name.len() + 1..]).ok()
+    } else {
+        None
+    }
+}
+
+fn attributes(args: &str) -> Result<Vec<(String, String)>, String> {
+    let mut attrs = vec![];
+    let mut rest = args.trim_start();
+    while !rest.is_empty() {
+        let name_end = rest.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(rest.len());
+        let name = &rest[..name_end];
+        if name.is_empty() || !rest[name_end..].starts_with('=') {
+            return Err(format!("expected attribute=\"value\" at {}", rest));
+        }
+        let (value, after) = attribute_value(&rest[name_end + 1..])?;
+        attrs.push((name.to_string(), value));
+        rest = after.trim_start();
+    }
+    Ok(attrs)
+}
+
+// A quoted value may contain its quote character escaped with a backslash.
+fn attribute_value(input: &str) -> Result<(String, &str), String> {
+    match input.chars().next() {
+        Some(quote) if quote == '"' || quote == '\'' => {
+            let mut value = String::new();
+            let mut escaped = false;
+            for (idx, c) in input.char_indices().skip(1) {
+                if escaped {
+                    if c != quote && c != '\\' {
+                        value.push('\\');
+                    }
+                    value.push(c);
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == quote {
+                    return Ok((value, &input[idx + 1..]));
+                } else {
+                    value.push(c);
+                }
+            }
+            Err("unterminated attribute value".to_string())
+        }
+        _ => {
+            let end = input.find(char::is_whitespace).unwrap_or(input.len());
+            Ok((input[..end].to_string(), &input[end..]))
+        }
+    }
+}
+
+fn only(directive: &str, attrs: Vec<(String, String)>, allowed: &[&str]) -> Result<Vec<(String, String)>, String> {
+    match attrs.iter().find(|&&(ref name, _)| !allowed.contains(&&name[..])) {
+        Some(&(ref name, _)) => Err(format!("#{} does not take {}", directive, name)),
+        None => Ok(attrs)
+    }
+}
+
+fn lookup<'a>(attrs: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    attrs.iter().find(|&&(ref n, _)| n == name).map(|&(_, ref value)| &value[..])
+}
+
+fn required(directive: &str, attrs: &[(String, String)], name: &str) -> Result<String, String> {
+    lookup(attrs, name)
+        .map(|value| value.to_string())
+        .ok_or_else(|| format!("#{} needs {}", directive, name))
+}
+
+fn source(directive: &str, attrs: Vec<(String, String)>) -> Result<Source, String> {
+    let attrs = only(directive, attrs, &["file", "virtual"])?;
+    match (lookup(&attrs, "file"), lookup(&attrs, "virtual")) {
+        (Some(file), None) => Ok(Source::File(file.to_string())),
+        (None, Some(virt)) => Ok(Source::Virtual(virt.to_string())),
+        _ => Err(format!("#{} needs exactly one of file or virtual", directive))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ssi::expr::{ Comparison, Expr };
+    use super::{ parse, Directive, Encoding, Segment, SizeFormat, Source };
+
+    fn directive(template: &str) -> Directive {
+        match parse(template.as_bytes()).pop() {
+            Some(Segment::Directive(directive)) => directive,
+            other => panic!("{} parsed as {:?}", template, other)
+        }
+    }
+
+    fn malformed(template: &str) -> String {
+        match parse(template.as_bytes()).pop() {
+            Some(Segment::Malformed(reason)) => reason,
+            other => panic!("{} parsed as {:?}", template, other)
+        }
+    }
+
+    #[test]
+    fn splits_text_around_directives() {
+        assert_eq!(parse(b"<h1><!--#echo var=\"DATE_GMT\" --></h1><!-- plain comment -->"),
+                   vec![Segment::Text(&b"<h1>"[..]),
+                        Segment::Directive(Directive::Echo { var: "DATE_GMT".to_string(), encoding: None }),
+                        Segment::Text(&b"</h1><!-- plain comment -->"[..])]);
+        assert_eq!(parse(b"no directives"), vec![Segment::Text(&b"no directives"[..])]);
+        assert_eq!(parse(b"<!--#echo var=\"x\""), vec![Segment::Text(&b"<!--#echo var=\"x\""[..])]);
+    }
+
+    #[test]
+    fn passes_other_encodings_through() {
+        assert_eq!(parse(b"caf\xe9 <!--#echo var=\"x\" -->\xff"),
+                   vec![Segment::Text(&b"caf\xe9 "[..]),
+                        Segment::Directive(Directive::Echo { var: "x".to_string(), encoding: None }),
+                        Segment::Text(&b"\xff"[..])]);
+        assert_eq!(parse(b"<!--#echo var=\"\xe9\" -->"), vec![Segment::Malformed("directive is not valid UTF-8".to_string())]);
+    }
+
+    #[test]
+    fn parses_sources_and_formats() {
+        assert_eq!(directive("<!--#include file=\"header.html\" -->"),
+                   Directive::Include(Source::File("header.html".to_string())));
+        assert_eq!(directive("<!-- #include virtual='/test/a b.html'-->"),
+                   Directive::Include(Source::Virtual("/test/a b.html".to_string())));
+        assert_eq!(directive("<!--#fsize file=large.html -->"),
+                   Directive::Fsize(Source::File("large.html".to_string())));
+        assert_eq!(directive("<!--#flastmod virtual=\"/index.html\" -->"),
+                   Directive::Flastmod(Source::Virtual("/index.html".to_string())));
+        assert_eq!(directive("<!--#config timefmt=\"%Y\" sizefmt=\"bytes\" errmsg=\"[\\\"oops\\\"]\" -->"),
+                   Directive::Config {
+                       timefmt: Some("%Y".to_string()),
+                       sizefmt: Some(SizeFormat::Bytes),
+                       errmsg: Some("[\"oops\"]".to_string())
+                   });
+    }
+
+    #[test]
+    fn parses_exec_with_or_without_attributes() {
+        assert_eq!(directive("<!-- #exec echo \"Hello World\" -->"),
+                   Directive::Exec { command: "echo \"Hello World\"".to_string(), ttl: None, encoding: None });
+        assert_eq!(directive("<!--#exec cmd=\"ls -l\" -->"),
+                   Directive::Exec { command: "ls -l".to_string(), ttl: None, encoding: None });
+        assert_eq!(directive("<!--#exec ttl=\"5\" date -->"),
+                   Directive::Exec { command: "date".to_string(), ttl: Some(5), encoding: None });
+        assert_eq!(directive("<!--#cache off -->"), Directive::Cache(None));
+        assert_eq!(directive("<!--#cache ttl=30 -->"), Directive::Cache(Some(30)));
+    }
+
+    #[test]
+    fn parses_encodings() {
+        assert_eq!(directive("<!--#echo encoding=\"url\" var=\"QUERY_STRING\" -->"),
+                   Directive::Echo { var: "QUERY_STRING".to_string(), encoding: Some(Encoding::Url) });
+        assert_eq!(directive("<!--#exec encoding=\"entity\" ttl=\"5\" cmd=\"date\" -->"),
+                   Directive::Exec { command: "date".to_string(), ttl: Some(5), encoding: Some(Encoding::Entity) });
+        assert_eq!(directive("<!--#exec encoding=none ls -->"),
+                   Directive::Exec { command: "ls".to_string(), ttl: None, encoding: Some(Encoding::None) });
+        assert_eq!(malformed("<!--#echo var=\"x\" encoding=\"base64\" -->"), "encoding must be none, entity or url, not base64");
+    }
+
+    #[test]
+    fn parses_set() {
+        assert_eq!(directive("<!--#set var=\"title\" value=\"${DOCUMENT_NAME} page\" -->"),
+                   Directive::Set { var: "title".to_string(), value: "${DOCUMENT_NAME} page".to_string() });
+    }
+
+    #[test]
+    fn parses_conditionals() {
+        assert_eq!(directive("<!--#if expr=\"$QUERY_STRING = 'a b'\" -->"),
+                   Directive::If(Ok(Expr::Compare("$QUERY_STRING".to_string(), Comparison::Equal, "a b".to_string()))));
+        assert_eq!(directive("<!--#elif expr=\"$DEBUG\" -->"), Directive::Elif(Ok(Expr::Text("$DEBUG".to_string()))));
+        assert_eq!(directive("<!--#else -->"), Directive::Else);
+        assert_eq!(directive("<!--#endif-->"), Directive::Endif);
+        assert_eq!(directive("<!--#if -->"), Directive::If(Err("#if needs expr".to_string())));
+        assert_eq!(directive("<!--#if expr=\"(a\" -->"), Directive::If(Err("missing ) in expression".to_string())));
+        assert_eq!(malformed("<!--#endif x=\"y\" -->"), "#endif takes no attributes");
+    }
+
+    #[test]
+    fn explains_malformed_directives() {
+        assert_eq!(malformed("<!--#include -->"), "#include needs exactly one of file or virtual");
+        assert_eq!(malformed("<!--#include file=\"a\" virtual=\"b\" -->"), "#include needs exactly one of file or virtual");
+        assert_eq!(malformed("<!--#echo name=\"x\" -->"), "#echo does not take name");
+        assert_eq!(malformed("<!--#exec cmd=\"ls\" encoding=\"url\" -->"), "#exec takes only cmd, ttl and encoding");
+        assert_eq!(malformed("<!--#set var=\"x\" -->"), "#set needs value");
+        assert_eq!(malformed("<!--#set var=\"a-b\" value=\"\" -->"), "a-b is not a valid variable name");
+        assert_eq!(malformed("<!--#set var=\"PATH\" value=\"/tmp\" -->"), "PATH may not be set");
+        assert_eq!(malformed("<!--#set var=\"LD_PRELOAD\" value=\"x.so\" -->"), "LD_PRELOAD may not be set");
+        assert_eq!(malformed("<!--#config sizefmt=\"huge\" -->"), "sizefmt must be bytes or abbrev, not huge");
+        assert_eq!(malformed("<!--#echo var=\"x -->"), "unterminated attribute value");
+        assert_eq!(malformed("<!--#exec -->"), "#exec needs a command");
+        assert_eq!(malformed("<!--#bogus -->"), "unknown directive #bogus");
+    }
+}
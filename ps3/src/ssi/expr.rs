@@ -0,0 +1,338 @@
+use regex::Regex;
+
+// Conditions for `#if` and `#elif`, in Apache's classic SSI syntax:
+//
+//     <!--#if expr="$QUERY_STRING = /debug/ && ${DOCUMENT_NAME} != 'index.shtml'" -->
+//
+// Strings are quoted with `'` or `"`, or are bare words; `$NAME` and
+// `${NAME}` inside them are replaced with variables when the condition is
+// evaluated. A string on its own is true when it is not empty. `=`, `==`,
+// `!=`, `<`, `<=`, `>` and `>=` compare strings, and `=` or `!=` followed by
+// `/regex/` tests for a match. `!`, `&&`, `||` and parentheses combine them,
+// with `&&` binding tighter than `||`.
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual
+}
+
+// Compared by source, so that parsed directives can be compared in tests.
+#[derive(Debug, Clone)]
+pub struct Pattern(Regex);
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Pattern) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Eq for Pattern {}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Expr {
+    Text(String),
+    Compare(String, Comparison, String),
+    Matches(String, Pattern),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>)
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Token {
+    Text(String),
+    Pattern(String),
+    Compare(Comparison),
+    Not,
+    And,
+    Or,
+    Open,
+    Close
+}
+
+impl Expr {
+    pub fn parse(source: &str) -> Result<Expr, String> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser { tokens: &tokens, next: 0 };
+        let expr = parser.or()?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(token) => Err(format!("unexpected {} in expression", describe(token)))
+        }
+    }
+
+    // `lookup` gives the value of a variable, or None if it is not set; unset
+    // variables are empty.
+    pub fn evaluate<F: Fn(&str) -> Option<String>>(&self, lookup: &F) -> bool {
+        match self {
+            &Expr::Text(ref text) => !interpolate(text, lookup).is_empty(),
+            &Expr::Compare(ref left, comparison, ref right) => {
+                let (left, right) = (interpolate(left, lookup), interpolate(right, lookup));
+                match comparison {
+                    Comparison::Equal => left == right,
+                    Comparison::NotEqual => left != right,
+                    Comparison::Less => left < right,
+                    Comparison::LessOrEqual => left <= right,
+                    Comparison::Greater => left > right,
+                    Comparison::GreaterOrEqual => left >= right
+                }
+            }
+            &Expr::Matches(ref text, Pattern(ref regex)) => regex.is_match(&interpolate(text, lookup)),
+            &Expr::Not(ref expr) => !expr.evaluate(lookup),
+            &Expr::And(ref left, ref right) => left.evaluate(lookup) && right.evaluate(lookup),
+            &Expr::Or(ref left, ref right) => left.evaluate(lookup) || right.evaluate(lookup)
+        }
+    }
+}
+
+// Replaces `$NAME` and `${NAME}` with variables; `\$` is a literal dollar.
+pub fn interpolate<F: Fn(&str) -> Option<String>>(text: &str, lookup: &F) -> String {
+    let mut result = String::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '\\' if chars.peek().map(|&(_, next)| next) == Some('$') => {
+                result.push('$');
+                chars.next();
+            }
+            '$' => {
+                let rest = &text[idx + 1..];
+                let (name, len) = if rest.starts_with('{') {
+                    match rest.find('}') {
+                        Some(end) => (&rest[1..end], end + 1),
+                        None => ("", 0)
+                    }
+                } else {
+                    let end = rest.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(rest.len());
+                    (&rest[..end], end)
+                };
+                if name.is_empty() {
+                    result.push('$');
+                } else {
+                    result.push_str(&lookup(name).unwrap_or_default());
+                    while chars.peek().map(|&(next, _)| next <= idx + len).unwrap_or(false) {
+                        chars.next();
+                    }
+                }
+            }
+            c => result.push(c)
+        }
+    }
+    result
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut rest = source.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let after_comparison = matches!(tokens.last(), Some(&Token::Compare(Comparison::Equal)) | Some(&Token::Compare(Comparison::NotEqual)));
+        let (token, len) = match c {
+            '(' => (Token::Open, 1),
+            ')' => (Token::Close, 1),
+            '&' if rest.starts_with("&&") => (Token::And, 2),
+            '|' if rest.starts_with("||") => (Token::Or, 2),
+            '!' if rest.starts_with("!=") => (Token::Compare(Comparison::NotEqual), 2),
+            '!' => (Token::Not, 1),
+            '=' if rest.starts_with("==") => (Token::Compare(Comparison::Equal), 2),
+            '=' => (Token::Compare(Comparison::Equal), 1),
+            '<' if rest.starts_with("<=") => (Token::Compare(Comparison::LessOrEqual), 2),
+            '<' => (Token::Compare(Comparison::Less), 1),
+            '>' if rest.starts_with(">=") => (Token::Compare(Comparison::GreaterOrEqual), 2),
+            '>' => (Token::Compare(Comparison::Greater), 1),
+            '/' if after_comparison => {
+                let (pattern, len) = delimited(rest, '/').ok_or("unterminated regular expression")?;
+                (Token::Pattern(pattern), len)
+            }
+            '\'' | '"' => {
+                let (text, len) = delimited(rest, c).ok_or("unterminated string")?;
+                (Token::Text(text), len)
+            }
+            '&' | '|' => return Err(format!("{} must be doubled", c)),
+            _ => {
+                let len = rest.find(|c: char| c.is_whitespace() || "()&|!=<>'\"".contains(c)).unwrap_or(rest.len());
+                (Token::Text(rest[..len].to_string()), len)
+            }
+        };
+        tokens.push(token);
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+// The text between `delimiter` at the start of `input` and the next unescaped
+// one, and the length consumed including both delimiters.
+fn delimited(input: &str, delimiter: char) -> Option<(String, usize)> {
+    let mut text = String::new();
+    let mut escaped = false;
+    for (idx, c) in input.char_indices().skip(1) {
+        if escaped {
+            if c != delimiter {
+                text.push('\\');
+            }
+            text.push(c);
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == delimiter {
+            return Some((text, idx + 1));
+        } else {
+            text.push(c);
+        }
+    }
+    None
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        &Token::Text(ref text) => format!("'{}'", text),
+        &Token::Pattern(ref pattern) => format!("/{}/", pattern),
+        &Token::Compare(_) => "comparison".to_string(),
+        &Token::Not => "!".to_string(),
+        &Token::And => "&&".to_string(),
+        &Token::Or => "||".to_string(),
+        &Token::Open => "(".to_string(),
+        &Token::Close => ")".to_string()
+    }
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    next: usize
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.next)
+    }
+
+    fn advance(&mut self) -> Option<&'a Token> {
+        let token = self.tokens.get(self.next);
+        self.next += 1;
+        token
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.advance();
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.advance();
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.advance() {
+            Some(&Token::Not) => self.unary().map(|expr| Expr::Not(Box::new(expr))),
+            Some(&Token::Open) => {
+                let expr = self.or()?;
+                match self.advance() {
+                    Some(&Token::Close) => Ok(expr),
+                    _ => Err("missing ) in expression".to_string())
+                }
+            }
+            Some(&Token::Text(ref left)) => self.comparison(left),
+            Some(token) => Err(format!("unexpected {} in expression", describe(token))),
+            None => Err("expression ends too soon".to_string())
+        }
+    }
+
+    fn comparison(&mut self, left: &str) -> Result<Expr, String> {
+        let comparison = match self.peek() {
+            Some(&Token::Compare(comparison)) => comparison,
+            _ => return Ok(Expr::Text(left.to_string()))
+        };
+        self.advance();
+        match self.advance() {
+            Some(&Token::Text(ref right)) => Ok(Expr::Compare(left.to_string(), comparison, right.clone())),
+            Some(&Token::Pattern(ref pattern)) => {
+                let regex = Regex::new(pattern).map_err(|e| format!("bad regular expression /{}/: {}", pattern, e))?;
+                let matches = Expr::Matches(left.to_string(), Pattern(regex));
+                Ok(if comparison == Comparison::NotEqual { Expr::Not(Box::new(matches)) } else { matches })
+            }
+            Some(token) => Err(format!("unexpected {} in expression", describe(token))),
+            None => Err("expression ends too soon".to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ interpolate, Expr };
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "QUERY_STRING" => Some("debug=1&page=2".to_string()),
+            "DOCUMENT_NAME" => Some("index.shtml".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None
+        }
+    }
+
+    fn holds(expr: &str) -> bool {
+        Expr::parse(expr).unwrap().evaluate(&lookup)
+    }
+
+    #[test]
+    fn interpolates_variables() {
+        assert_eq!(interpolate("$DOCUMENT_NAME!", &lookup), "index.shtml!");
+        assert_eq!(interpolate("${DOCUMENT_NAME}s", &lookup), "index.shtmls");
+        assert_eq!(interpolate("a${NOPE}b $ \\$EMPTY", &lookup), "ab $ $EMPTY");
+    }
+
+    #[test]
+    fn compares_strings() {
+        assert!(holds("$DOCUMENT_NAME = index.shtml"));
+        assert!(holds("'$DOCUMENT_NAME' == \"index.shtml\""));
+        assert!(holds("${DOCUMENT_NAME} != 'about.shtml'"));
+        assert!(holds("abc < abd"));
+        assert!(holds("b >= a"));
+        assert!(!holds("b <= a"));
+    }
+
+    #[test]
+    fn tests_truth_of_strings() {
+        assert!(holds("$QUERY_STRING"));
+        assert!(!holds("$EMPTY"));
+        assert!(!holds("$UNSET"));
+        assert!(holds("!$UNSET"));
+    }
+
+    #[test]
+    fn matches_regular_expressions() {
+        assert!(holds("$QUERY_STRING = /debug=1/"));
+        assert!(holds("$QUERY_STRING != /^page/"));
+        assert!(holds("$DOCUMENT_NAME = /\\.shtml$/"));
+        assert!(holds("/a/b = /^\\/a\\//"));
+    }
+
+    #[test]
+    fn combines_with_boolean_operators() {
+        assert!(holds("$EMPTY || $QUERY_STRING = /page/ && $DOCUMENT_NAME = index.shtml"));
+        assert!(!holds("($EMPTY || $QUERY_STRING) && $DOCUMENT_NAME = about.shtml"));
+        assert!(holds("!($EMPTY && $QUERY_STRING)"));
+    }
+
+    #[test]
+    fn explains_syntax_errors() {
+        assert_eq!(Expr::parse(""), Err("expression ends too soon".to_string()));
+        assert_eq!(Expr::parse("(a = b"), Err("missing ) in expression".to_string()));
+        assert_eq!(Expr::parse("a = b c"), Err("unexpected 'c' in expression".to_string()));
+        assert_eq!(Expr::parse("a & b"), Err("& must be doubled".to_string()));
+        assert_eq!(Expr::parse("'a"), Err("unterminated string".to_string()));
+        assert!(Expr::parse("a = /(/").unwrap_err().starts_with("bad regular expression /(/"));
+    }
+}
@@ -0,0 +1,113 @@
+use std::ffi::CString;
+use std::mem;
+use std::time::{ SystemTime, UNIX_EPOCH };
+use libc;
+use ssi::directive::{ Encoding, SizeFormat };
+
+// Apache's default `timefmt`.
+pub const DEFAULT_TIMEFMT: &'static str = "%A, %d-%b-%Y %H:%M:%S %Z";
+
+// Formats `time` with a strftime(3) pattern, in local time or UTC.
+pub fn format_time(time: SystemTime, timefmt: &str, local: bool) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0) as libc::time_t;
+    let format = match CString::new(timefmt) {
+        Ok(format) => format,
+        Err(_) => return String::new()
+    };
+    let mut buffer = vec![0u8; 256];
+    let written = unsafe {
+        let mut tm: libc::tm = mem::zeroed();
+        if local {
+            libc::localtime_r(&secs, &mut tm);
+        } else {
+            libc::gmtime_r(&secs, &mut tm);
+        }
+        libc::strftime(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len(), format.as_ptr(), &tm)
+    };
+    String::from_utf8_lossy(&buffer[..written]).into_owned()
+}
+
+// `bytes` groups digits with commas; `abbrev` rounds to K or M past 1024.
+pub fn format_size(len: u64, sizefmt: SizeFormat) -> String {
+    match sizefmt {
+        SizeFormat::Bytes => {
+            let digits = len.to_string();
+            let mut grouped = String::new();
+            for (idx, digit) in digits.chars().enumerate() {
+                if idx > 0 && (digits.len() - idx).is_multiple_of(3) {
+                    grouped.push(',');
+                }
+                grouped.push(digit);
+            }
+            grouped
+        }
+        SizeFormat::Abbrev if len < 1024 => len.to_string(),
+        SizeFormat::Abbrev if len < 1024 * 1024 => format!("{}K", (len + 512) / 1024),
+        SizeFormat::Abbrev => format!("{:.1}M", len as f64 / (1024.0 * 1024.0))
+    }
+}
+
+pub fn encode(text: &str, encoding: Encoding) -> String {
+    match encoding {
+        Encoding::None => text.to_string(),
+        Encoding::Entity => {
+            let mut encoded = String::with_capacity(text.len());
+            for c in text.chars() {
+                match c {
+                    '&' => encoded.push_str("&amp;"),
+                    '<' => encoded.push_str("&lt;"),
+                    '>' => encoded.push_str("&gt;"),
+                    '"' => encoded.push_str("&quot;"),
+                    '\'' => encoded.push_str("&#39;"),
+                    c => encoded.push(c)
+                }
+            }
+            encoded
+        }
+        Encoding::Url => {
+            let mut encoded = String::with_capacity(text.len());
+            for &byte in text.as_bytes() {
+                match byte {
+                    b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+                    byte => encoded.push_str(&format!("%{:02X}", byte))
+                }
+            }
+            encoded
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{ Duration, UNIX_EPOCH };
+    use ssi::directive::{ Encoding, SizeFormat };
+    use super::{ encode, format_size, format_time };
+
+    #[test]
+    fn formats_sizes() {
+        assert_eq!(format_size(40647, SizeFormat::Bytes), "40,647");
+        assert_eq!(format_size(1234567, SizeFormat::Bytes), "1,234,567");
+        assert_eq!(format_size(999, SizeFormat::Bytes), "999");
+        assert_eq!(format_size(25, SizeFormat::Abbrev), "25");
+        assert_eq!(format_size(40647, SizeFormat::Abbrev), "40K");
+        assert_eq!(format_size(3 * 1024 * 1024 + 200_000, SizeFormat::Abbrev), "3.2M");
+    }
+
+    #[test]
+    fn formats_times_with_strftime_patterns() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+
+        assert_eq!(format_time(time, "%Y-%m-%d %H:%M:%S", false), "2001-09-09 01:46:40");
+        assert_eq!(format_time(time, "%A, %d-%b-%Y", false), "Sunday, 09-Sep-2001");
+    }
+
+    #[test]
+    fn encodes_output() {
+        let text = "<a href=\"/?q=1&r=2\">it's</a>";
+
+        assert_eq!(encode(text, Encoding::None), text);
+        assert_eq!(encode(text, Encoding::Entity), "&lt;a href=&quot;/?q=1&amp;r=2&quot;&gt;it&#39;s&lt;/a&gt;");
+        assert_eq!(encode("a b/é?", Encoding::Url), "a%20b%2F%C3%A9%3F");
+        assert_eq!(encode("safe-_.~09", Encoding::Url), "safe-_.~09");
+    }
+}
@@ -0,0 +1,789 @@
+use std::cell::Cell;
+use std::collections::{ HashMap, VecDeque };
+use std::fs::{ self, File };
+use std::io::{ self, Read, Write };
+use std::path::{ Path, PathBuf };
+use std::sync::mpsc::{ Receiver, TryRecvError };
+use std::time::SystemTime;
+use document_root::DocumentRoot;
+use freshness::Stamp;
+use handler::valid_file_type;
+use request::RequestInfo;
+use sandbox::Sandbox;
+use shell_interpolation::{ CacheTtl, CommandPool, ShellCommand, is_template };
+
+pub mod directive;
+mod expr;
+pub mod format;
+
+use self::directive::{ parse, Condition, Directive, Encoding, Segment, SizeFormat, Source };
+use self::expr::interpolate;
+use self::format::{ encode, format_size, format_time, DEFAULT_TIMEFMT };
+
+// Includes may nest this deep, counting the page itself.
+const MAX_INCLUDE_DEPTH: usize = 8;
+pub const DEFAULT_ERRMSG: &'static str = "[an error occurred while processing this directive]";
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    // At most this many `#exec` commands of a page run at once.
+    pub exec_parallelism: usize,
+    pub sandbox: Sandbox,
+    // For `#echo` and `#exec` directives without an `encoding` attribute.
+    pub encoding: Encoding,
+    // Where `virtual` includes are found, and which no include may leave.
+    pub root: DocumentRoot
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings { exec_parallelism: 4, sandbox: Sandbox::default(), encoding: Encoding::None, root: DocumentRoot::default() }
+    }
+}
+
+// Variables every page can `#echo`, and which `#exec` commands see in their
+// environment.
+const BUILT_IN: &'static [&'static str] = &[
+    "DOCUMENT_NAME", "DOCUMENT_URI", "QUERY_STRING", "REMOTE_ADDR", "HTTP_USER_AGENT",
+    "DATE_LOCAL", "DATE_GMT", "LAST_MODIFIED", "VISITOR_COUNT"
+];
+// Built-in variables that can differ between requests for the same page. A
+// page that reads one is not render cached.
+const PER_REQUEST: &'static [&'static str] = &[
+    "DOCUMENT_URI", "QUERY_STRING", "REMOTE_ADDR", "HTTP_USER_AGENT", "VISITOR_COUNT"
+];
+// Each query parameter is also a variable, named with this prefix.
+const QUERY_PARAM: &'static str = "QUERY_PARAM_";
+
+// The request a page is rendered for.
+#[derive(Debug, Clone, Default)]
+pub struct Environment {
+    pub request: RequestInfo,
+    pub visitor_count: usize
+}
+
+#[cfg(test)]
+pub struct Page {
+    pub html: Vec<u8>,
+    pub ttl: CacheTtl,
+    // Every file read while rendering other than the page itself, stamped
+    // before it was read.
+    pub sources: Vec<(PathBuf, Stamp)>
+}
+
+// What is known about a page once it has been streamed.
+pub struct Outcome {
+    pub ttl: CacheTtl,
+    pub sources: Vec<(PathBuf, Stamp)>
+}
+
+struct State<'a> {
+    document: &'a Path,
+    root: &'a DocumentRoot,
+    env: &'a Environment,
+    encoding: Encoding,
+    // Set with `#set`; these hide built-in variables of the same name.
+    variables: HashMap<String, String>,
+    // Whether a per-request variable was read.
+    varies: Cell<bool>,
+    timefmt: String,
+    sizefmt: SizeFormat,
+    errmsg: String,
+    ttl: CacheTtl,
+    sources: Vec<(PathBuf, Stamp)>,
+    including: Vec<PathBuf>
+}
+
+// Output waiting on a command, in document order.
+enum Pending {
+    Text(Vec<u8>),
+    Command(Receiver<Result<String, String>>, Encoding)
+}
+
+// Writes a page as it is laid out. Text goes straight to the sink until an
+// `#exec` is reached; after that it is held back until the commands before it
+// have finished, while the rest of the page is laid out and later commands
+// start.
+struct Output<'a> {
+    sink: &'a mut dyn Write,
+    pending: VecDeque<Pending>,
+    pool: CommandPool,
+    // The first write that failed. Nothing is written after it.
+    error: Option<io::Error>
+}
+
+impl<'a> Output<'a> {
+    fn text(&mut self, text: &[u8]) {
+        self.write_ready(false);
+        if self.pending.is_empty() {
+            self.write(text);
+            return;
+        }
+        if let Some(&mut Pending::Text(ref mut held)) = self.pending.back_mut() {
+            held.extend_from_slice(text);
+            return;
+        }
+        self.pending.push_back(Pending::Text(text.to_vec()));
+    }
+
+    fn command(&mut self, command: ShellCommand, encoding: Encoding) {
+        let result = self.pool.submit(command);
+        self.pending.push_back(Pending::Command(result, encoding));
+    }
+
+    // Writes whatever is no longer waiting on a command, waiting for the
+    // commands if `block` is set.
+    fn write_ready(&mut self, block: bool) {
+        while let Some(next) = self.pending.pop_front() {
+            match next {
+                Pending::Text(text) => self.write(&text),
+                Pending::Command(result, encoding) => {
+                    let output = if block {
+                        result.recv().ok()
+                    } else {
+                        match result.try_recv() {
+                            Ok(output) => Some(output),
+                            Err(TryRecvError::Disconnected) => None,
+                            Err(TryRecvError::Empty) => {
+                                self.pending.push_front(Pending::Command(result, encoding));
+                                return;
+                            }
+                        }
+                    };
+                    match output {
+                        Some(Ok(output)) => self.write(encode(&output, encoding).as_bytes()),
+                        Some(Err(error_text)) => self.write(error_text.as_bytes()),
+                        None => ()
+                    }
+                }
+            }
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        if self.error.is_none() && !bytes.is_empty() {
+            if let Err(e) = self.sink.write_all(bytes).and_then(|_| self.sink.flush()) {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.write_ready(true);
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(())
+        }
+    }
+}
+
+// Renders a page into memory.
+#[cfg(test)]
+pub fn render(document: &Path, contents: &[u8], settings: &Settings, env: &Environment) -> Page {
+    let mut html = vec![];
+    let outcome = stream(document, contents, settings, env, &mut html)
+        .expect("writing to memory cannot fail");
+    Page { html: html, ttl: outcome.ttl, sources: outcome.sources }
+}
+
+// Renders a page into `sink`, writing each part as soon as everything before
+// it is known. Only an `#include` holds up the rest of the page while it is
+// read; `#exec` commands run in the background.
+pub fn stream(document: &Path, contents: &[u8], settings: &Settings, env: &Environment, sink: &mut dyn Write) -> io::Result<Outcome> {
+    let mut state = State {
+        document: document,
+        root: &settings.root,
+        env: env,
+        encoding: settings.encoding,
+        variables: HashMap::new(),
+        varies: Cell::new(false),
+        timefmt: DEFAULT_TIMEFMT.to_string(),
+        sizefmt: SizeFormat::Abbrev,
+        errmsg: DEFAULT_ERRMSG.to_string(),
+        ttl: CacheTtl::Default,
+        sources: vec![],
+        including: vec![]
+    };
+    let mut out = Output {
+        sink: sink,
+        pending: VecDeque::new(),
+        pool: CommandPool::new(settings.exec_parallelism, &settings.sandbox),
+        error: None
+    };
+    render_into(&mut state, document, contents, &mut out);
+    out.finish()?;
+    if state.varies.get() {
+        state.ttl = CacheTtl::Never;
+    }
+    Ok(Outcome { ttl: state.ttl, sources: state.sources })
+}
+
+// One `#if` block. Conditionals must balance within each file.
+struct Branch {
+    // Whether the current arm is being output.
+    active: bool,
+    // Whether an earlier arm was output, or the whole block is skipped, so no
+    // later arm may be.
+    taken: bool,
+    after_else: bool
+}
+
+fn render_into(state: &mut State, current: &Path, contents: &[u8], out: &mut Output) {
+    state.including.push(current.to_path_buf());
+    let mut branches: Vec<Branch> = vec![];
+    for segment in parse(contents) {
+        let outputting = branches.last().map(|branch| branch.active).unwrap_or(true);
+        let result = match segment {
+            Segment::Directive(Directive::If(condition)) => {
+                let holds = if outputting { holds(state, condition) } else { Ok(false) };
+                let active = *holds.as_ref().unwrap_or(&false);
+                branches.push(Branch { active: active, taken: active || !outputting, after_else: false });
+                holds.map(|_| ())
+            }
+            Segment::Directive(Directive::Elif(condition)) => {
+                match branches.last_mut() {
+                    None => Err("#elif without #if".to_string()),
+                    Some(ref branch) if branch.after_else => Err("#elif after #else".to_string()),
+                    Some(branch) => {
+                        let holds = if branch.taken { Ok(false) } else { holds(state, condition) };
+                        branch.active = *holds.as_ref().unwrap_or(&false);
+                        branch.taken = branch.taken || branch.active;
+                        holds.map(|_| ())
+                    }
+                }
+            }
+            Segment::Directive(Directive::Else) => {
+                match branches.last_mut() {
+                    None => Err("#else without #if".to_string()),
+                    Some(ref branch) if branch.after_else => Err("#else after #else".to_string()),
+                    Some(branch) => {
+                        branch.active = !branch.taken;
+                        branch.taken = true;
+                        branch.after_else = true;
+                        Ok(())
+                    }
+                }
+            }
+            Segment::Directive(Directive::Endif) => {
+                branches.pop().map(|_| ()).ok_or("#endif without #if".to_string())
+            }
+            _ if !outputting => Ok(()),
+            Segment::Text(text) => {
+                out.text(text);
+                Ok(())
+            }
+            Segment::Directive(directive) => apply(state, current, directive, out),
+            Segment::Malformed(reason) => Err(reason)
+        };
+        if let Err(reason) = result {
+            error(state, current, &reason, out);
+        }
+    }
+    if !branches.is_empty() {
+        error(state, current, "#if without #endif", out);
+    }
+    state.including.pop();
+}
+
+fn error(state: &State, current: &Path, reason: &str, out: &mut Output) {
+    println!("SSI error in [{}]: {}", current.display(), reason);
+    out.text(state.errmsg.as_bytes());
+}
+
+fn holds(state: &State, condition: Condition) -> Result<bool, String> {
+    condition.map(|expr| expr.evaluate(&|name: &str| variable(state, name)))
+}
+
+fn apply(state: &mut State, current: &Path, directive: Directive, out: &mut Output) -> Result<(), String> {
+    match directive {
+        Directive::Include(source) => include(state, current, &source, out),
+        Directive::Echo { var, encoding } => {
+            let value = variable(state, &var).unwrap_or_else(|| "(none)".to_string());
+            out.text(encode(&value, encoding.unwrap_or(state.encoding)).as_bytes());
+            Ok(())
+        }
+        Directive::Set { var, value } => {
+            let value = interpolate(&value, &|name: &str| variable(state, name));
+            state.variables.insert(var, value);
+            Ok(())
+        }
+        Directive::Fsize(source) => {
+            resolve(state.root, current, &source)
+                .and_then(|path| fs::metadata(&path).map_err(|e| format!("{}: {}", path.display(), e)))
+                .map(|data| out.text(format_size(data.len(), state.sizefmt).as_bytes()))
+        }
+        Directive::Flastmod(source) => {
+            resolve(state.root, current, &source)
+                .and_then(|path| modified(&path))
+                .map(|time| out.text(format_time(time, &state.timefmt, true).as_bytes()))
+        }
+        Directive::Config { timefmt, sizefmt, errmsg } => {
+            if let Some(timefmt) = timefmt {
+                state.timefmt = timefmt;
+            }
+            if let Some(sizefmt) = sizefmt {
+                state.sizefmt = sizefmt;
+            }
+            if let Some(errmsg) = errmsg {
+                state.errmsg = errmsg;
+            }
+            Ok(())
+        }
+        Directive::Exec { command, ttl, encoding } => {
+            state.ttl = state.ttl.shortest(ttl.map(CacheTtl::seconds).unwrap_or(CacheTtl::Default));
+            if names_request_variable(&command) {
+                state.varies.set(true);
+            }
+            let command = ShellCommand { line: command, env: environment(state) };
+            out.command(command, encoding.unwrap_or(state.encoding));
+            Ok(())
+        }
+        Directive::Cache(ttl) => {
+            state.ttl = state.ttl.shortest(ttl.map(CacheTtl::seconds).unwrap_or(CacheTtl::Never));
+            Ok(())
+        }
+        Directive::If(_) | Directive::Elif(_) | Directive::Else | Directive::Endif => {
+            unreachable!("conditionals are handled while rendering")
+        }
+    }
+}
+
+fn include(state: &mut State, current: &Path, source: &Source, out: &mut Output) -> Result<(), String> {
+    let path = resolve(state.root, current, source)?;
+    if !valid_file_type(&path) {
+        return Err(format!("{} is not a file type that can be included", path.display()));
+    }
+    if state.including.contains(&path) {
+        return Err(format!("{} includes itself", path.display()));
+    }
+    if state.including.len() >= MAX_INCLUDE_DEPTH {
+        return Err(format!("includes nested more than {} deep at {}", MAX_INCLUDE_DEPTH, path.display()));
+    }
+    let stamp = Stamp::of(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut contents = vec![];
+    File::open(&path)
+        .and_then(|mut f| f.read_to_end(&mut contents))
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    state.sources.push((path.clone(), stamp));
+    if is_template(&path) {
+        render_into(state, &path, &contents, out);
+    } else {
+        out.text(&contents);
+    }
+    Ok(())
+}
+
+// `file` sources are relative to the page containing the directive and
+// `virtual` ones to the document root; neither may leave the root.
+fn resolve(root: &DocumentRoot, current: &Path, source: &Source) -> Result<PathBuf, String> {
+    let relative = match source {
+        &Source::File(ref file) => {
+            let dir = current.parent().and_then(|dir| root.relative(dir));
+            dir.map(|dir| dir.join(file)).ok_or(format!("{} is outside the document root", current.display()))?
+        }
+        &Source::Virtual(ref uri) => PathBuf::from(uri.trim_start_matches('/'))
+    };
+    if relative.components().next().is_none() {
+        return Err("an include names no file".to_string());
+    }
+    root.resolve(&relative).map_err(|denied| format!("{} is {}", relative.display(), denied))
+}
+
+fn modified(path: &Path) -> Result<SystemTime, String> {
+    fs::metadata(path)
+        .and_then(|data| data.modified())
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn variable(state: &State, name: &str) -> Option<String> {
+    if let Some(value) = state.variables.get(name) {
+        return Some(value.clone());
+    }
+    if PER_REQUEST.contains(&name) || name.starts_with(QUERY_PARAM) {
+        state.varies.set(true);
+    }
+    built_in(state, name)
+}
+
+fn built_in(state: &State, name: &str) -> Option<String> {
+    let request = &state.env.request;
+    match name {
+        "DATE_LOCAL" => Some(format_time(SystemTime::now(), &state.timefmt, true)),
+        "DATE_GMT" => Some(format_time(SystemTime::now(), &state.timefmt, false)),
+        "DOCUMENT_NAME" => state.document.file_name().map(|name| name.to_string_lossy().into_owned()),
+        "DOCUMENT_URI" => Some(request.uri.clone()),
+        "QUERY_STRING" => Some(request.query.clone()),
+        "REMOTE_ADDR" => request.remote_addr.map(|address| address.to_string()),
+        "HTTP_USER_AGENT" => request.user_agent.clone(),
+        "LAST_MODIFIED" => modified(state.document).ok().map(|time| format_time(time, &state.timefmt, true)),
+        "VISITOR_COUNT" => Some(state.env.visitor_count.to_string()),
+        _ if name.starts_with(QUERY_PARAM) => request.param(&name[QUERY_PARAM.len()..]).map(|value| value.to_string()),
+        _ => None
+    }
+}
+
+// Whether a command line mentions a request-specific variable, as a command
+// that reads one from its environment has to. Commands that do not are taken
+// to give the same output for every request.
+fn names_request_variable(line: &str) -> bool {
+    PER_REQUEST.iter().any(|name| line.contains(name)) || line.contains(QUERY_PARAM)
+}
+
+// Built-in and `#set` variables, for the environment of an `#exec`.
+fn environment(state: &State) -> Vec<(String, String)> {
+    let mut env = BUILT_IN.iter()
+        .filter_map(|&name| built_in(state, name).map(|value| (name.to_string(), value)))
+        .collect::<Vec<(String, String)>>();
+    env.extend(state.env.request.params.iter().map(|&(ref name, ref value)| (format!("{}{}", QUERY_PARAM, name), value.clone())));
+    env.extend(state.variables.iter().map(|(name, value)| (name.clone(), value.clone())));
+    env
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{ File, create_dir_all, remove_file };
+    use std::io::{ self, Read, Write };
+    use std::net::{ IpAddr, Ipv4Addr };
+    use std::path::{ Path, PathBuf };
+    use std::time::{ Duration, Instant };
+    use freshness::Stamp;
+    use request::RequestInfo;
+    use sandbox::Sandbox;
+    use shell_interpolation::CacheTtl;
+    use super::directive::Encoding;
+    use super::{ stream, Environment, Settings, DEFAULT_ERRMSG };
+
+    struct Page {
+        html: String,
+        ttl: CacheTtl,
+        sources: Vec<(PathBuf, Stamp)>
+    }
+
+    fn render(document: &Path, contents: &str, settings: &Settings, env: &Environment) -> Page {
+        let page = super::render(document, contents.as_bytes(), settings, env);
+        Page { html: String::from_utf8(page.html).unwrap(), ttl: page.ttl, sources: page.sources }
+    }
+
+    fn render_page(path: &str, contents: &str) -> Page {
+        render(Path::new(path), contents, &Settings::default(), &Environment::default())
+    }
+
+    fn visit() -> Environment {
+        Environment {
+            request: RequestInfo {
+                uri: "/test/ssi/page.shtml".to_string(),
+                query: "q=rust".to_string(),
+                params: vec![("q".to_string(), "rust".to_string())],
+                remote_addr: Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 7))),
+                user_agent: Some("curl/7.54".to_string()),
+                ..RequestInfo::default()
+            },
+            visitor_count: 42
+        }
+    }
+
+    fn render_fixture(path: &str) -> String {
+        let mut contents = String::new();
+        File::open(path).unwrap().read_to_string(&mut contents).unwrap();
+        render_page(path, &contents).html
+    }
+
+    #[test]
+    fn includes_files_and_virtual_paths() {
+        let page = render_fixture("test/ssi/page.shtml");
+
+        assert_eq!(page, "<header>Header</header>\n\n\
+                          <p>page.shtml</p>\n\
+                          <footer>24 bytes</footer>\n\n");
+    }
+
+    #[test]
+    fn records_included_files_as_sources() {
+        let mut contents = String::new();
+        File::open("test/ssi/page.shtml").unwrap().read_to_string(&mut contents).unwrap();
+        let page = render_page("test/ssi/page.shtml", &contents);
+
+        let sources = page.sources.iter().map(|&(ref path, _)| path.clone()).collect::<Vec<PathBuf>>();
+        assert_eq!(sources, vec![PathBuf::from("test/ssi/header.html"), PathBuf::from("test/ssi/footer.shtml")]);
+        assert_eq!(page.ttl, CacheTtl::Default);
+    }
+
+    #[test]
+    fn stops_recursive_and_unsafe_includes() {
+        let looped = render_fixture("test/ssi/loop.shtml");
+        assert_eq!(looped, format!("<p>{}</p>\n", DEFAULT_ERRMSG));
+
+        let escaped = render_fixture("test/ssi/escape.shtml");
+        assert_eq!(escaped, format!("{0}\n{0}\n{0}\n", DEFAULT_ERRMSG));
+    }
+
+    #[test]
+    fn limits_include_depth() {
+        create_dir_all("test/tmp/ssi_depth").unwrap();
+        for level in 0..10 {
+            let next = format!("<!--#include file=\"level{}.shtml\" -->", level + 1);
+            File::create(format!("test/tmp/ssi_depth/level{}.shtml", level)).unwrap()
+                .write_all(format!("{}{}", level, next).as_bytes()).unwrap();
+        }
+        File::create("test/tmp/ssi_depth/level10.shtml").unwrap().write_all(b"bottom").unwrap();
+
+        let page = render_fixture("test/tmp/ssi_depth/level0.shtml");
+        for level in 0..11 {
+            let _ = remove_file(format!("test/tmp/ssi_depth/level{}.shtml", level));
+        }
+
+        assert_eq!(page, format!("01234567{}", DEFAULT_ERRMSG));
+    }
+
+    #[test]
+    fn applies_config_to_later_directives() {
+        let page = render_fixture("test/ssi/config.shtml");
+
+        assert_eq!(page, "40K\n40,647\n[oops]\n");
+    }
+
+    #[test]
+    fn echoes_document_variables() {
+        let page = render_page("test/ssi/page.shtml",
+                               "<!--#config timefmt=\"%Y\" --><!--#echo var=\"DOCUMENT_NAME\" --> \
+                                <!--#echo var=\"DATE_GMT\" --> <!--#echo var=\"NOPE\" -->").html;
+        let words = page.split(' ').collect::<Vec<&str>>();
+
+        assert_eq!(words[0], "page.shtml");
+        assert!(words[1].parse::<u32>().unwrap() >= 2017);
+        assert_eq!(words[2], "(none)");
+    }
+
+    #[test]
+    fn formats_last_modified_times() {
+        let page = render_page("test/ssi/page.shtml",
+                               "<!--#config timefmt=\"%Y\" --><!--#flastmod file=\"header.html\" -->").html;
+
+        assert!(page.parse::<u32>().unwrap() >= 2017);
+    }
+
+    #[test]
+    fn replaces_every_exec_in_document_order() {
+        let page = render_page("test/ssi/page.shtml",
+                               "<!--#exec echo one -->,<!--#exec cmd=\"echo two\" -->,<!--#exec echo three -->").html;
+
+        assert_eq!(page, "one\n,two\n,three\n");
+    }
+
+    #[test]
+    fn runs_execs_in_included_files_in_place() {
+        create_dir_all("test/tmp/ssi_exec").unwrap();
+        File::create("test/tmp/ssi_exec/inner.shtml").unwrap()
+            .write_all(b"[<!--#exec echo inner -->]").unwrap();
+
+        let page = render_page("test/tmp/ssi_exec/outer.shtml",
+                               "<!--#exec echo before --><!--#include file=\"inner.shtml\" --><!--#exec echo after -->").html;
+        let _ = remove_file("test/tmp/ssi_exec/inner.shtml");
+
+        assert_eq!(page, "before\n[inner\n]after\n");
+    }
+
+    #[test]
+    fn runs_execs_concurrently_up_to_the_limit() {
+        let template = "<!--#exec sleep 0.3 --><!--#exec sleep 0.3 --><!--#exec sleep 0.3 -->";
+
+        let started = Instant::now();
+        render(Path::new("test/ssi/page.shtml"), template, &Settings { exec_parallelism: 3, ..Settings::default() }, &Environment::default());
+        assert!(started.elapsed() < Duration::from_millis(800));
+
+        let started = Instant::now();
+        render(Path::new("test/ssi/page.shtml"), template, &Settings { exec_parallelism: 1, ..Settings::default() }, &Environment::default());
+        assert!(started.elapsed() >= Duration::from_millis(900));
+    }
+
+    #[test]
+    fn outputs_first_arm_whose_condition_holds() {
+        let template = "<!--#if expr=\"$DOCUMENT_NAME = about.shtml\" -->about\
+                        <!--#elif expr=\"$DOCUMENT_NAME = /^page/\" -->page\
+                        <!--#elif expr=\"$DOCUMENT_NAME\" -->named\
+                        <!--#else -->other<!--#endif -->";
+
+        assert_eq!(render_page("test/ssi/page.shtml", template).html, "page");
+        assert_eq!(render_page("test/ssi/about.shtml", template).html, "about");
+        assert_eq!(render_page("test/ssi/other.shtml", template).html, "named");
+    }
+
+    #[test]
+    fn skips_directives_in_untaken_arms() {
+        let template = "<!--#if expr=\"$NOPE\" --><!--#include file=\"missing.html\" --><!--#exec echo no -->\
+                        <!--#if expr=\"a\" -->inner<!--#else -->inner else<!--#endif -->\
+                        <!--#else -->[<!--#if expr=\"a = b\" -->no<!--#elif expr=\"b\" -->nested<!--#endif -->]\
+                        <!--#endif -->";
+
+        assert_eq!(render_page("test/ssi/page.shtml", template).html, "[nested]");
+    }
+
+    #[test]
+    fn reports_unbalanced_conditionals() {
+        let error = |template: &str| render_page("test/ssi/page.shtml", template).html;
+
+        assert_eq!(error("a<!--#endif -->b"), format!("a{}b", DEFAULT_ERRMSG));
+        assert_eq!(error("<!--#else -->b"), format!("{}b", DEFAULT_ERRMSG));
+        assert_eq!(error("<!--#if expr=\"a\" -->a"), format!("a{}", DEFAULT_ERRMSG));
+        assert_eq!(error("<!--#if expr=\"a\" -->a<!--#else -->b<!--#else -->c<!--#endif -->"),
+                   format!("a{}", DEFAULT_ERRMSG));
+        assert_eq!(error("<!--#if expr=\"a\" -->a<!--#else -->b<!--#elif expr=\"c\" -->c<!--#endif -->"),
+                   format!("a{}", DEFAULT_ERRMSG));
+        assert_eq!(error("<!--#if expr=\"(a\" -->a<!--#else -->b<!--#endif -->"), format!("{}b", DEFAULT_ERRMSG));
+    }
+
+    #[test]
+    fn conditionals_balance_within_each_included_file() {
+        create_dir_all("test/tmp/ssi_if").unwrap();
+        File::create("test/tmp/ssi_if/open.shtml").unwrap().write_all(b"<!--#if expr=\"a\" -->open").unwrap();
+
+        let page = render_page("test/tmp/ssi_if/page.shtml",
+                               "<!--#include file=\"open.shtml\" -->,<!--#endif -->").html;
+        let _ = remove_file("test/tmp/ssi_if/open.shtml");
+
+        assert_eq!(page, format!("open{0},{0}", DEFAULT_ERRMSG));
+    }
+
+    #[test]
+    fn echoes_request_variables() {
+        let template = "<!--#echo var=\"DOCUMENT_URI\" -->?<!--#echo var=\"QUERY_STRING\" --> \
+                        <!--#echo var=\"REMOTE_ADDR\" --> <!--#echo var=\"HTTP_USER_AGENT\" --> \
+                        <!--#echo var=\"VISITOR_COUNT\" -->";
+        let page = render(Path::new("test/ssi/page.shtml"), template, &Settings::default(), &visit());
+
+        assert_eq!(page.html, "/test/ssi/page.shtml?q=rust 10.0.0.7 curl/7.54 42");
+        assert_eq!(page.ttl, CacheTtl::Never);
+    }
+
+    #[test]
+    fn reads_query_parameters_as_variables() {
+        let mut env = visit();
+        env.request.params.push(("tag".to_string(), "a b".to_string()));
+        let template = "<!--#echo var=\"QUERY_PARAM_q\" -->,<!--#echo var=\"QUERY_PARAM_missing\" -->,\
+                        <!--#if expr=\"$QUERY_PARAM_tag = 'a b'\" -->tagged<!--#endif -->,\
+                        <!--#exec printenv QUERY_PARAM_tag -->";
+        let page = render(Path::new("test/ssi/page.shtml"), template, &Settings::default(), &env);
+
+        assert_eq!(page.html, "rust,(none),tagged,a b\n");
+        assert_eq!(page.ttl, CacheTtl::Never);
+    }
+
+    #[test]
+    fn pages_without_request_variables_stay_cacheable() {
+        let page = render(Path::new("test/ssi/page.shtml"),
+                          "<!--#set var=\"QUERY_STRING\" value=\"fixed\" --><!--#echo var=\"QUERY_STRING\" -->\
+                           <!--#echo var=\"DOCUMENT_NAME\" -->",
+                          &Settings::default(), &visit());
+
+        assert_eq!(page.html, "fixedpage.shtml");
+        assert_eq!(page.ttl, CacheTtl::Default);
+    }
+
+    #[test]
+    fn sets_variables_for_later_directives() {
+        let template = "<!--#set var=\"greeting\" value=\"hello ${HTTP_USER_AGENT}\" -->\
+                        <!--#if expr=\"$greeting = /curl/\" --><!--#echo var=\"greeting\" --><!--#endif -->\
+                        <!--#set var=\"greeting\" value=\"bye\" -->, <!--#echo var=\"greeting\" -->";
+        let page = render(Path::new("test/ssi/page.shtml"), template, &Settings::default(), &visit());
+
+        assert_eq!(page.html, "hello curl/7.54, bye");
+    }
+
+    #[test]
+    fn passes_variables_to_exec_as_environment() {
+        let template = "<!--#set var=\"color\" value=\"blue\" -->\
+                        <!--#exec printenv QUERY_STRING color DOCUMENT_NAME -->\
+                        <!--#set var=\"color\" value=\"red\" --><!--#exec printenv color -->";
+        let page = render(Path::new("test/ssi/page.shtml"), template, &Settings::default(), &visit());
+
+        assert_eq!(page.html, "q=rust\nblue\npage.shtml\nred\n");
+    }
+
+    #[test]
+    fn commands_naming_request_variables_keep_pages_uncached() {
+        let private = render(Path::new("test/ssi/page.shtml"), "<!--#cache ttl=\"60\" --><!--#exec printenv REMOTE_ADDR -->",
+                             &Settings::default(), &visit());
+        let params = render(Path::new("test/ssi/page.shtml"), "<!--#cache ttl=\"60\" --><!--#exec printenv QUERY_PARAM_q -->",
+                            &Settings::default(), &visit());
+        let shared = render(Path::new("test/ssi/page.shtml"), "<!--#cache ttl=\"60\" --><!--#exec printenv DOCUMENT_NAME -->",
+                            &Settings::default(), &visit());
+
+        assert_eq!((private.html, private.ttl), ("10.0.0.7\n".to_string(), CacheTtl::Never));
+        assert_eq!((params.html, params.ttl), ("rust\n".to_string(), CacheTtl::Never));
+        assert_eq!((shared.html, shared.ttl), ("page.shtml\n".to_string(), CacheTtl::For(Duration::from_secs(60))));
+    }
+
+    #[test]
+    fn renders_error_text_for_refused_commands() {
+        let sandbox = Sandbox { allow: vec!["echo".to_string()], error_text: "[refused]".to_string(), ..Sandbox::default() };
+        let settings = Settings { sandbox: sandbox, ..Settings::default() };
+        let page = render(Path::new("test/ssi/page.shtml"), "<!--#exec echo ok -->,<!--#exec cat /etc/passwd -->",
+                          &settings, &Environment::default());
+
+        assert_eq!(page.html, "ok\n,[refused]");
+    }
+
+    #[test]
+    fn encodes_echo_and_exec_output() {
+        let env = Environment {
+            request: RequestInfo { query: "q=<b>&x=1".to_string(), ..RequestInfo::default() },
+            visitor_count: 0
+        };
+        let template = "<!--#echo var=\"QUERY_STRING\" --> <!--#echo encoding=\"url\" var=\"QUERY_STRING\" --> \
+                        <!--#exec encoding=\"none\" echo <i> --><!--#exec echo <i> -->";
+        let page = |encoding| {
+            render(Path::new("test/ssi/page.shtml"), template, &Settings { encoding: encoding, ..Settings::default() }, &env).html
+        };
+
+        assert_eq!(page(Encoding::None), "q=<b>&x=1 q%3D%3Cb%3E%26x%3D1 <i>\n<i>\n");
+        assert_eq!(page(Encoding::Entity), "q=&lt;b&gt;&amp;x=1 q%3D%3Cb%3E%26x%3D1 <i>\n&lt;i&gt;\n");
+    }
+
+    #[test]
+    fn leaves_sandbox_error_text_unencoded() {
+        let sandbox = Sandbox { timeout: Duration::from_millis(100), error_text: "<b>slow</b>".to_string(), ..Sandbox::default() };
+        let settings = Settings { sandbox: sandbox, encoding: Encoding::Entity, ..Settings::default() };
+        let page = render(Path::new("test/ssi/page.shtml"), "<!--#exec sleep 1 -->", &settings, &Environment::default());
+
+        assert_eq!(page.html, "<b>slow</b>");
+    }
+
+    // Remembers when each write arrived.
+    struct Timed {
+        writes: Vec<(Instant, Vec<u8>)>
+    }
+
+    impl Write for Timed {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes.push((Instant::now(), buf.to_vec()));
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn writes_text_before_slow_commands_finish() {
+        let mut sink = Timed { writes: vec![] };
+        let started = Instant::now();
+        stream(Path::new("test/ssi/page.shtml"), b"<h1>head</h1><!--#exec sleep 0.3 --><p>tail</p>",
+               &Settings::default(), &Environment::default(), &mut sink).unwrap();
+
+        let (first_at, ref first) = sink.writes[0];
+        assert_eq!(&first[..], &b"<h1>head</h1>"[..]);
+        assert!(first_at - started < Duration::from_millis(200));
+        let rest = sink.writes[1..].iter().flat_map(|&(_, ref bytes)| bytes.clone()).collect::<Vec<u8>>();
+        assert_eq!(&rest[..], &b"<p>tail</p>"[..]);
+        assert!(started.elapsed() >= Duration::from_millis(300));
+    }
+
+    #[test]
+    fn passes_bytes_that_are_not_utf8_through() {
+        let page = super::render(Path::new("test/ssi/page.shtml"), b"caf\xe9 <!--#echo var=\"DOCUMENT_NAME\" --> \xff",
+                                 &Settings::default(), &Environment::default());
+
+        assert_eq!(&page.html[..], &b"caf\xe9 page.shtml \xff"[..]);
+    }
+}
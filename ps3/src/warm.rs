@@ -0,0 +1,260 @@
+use std::collections::{ HashMap, HashSet };
+use std::fs::{ self, File };
+use std::io::{ self, BufRead, BufReader, Write };
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Mutex };
+use std::thread;
+use std::time::{ Duration, Instant };
+use freshness::cache_key;
+use handler::valid_file_type;
+use loader::Loader;
+
+// Counts how often each file has been served, so that the most popular ones
+// can be written to a manifest at shutdown and loaded first at the next start.
+#[derive(Clone)]
+pub struct Popularity {
+    counts: Arc<Mutex<HashMap<PathBuf, usize>>>
+}
+
+impl Popularity {
+    pub fn new() -> Self {
+        Popularity { counts: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    pub fn record(&self, path: &Path) {
+        *self.counts.lock().unwrap().entry(path.to_path_buf()).or_insert(0) += 1;
+    }
+
+    // The `n` most requested files, most popular first.
+    pub fn top(&self, n: usize) -> Vec<(PathBuf, usize)> {
+        let mut counts = self.counts.lock().unwrap().iter()
+            .map(|(path, &count)| (path.clone(), count))
+            .collect::<Vec<(PathBuf, usize)>>();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts.truncate(n);
+        counts
+    }
+
+    // Written as `<count> <path>` lines, most popular first.
+    pub fn write_manifest(&self, manifest: &Path, n: usize) -> io::Result<()> {
+        let lines = self.top(n).iter()
+            .map(|&(ref path, count)| format!("{} {}\n", count, path.display()))
+            .collect::<String>();
+        let partial = manifest.with_extension("tmp");
+        File::create(&partial)
+            .and_then(|mut f| f.write_all(lines.as_bytes()))
+            .and_then(|_| fs::rename(&partial, manifest))
+    }
+}
+
+pub fn read_manifest(manifest: &Path) -> io::Result<Vec<PathBuf>> {
+    let lines = File::open(manifest)
+        .and_then(|f| BufReader::new(f).lines().collect::<io::Result<Vec<String>>>())?;
+    Ok(lines.into_iter()
+        .filter_map(|line| {
+            let mut fields = line.trim().splitn(2, ' ');
+            match (fields.next().and_then(|n| n.parse::<usize>().ok()), fields.next()) {
+                (Some(_), Some(path)) => Some(PathBuf::from(path)),
+                _ => None
+            }
+        })
+        .collect())
+}
+
+// Every servable file named by `pattern`: a file, a directory (walked
+// recursively) or a glob where `*` and `?` match within a path segment and
+// `**` matches any number of segments.
+pub fn expand(pattern: &str) -> Vec<PathBuf> {
+    if !pattern.contains(['*', '?']) {
+        let mut files = vec![];
+        walk(Path::new(pattern), &mut files);
+        return files;
+    }
+    let root = pattern.split('/')
+        .take_while(|segment| !segment.contains(['*', '?']))
+        .collect::<Vec<&str>>()
+        .join("/");
+    let mut files = vec![];
+    walk(Path::new(if root.is_empty() { "." } else { &root }), &mut files);
+    files.into_iter()
+        .filter(|file| {
+            let key = cache_key(file);
+            key.to_str().map(|name| glob_match(pattern, name)).unwrap_or(false)
+        })
+        .collect()
+}
+
+fn walk(path: &Path, files: &mut Vec<PathBuf>) {
+    match fs::metadata(path) {
+        Ok(ref data) if data.is_dir() => {
+            let mut entries = fs::read_dir(path)
+                .map(|entries| entries.filter_map(|e| e.ok()).map(|e| e.path()).collect::<Vec<PathBuf>>())
+                .unwrap_or_default();
+            entries.sort();
+            for entry in entries {
+                walk(&entry, files);
+            }
+        }
+        Ok(_) if valid_file_type(path) => files.push(cache_key(path)),
+        _ => ()
+    }
+}
+
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    matches(pattern.as_bytes(), name.as_bytes())
+}
+
+fn matches(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some(&b'*') if pattern.get(1) == Some(&b'*') => {
+            let rest = if pattern.get(2) == Some(&b'/') { &pattern[3..] } else { &pattern[2..] };
+            rest.is_empty() || (0..name.len() + 1)
+                .filter(|&i| i == 0 || name[i - 1] == b'/')
+                .any(|i| matches(rest, &name[i..]))
+        }
+        Some(&b'*') => {
+            let segment = name.iter().position(|&c| c == b'/').unwrap_or(name.len());
+            (0..segment + 1).any(|i| matches(&pattern[1..], &name[i..]))
+        }
+        Some(&b'?') => {
+            match name.first() {
+                Some(&c) if c != b'/' => matches(&pattern[1..], &name[1..]),
+                _ => false
+            }
+        }
+        Some(&c) => name.first() == Some(&c) && matches(&pattern[1..], &name[1..])
+    }
+}
+
+// Reads the files listed in `manifest` (most popular first), then those
+// named by `patterns`, into the cache on a background thread. At most `rate`
+// bytes are read per second so that warm-up does not starve requests that
+// arrive meanwhile.
+pub fn warm_up(loader: Loader, manifest: Option<PathBuf>, patterns: Vec<String>, rate: usize) -> thread::JoinHandle<usize> {
+    thread::spawn(move || {
+        let started = Instant::now();
+        let popular = manifest.and_then(|m| read_manifest(&m).ok()).unwrap_or_default();
+        let named = patterns.iter().flat_map(|pattern| expand(pattern));
+        let mut seen = HashSet::new();
+        let mut read = 0;
+        let mut files = 0;
+        for path in popular.into_iter().chain(named).filter(|path| seen.insert(path.clone())) {
+            if let Some(bytes) = loader.prefetch(&path) {
+                read += bytes;
+                files += 1;
+                throttle(started, read, rate);
+            }
+        }
+        println!("Cache warmed with {} files ({} bytes)", files, read);
+        files
+    })
+}
+
+fn throttle(started: Instant, read: usize, rate: usize) {
+    if rate == 0 {
+        return;
+    }
+    let due = Duration::from_millis((read as u64).saturating_mul(1000) / rate as u64);
+    let elapsed = started.elapsed();
+    if due > elapsed {
+        thread::sleep(due - elapsed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{ File, create_dir_all, remove_file };
+    use std::io::Write;
+    use std::path::{ Path, PathBuf };
+    use std::sync::Arc;
+    use std::time::{ Duration, Instant };
+    use cache::{ ShardedCache, Policy };
+    use loader::Loader;
+    use super::{ Popularity, read_manifest, expand, glob_match, warm_up };
+
+    #[test]
+    fn matches_globs_within_and_across_segments() {
+        assert!(glob_match("test/*.html", "test/small.html"));
+        assert!(!glob_match("test/*.html", "test/tmp/small.html"));
+        assert!(glob_match("test/**/*.html", "test/small.html"));
+        assert!(glob_match("test/**/*.html", "test/tmp/deep/small.html"));
+        assert!(glob_match("**", "anything/at/all"));
+        assert!(glob_match("test/?mall.html", "test/small.html"));
+        assert!(!glob_match("test/?mall.html", "test/mall.html"));
+        assert!(!glob_match("test/*.css", "test/small.html"));
+    }
+
+    #[test]
+    fn expands_files_directories_and_globs() {
+        let named = expand("test/response.html");
+        assert_eq!(named, vec![PathBuf::from("test/response.html")]);
+
+        let walked = expand("test");
+        assert!(walked.contains(&PathBuf::from("test/small.shtml")));
+        assert!(walked.contains(&PathBuf::from("test/pixel.png")));
+        assert!(!walked.contains(&PathBuf::from("test/passwords.txt")));
+
+        let globbed = expand("test/*.shtml");
+        assert!(globbed.contains(&PathBuf::from("test/small.shtml")));
+        assert!(globbed.iter().all(|p| p.extension().unwrap() == "shtml"));
+
+        assert!(expand("test/missing.html").is_empty());
+    }
+
+    #[test]
+    fn manifest_round_trips_most_popular_files() {
+        create_dir_all("test/tmp").unwrap();
+        let manifest = Path::new("test/tmp/popular.manifest");
+        let popularity = Popularity::new();
+        for _ in 0..3 {
+            popularity.record(Path::new("test/small.html"));
+        }
+        popularity.record(Path::new("test/medium.html"));
+        popularity.record(Path::new("test/response.html"));
+        popularity.record(Path::new("test/response.html"));
+
+        popularity.write_manifest(manifest, 2).unwrap();
+
+        assert_eq!(read_manifest(manifest).unwrap(),
+                   vec![PathBuf::from("test/small.html"), PathBuf::from("test/response.html")]);
+        remove_file(manifest).unwrap();
+    }
+
+    #[test]
+    fn manifest_skips_malformed_lines() {
+        create_dir_all("test/tmp").unwrap();
+        let manifest = Path::new("test/tmp/malformed.manifest");
+        File::create(manifest).unwrap().write_all(b"3 test/small.html\ngarbage\n\n1 test/a b.html\n").unwrap();
+
+        assert_eq!(read_manifest(manifest).unwrap(),
+                   vec![PathBuf::from("test/small.html"), PathBuf::from("test/a b.html")]);
+        remove_file(manifest).unwrap();
+    }
+
+    #[test]
+    fn manifest_that_cannot_be_read_is_an_error() {
+        create_dir_all("test/tmp/manifest.d").unwrap();
+
+        assert!(read_manifest(Path::new("test/tmp/manifest.d")).is_err());
+    }
+
+    #[test]
+    fn warms_cache_in_background_at_bounded_rate() {
+        create_dir_all("test/tmp").unwrap();
+        let manifest = Path::new("test/tmp/warm.manifest");
+        File::create(manifest).unwrap().write_all(b"9 test/response.html\n2 test/missing.html\n").unwrap();
+        let cache = Arc::new(ShardedCache::new(1 << 20, 1 << 16, Policy::Lru, 4));
+        let loader = Loader::new(cache.clone(), 1, Duration::from_secs(5));
+        let patterns = vec!["test/small.html".to_string(), "test/response.html".to_string()];
+        let started = Instant::now();
+
+        let warmed = warm_up(loader, Some(manifest.to_path_buf()), patterns, 200).join().unwrap();
+
+        assert_eq!(warmed, 2);
+        assert!(cache.contains(&PathBuf::from("test/response.html")));
+        assert!(cache.contains(&PathBuf::from("test/small.html")));
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        remove_file(manifest).unwrap();
+    }
+}
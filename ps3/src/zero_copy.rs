@@ -0,0 +1,173 @@
+use std::fs::File;
+use std::io::{ self, BufReader, Write, copy };
+use std::net::TcpStream;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::{ AsRawFd, RawFd };
+
+// Something a response can be written to. Sockets expose their descriptor so
+// that untransformed files can be handed to the kernel with sendfile(2).
+pub trait Sink: Write {
+    #[cfg(target_os = "linux")]
+    fn socket(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+impl Sink for TcpStream {
+    #[cfg(target_os = "linux")]
+    fn socket(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+impl Sink for Vec<u8> {}
+
+// Writes a file that is being served as-is. Files of at least `min` bytes go
+// through sendfile(2) when the sink is a socket; everything else, and any file
+// the kernel will not send, is copied through userspace.
+pub fn send_stream<T: Sink>(file: &mut BufReader<File>, sink: &mut T, min: Option<u64>) -> io::Result<u64> {
+    let len = file.get_ref().metadata().map(|data| data.len()).unwrap_or(0);
+    let eligible = file.buffer().is_empty() && min.map(|min| len >= min).unwrap_or(false);
+    if eligible {
+        if let Some(sent) = send_file(file.get_ref(), len, sink) {
+            return sent;
+        }
+    }
+    copy(file, sink)
+}
+
+// None if sendfile is unavailable for this sink, in which case nothing has
+// been written.
+#[cfg(target_os = "linux")]
+fn send_file<T: Sink>(file: &File, len: u64, sink: &mut T) -> Option<io::Result<u64>> {
+    let socket = sink.socket()?;
+    let mut offset: libc::off_t = 0;
+    while (offset as u64) < len {
+        let remaining = (len - offset as u64) as usize;
+        let sent = unsafe { libc::sendfile(socket, file.as_raw_fd(), &mut offset, remaining) };
+        if sent < 0 {
+            let error = io::Error::last_os_error();
+            match error.raw_os_error() {
+                Some(libc::EINTR) | Some(libc::EAGAIN) => continue,
+                Some(libc::EINVAL) | Some(libc::ENOSYS) if offset == 0 => return None,
+                _ => return Some(Err(error))
+            }
+        }
+        if sent == 0 {
+            break;
+        }
+    }
+    Some(Ok(offset as u64))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_file<T: Sink>(_file: &File, _len: u64, _sink: &mut T) -> Option<io::Result<u64>> {
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{ File, create_dir_all, remove_file };
+    use std::io::{ BufReader, Read, Write, copy, sink };
+    use std::net::{ TcpListener, TcpStream };
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Instant;
+    use super::send_stream;
+
+    fn write_fixture(path: &Path, len: usize) {
+        create_dir_all(path.parent().unwrap()).unwrap();
+        let contents = (0..len).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        File::create(path).unwrap().write_all(&contents).unwrap();
+    }
+
+    // A connected socket pair; the far end is drained on another thread which
+    // returns everything it received.
+    fn socket_pair() -> (TcpStream, thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let reader = thread::spawn(move || {
+            let mut received = Vec::new();
+            listener.accept().unwrap().0.read_to_end(&mut received).unwrap();
+            received
+        });
+        (TcpStream::connect(address).unwrap(), reader)
+    }
+
+    #[test]
+    fn sends_large_files_to_sockets_intact() {
+        let path = Path::new("test/tmp/zero_copy/large.bin");
+        write_fixture(path, 300_000);
+        let (mut socket, reader) = socket_pair();
+
+        let sent = send_stream(&mut BufReader::new(File::open(path).unwrap()), &mut socket, Some(1024)).unwrap();
+        drop(socket);
+        let received = reader.join().unwrap();
+
+        let mut expected = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut expected).unwrap();
+        let _ = remove_file(path);
+        assert_eq!(sent, 300_000);
+        assert!(received == expected, "socket received different bytes");
+    }
+
+    #[test]
+    fn copies_when_sink_is_not_a_socket_or_file_is_small() {
+        let path = Path::new("test/response.html");
+        let mut buffer: Vec<u8> = Vec::new();
+        assert_eq!(send_stream(&mut BufReader::new(File::open(path).unwrap()), &mut buffer, Some(0)).unwrap(), 23);
+        assert_eq!(buffer, b"<h1>Test Response</h1>\n".to_vec());
+
+        let (mut socket, reader) = socket_pair();
+        send_stream(&mut BufReader::new(File::open(path).unwrap()), &mut socket, Some(1 << 20)).unwrap();
+        drop(socket);
+        assert_eq!(reader.join().unwrap(), b"<h1>Test Response</h1>\n".to_vec());
+    }
+
+    // Compares the ways a file can reach a socket. Ignored by default; run with
+    //
+    //     cargo test --release -- --ignored --nocapture serving_paths
+    #[test]
+    #[ignore]
+    fn serving_paths() {
+        let path = Path::new("test/tmp/zero_copy/bench.bin");
+        let len = 64 << 20;
+        let rounds = 10;
+        write_fixture(path, len);
+        let mut cached = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut cached).unwrap();
+        let cached: Arc<[u8]> = Arc::from(cached);
+
+        let time = |name: &str, serve: &dyn Fn(&mut TcpStream)| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+            let reader = thread::spawn(move || {
+                let mut socket = listener.accept().unwrap().0;
+                copy(&mut socket, &mut sink()).unwrap()
+            });
+            let mut socket = TcpStream::connect(address).unwrap();
+            let started = Instant::now();
+            for _ in 0..rounds {
+                serve(&mut socket);
+            }
+            drop(socket);
+            let received = reader.join().unwrap();
+            let elapsed = started.elapsed();
+            let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+            println!("    {:10} {:8.1} MB/s", name, received as f64 / secs / (1 << 20) as f64);
+        };
+
+        println!("serving a {} MB file {} times", len >> 20, rounds);
+        time("copy", &|socket| {
+            send_stream(&mut BufReader::new(File::open(path).unwrap()), socket, None).unwrap();
+        });
+        time("sendfile", &|socket| {
+            send_stream(&mut BufReader::new(File::open(path).unwrap()), socket, Some(0)).unwrap();
+        });
+        time("cached", &|socket| {
+            socket.write_all(&cached).unwrap();
+        });
+        let _ = remove_file(path);
+    }
+}
@@ -0,0 +1,6 @@
+#!/bin/sh
+echo "Content-Type: text/plain"
+echo
+for name in GATEWAY_INTERFACE REQUEST_METHOD SCRIPT_NAME PATH_INFO QUERY_STRING CONTENT_LENGTH CONTENT_TYPE HTTP_X_GREETING; do
+    eval "echo $name=\$$name"
+done
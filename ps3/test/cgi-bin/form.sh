@@ -0,0 +1,6 @@
+#!/bin/sh
+echo "Status: 201 Created"
+echo "Content-Type: text/plain"
+echo "X-Script: form"
+echo
+echo "received $(cat)"